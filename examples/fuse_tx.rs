@@ -0,0 +1,68 @@
+//! 通过 FUSE 挂载 DBFS-T (事务引擎)
+//!
+//! 元数据与日志保存在 `--data-dir` 下的 `dbfs-t.db` 和 `dbfs-t.log` 中。
+
+#[cfg(feature = "dbfs_t")]
+mod dbfs_t {
+    use clap::Parser;
+    use dbfs2::fuse::txfs::DbfsTxFuse;
+    use fuser::MountOption;
+
+    #[derive(Parser, Debug)]
+    #[command(author, version, about, long_about = None)]
+    struct Args {
+        /// Mount point
+        #[arg(long)]
+        mount_point: String,
+        /// Directory holding the metadata database and the log file
+        #[arg(long)]
+        data_dir: String,
+        /// Size of the log file in MiB when it is created
+        #[arg(long, default_value_t = 256)]
+        log_size: u64,
+        /// Automatically unmount on process exit
+        #[arg(long)]
+        auto_unmount: bool,
+        /// Allow root user to access filesystem
+        #[arg(long)]
+        allow_other: bool,
+    }
+
+    pub fn main() {
+        let args = Args::parse();
+
+        let mut options = vec![MountOption::FSName("dbfs-t".to_string())];
+        if args.auto_unmount {
+            options.push(MountOption::AutoUnmount);
+        }
+        if args.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        options.push(MountOption::DefaultPermissions);
+        options.push(MountOption::RW);
+
+        let fs = match DbfsTxFuse::open(&args.data_dir, args.log_size * 1024 * 1024) {
+            Ok(fs) => fs,
+            Err(e) => {
+                eprintln!("Failed to open {}: {:?}", args.data_dir, e);
+                std::process::exit(1);
+            }
+        };
+
+        println!("Mount options: {:?}", options);
+        if let Err(e) = fuser::mount2(fs, args.mount_point, &options) {
+            eprintln!("Failed to mount FUSE filesystem: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "dbfs_t")]
+fn main() {
+    dbfs_t::main();
+}
+
+#[cfg(not(feature = "dbfs_t"))]
+fn main() {
+    eprintln!("fuse_tx requires the dbfs_t feature");
+}
@@ -20,12 +20,23 @@ use super::{dentry::DbfsDentry, superblock::DbfsSuperBlock};
 pub struct DbfsFsType {
     /// Database path (Phase 1: 暂不使用，为未来预留)
     _db_path: String,
+    /// 是否把目录树持久化到 jammdb
+    persistent: bool,
 }
 
 impl DbfsFsType {
     /// Create a new DBFS filesystem type
     pub fn new(db_path: String) -> Self {
-        Self { _db_path: db_path }
+        Self {
+            _db_path: db_path,
+            persistent: false,
+        }
+    }
+
+    /// 卸载时把目录树写入 jammdb, 挂载时重新加载 (需要先调用 `init_dbfs`)
+    pub fn with_persistence(mut self) -> Self {
+        self.persistent = true;
+        self
     }
 }
 
@@ -40,7 +51,7 @@ impl VfsFsType for DbfsFsType {
         info!("✓ DBFS: Mounting DBFS filesystem");
 
         // Create superblock
-        let sb = Arc::new(DbfsSuperBlock::new(self._db_path.clone()));
+        let sb = Arc::new(DbfsSuperBlock::new(self._db_path.clone(), self.persistent));
 
         // Create root inode
         let root_inode = sb.root_inode()?;
@@ -52,12 +63,9 @@ impl VfsFsType for DbfsFsType {
         Ok(root_dentry)
     }
 
-    fn kill_sb(
-        &self,
-        _sb: Arc<dyn vfscore::superblock::VfsSuperBlock>,
-    ) -> VfsResult<()> {
+    fn kill_sb(&self, sb: Arc<dyn vfscore::superblock::VfsSuperBlock>) -> VfsResult<()> {
         info!("✓ DBFS: Unmounting DBFS");
-        Ok(())
+        sb.sync_fs(true)
     }
 
     fn fs_flag(&self) -> FileSystemFlags {
@@ -88,10 +96,7 @@ impl VfsFsType for DummyFsType {
         Err(VfsError::NoSys)
     }
 
-    fn kill_sb(
-        &self,
-        _sb: Arc<dyn vfscore::superblock::VfsSuperBlock>,
-    ) -> VfsResult<()> {
+    fn kill_sb(&self, _sb: Arc<dyn vfscore::superblock::VfsSuperBlock>) -> VfsResult<()> {
         Ok(())
     }
 
@@ -10,11 +10,14 @@
 //! - ✅ write_at: 写入文件
 //! - ✅ unlink: 删除文件
 //! - ✅ rmdir: 删除目录
+//! - ✅ readdir: 列出目录项
+//! - ✅ truncate: 截断/扩展文件
+//! - ✅ rename_to: 重命名/跨目录移动
 //!
 //! ❌ 不实现: xattr, symlink, 权限检查
 
 use alloc::{collections::BTreeMap, string::String, string::ToString, sync::Arc, vec::Vec};
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 use vfscore::{
     error::VfsError,
@@ -22,18 +25,20 @@ use vfscore::{
     inode::{InodeAttr, VfsInode},
     superblock::VfsSuperBlock,
     utils::{
-        VfsDirEntry, VfsNodePerm, VfsNodeType, VfsRenameFlag, VfsTime, VfsTimeSpec,
-        VfsFileStat,
+        VfsDirEntry, VfsFileStat, VfsNodePerm, VfsNodeType, VfsRenameFlag, VfsTime, VfsTimeSpec,
     },
     VfsResult,
 };
 
 use super::superblock::DbfsSuperBlock;
+use crate::common::DbfsFileType;
 
 /// Inode 数据存储
 #[derive(Debug)]
 enum InodeData {
-    File { data: Vec<u8> },
+    File {
+        data: Vec<u8>,
+    },
     Directory {
         entries: BTreeMap<String, (u64, VfsNodeType)>, // name -> (ino, type)
     },
@@ -53,6 +58,12 @@ pub struct DbfsInode {
     perm: VfsNodePerm,
     /// 下一个可用的 inode 号 (全局)
     next_ino: Arc<AtomicU64>,
+    /// 最后修改时间
+    mtime: Mutex<VfsTimeSpec>,
+    /// 最后状态改变时间
+    ctime: Mutex<VfsTimeSpec>,
+    /// 上次同步之后是否修改过, 文件为内容, 目录为目录项; 同步只写入修改过的 inode
+    dirty: AtomicBool,
 }
 
 impl DbfsInode {
@@ -67,13 +78,17 @@ impl DbfsInode {
             }),
             perm: VfsNodePerm::from_bits_truncate(0o755),
             next_ino: Arc::new(AtomicU64::new(2)), // 下一个从 2 开始
+            mtime: Mutex::new(Self::current_time()),
+            ctime: Mutex::new(Self::current_time()),
+            // 新建的 inode 还没有写入过数据库
+            dirty: AtomicBool::new(true),
         })
     }
 
     /// Create a new inode
     fn new_inode(
         sb: Arc<DbfsSuperBlock>,
-        parent: &Arc<Self>,
+        parent: &Self,
         name: &str,
         type_: VfsNodeType,
     ) -> Arc<Self> {
@@ -99,14 +114,182 @@ impl DbfsInode {
             data: Mutex::new(data),
             perm,
             next_ino: parent.next_ino.clone(),
+            mtime: Mutex::new(Self::current_time()),
+            ctime: Mutex::new(Self::current_time()),
+            // 新建的 inode 还没有写入过数据库
+            dirty: AtomicBool::new(true),
+        })
+    }
+
+    /// Get inode number
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// 取出并清除修改标记
+    pub(super) fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    /// 重新标记为已修改, 用于同步失败后重试
+    pub(super) fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// 持久化时使用的类型字节, 与 rvfs2 目录项的类型字节相同
+    fn type_tag(ty: VfsNodeType) -> u8 {
+        match ty {
+            VfsNodeType::Dir => DbfsFileType::Directory,
+            VfsNodeType::SymLink => DbfsFileType::Symlink,
+            VfsNodeType::CharDevice => DbfsFileType::CharDevice,
+            VfsNodeType::BlockDevice => DbfsFileType::BlockDevice,
+            VfsNodeType::Fifo => DbfsFileType::NamedPipe,
+            VfsNodeType::Socket => DbfsFileType::Socket,
+            _ => DbfsFileType::RegularFile,
+        }
+        .tag()
+    }
+
+    /// [`type_tag`](Self::type_tag) 的逆映射, 未知的类型字节返回 `None`
+    fn type_from_tag(tag: u8) -> Option<VfsNodeType> {
+        Some(match DbfsFileType::from_tag(tag)? {
+            DbfsFileType::Directory => VfsNodeType::Dir,
+            DbfsFileType::RegularFile => VfsNodeType::File,
+            DbfsFileType::Symlink => VfsNodeType::SymLink,
+            DbfsFileType::CharDevice => VfsNodeType::CharDevice,
+            DbfsFileType::BlockDevice => VfsNodeType::BlockDevice,
+            DbfsFileType::NamedPipe => VfsNodeType::Fifo,
+            DbfsFileType::Socket => VfsNodeType::Socket,
         })
     }
 
+    /// 目录中所有子项的 inode 号, 文件返回空
+    pub(super) fn children(&self) -> Vec<u64> {
+        match &*self.data.lock() {
+            InodeData::File { .. } => Vec::new(),
+            InodeData::Directory { entries } => entries.values().map(|&(ino, _)| ino).collect(),
+        }
+    }
+
+    /// 序列化 inode 内容, 用于持久化到 jammdb
+    ///
+    /// 格式: 类型字节 ([`DbfsFileType::tag`]) + 内容。目录内容为若干
+    /// `[ino: u64][类型: u8][名字长度: u16][名字]`, 其他类型的内容为原始数据。
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = alloc::vec![Self::type_tag(self.inode_type)];
+        match &*self.data.lock() {
+            InodeData::File { data } => buf.extend_from_slice(data),
+            InodeData::Directory { entries } => {
+                for (name, &(ino, ty)) in entries {
+                    buf.extend_from_slice(&ino.to_be_bytes());
+                    buf.push(Self::type_tag(ty));
+                    buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                    buf.extend_from_slice(name.as_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    /// 从 [`encode`](Self::encode) 的结果重建 inode
+    pub(super) fn decode(
+        sb: Arc<DbfsSuperBlock>,
+        ino: u64,
+        bytes: &[u8],
+        next_ino: Arc<AtomicU64>,
+    ) -> VfsResult<Arc<Self>> {
+        let (&kind, mut rest) = bytes.split_first().ok_or(VfsError::Invalid)?;
+        let inode_type = Self::type_from_tag(kind).ok_or(VfsError::Invalid)?;
+        let data = match inode_type {
+            VfsNodeType::Dir => {
+                let mut entries = BTreeMap::new();
+                while !rest.is_empty() {
+                    if rest.len() < 11 {
+                        return Err(VfsError::Invalid);
+                    }
+                    let child = u64::from_be_bytes(rest[..8].try_into().unwrap());
+                    let ty = Self::type_from_tag(rest[8]).ok_or(VfsError::Invalid)?;
+                    let name_len = u16::from_be_bytes(rest[9..11].try_into().unwrap()) as usize;
+                    let name = rest.get(11..11 + name_len).ok_or(VfsError::Invalid)?;
+                    let name = core::str::from_utf8(name).map_err(|_| VfsError::Invalid)?;
+                    entries.insert(name.to_string(), (child, ty));
+                    rest = &rest[11 + name_len..];
+                }
+                InodeData::Directory { entries }
+            }
+            _ => InodeData::File {
+                data: rest.to_vec(),
+            },
+        };
+
+        let perm = match inode_type {
+            VfsNodeType::Dir => VfsNodePerm::from_bits_truncate(0o755),
+            _ => VfsNodePerm::from_bits_truncate(0o644),
+        };
+
+        Ok(Arc::new(Self {
+            sb,
+            ino,
+            inode_type,
+            data: Mutex::new(data),
+            perm,
+            next_ino,
+            mtime: Mutex::new(Self::current_time()),
+            ctime: Mutex::new(Self::current_time()),
+            dirty: AtomicBool::new(false),
+        }))
+    }
+
     /// Get current time (simplified)
     fn current_time() -> VfsTimeSpec {
         VfsTimeSpec::default()
     }
 
+    /// 查找目录项, 本 inode 不是目录时返回 `NotDir`
+    fn entry(&self, name: &str) -> VfsResult<(u64, VfsNodeType)> {
+        match &*self.data.lock() {
+            InodeData::Directory { entries } => entries.get(name).copied().ok_or(VfsError::NoEntry),
+            InodeData::File { .. } => Err(VfsError::NotDir),
+        }
+    }
+
+    /// 检查移动会不会把目录移到自己下面, 以及被覆盖的目录是否为空
+    ///
+    /// 需要查看其他 inode 的目录项, 所以在给两个父目录加锁之前调用;
+    /// 加锁之后仍由 [`Self::check_replace`] 检查类型。
+    fn check_move(&self, old_name: &str, new_parent: &Self, new_name: &str) -> VfsResult<()> {
+        let (ino, ty) = self.entry(old_name)?;
+        if ty == VfsNodeType::Dir {
+            // 从目标目录沿父目录向上走到根, 途中遇到被移动的目录就是移到了自己下面
+            let mut cur = Some(new_parent.ino);
+            while let Some(dir) = cur {
+                if dir == ino {
+                    return Err(VfsError::Invalid);
+                }
+                cur = self.sb.parent_of(dir);
+            }
+        }
+        match new_parent.entry(new_name) {
+            Ok((target, VfsNodeType::Dir)) if target != ino && !self.sb.is_empty_dir(target) => {
+                Err(VfsError::NotEmpty)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 检查 `ty` 类型的目录项能否覆盖已有的目标项
+    ///
+    /// 被覆盖的目录是否为空由 [`Self::check_move`] 在加锁前检查
+    fn check_replace(target: Option<&(u64, VfsNodeType)>, ty: VfsNodeType) -> VfsResult<()> {
+        match target {
+            Some(&(_, VfsNodeType::Dir)) if ty != VfsNodeType::Dir => Err(VfsError::IsDir),
+            Some(&(_, target_ty)) if target_ty != VfsNodeType::Dir && ty == VfsNodeType::Dir => {
+                Err(VfsError::NotDir)
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Get file size
     fn get_size(&self) -> usize {
         match &*self.data.lock() {
@@ -147,12 +330,14 @@ impl VfsInode for DbfsInode {
 
         // Create new inode
         let new_inode = Self::new_inode(self.sb.clone(), self, name, ty);
+        self.sb.insert_inode(new_inode.clone());
 
         // Insert into parent
         let mut data = self.data.lock();
         if let InodeData::Directory { ref mut entries } = &mut *data {
             entries.insert(name.to_string(), (new_inode.ino, ty));
         }
+        self.dirty.store(true, Ordering::Release);
 
         Ok(new_inode as Arc<dyn VfsInode>)
     }
@@ -172,17 +357,14 @@ impl VfsInode for DbfsInode {
 
         let mut data = self.data.lock();
         if let InodeData::Directory { ref mut entries } = &mut *data {
-            entries.remove(name)
-                .ok_or(VfsError::NoEntry)?;
+            let (ino, _) = entries.remove(name).ok_or(VfsError::NoEntry)?;
+            self.sb.remove_inode(ino);
         }
+        self.dirty.store(true, Ordering::Release);
         Ok(())
     }
 
-    fn symlink(
-        &self,
-        _name: &str,
-        _sy_name: &str,
-    ) -> VfsResult<Arc<dyn VfsInode>> {
+    fn symlink(&self, _name: &str, _sy_name: &str) -> VfsResult<Arc<dyn VfsInode>> {
         Err(VfsError::NoSys)
     }
 
@@ -199,15 +381,16 @@ impl VfsInode for DbfsInode {
                 ino: self.ino,
                 inode_type: self.inode_type,
                 data: Mutex::new(match &*self.data.lock() {
-                    InodeData::File { data } => InodeData::File {
-                        data: data.clone(),
-                    },
+                    InodeData::File { data } => InodeData::File { data: data.clone() },
                     InodeData::Directory { entries } => InodeData::Directory {
                         entries: entries.clone(),
                     },
                 }),
                 perm: self.perm,
                 next_ino: self.next_ino.clone(),
+                mtime: Mutex::new(*self.mtime.lock()),
+                ctime: Mutex::new(*self.ctime.lock()),
+                dirty: AtomicBool::new(false),
             }) as Arc<dyn VfsInode>);
         }
 
@@ -215,6 +398,10 @@ impl VfsInode for DbfsInode {
         let data = self.data.lock();
         if let InodeData::Directory { ref entries } = &*data {
             if let Some(&(ino, type_)) = entries.get(name) {
+                if let Some(inode) = self.sb.get_inode(ino) {
+                    return Ok(inode as Arc<dyn VfsInode>);
+                }
+
                 // Phase 1: 简化实现，创建一个临时 inode
                 // 实际需要从全局 inode 表中查找
                 let new_data = match type_ {
@@ -238,6 +425,9 @@ impl VfsInode for DbfsInode {
                     data: Mutex::new(new_data),
                     perm,
                     next_ino: Arc::new(AtomicU64::new(0)),
+                    mtime: Mutex::new(Self::current_time()),
+                    ctime: Mutex::new(Self::current_time()),
+                    dirty: AtomicBool::new(false),
                 }) as Arc<dyn VfsInode>);
             }
         }
@@ -246,7 +436,20 @@ impl VfsInode for DbfsInode {
     }
 
     fn rmdir(&self, name: &str) -> VfsResult<()> {
-        // Phase 1: 简化实现，不检查目录是否为空
+        if self.inode_type != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        if name == "." || name == ".." {
+            return Err(VfsError::EExist);
+        }
+
+        let (ino, ty) = self.entry(name)?;
+        if ty != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        if !self.sb.is_empty_dir(ino) {
+            return Err(VfsError::NotEmpty);
+        }
         self.unlink(name)
     }
 
@@ -270,8 +473,8 @@ impl VfsInode for DbfsInode {
             st_ino: self.ino,
             st_rdev: 0,
             st_atim: Self::current_time(),
-            st_mtim: Self::current_time(),
-            st_ctim: Self::current_time(),
+            st_mtim: *self.mtime.lock(),
+            st_ctim: *self.ctime.lock(),
             st_blksize: 4096,
             st_flags: 0,
         })
@@ -285,18 +488,95 @@ impl VfsInode for DbfsInode {
         self.inode_type
     }
 
-    fn truncate(&self, _len: u64) -> VfsResult<()> {
-        Err(VfsError::NoSys)
+    fn truncate(&self, len: u64) -> VfsResult<()> {
+        let mut data = self.data.lock();
+        match &mut *data {
+            // 缩小时丢弃尾部, 扩展时补零
+            InodeData::File { ref mut data } => data.resize(len as usize, 0),
+            InodeData::Directory { .. } => return Err(VfsError::IsDir),
+        }
+        drop(data);
+        self.dirty.store(true, Ordering::Release);
+
+        let now = Self::current_time();
+        *self.mtime.lock() = now;
+        *self.ctime.lock() = now;
+        Ok(())
     }
 
     fn rename_to(
         &self,
-        _old_name: &str,
-        _new_parent: Arc<dyn VfsInode>,
-        _new_name: &str,
+        old_name: &str,
+        new_parent: Arc<dyn VfsInode>,
+        new_name: &str,
         _flag: VfsRenameFlag,
     ) -> VfsResult<()> {
-        Err(VfsError::NoSys)
+        if self.inode_type != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        if [old_name, new_name].iter().any(|&n| n == "." || n == "..") {
+            return Err(VfsError::Invalid);
+        }
+
+        let new_parent = new_parent
+            .downcast_arc::<DbfsInode>()
+            .map_err(|_| VfsError::Invalid)?;
+        if new_parent.inode_type != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        if old_name != new_name || new_parent.ino != self.ino {
+            self.check_move(old_name, &new_parent, new_name)?;
+        }
+
+        // 同一目录内重命名
+        if new_parent.ino == self.ino {
+            let mut data = self.data.lock();
+            let entries = match &mut *data {
+                InodeData::Directory { entries } => entries,
+                _ => return Err(VfsError::NotDir),
+            };
+            let entry = *entries.get(old_name).ok_or(VfsError::NoEntry)?;
+            if old_name == new_name {
+                return Ok(());
+            }
+            Self::check_replace(entries.get(new_name), entry.1)?;
+            entries.remove(old_name);
+            if let Some((replaced, _)) = entries.insert(new_name.to_string(), entry) {
+                self.sb.remove_inode(replaced);
+            }
+            self.dirty.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        // 跨目录移动: 按 inode 号顺序加锁, 避免相反方向的移动互相死锁
+        let (mut old_data, mut new_data) = if self.ino < new_parent.ino {
+            let old_data = self.data.lock();
+            (old_data, new_parent.data.lock())
+        } else {
+            let new_data = new_parent.data.lock();
+            (self.data.lock(), new_data)
+        };
+        let (old_entries, new_entries) = match (&mut *old_data, &mut *new_data) {
+            (
+                InodeData::Directory {
+                    entries: old_entries,
+                },
+                InodeData::Directory {
+                    entries: new_entries,
+                },
+            ) => (old_entries, new_entries),
+            _ => return Err(VfsError::NotDir),
+        };
+
+        let entry = *old_entries.get(old_name).ok_or(VfsError::NoEntry)?;
+        Self::check_replace(new_entries.get(new_name), entry.1)?;
+        old_entries.remove(old_name);
+        if let Some((replaced, _)) = new_entries.insert(new_name.to_string(), entry) {
+            self.sb.remove_inode(replaced);
+        }
+        self.dirty.store(true, Ordering::Release);
+        new_parent.dirty.store(true, Ordering::Release);
+        Ok(())
     }
 
     fn update_time(&self, _time: VfsTime, _now: VfsTimeSpec) -> VfsResult<()> {
@@ -306,8 +586,10 @@ impl VfsInode for DbfsInode {
 
 impl VfsFile for DbfsInode {
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
-        if self.inode_type != VfsNodeType::File {
-            return Err(VfsError::IsDir);
+        match self.inode_type {
+            VfsNodeType::File => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
         }
 
         let data = self.data.lock();
@@ -327,21 +609,25 @@ impl VfsFile for DbfsInode {
     }
 
     fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
-        if self.inode_type != VfsNodeType::File {
-            return Err(VfsError::IsDir);
+        match self.inode_type {
+            VfsNodeType::File => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
         }
 
         let mut data = self.data.lock();
         if let InodeData::File { ref mut data } = &mut *data {
-            let start = offset as usize;
+            let start = usize::try_from(offset).map_err(|_| VfsError::Invalid)?;
+            let end = start.checked_add(buf.len()).ok_or(VfsError::Invalid)?;
 
             // Extend if necessary
-            if start + buf.len() > data.len() {
-                data.resize(start + buf.len(), 0);
+            if end > data.len() {
+                data.resize(end, 0);
             }
 
             // Write data
-            data[start..start + buf.len()].copy_from_slice(buf);
+            data[start..end].copy_from_slice(buf);
+            self.dirty.store(true, Ordering::Release);
 
             Ok(buf.len())
         } else {
@@ -349,11 +635,51 @@ impl VfsFile for DbfsInode {
         }
     }
 
+    fn readdir(&self, start_index: usize) -> VfsResult<Option<VfsDirEntry>> {
+        if self.inode_type != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+
+        // 索引 0 和 1 是合成的 . 和 ..
+        // Phase 1: 不记录父目录, 与 lookup 一致, .. 指向自身
+        if start_index < 2 {
+            let name = if start_index == 0 { "." } else { ".." };
+            return Ok(Some(VfsDirEntry {
+                ino: self.ino,
+                ty: VfsNodeType::Dir,
+                name: name.to_string(),
+            }));
+        }
+
+        let data = self.data.lock();
+        if let InodeData::Directory { ref entries } = &*data {
+            // BTreeMap 按名字排序, 保证遍历顺序稳定
+            Ok(entries
+                .iter()
+                .nth(start_index - 2)
+                .map(|(name, &(ino, ty))| VfsDirEntry {
+                    ino,
+                    ty,
+                    name: name.clone(),
+                }))
+        } else {
+            Err(VfsError::NotDir)
+        }
+    }
+
+    /// 有未同步的修改时把修改过的 inode 写入数据库 (非持久化挂载时无需写入), 没有修改时直接返回
     fn flush(&self) -> VfsResult<()> {
-        Ok(())
+        if !self.dirty.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.sb.sync_fs(true)
     }
 
-    fn fsync(&self, _datasync: bool) -> VfsResult<()> {
-        Ok(())
+    /// `datasync` 时只写入本 inode 的内容, 否则连同目录结构写入所有修改
+    fn fsync(&self, datasync: bool) -> VfsResult<()> {
+        if datasync {
+            return self.sb.sync_data(self.ino);
+        }
+        self.sb.sync_fs(true)
     }
 }
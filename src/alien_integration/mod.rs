@@ -7,6 +7,7 @@
 //! - ✅ 支持基本的 inode 操作: lookup, create, mkdir, read_at, write_at, unlink
 //! - ✅ 支持基本的 dentry 操作: insert, remove, parent
 //! - ✅ 可以像 ramfs 一样使用
+//! - ✅ 可选持久化: `DbfsFsType::with_persistence` 在卸载时把目录树写入 jammdb
 //!
 //! ❌ 不实现: BEGIN / COMMIT / 事务性 / ioctl
 
@@ -1,9 +1,23 @@
 //! DBFS SuperBlock for Alien Integration
 //!
 //! Phase 1: 最小化实现，为事务预留结构
+//!
+//! 可选持久化: 修改过的 inode 在 `sync_fs` 时写入 jammdb, 挂载时重新加载。
+//! 读写仍然走内存中的 inode, 数据库只在同步和挂载时访问。
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    string::ToString,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::AtomicU64;
 
-use alloc::{string::String, string::ToString, sync::Arc};
+use spin::Mutex;
 use vfscore::{
+    error::VfsError,
     fstype::VfsFsType,
     superblock::{SuperType, VfsSuperBlock},
     utils::VfsFsStat,
@@ -11,47 +25,200 @@ use vfscore::{
 };
 
 use super::{fstype::DummyFsType, inode::DbfsInode};
+use crate::try_clone_db;
+
+/// 持久化目录树使用的 bucket (ino -> 序列化的 inode)
+const ALIEN_INODES_BUCKET: &str = "alien_inodes";
 
 /// DBFS SuperBlock
 ///
 /// Phase 1: 简化实现，使用内存存储
-/// 未来: 可以添加真实数据库支持
+/// 开启持久化后, 内存中的目录树会同步到全局 jammdb 数据库
 pub struct DbfsSuperBlock {
     /// Block size (固定 4KB)
     block_size: u64,
     /// 文件系统类型引用 (用于 fs_type())
     db_path: String,
+    /// 是否把目录树持久化到 jammdb
+    persistent: bool,
+    /// inode 表 (ino -> inode)
+    inodes: Mutex<BTreeMap<u64, Arc<DbfsInode>>>,
+    /// 上次同步之后移除的 inode, 下次同步时从数据库删除
+    removed: Mutex<BTreeSet<u64>>,
 }
 
 impl DbfsSuperBlock {
     /// Create a new superblock
-    pub fn new(db_path: String) -> Self {
+    pub fn new(db_path: String, persistent: bool) -> Self {
         Self {
             block_size: 4096,
             db_path,
+            persistent,
+            inodes: Mutex::new(BTreeMap::new()),
+            removed: Mutex::new(BTreeSet::new()),
         }
     }
 
     /// Create root inode
+    ///
+    /// 开启持久化时优先从数据库加载已有的目录树
     pub fn root_inode(self: &Arc<Self>) -> VfsResult<Arc<dyn vfscore::inode::VfsInode>> {
-        Ok(DbfsInode::new_root(self.clone()))
+        if let Some(root) = self.get_inode(1) {
+            return Ok(root);
+        }
+
+        let loaded = if self.persistent { self.load()? } else { None };
+        let root = loaded.unwrap_or_else(|| DbfsInode::new_root(self.clone()));
+        self.insert_inode(root.clone());
+        Ok(root)
+    }
+
+    /// 把 inode 加入 inode 表
+    pub fn insert_inode(&self, inode: Arc<DbfsInode>) {
+        self.inodes.lock().insert(inode.ino(), inode);
+    }
+
+    /// 从 inode 表中查找 inode
+    pub fn get_inode(&self, ino: u64) -> Option<Arc<DbfsInode>> {
+        self.inodes.lock().get(&ino).cloned()
+    }
+
+    /// 查找目录项中包含 `ino` 的目录, 根目录和不在 inode 表中的 inode 返回 `None`
+    pub fn parent_of(&self, ino: u64) -> Option<u64> {
+        // 先复制 inode 表再逐个加锁查看, 不在持有表锁时锁 inode
+        let inodes: Vec<_> = self.inodes.lock().values().cloned().collect();
+        inodes
+            .into_iter()
+            .find(|dir| dir.children().contains(&ino))
+            .map(|dir| dir.ino())
+    }
+
+    /// 目录是否没有任何目录项, 不在 inode 表中的 inode 视为空
+    pub fn is_empty_dir(&self, ino: u64) -> bool {
+        self.get_inode(ino)
+            .map_or(true, |inode| inode.children().is_empty())
+    }
+
+    /// 从 inode 表中移除 inode, 目录连同其下的所有 inode 一起移除
+    pub fn remove_inode(&self, ino: u64) {
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            if let Some(inode) = self.inodes.lock().remove(&ino) {
+                pending.extend(inode.children());
+            }
+            self.removed.lock().insert(ino);
+        }
+    }
+
+    /// 从 jammdb 重建目录树, 数据库中没有时返回 `None`
+    fn load(self: &Arc<Self>) -> VfsResult<Option<Arc<DbfsInode>>> {
+        let db = try_clone_db()?;
+        let tx = db.tx(false).map_err(|_| VfsError::IoError)?;
+        let bucket = match tx.get_bucket(ALIEN_INODES_BUCKET) {
+            Ok(bucket) => bucket,
+            Err(_) => return Ok(None),
+        };
+
+        let mut records = Vec::new();
+        for kv in bucket.kv_pairs() {
+            let ino = u64::from_be_bytes(kv.key().try_into().map_err(|_| VfsError::Invalid)?);
+            records.push((ino, kv.value().to_vec()));
+        }
+
+        let max_ino = records.iter().map(|&(ino, _)| ino).max().unwrap_or(1);
+        let next_ino = Arc::new(AtomicU64::new(max_ino + 1));
+        for (ino, value) in records {
+            let inode = DbfsInode::decode(self.clone(), ino, &value, next_ino.clone())?;
+            self.insert_inode(inode);
+        }
+        Ok(self.get_inode(1))
+    }
+
+    /// 把从根可达且修改过的 inode 写入 jammdb, 并删除上次同步之后移除的 inode
+    ///
+    /// 写入失败时修改标记和移除记录都恢复原样, 下次同步重试。
+    fn flush(&self) -> VfsResult<()> {
+        let root = match self.get_inode(1) {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+
+        let mut changed = Vec::new();
+        let mut stack = vec![root];
+        while let Some(inode) = stack.pop() {
+            stack.extend(
+                inode
+                    .children()
+                    .into_iter()
+                    .filter_map(|ino| self.get_inode(ino)),
+            );
+            if inode.take_dirty() {
+                changed.push(inode);
+            }
+        }
+        let removed = core::mem::take(&mut *self.removed.lock());
+
+        let result = Self::write(&changed, &removed);
+        if result.is_err() {
+            changed.iter().for_each(|inode| inode.mark_dirty());
+            self.removed.lock().extend(removed);
+        }
+        result
+    }
+
+    /// 只写入 `ino` 自己的内容, 不涉及目录结构, 用于 `fdatasync`
+    pub fn sync_data(&self, ino: u64) -> VfsResult<()> {
+        if !self.persistent {
+            return Ok(());
+        }
+        let inode = match self.get_inode(ino) {
+            Some(inode) if inode.take_dirty() => inode,
+            _ => return Ok(()),
+        };
+        let changed = [inode];
+        Self::write(&changed, &BTreeSet::new()).inspect_err(|_| changed[0].mark_dirty())
+    }
+
+    /// 在一个事务中写入 `changed` 并删除 `removed`
+    fn write(changed: &[Arc<DbfsInode>], removed: &BTreeSet<u64>) -> VfsResult<()> {
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        let db = try_clone_db()?;
+        let tx = db.tx(true).map_err(|_| VfsError::IoError)?;
+        let bucket = tx
+            .get_or_create_bucket(ALIEN_INODES_BUCKET)
+            .map_err(|_| VfsError::IoError)?;
+        for ino in removed {
+            let _ = bucket.delete(ino.to_be_bytes());
+        }
+        for inode in changed {
+            bucket
+                .put(inode.ino().to_be_bytes(), inode.encode())
+                .map_err(|_| VfsError::IoError)?;
+        }
+        tx.commit().map_err(|_| VfsError::IoError)?;
+        Ok(())
     }
 }
 
 impl VfsSuperBlock for DbfsSuperBlock {
     fn sync_fs(&self, _wait: bool) -> VfsResult<()> {
-        // Phase 1: 同步操作，无需实际 sync
-        Ok(())
+        // 纯内存模式无需 sync
+        if !self.persistent {
+            return Ok(());
+        }
+        self.flush()
     }
 
     fn stat_fs(&self) -> VfsResult<VfsFsStat> {
         Ok(VfsFsStat {
             f_bsize: self.block_size as i64,
             f_frsize: self.block_size as i64,
-            f_blocks: 1024,    // 假设 4MB 空间
+            f_blocks: 1024, // 假设 4MB 空间
             f_bfree: 512,
             f_bavail: 512,
-            f_files: 100,      // 最多 100 个 inode
+            f_files: 100, // 最多 100 个 inode
             f_ffree: 50,
             f_favail: 50,
             f_fsid: 0x44424653, // "DBFS"
@@ -73,9 +240,11 @@ impl VfsSuperBlock for DbfsSuperBlock {
     }
 
     fn root_inode(&self) -> VfsResult<Arc<dyn vfscore::inode::VfsInode>> {
-        // Phase 1: 创建一个新的 root inode
-        // 注意: 每次调用都会创建新的，这是一个简化实现
-        let dummy_sb = Arc::new(DbfsSuperBlock::new(self.db_path.clone()));
-        Ok(DbfsInode::new_root(dummy_sb))
+        // 根 inode 在挂载时由 `DbfsSuperBlock::root_inode` 放入 inode 表, 每次都返回同一个;
+        // 这里只有 `&self`, 还没有挂载时无法创建
+        match self.get_inode(1) {
+            Some(root) => Ok(root),
+            None => Err(VfsError::Invalid),
+        }
     }
 }
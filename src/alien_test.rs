@@ -0,0 +1,477 @@
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+
+use jammdb::{
+    memfile::{FakeMap, FileOpenOptions},
+    DB,
+};
+use spin::{Mutex, Once};
+use vfscore::{
+    error::VfsError,
+    fstype::VfsFsType,
+    inode::VfsInode,
+    utils::{VfsNodePerm, VfsNodeType, VfsRenameFlag},
+};
+
+use crate::alien_integration::DbfsFsType;
+
+/// 挂载一个新的 alien DBFS 并返回根 inode
+fn mount() -> Arc<dyn VfsInode> {
+    let fs_type = Arc::new(DbfsFsType::new("alien-test.db".to_string()));
+    let root_dentry = fs_type.mount(0, "/", None, &[]).expect("Mount failed");
+    root_dentry.inode().expect("Get root inode failed")
+}
+
+/// 持久化的测试共用同一个数据库, 依次运行
+static PERSIST_LOCK: Mutex<()> = Mutex::new(());
+
+/// 以持久化模式挂载, 目录树保存在全局数据库中
+fn mount_persistent() -> Arc<dyn VfsInode> {
+    static INIT: Once<()> = Once::new();
+    INIT.call_once(|| {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "alien-test.db").unwrap();
+        crate::init_dbfs(db);
+    });
+
+    let fs_type = Arc::new(DbfsFsType::new("alien-test.db".to_string()).with_persistence());
+    let root_dentry = fs_type.mount(0, "/", None, &[]).expect("Mount failed");
+    root_dentry.inode().expect("Get root inode failed")
+}
+
+/// 卸载 `root` 所在的文件系统
+fn unmount(root: &Arc<dyn VfsInode>) {
+    let sb = root.get_super_block().expect("Get super block failed");
+    let fs_type = DbfsFsType::new("alien-test.db".to_string()).with_persistence();
+    fs_type.kill_sb(sb).expect("Unmount failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readdir_types() {
+        let root = mount();
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+        root.create("b_file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        root.create("a_dir", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        root.create("c_file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+
+        let mut entries = Vec::new();
+        let mut idx = 0;
+        while let Some(entry) = root.readdir(idx).expect("Readdir failed") {
+            entries.push((entry.name, entry.ty));
+            idx += 1;
+        }
+
+        // . 和 .. 在最前面, 其余按名字排序
+        assert_eq!(
+            entries,
+            [
+                (".".to_string(), VfsNodeType::Dir),
+                ("..".to_string(), VfsNodeType::Dir),
+                ("a_dir".to_string(), VfsNodeType::Dir),
+                ("b_file".to_string(), VfsNodeType::File),
+                ("c_file".to_string(), VfsNodeType::File),
+            ]
+        );
+        assert!(root.readdir(idx).expect("Readdir failed").is_none());
+    }
+
+    #[test]
+    fn test_truncate_shrink() {
+        let root = mount();
+        let file = root
+            .create(
+                "shrink",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        file.write_at(0, b"hello world").expect("Write failed");
+
+        file.truncate(5).expect("Truncate failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 5);
+
+        // 新长度之后的数据被丢弃
+        let mut buf = [0u8; 16];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"hello");
+    }
+
+    #[test]
+    fn test_truncate_extend() {
+        let root = mount();
+        let file = root
+            .create(
+                "extend",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        file.write_at(0, b"abc").expect("Write failed");
+
+        file.truncate(8).expect("Truncate failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 8);
+
+        // 扩展的部分补零
+        let mut buf = [0xffu8; 8];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(n, 8);
+        assert_eq!(&buf, b"abc\0\0\0\0\0");
+
+        let dir = root
+            .create(
+                "extend_dir",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create dir failed");
+        assert_eq!(dir.truncate(0).err(), Some(VfsError::IsDir));
+    }
+
+    #[test]
+    fn test_rename_same_dir() {
+        let root = mount();
+        let file = root
+            .create(
+                "old_name",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino;
+
+        root.rename_to("old_name", root.clone(), "new_name", VfsRenameFlag::empty())
+            .expect("Rename failed");
+        assert_eq!(root.lookup("old_name").err(), Some(VfsError::NoEntry));
+        let found = root.lookup("new_name").expect("Lookup renamed file failed");
+        assert_eq!(found.get_attr().expect("Get attr failed").st_ino, ino);
+
+        // 不能重命名 . 和 ..
+        assert_eq!(
+            root.rename_to(".", root.clone(), "dot", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_rename_cross_dir() {
+        let root = mount();
+        let dir = root
+            .create(
+                "dest_dir",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create dir failed");
+        let file = root
+            .create(
+                "moving",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino;
+
+        root.rename_to("moving", dir.clone(), "moved", VfsRenameFlag::empty())
+            .expect("Move failed");
+        assert_eq!(root.lookup("moving").err(), Some(VfsError::NoEntry));
+        let found = dir.lookup("moved").expect("Lookup moved file failed");
+        assert_eq!(found.get_attr().expect("Get attr failed").st_ino, ino);
+        assert_eq!(found.inode_type(), VfsNodeType::File);
+    }
+
+    #[test]
+    fn test_rename_overwrite() {
+        let root = mount();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let src = root
+            .create("src", VfsNodeType::File, perm, None)
+            .expect("Create src failed");
+        root.create("dst", VfsNodeType::File, perm, None)
+            .expect("Create dst failed");
+        let ino = src.get_attr().expect("Get attr failed").st_ino;
+
+        // 已存在的目标被覆盖
+        root.rename_to("src", root.clone(), "dst", VfsRenameFlag::empty())
+            .expect("Rename over existing failed");
+        assert_eq!(root.lookup("src").err(), Some(VfsError::NoEntry));
+        let found = root.lookup("dst").expect("Lookup dst failed");
+        assert_eq!(found.get_attr().expect("Get attr failed").st_ino, ino);
+
+        // 文件不能覆盖目录
+        root.create("file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        root.create("dir", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        assert_eq!(
+            root.rename_to("file", root.clone(), "dir", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::IsDir)
+        );
+    }
+
+    #[test]
+    fn test_persist_across_remount() {
+        let _guard = PERSIST_LOCK.lock();
+        let root = mount_persistent();
+        let dir = root
+            .create(
+                "persist_dir",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create dir failed");
+        let file = dir
+            .create(
+                "persist_file",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        file.write_at(0, b"survives unmount").expect("Write failed");
+        unmount(&root);
+
+        // 重新挂载后目录树和文件内容都从数据库恢复
+        let root = mount_persistent();
+        let dir = root.lookup("persist_dir").expect("Lookup dir failed");
+        assert_eq!(dir.inode_type(), VfsNodeType::Dir);
+        let file = dir.lookup("persist_file").expect("Lookup file failed");
+        let mut buf = [0u8; 32];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"survives unmount");
+
+        // 新分配的 inode 号不会与恢复的 inode 冲突
+        let other = root
+            .create(
+                "after_remount",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        assert!(
+            other.get_attr().expect("Get attr failed").st_ino
+                > file.get_attr().expect("Get attr failed").st_ino
+        );
+    }
+
+    #[test]
+    fn test_dir_read_write_is_dir() {
+        let root = mount();
+        let dir = root
+            .create(
+                "rw_dir",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create dir failed");
+
+        let mut buf = [0u8; 8];
+        assert_eq!(dir.read_at(0, &mut buf).err(), Some(VfsError::IsDir));
+        assert_eq!(dir.write_at(0, b"data").err(), Some(VfsError::IsDir));
+    }
+
+    #[test]
+    fn test_root_inode_is_stable() {
+        let root = mount();
+        let sb = root.get_super_block().expect("Get super block failed");
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        root.create("before", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+
+        // 1. 两次取得的根 inode 都是挂载时的那个, 能看到已有的文件
+        let first = sb.root_inode().expect("Get root inode failed");
+        let second = sb.root_inode().expect("Get root inode failed");
+        assert!(first.lookup("before").is_ok());
+        assert!(second.lookup("before").is_ok());
+
+        // 2. 通过其中一个创建, 另一个立即可见
+        first
+            .create("after", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        let a = second.lookup("after").expect("Lookup failed");
+        let b = root.lookup("after").expect("Lookup failed");
+        assert_eq!(
+            a.get_attr().expect("Get attr failed").st_ino,
+            b.get_attr().expect("Get attr failed").st_ino
+        );
+    }
+
+    #[test]
+    fn test_persist_types_and_only_changed_inodes() {
+        let _guard = PERSIST_LOCK.lock();
+        let root = mount_persistent();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let fifo = root
+            .create("typed_fifo", VfsNodeType::Fifo, perm, None)
+            .expect("Create fifo failed");
+        let kept = root
+            .create("typed_kept", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        kept.write_at(0, b"original").expect("Write failed");
+        let changed = root
+            .create("typed_changed", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        let kept_ino = kept.get_attr().expect("Get attr failed").st_ino;
+        unmount(&root);
+
+        // 1. 在数据库中直接改写未修改文件的记录
+        {
+            let db = crate::clone_db();
+            let tx = db.tx(true).unwrap();
+            let bucket = tx.get_bucket("alien_inodes").unwrap();
+            bucket
+                .put(kept_ino.to_be_bytes(), b"fmarker".to_vec())
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        // 2. 只修改另一个文件后同步, 未修改的记录不会被重写
+        changed.write_at(0, b"new data").expect("Write failed");
+        unmount(&root);
+
+        let root = mount_persistent();
+        let mut buf = [0u8; 16];
+        let kept = root.lookup("typed_kept").expect("Lookup failed");
+        let n = kept.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"marker");
+        let n = root
+            .lookup("typed_changed")
+            .expect("Lookup failed")
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"new data");
+
+        // 3. 特殊文件的类型在重新挂载后保持不变
+        assert_eq!(
+            root.lookup("typed_fifo")
+                .expect("Lookup failed")
+                .inode_type(),
+            VfsNodeType::Fifo
+        );
+        drop(fifo);
+    }
+
+    #[test]
+    fn test_datasync_writes_only_file_data() {
+        let _guard = PERSIST_LOCK.lock();
+        let root = mount_persistent();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let file = root
+            .create("datasync_file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        unmount(&root);
+
+        // 1. datasync 只写入文件内容, 新建的目录项留到完整同步
+        file.write_at(0, b"data").expect("Write failed");
+        root.create("datasync_new", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        file.fsync(true).expect("Datasync failed");
+        let remounted = mount_persistent();
+        let mut buf = [0u8; 8];
+        let n = remounted
+            .lookup("datasync_file")
+            .expect("Lookup failed")
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"data");
+        assert_eq!(
+            remounted.lookup("datasync_new").err(),
+            Some(VfsError::NoEntry)
+        );
+
+        // 2. 完整的 fsync 写入目录结构
+        file.fsync(false).expect("Fsync failed");
+        assert!(mount_persistent().lookup("datasync_new").is_ok());
+    }
+
+    #[test]
+    fn test_rmdir_checks_type_and_empty() {
+        let root = mount();
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+        root.create("file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        let dir = root
+            .create("dir", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        dir.create("child", VfsNodeType::File, perm, None)
+            .expect("Create child failed");
+
+        // 1. 不是目录
+        assert_eq!(root.rmdir("file").err(), Some(VfsError::NotDir));
+        assert!(root.lookup("file").is_ok());
+
+        // 2. 目录不为空, 目录和其下的文件都保留
+        assert_eq!(root.rmdir("dir").err(), Some(VfsError::NotEmpty));
+        assert!(dir.lookup("child").is_ok());
+
+        // 3. 清空之后可以删除
+        dir.unlink("child").expect("Unlink child failed");
+        root.rmdir("dir").expect("Rmdir failed");
+        assert_eq!(root.lookup("dir").err(), Some(VfsError::NoEntry));
+    }
+
+    #[test]
+    fn test_rename_dir_checks() {
+        let root = mount();
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+        let a = root
+            .create("a", VfsNodeType::Dir, perm, None)
+            .expect("Create a failed");
+        let b = a
+            .create("b", VfsNodeType::Dir, perm, None)
+            .expect("Create b failed");
+        let full = root
+            .create("full", VfsNodeType::Dir, perm, None)
+            .expect("Create full failed");
+        full.create("child", VfsNodeType::File, perm, None)
+            .expect("Create child failed");
+
+        // 1. 目录不能移到自己或自己的子目录下面
+        assert_eq!(
+            root.rename_to("a", a.clone(), "self", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::Invalid)
+        );
+        assert_eq!(
+            root.rename_to("a", b.clone(), "inner", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::Invalid)
+        );
+        assert!(root.lookup("a").is_ok());
+
+        // 2. 不能覆盖非空目录, 被覆盖的目录和其内容都保留
+        assert_eq!(
+            a.rename_to("b", root.clone(), "full", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::NotEmpty)
+        );
+        assert!(full.lookup("child").is_ok());
+        assert!(a.lookup("b").is_ok());
+
+        // 3. 可以覆盖空目录
+        full.unlink("child").expect("Unlink child failed");
+        a.rename_to("b", root.clone(), "full", VfsRenameFlag::empty())
+            .expect("Rename over empty dir failed");
+        assert_eq!(a.lookup("b").err(), Some(VfsError::NoEntry));
+        let found = root.lookup("full").expect("Lookup full failed");
+        assert_eq!(
+            found.get_attr().expect("Get attr failed").st_ino,
+            b.get_attr().expect("Get attr failed").st_ino
+        );
+    }
+}
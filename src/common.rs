@@ -11,10 +11,13 @@ use onlyerror::Error;
 use rvfs::dentry::DirentType;
 use spin::{Once, RwLock};
 
-use crate::{u32, u64};
+use crate::{u32, u64, usize};
 
 pub const FMODE_EXEC: i32 = 0x20;
 pub const MAX_PATH_LEN: usize = 255;
+pub const MAX_NAME_LEN: usize = 255;
+/// Deepest directory nesting a path walk or tree removal will descend into
+pub const MAX_TREE_DEPTH: usize = 1 << 16;
 
 pub const ACCESS_R_OK: u16 = 4;
 pub const ACCESS_F_OK: u16 = 0;
@@ -45,16 +48,23 @@ impl Debug for DbfsDirEntry {
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Copy)]
 pub enum DbfsError {
     #[error("DbfsError::PermissionDenied")]
     PermissionDenied = 1,
     #[error("DbfsError::NotFound")]
     NotFound = 2,
+    /// The global database was used before `init_dbfs`
+    #[error("DbfsError::NotInitialized")]
+    NotInitialized = 19,
     #[error("DbfsError::AccessError")]
     AccessError = 13,
     #[error("DbfsError::FileExists")]
     FileExists = 17,
+    #[error("DbfsError::NotDir")]
+    NotDir = 20,
+    #[error("DbfsError::IsDir")]
+    IsDir = 21,
     #[error("DbfsError::InvalidArgument")]
     InvalidArgument = 22,
     #[error("DbfsError::NoSpace")]
@@ -67,6 +77,8 @@ pub enum DbfsError {
     NoSys = 38,
     #[error("DbfsError::NotEmpty")]
     NotEmpty = 39,
+    #[error("DbfsError::Loop")]
+    Loop = 40,
     #[error("DbfsError::Io")]
     Io = 5,
     #[error("DbfsError::NotSupported")]
@@ -95,6 +107,33 @@ impl From<jammdb::Error> for DbfsError {
     }
 }
 
+#[cfg(any(
+    feature = "rvfs2",
+    feature = "rvfs2_demo",
+    feature = "alien_integration"
+))]
+impl From<DbfsError> for vfscore::error::VfsError {
+    fn from(value: DbfsError) -> Self {
+        use vfscore::error::VfsError;
+        match value {
+            DbfsError::PermissionDenied | DbfsError::AccessError => VfsError::PermissionDenied,
+            // Missing buckets and keys surface as NoData
+            DbfsError::NotFound | DbfsError::NoData => VfsError::NoEntry,
+            DbfsError::FileExists => VfsError::EExist,
+            DbfsError::NotDir => VfsError::NotDir,
+            DbfsError::IsDir => VfsError::IsDir,
+            DbfsError::InvalidArgument | DbfsError::RangeError | DbfsError::Loop => {
+                VfsError::Invalid
+            }
+            DbfsError::NoSpace => VfsError::NoSpace,
+            DbfsError::NameTooLong => VfsError::NameTooLong,
+            DbfsError::NoSys | DbfsError::NotSupported => VfsError::NoSys,
+            DbfsError::NotEmpty => VfsError::NotEmpty,
+            DbfsError::Io | DbfsError::NotInitialized | DbfsError::Other => VfsError::IoError,
+        }
+    }
+}
+
 bitflags! {
     pub struct DbfsPermission: u16 {
         const S_IFMT = 0o17_0000;
@@ -123,6 +162,27 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Flags of an `extent_map` entry; values follow Linux `FIEMAP_EXTENT_*` where one exists
+    pub struct ExtentFlags: u32 {
+        /// Last entry of the map
+        const LAST = 0x1;
+        /// Written but still only in the page cache
+        const DELALLOC = 0x4;
+        /// Stored compressed, the physical address is where the compressed data starts
+        const ENCODED = 0x8;
+        /// Preallocated but never written, reads return zeros
+        const UNWRITTEN = 0x800;
+        /// Physical data also referenced by other extents
+        const SHARED = 0x2000;
+        /// No data at all, reads return zeros (not a FIEMAP flag)
+        const HOLE = 0x8000_0000;
+    }
+}
+
+/// One entry of an inode's physical layout: `(logical_off, physical, len, flags)`
+pub type ExtentMapping = (u64, u64, u64, ExtentFlags);
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum DbfsFileType {
     NamedPipe,
@@ -155,6 +215,54 @@ impl Into<DirentType> for DbfsFileType {
     }
 }
 
+impl DbfsFileType {
+    /// One-letter tag, the same letters `From<&[u8]>` reads
+    pub fn tag(self) -> u8 {
+        match self {
+            DbfsFileType::NamedPipe => b'p',
+            DbfsFileType::CharDevice => b'c',
+            DbfsFileType::BlockDevice => b'b',
+            DbfsFileType::Directory => b'd',
+            DbfsFileType::RegularFile => b'f',
+            DbfsFileType::Symlink => b'l',
+            DbfsFileType::Socket => b's',
+        }
+    }
+
+    /// Inverse of [`DbfsFileType::tag`], `None` for an unknown letter
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            b'p' => Some(DbfsFileType::NamedPipe),
+            b'c' => Some(DbfsFileType::CharDevice),
+            b'b' => Some(DbfsFileType::BlockDevice),
+            b'd' => Some(DbfsFileType::Directory),
+            b'f' => Some(DbfsFileType::RegularFile),
+            b'l' => Some(DbfsFileType::Symlink),
+            b's' => Some(DbfsFileType::Socket),
+            _ => None,
+        }
+    }
+}
+
+/// Directory entry value carrying the entry's type: `[type tag][ino: u64 big-endian]`
+///
+/// Entries written before the type was stored hold only the inode number; both forms are
+/// read by [`decode_dentry`].
+pub fn encode_dentry(ino: usize, kind: DbfsFileType) -> [u8; 9] {
+    let mut value = [0u8; 9];
+    value[0] = kind.tag();
+    value[1..].copy_from_slice(&(ino as u64).to_be_bytes());
+    value
+}
+
+/// Inode number of a directory entry value, and its type if the entry records one
+pub fn decode_dentry(value: &[u8]) -> (usize, Option<DbfsFileType>) {
+    match value.len() {
+        9 => (usize!(&value[1..]), DbfsFileType::from_tag(value[0])),
+        _ => (usize!(value), None),
+    }
+}
+
 impl Default for DbfsFileType {
     fn default() -> Self {
         DbfsFileType::RegularFile
@@ -312,6 +420,46 @@ pub struct DbfsFsStat {
     pub name: [u8; 32],
 }
 
+/// Check that `name` can be stored as a single directory entry.
+///
+/// Names longer than [`MAX_NAME_LEN`] bytes are rejected with `NameTooLong`; empty names,
+/// `.`, `..` and names containing `/` or NUL are rejected with `InvalidArgument`.
+pub fn dbfs_check_name(name: &str) -> DbfsResult<()> {
+    dbfs_check_name_bytes(name.as_bytes())
+}
+
+/// [`dbfs_check_name`] for raw names, which need not be UTF-8
+pub fn dbfs_check_name_bytes(name: &[u8]) -> DbfsResult<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(DbfsError::NameTooLong);
+    }
+    if name.is_empty() || name == b"." || name == b".." || name.iter().any(|&b| b == b'/' || b == 0)
+    {
+        return Err(DbfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// 简单的 CRC32 实现
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFFFFFF, data)
+}
+
+/// 分段计算 CRC32: 从 `0xFFFFFFFF` 开始依次传入各段, 最后取反即为 [`crc32`]
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
 pub fn generate_data_key_with_number(num: u32) -> Vec<u8> {
     let mut datakey = b"zdata:".to_vec();
     datakey.extend_from_slice(&num.to_be_bytes());
@@ -1,15 +1,29 @@
 #[cfg(test)]
 mod tests {
-    use crate::transaction::{TransactionManager, Transaction};
     use crate::operation::TransactionOperation;
-    use crate::wal::{WalStorage, WriteAheadLog};
+    use crate::transaction::{Transaction, TransactionManager};
+    use crate::wal::{RecoveryStatus, WalStorage, WriteAheadLog};
+    use alloc::string::String;
     use alloc::sync::Arc;
     use alloc::vec::Vec;
-    use alloc::string::String;
+    use core::sync::atomic::{AtomicUsize, Ordering};
     use spin::Mutex;
 
     struct MockStorage {
         data: Mutex<Vec<u8>>,
+        flushes: AtomicUsize,
+        /// 下一次 flush 的故障: 0 正常, 1 返回错误, 2 panic
+        flush_fault: AtomicUsize,
+    }
+
+    impl MockStorage {
+        fn new() -> Self {
+            Self {
+                data: Mutex::new(Vec::new()),
+                flushes: AtomicUsize::new(0),
+                flush_fault: AtomicUsize::new(0),
+            }
+        }
     }
 
     impl WalStorage for MockStorage {
@@ -35,55 +49,112 @@ mod tests {
             storage.truncate(length as usize);
             Ok(())
         }
-        fn flush(&self) -> Result<(), String> { Ok(()) }
+        fn flush(&self) -> Result<(), String> {
+            match self.flush_fault.swap(0, Ordering::SeqCst) {
+                1 => return Err("Flush failed".to_string()),
+                2 => panic!("flush panicked"),
+                _ => {}
+            }
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// A transaction manager on its own database, holding directory `dir` with a file in it.
+    /// Returns the manager, its database and the directory and file inode numbers.
+    #[cfg(feature = "rvfs2")]
+    fn manager(path: &str) -> (TransactionManager, Arc<crate::SafeDb>, usize, usize) {
+        use crate::common::{DbfsFileType, DbfsPermission, DbfsTimeSpec};
+        use crate::rvfs2::common as rvfs2;
+        use jammdb::memfile::{FakeMap, FileOpenOptions};
+
+        let db = jammdb::DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), path).unwrap();
+        crate::fs_common::dbfs_common_format_in(&db, 4096, 0x44424653).expect("Format failed");
+        crate::fs_common::dbfs_common_root_inode_in(&db, 0, 0, DbfsTimeSpec::default())
+            .expect("Root inode failed");
+        let dir = rvfs2::dbfs_mkdir(
+            &db,
+            1,
+            "dir",
+            0,
+            0,
+            DbfsPermission::from_bits_truncate(0o40755),
+        )
+        .expect("Mkdir failed");
+        let file = rvfs2::dbfs_create(
+            &db,
+            dir,
+            "file",
+            DbfsFileType::RegularFile,
+            0,
+            0,
+            DbfsPermission::from_bits_truncate(0o100644),
+            None,
+        )
+        .expect("Create failed");
+
+        let db = Arc::new(crate::SafeDb(db));
+        let tm = TransactionManager::new();
+        tm.set_db(db.clone());
+        (tm, db, dir, file)
+    }
+
+    /// Contents of `ino`, read straight from the database
+    #[cfg(feature = "rvfs2")]
+    fn contents(db: &jammdb::DB, ino: usize) -> Vec<u8> {
+        let mut buf = alloc::vec![0u8; 256];
+        let n = crate::rvfs2::common::dbfs_read(db, ino, &mut buf, 0, 4096).expect("Read failed");
+        buf.truncate(n);
+        buf
     }
 
     #[test]
+    #[cfg(feature = "rvfs2")]
     fn test_transaction_recovery() {
-        let tm = TransactionManager::new();
-        let storage = Arc::new(MockStorage { data: Mutex::new(Vec::new()) });
+        let (tm, db, _, file) = manager("dbfs-tm-recovery.db");
+        let storage = Arc::new(MockStorage::new());
         tm.set_wal_storage(storage.clone());
 
         // 1. Simulate a transaction that was written to WAL but the application crashed before apply()
         let mut txn = tm.begin_transaction();
         txn.record(TransactionOperation::Write {
-            ino: 1,
+            ino: file,
             offset: 0,
             data: b"Recovery Data".to_vec(),
         });
-        
+
         // Manual append to WAL to simulate "written to log but not applied"
         tm.commit_into_wal_only(txn).expect("WAL write failed");
+        assert!(contents(&db, file).is_empty());
 
         // 2. Perform recovery (replay)
         tm.replay().expect("Replay failed");
-        
+
         // 3. Verify that the operation was finally applied
-        // (In a real test, verify side effects on Bottom FS)
+        assert_eq!(contents(&db, file), b"Recovery Data");
     }
 
     #[test]
+    #[cfg(feature = "rvfs2")]
     fn test_transaction_atomicity() {
-        let tm = TransactionManager::new();
-        let storage = Arc::new(MockStorage { data: Mutex::new(Vec::new()) });
+        let (tm, db, _, file) = manager("dbfs-tm-atomicity.db");
+        let storage = Arc::new(MockStorage::new());
         tm.set_wal_storage(storage.clone());
 
         let mut txn = tm.begin_transaction();
         txn.record(TransactionOperation::Write {
-            ino: 1,
+            ino: file,
             offset: 0,
             data: b"Atomicity Test".to_vec(),
         });
 
-        // Before commit, storage should be empty (managed by WAL)
-        // Wait, apply() is called during commit.
+        // apply() runs during commit, once the WAL records are durable
         tm.commit(txn).expect("Commit failed");
+        assert!(storage.flushes.load(Ordering::SeqCst) > 0);
 
-        // Verify WAL has data
-        assert!(storage.data.lock().len() > 0);
-        
-        // In a real test, we'd verify that the "Bottom FS" (mocked in apply) 
-        // received the data.
+        // The Bottom FS received the data and the applied records left the WAL
+        assert_eq!(contents(&db, file), b"Atomicity Test");
+        assert_eq!(tm.pending_ops(), 0);
     }
 
     #[test]
@@ -95,8 +166,585 @@ mod tests {
             offset: 0,
             data: b"Should not persist".to_vec(),
         });
-        
+
         tm.rollback(txn);
         // Rollback for deferred execution is just dropping the txn.
     }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_fsync_drains_wal() {
+        let (tm, db, dir, file) = manager("dbfs-tm-fsync.db");
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+
+        // 记录到 WAL 但尚未应用的数据操作和元数据操作
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino: file,
+            offset: 0,
+            data: b"fsync data".to_vec(),
+        });
+        txn.record(TransactionOperation::Mkdir {
+            parent_ino: dir,
+            name: "fsync_dir".to_string(),
+            uid: 0,
+            gid: 0,
+            perm: 0o755,
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        assert_eq!(tm.pending_ops(), 2);
+
+        // datasync 只刷新数据操作, 元数据操作仍然挂起
+        let flushes = storage.flushes.load(Ordering::SeqCst);
+        tm.sync_inode(file, true).expect("fdatasync failed");
+        assert_eq!(tm.pending_ops(), 1);
+        assert!(storage.flushes.load(Ordering::SeqCst) > flushes);
+        assert!(storage.data.lock().len() > 0);
+        assert_eq!(contents(&db, file), b"fsync data");
+
+        // 目录的 fsync 应用剩余的元数据操作, 并在没有挂起操作后清空 WAL
+        tm.sync_inode(dir, false).expect("fsync failed");
+        assert_eq!(tm.pending_ops(), 0);
+        assert!(storage.data.lock().is_empty());
+        assert!(crate::rvfs2::common::dbfs_lookup(&db, dir, "fsync_dir")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_wal_stats() {
+        let (tm, _, _, file) = manager("dbfs-tm-stats.db");
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+        assert_eq!(tm.wal_stats(), crate::wal::WalStats::default());
+
+        // 两个事务共三个操作, 只写入 WAL
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino: file,
+            offset: 0,
+            data: b"stats".to_vec(),
+        });
+        txn.record(TransactionOperation::Truncate {
+            ino: file,
+            length: 2,
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        let mut txn = tm.begin_transaction();
+        let last_txn = txn.id;
+        txn.record(TransactionOperation::Write {
+            ino: file,
+            offset: 2,
+            data: b"more".to_vec(),
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+
+        let stats = tm.wal_stats();
+        assert_eq!(stats.pending_entries, 3);
+        assert_eq!(stats.next_offset, storage.data.lock().len() as u64);
+        assert_eq!(stats.bytes_on_disk, stats.next_offset);
+        assert_eq!(stats.last_checkpoint_txn, 0);
+
+        // 应用全部操作后 WAL 被 checkpoint, 统计归零
+        tm.sync_inode(file, false).expect("fsync failed");
+        let stats = tm.wal_stats();
+        assert_eq!(stats.pending_entries, 0);
+        assert_eq!(stats.bytes_on_disk, 0);
+        assert_eq!(stats.next_offset, 0);
+        assert_eq!(stats.last_checkpoint_txn, last_txn);
+    }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_checkpoint_if_needed() {
+        let (tm, _, _, file) = manager("dbfs-tm-checkpoint.db");
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+
+        // 低于阈值时不做 checkpoint
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino: file,
+            offset: 0,
+            data: alloc::vec![b'x'; 64],
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        assert!(!tm.checkpoint_if_needed(4096).expect("Checkpoint failed"));
+        assert_eq!(tm.pending_ops(), 1);
+
+        // 写满超过阈值后, checkpoint 应用挂起的操作并收缩 WAL
+        for i in 0..64 {
+            let mut txn = tm.begin_transaction();
+            txn.record(TransactionOperation::Write {
+                ino: file,
+                offset: i * 64,
+                data: alloc::vec![b'x'; 64],
+            });
+            tm.commit_into_wal_only(txn).expect("WAL write failed");
+        }
+        let before = tm.wal_stats().next_offset;
+        assert!(before > 4096);
+        assert!(tm.checkpoint_if_needed(4096).expect("Checkpoint failed"));
+        assert_eq!(tm.pending_ops(), 0);
+        assert_eq!(tm.wal_stats().next_offset, 0);
+        assert!(storage.data.lock().is_empty());
+
+        // force_checkpoint 不依赖阈值
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Truncate {
+            ino: file,
+            length: 0,
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        tm.force_checkpoint().expect("Checkpoint failed");
+        assert_eq!(tm.pending_ops(), 0);
+        assert!(storage.data.lock().is_empty());
+    }
+
+    #[test]
+    fn test_wal_entries_stay_bounded() {
+        let mut wal = WriteAheadLog::new();
+
+        // 一个迟迟不提交的事务阻止了整体 checkpoint
+        wal.append(1, TransactionOperation::Truncate { ino: 13, length: 0 })
+            .expect("Append failed");
+
+        // 大量短事务: 每个事务完成后立即从内存中移除
+        for id in 2..5000u64 {
+            wal.append(
+                id,
+                TransactionOperation::Write {
+                    ino: 13,
+                    offset: 0,
+                    data: b"short".to_vec(),
+                },
+            )
+            .expect("Append failed");
+            wal.clear_txn(id);
+            assert_eq!(wal.pending(), 1);
+        }
+
+        // 一次突发的大事务结束后, 缓冲区容量被收回
+        for _ in 0..4096 {
+            wal.append(5000, TransactionOperation::Truncate { ino: 13, length: 1 })
+                .expect("Append failed");
+        }
+        assert!(wal.capacity() >= 4096);
+        wal.clear_txn(5000);
+        assert_eq!(wal.pending(), 1);
+        assert!(wal.capacity() <= 64);
+    }
+
+    #[test]
+    fn test_wal_recovery_stops_at_blank_tail() {
+        let storage = Arc::new(MockStorage::new());
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        wal.append(
+            1,
+            TransactionOperation::Write {
+                ino: 17,
+                offset: 0,
+                data: b"tail".to_vec(),
+            },
+        )
+        .expect("Append failed");
+        wal.append(2, TransactionOperation::Truncate { ino: 17, length: 2 })
+            .expect("Append failed");
+        let end = storage.data.lock().len();
+
+        // 块设备把未写入的尾部读成全零, 或者读出任意残留数据
+        for fill in [0u8, 0xab] {
+            {
+                let mut data = storage.data.lock();
+                data.truncate(end);
+                data.resize(end + 4096, fill);
+            }
+            let mut wal = WriteAheadLog::new();
+            wal.set_storage(storage.clone());
+            let (entries, status) = wal.recover().expect("Recover failed");
+            assert_eq!(status, RecoveryStatus::Clean);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].txn_id, 2);
+            assert_eq!(wal.stats().next_offset, end as u64);
+        }
+    }
+
+    #[test]
+    fn test_wal_recovery_drops_torn_tail() {
+        let storage = Arc::new(MockStorage::new());
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        wal.append(
+            1,
+            TransactionOperation::Write {
+                ino: 19,
+                offset: 0,
+                data: b"kept".to_vec(),
+            },
+        )
+        .expect("Append failed");
+        wal.append(2, TransactionOperation::Truncate { ino: 19, length: 1 })
+            .expect("Append failed");
+        let end = storage.data.lock().len();
+        wal.append(
+            3,
+            TransactionOperation::Write {
+                ino: 19,
+                offset: 4,
+                data: b"torn".to_vec(),
+            },
+        )
+        .expect("Append failed");
+        let full = storage.data.lock().len();
+
+        // 崩溃发生在最后一帧写到一半 (截断), 或者预分配的设备上 crc 尚未写入 (清零)
+        for crc_only in [false, true] {
+            {
+                let mut data = storage.data.lock();
+                if crc_only {
+                    data[full - 4..].fill(0);
+                } else {
+                    data.truncate(end + (full - end) / 2);
+                }
+            }
+            let mut wal = WriteAheadLog::new();
+            wal.set_storage(storage.clone());
+            let (entries, status) = wal.recover().expect("Recover failed");
+            assert_eq!(status, RecoveryStatus::TruncatedTail);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].txn_id, 2);
+            assert_eq!(wal.stats().next_offset, end as u64);
+            assert_eq!(storage.data.lock().len(), end);
+
+            // 截掉残帧后再次恢复是干净的结尾
+            let mut wal = WriteAheadLog::new();
+            wal.set_storage(storage.clone());
+            let (entries, status) = wal.recover().expect("Recover failed");
+            assert_eq!(status, RecoveryStatus::Clean);
+            assert_eq!(entries.len(), 2);
+
+            // 恢复过程会截掉残帧, 下一轮重新写出完整的第三帧
+            wal.append(
+                3,
+                TransactionOperation::Write {
+                    ino: 19,
+                    offset: 4,
+                    data: b"torn".to_vec(),
+                },
+            )
+            .expect("Append failed");
+            assert_eq!(storage.data.lock().len(), full);
+        }
+    }
+
+    #[test]
+    fn test_cache_size() {
+        use crate::common::DbfsError;
+        use buddy_system_allocator::LockedHeap;
+        use core::alloc::Layout;
+
+        // 大小必须是 CACHE_ALIGN 的非零倍数
+        assert!(matches!(
+            crate::check_cache_size(0),
+            Err(DbfsError::InvalidArgument)
+        ));
+        assert!(matches!(
+            crate::check_cache_size(1000),
+            Err(DbfsError::InvalidArgument)
+        ));
+
+        // 1MB 缓存内的分配成功, 超出容量的分配返回错误而不是崩溃
+        let heap = LockedHeap::<32>::empty();
+        crate::init_heap(&heap, 1024 * 1024).expect("Init heap failed");
+        let big = Layout::from_size_align(2 * 1024 * 1024, 8).unwrap();
+        assert!(heap.lock().alloc(big).is_err());
+
+        let chunk = Layout::from_size_align(64 * 1024, 8).unwrap();
+        let mut blocks = Vec::new();
+        while let Ok(block) = heap.lock().alloc(chunk) {
+            blocks.push(block);
+            assert!(blocks.len() <= 16, "allocated past the cache size");
+        }
+        assert!(!blocks.is_empty());
+        for block in blocks {
+            heap.lock().dealloc(block, chunk);
+        }
+        assert!(heap.lock().alloc(chunk).is_ok());
+    }
+
+    #[test]
+    fn test_fast_copy_matches_copy_from_slice() {
+        let src: Vec<u8> = (0..300u32).map(|i| (i * 7 + 3) as u8).collect();
+
+        // 覆盖 16/8/4/2 字节对齐以及完全不对齐的起点和长度
+        for src_off in [0usize, 1, 2, 4, 8, 16] {
+            for dst_off in [0usize, 1, 2, 4, 8, 16] {
+                for len in [0usize, 1, 2, 3, 4, 8, 15, 16, 64, 255] {
+                    let src = &src[src_off..src_off + len];
+                    let mut expected = alloc::vec![0u8; 300];
+                    expected[dst_off..dst_off + len].copy_from_slice(src);
+
+                    let mut widened = alloc::vec![0u8; 300];
+                    crate::copy_data(src.as_ptr(), widened[dst_off..].as_mut_ptr(), len);
+                    assert_eq!(
+                        widened, expected,
+                        "copy_data {} {} {}",
+                        src_off, dst_off, len
+                    );
+
+                    let mut fast = alloc::vec![0u8; 300];
+                    crate::fast_copy(&mut fast[dst_off..dst_off + len], src);
+                    assert_eq!(fast, expected, "fast_copy {} {} {}", src_off, dst_off, len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_group_commit_batches_flushes() {
+        let tm = Arc::new(manager("dbfs-tm-group.db").0);
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+        tm.set_group_commit_window(1_000_000);
+
+        // 8 个线程各提交 25 个事务, 并发的提交共享一次 flush
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tm = tm.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let txn = tm.begin_transaction();
+                        tm.commit(txn).expect("Commit failed");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("Commit thread panicked");
+        }
+
+        let flushes = storage.flushes.load(Ordering::SeqCst);
+        assert!(flushes > 0);
+        assert!(flushes * 4 <= 200, "{} flushes for 200 commits", flushes);
+        assert_eq!(tm.pending_ops(), 0);
+    }
+
+    #[test]
+    fn test_wal_binary_entries_and_frame_limit() {
+        let storage = Arc::new(MockStorage::new());
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+
+        // 每种操作都以二进制编码写入并原样恢复
+        let ops = alloc::vec![
+            TransactionOperation::Write {
+                ino: 23,
+                offset: 5,
+                data: b"binary".to_vec()
+            },
+            TransactionOperation::Create {
+                parent_ino: 1,
+                name: "node".to_string(),
+                uid: 1000,
+                gid: 100,
+                perm: 0o20644,
+                dev: Some(0x0801),
+            },
+            TransactionOperation::Delete {
+                parent_ino: 1,
+                name: "gone".to_string()
+            },
+            TransactionOperation::Rename {
+                old_parent_ino: 1,
+                old_name: "a".to_string(),
+                new_parent_ino: 2,
+                new_name: "b".to_string(),
+            },
+            TransactionOperation::Mkdir {
+                parent_ino: 1,
+                name: "d".to_string(),
+                uid: 0,
+                gid: 0,
+                perm: 0o755
+            },
+            TransactionOperation::Truncate { ino: 23, length: 1 },
+        ];
+        for op in &ops {
+            wal.append(1, op.clone()).expect("Append failed");
+        }
+        // 二进制编码远小于 JSON
+        assert!(storage.data.lock().len() < 400);
+
+        // 超过一帧的写入被拆成同一事务中连续的写入
+        let big = alloc::vec![0x5au8; 3 * 1024 * 1024];
+        wal.append(
+            2,
+            TransactionOperation::Write {
+                ino: 23,
+                offset: 100,
+                data: big.clone(),
+            },
+        )
+        .expect("Append failed");
+
+        // 其他超过帧大小上限的记录被拒绝, 不写入任何内容
+        let end = storage.data.lock().len();
+        let huge_name = "n".repeat(2 * 1024 * 1024);
+        assert!(wal
+            .append(
+                3,
+                TransactionOperation::Delete {
+                    parent_ino: 1,
+                    name: huge_name
+                }
+            )
+            .is_err());
+        assert_eq!(storage.data.lock().len(), end);
+
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        let (entries, status) = wal.recover().expect("Recover failed");
+        assert_eq!(status, RecoveryStatus::Clean);
+        for (entry, op) in entries.iter().zip(&ops) {
+            assert_eq!(
+                alloc::format!("{:?}", entry.operation),
+                alloc::format!("{:?}", op)
+            );
+        }
+
+        let mut joined = Vec::new();
+        for entry in &entries[ops.len()..] {
+            assert_eq!(entry.txn_id, 2);
+            match &entry.operation {
+                TransactionOperation::Write {
+                    ino: 23,
+                    offset,
+                    data,
+                } => {
+                    assert_eq!(*offset, 100 + joined.len() as u64);
+                    joined.extend_from_slice(data);
+                }
+                other => panic!("unexpected entry {:?}", other),
+            }
+        }
+        assert!(entries.len() - ops.len() > 3);
+        assert_eq!(joined, big);
+    }
+
+    #[test]
+    fn test_wal_recovery_rejects_oversized_frame() {
+        let storage = Arc::new(MockStorage::new());
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        wal.append(1, TransactionOperation::Truncate { ino: 29, length: 0 })
+            .expect("Append failed");
+        let end = storage.data.lock().len();
+
+        // 一个完整且 crc 正确, 但超过帧大小上限的帧
+        let payload = alloc::vec![0x11u8; 1024 * 1024 + 1];
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&0x5741_4c32u32.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&crate::common::crc32(&payload).to_le_bytes());
+        storage.write(end as u64, &frame).unwrap();
+
+        // 这是损坏而不是崩溃留下的残帧: 报错, 日志保持原样
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        assert!(wal.recover().is_err());
+        assert_eq!(storage.data.lock().len(), end + frame.len());
+
+        // 同样的帧只写了一半时仍按残帧截掉
+        storage.truncate((end + frame.len() / 2) as u64).unwrap();
+        let mut wal = WriteAheadLog::new();
+        wal.set_storage(storage.clone());
+        let (entries, status) = wal.recover().expect("Recover failed");
+        assert_eq!(status, RecoveryStatus::TruncatedTail);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(storage.data.lock().len(), end);
+    }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_sync_inode_keeps_log_order() {
+        let (tm, db, dir, file) = manager("dbfs-tm-order.db");
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+
+        // 先写文件, 再从目录中删除它; 两个操作的目标 Inode 不同
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino: file,
+            offset: 0,
+            data: b"before unlink".to_vec(),
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Delete {
+            parent_ino: dir,
+            name: "file".to_string(),
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Mkdir {
+            parent_ino: dir,
+            name: "later".to_string(),
+            uid: 0,
+            gid: 0,
+            perm: 0o755,
+        });
+        tm.commit_into_wal_only(txn).expect("WAL write failed");
+
+        // 目录的 fdatasync 不涉及其元数据操作, 什么也不应用
+        tm.sync_inode(dir, true).expect("fdatasync failed");
+        assert_eq!(tm.pending_ops(), 3);
+
+        // 目录的 fsync 连同之前对文件的写入一起按日志顺序应用, 写入不会落在删除之后
+        tm.sync_inode(dir, false).expect("fsync failed");
+        assert_eq!(tm.pending_ops(), 0);
+        let lookup = |name| crate::rvfs2::common::dbfs_lookup(&db, dir, name).unwrap();
+        assert_eq!(lookup("file"), None);
+        assert!(lookup("later").is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "rvfs2")]
+    fn test_failed_commit_releases_turn() {
+        let (tm, db, _, file) = manager("dbfs-tm-fault.db");
+        let tm = Arc::new(tm);
+        let storage = Arc::new(MockStorage::new());
+        tm.set_wal_storage(storage.clone());
+        let write = |data: &[u8]| {
+            let mut txn = tm.begin_transaction();
+            txn.record(TransactionOperation::Write {
+                ino: file,
+                offset: 0,
+                data: data.to_vec(),
+            });
+            txn
+        };
+
+        // 1. WAL flush 失败: 提交报错, 记录不留在日志中, 也不被应用
+        storage.flush_fault.store(1, Ordering::SeqCst);
+        assert!(tm.commit(write(b"not durable")).is_err());
+        assert_eq!(tm.pending_ops(), 0);
+        assert!(contents(&db, file).is_empty());
+
+        // 2. 提交过程中 panic: 之后的提交不会一直等待它的顺序号
+        storage.flush_fault.store(2, Ordering::SeqCst);
+        let panicking = {
+            let tm = tm.clone();
+            let txn = write(b"panicked");
+            std::thread::spawn(move || tm.commit(txn))
+        };
+        assert!(panicking.join().is_err());
+
+        tm.commit(write(b"after panic")).expect("Commit failed");
+        assert_eq!(contents(&db, file), b"after panic");
+    }
 }
@@ -3,29 +3,46 @@
 //! This module provides core functionality that doesn't depend on
 //! any specific VFS API.
 
-use alloc::vec::Vec;
-use jammdb::Data;
+use alloc::{string::ToString, vec, vec::Vec};
+use jammdb::{Data, DB};
 
 use crate::{
-    clone_db,
-    common::{DbfsFsStat, DbfsPermission, DbfsResult, DbfsTimeSpec},
+    common::{
+        decode_dentry, DbfsError, DbfsFsStat, DbfsPermission, DbfsResult, DbfsTimeSpec,
+        MAX_TREE_DEPTH,
+    },
     inode_common::DBFS_INODE_NUMBER,
-    u32, u64, usize,
+    try_clone_db, u16, u32, u64, usize,
 };
 
+/// Maximum number of symlinks followed while resolving one path (Linux `MAXSYMLINKS`)
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
 /// Initialize the root inode
 ///
 /// This is a simplified version that works with the new vfscore API
 pub fn dbfs_common_root_inode(uid: u32, gid: u32, ctime: DbfsTimeSpec) -> DbfsResult<usize> {
-    let db = clone_db();
+    dbfs_common_root_inode_in(&try_clone_db()?, uid, gid, ctime)
+}
+
+/// Initialize the root inode of `db` rather than of the global database
+pub fn dbfs_common_root_inode_in(
+    db: &DB,
+    uid: u32,
+    gid: u32,
+    ctime: DbfsTimeSpec,
+) -> DbfsResult<usize> {
     let tx = db.tx(true)?;
 
     if tx.get_bucket(1usize.to_be_bytes()).is_err() {
-        // Create root directory inode
+        // Create root directory inode; the counter must start past it
         let permission = DbfsPermission::from_bits_truncate(0o755) | DbfsPermission::S_IFDIR;
         let new_inode = tx.create_bucket(1usize.to_be_bytes()).unwrap();
-        let old = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-        assert_eq!(old, 1);
+        let super_blk = tx.get_or_create_bucket("super_blk")?;
+        if super_blk.get_kv("continue_number").is_none() {
+            super_blk.put("continue_number", 2usize.to_be_bytes())?;
+        }
+        DBFS_INODE_NUMBER.fetch_max(2, core::sync::atomic::Ordering::SeqCst);
 
         new_inode
             .put("mode", permission.bits().to_be_bytes())
@@ -40,10 +57,156 @@ pub fn dbfs_common_root_inode(uid: u32, gid: u32, ctime: DbfsTimeSpec) -> DbfsRe
         new_inode.put("ctime", ctime.to_be_bytes()).unwrap();
     }
 
-    drop(tx);
+    tx.commit()?;
     Ok(1)
 }
 
+/// Highest inode number in use, from the rvfs2 inode buckets and the DBFS-T `inodes` table
+pub fn dbfs_common_highest_ino(tx: &jammdb::Tx) -> usize {
+    let buckets = tx
+        .buckets()
+        .filter_map(|(name, _)| <[u8; 8]>::try_from(name.name()).ok())
+        .map(usize::from_be_bytes);
+    let table = tx.get_bucket("inodes").ok().map(|inodes| {
+        inodes
+            .kv_pairs()
+            .filter_map(|kv| <[u8; 8]>::try_from(kv.key()).ok())
+            .map(|key| u64::from_be_bytes(key) as usize)
+            .max()
+            .unwrap_or(0)
+    });
+    buckets.chain(table).max().unwrap_or(0).max(1)
+}
+
+/// Hand out the next inode number from the persisted `continue_number` counter
+///
+/// The counter lives in `super_blk` and is bumped inside `tx`, so a number is only used up
+/// when the transaction creating the inode commits. Every adapter allocates through here, so
+/// two adapters mounting the same database never hand out the same number. An image without
+/// the counter starts past the highest inode it already holds.
+pub fn dbfs_common_alloc_ino(tx: &jammdb::Tx) -> DbfsResult<usize> {
+    let bucket = tx.get_or_create_bucket("super_blk")?;
+    let next = bucket
+        .get_kv("continue_number")
+        .map(|kv| usize!(kv.value()))
+        .unwrap_or_else(|| dbfs_common_highest_ino(tx) + 1)
+        .max(DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst));
+
+    bucket.put("continue_number", (next + 1).to_be_bytes())?;
+    DBFS_INODE_NUMBER.fetch_max(next + 1, core::sync::atomic::Ordering::SeqCst);
+    Ok(next)
+}
+
+/// Write the superblock of a DBFS image
+///
+/// The block size is recorded in `super_blk` here and read back on every mount, so one
+/// binary can mount images formatted with different block sizes. The inode counter is only
+/// initialised if the image does not have one yet.
+pub fn dbfs_common_format(blk_size: u32, magic: u32) -> DbfsResult<()> {
+    dbfs_common_format_in(&try_clone_db()?, blk_size, magic)
+}
+
+/// Write the superblock of `db` rather than of the global database
+pub fn dbfs_common_format_in(db: &DB, blk_size: u32, magic: u32) -> DbfsResult<()> {
+    if !blk_size.is_power_of_two() {
+        return Err(DbfsError::InvalidArgument);
+    }
+
+    let tx = db.tx(true)?;
+    let bucket = tx.get_or_create_bucket("super_blk")?;
+
+    if bucket.get_kv("continue_number").is_none() {
+        bucket.put("continue_number", 2usize.to_be_bytes())?;
+    }
+    bucket.put("blk_size", blk_size.to_be_bytes())?;
+    bucket.put("magic", magic.to_be_bytes())?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Resolve `path` to an inode number, starting from the directory `root_ino`
+///
+/// `.` and `..` are handled lexically (`..` at the root stays at the root) and symlinks are
+/// followed wherever they appear, including the last component. Absolute link targets restart
+/// from `root_ino`. Following more than [`MAX_SYMLINK_DEPTH`] links fails with
+/// `DbfsError::Loop`; descending more than [`MAX_TREE_DEPTH`] directories fails with
+/// `DbfsError::NameTooLong`.
+pub fn resolve_path(root_ino: usize, path: &str) -> DbfsResult<usize> {
+    let db = try_clone_db()?;
+    let tx = db.tx(false)?;
+
+    // Directories walked so far; the last one is the current directory
+    let mut dirs = vec![root_ino];
+    // Components still to walk, reversed so that `pop` yields the next one
+    let mut pending: Vec<_> = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .rev()
+        .map(|c| c.to_string())
+        .collect();
+    let mut links = 0;
+
+    while let Some(name) = pending.pop() {
+        match name.as_str() {
+            "." => continue,
+            ".." => {
+                if dirs.len() > 1 {
+                    dirs.pop();
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let dir = tx.get_bucket(dirs.last().unwrap().to_be_bytes())?;
+        let dir_mode = dir
+            .get_kv("mode")
+            .map(|kv| DbfsPermission::from_bits_truncate(u16!(kv.value())))
+            .ok_or(DbfsError::NotFound)?;
+        if dir_mode & DbfsPermission::S_IFMT != DbfsPermission::S_IFDIR {
+            return Err(DbfsError::NotDir);
+        }
+
+        let ino = dir
+            .get_kv(name.as_bytes())
+            .map(|kv| decode_dentry(kv.value()).0)
+            .ok_or(DbfsError::NotFound)?;
+        let inode = tx.get_bucket(ino.to_be_bytes())?;
+        let mode = inode
+            .get_kv("mode")
+            .map(|kv| DbfsPermission::from_bits_truncate(u16!(kv.value())))
+            .ok_or(DbfsError::NotFound)?;
+
+        if mode & DbfsPermission::S_IFMT == DbfsPermission::S_IFLNK {
+            links += 1;
+            if links > MAX_SYMLINK_DEPTH {
+                return Err(DbfsError::Loop);
+            }
+            let target = inode.get_kv("symlink_target").ok_or(DbfsError::NotFound)?;
+            let target =
+                core::str::from_utf8(target.value()).map_err(|_| DbfsError::InvalidArgument)?;
+            if target.starts_with('/') {
+                dirs.truncate(1);
+            }
+            pending.extend(
+                target
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .rev()
+                    .map(|c| c.to_string()),
+            );
+        } else {
+            if dirs.len() > MAX_TREE_DEPTH {
+                return Err(DbfsError::NameTooLong);
+            }
+            dirs.push(ino);
+        }
+    }
+
+    Ok(*dirs.last().unwrap())
+}
+
 /// Unmount DBFS
 pub fn dbfs_common_umount() -> DbfsResult<()> {
     // In the new architecture, unmounting is handled by the VFS layer
@@ -20,7 +20,7 @@ use rvfs::{
 use spin::Mutex;
 
 use crate::{
-    clone_db,
+    cache_size, clone_db,
     common::{generate_data_key, DbfsFsStat, DbfsResult, DbfsTimeSpec},
     file::DBFS_DIR_FILE_OPS,
     init_cache,
@@ -106,7 +106,7 @@ fn dbfs_create_simple_super_blk(
     let continue_number = usize!(continue_number.value());
     // set the next inode number
     DBFS_INODE_NUMBER.store(continue_number, core::sync::atomic::Ordering::SeqCst);
-    init_cache();
+    init_cache(cache_size()).map_err(|_| "dbfs_fill_super_block: init cache failed")?;
     let blk_size = bucket.get_kv("blk_size").unwrap();
     let blk_size = u32!(blk_size.value());
     let magic = bucket.get_kv("magic").unwrap();
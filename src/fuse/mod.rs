@@ -4,6 +4,8 @@ pub mod inode;
 pub mod link;
 pub mod mkfs;
 pub mod sblk;
+#[cfg(feature = "dbfs_t")]
+pub mod txfs;
 
 extern crate std;
 
@@ -22,6 +24,7 @@ use log::{error, info, trace};
 pub use mkfs::init_dbfs_fuse;
 
 use crate::{
+    cache_size,
     common::DbfsTimeSpec,
     fs_type::dbfs_common_root_inode,
     fuse::{
@@ -74,7 +77,7 @@ impl Filesystem for DbfsFuse {
                 .map_err(|_| -1)?; // TODO: error handling
         init_db(&db, FILE_SIZE as u64);
         init_dbfs(db);
-        init_cache();
+        init_cache(cache_size()).map_err(|_| -1)?;
         let uid = unsafe { libc::getuid() };
         let gid = unsafe { libc::getgid() };
         let time = DbfsTimeSpec::from(SystemTime::now());
@@ -0,0 +1,381 @@
+//! DBFS-T 的 FUSE 前端
+//!
+//! 把内核请求直接转发给 [`TransactionEngine`]: 元数据保存在宿主机上的 jammdb 文件中,
+//! 文件数据追加到另一个作为日志设备的宿主机文件中。每个修改类请求都是一个独立的事务。
+extern crate std;
+
+use alloc::{format, sync::Arc, vec};
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyWrite, Request, TimeOrNow,
+};
+use jammdb::DB;
+use log::error;
+
+use super::mkfs::{FakeMMap, FakePath, MyOpenOptions};
+use crate::{
+    common::{DbfsError, DbfsResult},
+    log_manager::{BlockDevice, LogManager},
+    models::InodeMetadata,
+    tx_engine::{recover_append_pos, TransactionEngine},
+};
+
+const TTL: Duration = Duration::from_secs(1);
+/// 元数据文件的大小 (稀疏文件, 按需占用空间)
+const TX_DB_SIZE: usize = 1024 * 1024 * 1024;
+const ROOT_INO: u64 = 1;
+const BLOCK_SIZE: u32 = 4096;
+
+/// 以宿主机文件作为日志设备
+pub struct FileBlockDevice {
+    file: File,
+    size: u64,
+}
+
+impl FileBlockDevice {
+    /// 打开或创建 `path`, 文件不足 `size` 字节时扩展到 `size`
+    pub fn open<P: AsRef<Path>>(path: P, size: u64) -> DbfsResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|_| DbfsError::Io)?;
+        let len = file.metadata().map_err(|_| DbfsError::Io)?.len();
+        if len < size {
+            file.set_len(size).map_err(|_| DbfsError::Io)?;
+        }
+        Ok(Self {
+            file,
+            size: len.max(size),
+        })
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        FileExt::read_at(&self.file, buf, pos).map_err(|_| DbfsError::Io)
+    }
+    fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+        FileExt::write_at(&self.file, buf, pos).map_err(|_| DbfsError::Io)
+    }
+    fn size(&self) -> u64 {
+        self.size
+    }
+    fn flush(&self) -> DbfsResult<()> {
+        self.file.sync_data().map_err(|_| DbfsError::Io)
+    }
+}
+
+/// 基于 [`TransactionEngine`] 的 FUSE 文件系统
+pub struct DbfsTxFuse {
+    engine: TransactionEngine<FileBlockDevice>,
+}
+
+impl DbfsTxFuse {
+    /// 打开 `dir` 下的 `dbfs-t.db` 与 `dbfs-t.log`, 不存在时创建并格式化
+    ///
+    /// `log_size` 只在创建日志文件时使用, 已有的日志文件保持原大小。
+    pub fn open<P: AsRef<Path>>(dir: P, log_size: u64) -> DbfsResult<Self> {
+        let dir = dir.as_ref();
+        let db_path = dir.join("dbfs-t.db");
+        let db_path = FakePath::new(db_path.to_str().ok_or(DbfsError::InvalidArgument)?);
+        let db = DB::open::<MyOpenOptions<TX_DB_SIZE>, _>(Arc::new(FakeMMap), db_path)
+            .map_err(|_| DbfsError::Io)?;
+        format_root(&db)?;
+
+        // 元数据不在日志设备中, 日志区从 0 开始
+        let device = FileBlockDevice::open(dir.join("dbfs-t.log"), log_size)?;
+        let next_append_pos = recover_append_pos(&db, 0)?;
+        let log_manager = LogManager::new(device, 0).resume_at(next_append_pos);
+        Ok(Self {
+            engine: TransactionEngine::new(db, log_manager),
+        })
+    }
+
+    fn attr(&self, ino: u64) -> DbfsResult<FileAttr> {
+        self.engine.get_metadata(ino).map(|meta| file_attr(&meta))
+    }
+
+    fn lookup_child(&self, parent: u64, name: &OsStr) -> DbfsResult<u64> {
+        self.engine.lookup_dentry(parent, name.as_bytes())
+    }
+
+    // 删除目录项, 最后一个链接被删除时一并删除 Inode
+    fn remove(&mut self, parent: u64, name: &OsStr, dir: bool) -> DbfsResult<()> {
+        let ino = self.lookup_child(parent, name)?;
+        let meta = self.engine.get_metadata(ino)?;
+        match (is_dir(meta.mode), dir) {
+            (true, false) => return Err(DbfsError::IsDir),
+            (false, true) => return Err(DbfsError::NotDir),
+            (true, true) if !self.engine.is_dir_empty(ino)? => return Err(DbfsError::NotEmpty),
+            _ => {}
+        }
+        self.engine.batch(|ctx| {
+            ctx.delete_dentry(parent, name.as_bytes())?;
+            let mut meta = ctx.get_metadata(ino)?;
+            if !dir && meta.nlink > 1 {
+                meta.nlink -= 1;
+                ctx.update_metadata(&meta)
+            } else {
+                ctx.delete_inode(ino)
+            }
+        })
+    }
+}
+
+/// 首次打开时创建 `inodes` bucket 和根目录 (与 DBFS-T 的 rvfs 适配层格式相同)
+fn format_root(db: &DB) -> DbfsResult<()> {
+    let tx = db.tx(true)?;
+    if tx.get_bucket("inodes").is_ok() {
+        return Ok(());
+    }
+    let inodes = tx.create_bucket("inodes")?;
+    let root_meta = InodeMetadata {
+        ino: ROOT_INO,
+        size: 0,
+        mode: 0o040755,
+        nlink: 2,
+        extents: alloc::vec::Vec::new(),
+        atime: 0,
+        mtime: 0,
+        uid: 0,
+        entry_count: Some(0),
+        version: crate::models::INODE_METADATA_VERSION,
+    };
+    let value = serde_json::to_vec(&root_meta).map_err(|_| DbfsError::Other)?;
+    inodes.put(ROOT_INO.to_be_bytes(), value)?;
+    let root_dir = tx.create_bucket(&format!("dir_{}", ROOT_INO))?;
+    root_dir.put(".", ROOT_INO.to_be_bytes())?;
+    root_dir.put("..", ROOT_INO.to_be_bytes())?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn is_dir(mode: u32) -> bool {
+    (mode & 0o170000) == 0o040000
+}
+
+fn file_type(mode: u32) -> FileType {
+    match mode & 0o170000 {
+        0o040000 => FileType::Directory,
+        0o120000 => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+fn file_attr(meta: &InodeMetadata) -> FileAttr {
+    let time = |secs: i64| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+    FileAttr {
+        ino: meta.ino,
+        size: meta.size,
+        blocks: meta.size.div_ceil(512),
+        atime: time(meta.atime),
+        mtime: time(meta.mtime),
+        ctime: time(meta.mtime),
+        crtime: time(meta.mtime),
+        kind: file_type(meta.mode),
+        perm: (meta.mode & 0o7777) as u16,
+        nlink: meta.nlink,
+        uid: meta.uid,
+        gid: 0,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+impl Filesystem for DbfsTxFuse {
+    /// 卸载时让日志落盘并记录日志写指针
+    fn destroy(&mut self) {
+        if let Err(e) = self.engine.unmount() {
+            error!("dbfs-t unmount failed: {:?}", e);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .lookup_child(parent, name)
+            .and_then(|ino| self.attr(ino))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    /// 只支持修改大小 (truncate), 其余属性保持不变
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let res = match size {
+            Some(size) => self.engine.truncate_file(ino, size),
+            None => Ok(()),
+        };
+        match res.and_then(|_| self.attr(ino)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let mut buf = vec![0u8; size as usize];
+        match self.engine.read_file(ino, offset as u64, &mut buf) {
+            Ok(n) => reply.data(&buf[..n]),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self
+            .engine
+            .write_file_transactional(ino, offset as u64, data)
+        {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    /// `.` 和 `..` 由这里生成, 不依赖目录 bucket 中是否保存了它们
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        // 偏移 0/1 之后是 `.`/`..`, 之后的偏移为目录 bucket 中的下标加 3
+        let dots: [(&[u8], i64); 2] = [(b".", 1), (b"..", 2)];
+        for (name, next) in dots.into_iter().skip(offset as usize) {
+            if reply.add(ino, next, FileType::Directory, OsStr::from_bytes(name)) {
+                return reply.ok();
+            }
+        }
+        let mut index = (offset - 2).max(0) as usize;
+        loop {
+            let (name, child) = match self.engine.list_dentries_raw(ino, index) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(x) => return reply.error(x as i32),
+            };
+            index += 1;
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let kind = match self.engine.get_metadata(child) {
+                Ok(meta) => file_type(meta.mode),
+                Err(x) => return reply.error(x as i32),
+            };
+            if reply.add(child, index as i64 + 2, kind, OsStr::from_bytes(&name)) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mode = 0o100000 | (mode & !umask & 0o7777);
+        let res = self
+            .engine
+            .create_exclusive(parent, name.as_bytes(), mode)
+            .and_then(|ino| self.attr(ino));
+        match res {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let mode = 0o040000 | (mode & !umask & 0o7777);
+        let res = self
+            .engine
+            .create_exclusive(parent, name.as_bytes(), mode)
+            .and_then(|ino| self.attr(ino));
+        match res {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x as i32),
+        }
+    }
+}
@@ -20,7 +20,7 @@ use crate::{
     dbfs_time_spec,
     file::{DBFS_DIR_FILE_OPS, DBFS_FILE_FILE_OPS, DBFS_SYMLINK_FILE_OPS},
     link::{dbfs_common_readlink, dbfs_common_unlink},
-    u16, u32, u64, usize, SLICE_SIZE,
+    try_clone_db, u16, u32, u64, usize, SLICE_SIZE,
 };
 
 pub static DBFS_INODE_NUMBER: AtomicUsize = AtomicUsize::new(1);
@@ -110,7 +110,7 @@ pub fn dbfs_common_link(
         return Err(DbfsError::AccessError);
     }
 
-    let db = clone_db();
+    let db = try_clone_db()?;
     // update new inode data in db
     let tx = db.tx(true)?;
     let bucket = tx.get_bucket(new_ino.to_be_bytes())?;
@@ -198,7 +198,7 @@ fn dbfs_lookup(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
 }
 
 pub fn dbfs_common_lookup(dir: usize, name: &str) -> DbfsResult<DbfsAttr> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(false)?;
     let bucket = tx.get_bucket(dir.to_be_bytes())?;
 
@@ -216,7 +216,7 @@ pub fn dbfs_common_lookup(dir: usize, name: &str) -> DbfsResult<DbfsAttr> {
 }
 
 pub fn dbfs_common_attr(number: usize) -> DbfsResult<DbfsAttr> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(false)?;
     let bucket = tx.get_bucket(number.to_be_bytes())?;
     let size = bucket.get_kv("size").unwrap();
@@ -557,7 +557,7 @@ pub fn dbfs_common_create(
 ) -> DbfsResult<DbfsAttr> {
     ddebug!("dbfs_common_create");
     let new_number = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(true)?;
 
     // find the dir
@@ -686,7 +686,7 @@ pub fn dbfs_common_create(
 }
 
 pub fn dbfs_common_access(p_uid: u32, p_gid: u32, ino: usize, mask: i32) -> DbfsResult<bool> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(false)?;
     let inode = tx.get_bucket(ino.to_be_bytes())?;
     let mode = inode.get_kv("mode").unwrap();
@@ -713,7 +713,7 @@ pub fn dbfs_common_truncate(
         return Err(DbfsError::AccessError);
     }
 
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(true)?;
     let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
     let start = f_size / SLICE_SIZE;
@@ -790,7 +790,7 @@ pub fn dbfs_common_rmdir(
     name: &str,
     c_time: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(true)?;
     let p_bucket = tx.get_bucket(p_ino.to_be_bytes())?;
 
@@ -851,7 +851,7 @@ pub fn dbfs_common_fallocate(
     mode: u32,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let tx = db.tx(true)?;
     let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
 
@@ -911,7 +911,7 @@ pub fn dbfs_common_rename(
     flags: u32,
     ctime: DbfsTimeSpec,
 ) -> DbfsResult<()> {
-    let db = clone_db();
+    let db = try_clone_db()?;
     let (old_key, old_number, old_uid, old_gid, old_perm) = {
         let tx = db.tx(false)?;
         let old_dir_bucket = tx.get_bucket(old_dir.to_be_bytes())?;
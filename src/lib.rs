@@ -24,16 +24,12 @@ pub mod alien_integration;
 
 // DBFS Transactional Core
 pub mod operation;
-pub mod wal;
 pub mod transaction;
+pub mod wal;
 
 #[cfg(test)]
 mod dbfs_test;
 
-// Common DBFS functions for both old and new RVFS
-#[cfg(feature = "rvfs")]
-mod common;
-
 mod fs_common;
 
 // Old RVFS modules (only compile when rvfs feature is available)
@@ -50,6 +46,7 @@ use alloc::{alloc::alloc, sync::Arc};
 use core::{
     alloc::Layout,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use buddy_system_allocator::LockedHeap;
@@ -57,26 +54,34 @@ use buddy_system_allocator::LockedHeap;
 #[cfg(feature = "rvfs")]
 pub use fs_type::DBFS;
 
+use common::{DbfsError, DbfsResult};
 use jammdb::DB;
-use log::error;
-use spin::Once;
+use log::debug;
+use spin::{Once, RwLock};
 
 #[cfg(feature = "dbop")]
 pub mod extend;
 
-#[cfg(feature = "dbop")]
+#[cfg(feature = "dbfs_t")]
 pub mod models;
 
-#[cfg(feature = "dbop")]
+#[cfg(feature = "dbfs_t")]
 pub mod log_manager;
 
-#[cfg(feature = "dbop")]
+#[cfg(feature = "dbfs_t")]
+pub mod metrics;
+
+#[cfg(feature = "dbfs_t")]
 pub mod tx_engine;
 
-#[cfg(feature = "dbop")]
+#[cfg(feature = "dbfs_t")]
 pub mod rvfs_adapter;
 
-#[cfg(all(test, feature = "dbop"))]
+#[cfg(all(test, feature = "alien_integration"))]
+mod alien_test;
+#[cfg(all(test, feature = "rvfs2"))]
+mod rvfs2_test;
+#[cfg(all(test, feature = "dbfs_t"))]
 mod rvfs_test;
 #[cfg(feature = "fuse")]
 pub use file::FLAG;
@@ -105,15 +110,60 @@ impl DerefMut for SafeDb {
 unsafe impl Sync for SafeDb {}
 unsafe impl Send for SafeDb {}
 
-static DB: Once<Arc<SafeDb>> = Once::new();
+type DbSlot = RwLock<Option<Arc<SafeDb>>>;
+
+static DB: DbSlot = RwLock::new(None);
 
 /// Initialize the global DBFS database
 pub fn init_dbfs(db: DB) {
-    DB.call_once(|| Arc::new(SafeDb(db)));
+    init_dbfs_in(&DB, db);
 }
 
+/// Fill `slot` unless it already holds a database; later calls are ignored
+fn init_dbfs_in(slot: &DbSlot, db: DB) {
+    let mut slot = slot.write();
+    if slot.is_none() {
+        *slot = Some(Arc::new(SafeDb(db)));
+    }
+}
+
+/// Empty `slot` so the next `init_dbfs_in` installs a fresh database
+#[cfg(test)]
+fn reset_dbfs_in(slot: &DbSlot) -> Option<Arc<SafeDb>> {
+    slot.write().take()
+}
+
+/// Settings the embedder picks when initializing DBFS
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DbfsOptions {
+    /// Bytes reserved for the buffer cache, `DEFAULT_CACHE_SIZE` if unset. Must be a non-zero
+    /// multiple of `CACHE_ALIGN`.
+    pub cache_size: Option<usize>,
+}
+
+/// Initialize the global DBFS database with explicit options
+pub fn init_dbfs_with_options(db: DB, options: DbfsOptions) -> DbfsResult<()> {
+    if let Some(size) = options.cache_size {
+        check_cache_size(size)?;
+        CACHE_SIZE.store(size, Ordering::SeqCst);
+    }
+    init_dbfs(db);
+    Ok(())
+}
+
+/// The global database; only for call sites that cannot run before `init_dbfs`
 fn clone_db() -> Arc<SafeDb> {
-    DB.get().unwrap().clone()
+    DB.read().as_ref().unwrap().clone()
+}
+
+/// The global database, or `NotInitialized` if `init_dbfs` has not been called yet
+fn try_clone_db() -> DbfsResult<Arc<SafeDb>> {
+    try_clone_db_from(&DB)
+}
+
+/// `try_clone_db` against an explicit slot
+fn try_clone_db_from(slot: &DbSlot) -> DbfsResult<Arc<SafeDb>> {
+    slot.read().clone().ok_or(DbfsError::NotInitialized)
 }
 
 #[macro_export]
@@ -166,17 +216,61 @@ pub const SLICE_SIZE: usize = 8192;
 pub const SLICE_SIZE: usize = 8192 * 2 * 2;
 
 static BUDDY_ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
-const MAX_BUF_SIZE: usize = 8 * 1024 * 1024; // 8MB
+/// Buffer cache size used when the embedder does not choose one
+pub const DEFAULT_CACHE_SIZE: usize = 8 * 1024 * 1024; // 8MB
+/// Granularity and alignment of the buffer cache
+pub const CACHE_ALIGN: usize = 4096;
+static CACHE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_CACHE_SIZE);
 
 pub const BUCKET_DATA_SIZE: usize = 128 * 1024 * 1024; // 512
 
-fn init_cache() {
-    error!("alloc {}MB for cache", 8);
-    unsafe {
-        let ptr = alloc(Layout::from_size_align_unchecked(MAX_BUF_SIZE, 8));
-        BUDDY_ALLOCATOR.lock().init(ptr as usize, MAX_BUF_SIZE);
-    };
-    error!("alloc ok");
+static CACHE_INIT: Once<DbfsResult<()>> = Once::new();
+
+fn check_cache_size(size: usize) -> DbfsResult<()> {
+    if size == 0 || size % CACHE_ALIGN != 0 {
+        return Err(DbfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// The cache size chosen in `init_dbfs_with_options`
+fn cache_size() -> usize {
+    CACHE_SIZE.load(Ordering::SeqCst)
+}
+
+/// Hand `size` freshly allocated bytes to `heap`
+fn init_heap(heap: &LockedHeap<32>, size: usize) -> DbfsResult<()> {
+    check_cache_size(size)?;
+    let layout =
+        Layout::from_size_align(size, CACHE_ALIGN).map_err(|_| DbfsError::InvalidArgument)?;
+    let ptr = unsafe { alloc(layout) };
+    if ptr.is_null() {
+        return Err(DbfsError::NoSpace);
+    }
+    unsafe { heap.lock().init(ptr as usize, size) };
+    Ok(())
+}
+
+/// Give `BUDDY_ALLOCATOR` `size` bytes of memory; later calls return the first call's result
+fn init_cache(size: usize) -> DbfsResult<()> {
+    *CACHE_INIT.call_once(|| {
+        let result = init_heap(&BUDDY_ALLOCATOR, size);
+        if result.is_ok() {
+            debug!("alloc {}KB for cache", size / 1024);
+        }
+        result
+    })
+}
+
+/// `dst.copy_from_slice(src)`, taking the widened copies of `copy_data` when both buffers and
+/// the length are at least 4-byte aligned
+pub(crate) fn fast_copy(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "fast_copy: length mismatch");
+    if (src.as_ptr() as usize | dst.as_ptr() as usize | src.len()) % 4 == 0 {
+        copy_data(src.as_ptr(), dst.as_mut_ptr(), src.len());
+    } else {
+        dst.copy_from_slice(src);
+    }
 }
 
 fn copy_data(src: *const u8, dest: *mut u8, len: usize) {
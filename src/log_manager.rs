@@ -1,62 +1,341 @@
-use crate::common::DbfsResult;
+pub use crate::common::crc32;
+use crate::common::{DbfsError, DbfsResult};
+use crate::metrics::{ops, Metrics, Timer};
+use alloc::sync::Arc;
 
 pub trait BlockDevice: Send + Sync {
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize>;
     fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize>;
     fn size(&self) -> u64;
+
+    /// 以借用的方式访问 `[pos, pos + len)` 的数据, 切片只在回调期间有效
+    ///
+    /// 默认实现读入临时缓冲区; 内存或 mmap 设备可以覆盖此方法直接借出底层数据, 避免拷贝。
+    fn with_slice(&self, pos: u64, len: usize, f: &mut dyn FnMut(&[u8])) -> DbfsResult<()> {
+        let mut buf = alloc::vec![0u8; len];
+        let n = self.read_at(pos, &mut buf)?;
+        f(&buf[..n]);
+        Ok(())
+    }
+
+    /// 一次提交多个读请求, 每个请求是 (物理偏移, 目标缓冲区), 返回读取的总字节数
+    ///
+    /// 默认实现逐个调用 `read_at`; 支持 scatter-gather 的设备可以覆盖此方法并行读取。
+    fn read_vectored(&self, reqs: &mut [(u64, &mut [u8])]) -> DbfsResult<usize> {
+        let mut total = 0;
+        for (pos, buf) in reqs.iter_mut() {
+            total += self.read_at(*pos, buf)?;
+        }
+        Ok(total)
+    }
+
+    /// 写屏障: 返回前此前写入的数据都已落盘
+    ///
+    /// 默认实现为空操作, 适用于没有写缓存的设备。
+    fn flush(&self) -> DbfsResult<()> {
+        Ok(())
+    }
+}
+
+/// 异步块设备, 用于 Alien 中基于中断的 NVMe/virtio 驱动
+///
+/// 通过 [`BlockOn`] 适配为同步的 [`BlockDevice`] 后交给 `LogManager`/`TransactionEngine` 使用。
+#[cfg(feature = "async_device")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockDevice: Send + Sync {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize>;
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize>;
+    fn size(&self) -> u64;
+
+    /// 写屏障, 语义同 [`BlockDevice::flush`]
+    async fn flush(&self) -> DbfsResult<()> {
+        Ok(())
+    }
+}
+
+/// 在当前线程上轮询 future 直到完成
+///
+/// 没有可用的执行器时, 等待期间自旋; 设备驱动应在 I/O 完成后唤醒或直接返回 `Ready`。
+#[cfg(feature = "async_device")]
+pub fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+
+    let waker = unsafe { Waker::from_raw(noop_raw()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// 把 [`AsyncBlockDevice`] 适配为同步的 [`BlockDevice`], 每次调用阻塞直到 I/O 完成
+#[cfg(feature = "async_device")]
+pub struct BlockOn<A: AsyncBlockDevice>(pub A);
+
+#[cfg(feature = "async_device")]
+impl<A: AsyncBlockDevice> BlockDevice for BlockOn<A> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        block_on(self.0.read_at(pos, buf))
+    }
+
+    fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+        block_on(self.0.write_at(pos, buf))
+    }
+
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn flush(&self) -> DbfsResult<()> {
+        block_on(self.0.flush())
+    }
+}
+
+/// 日志写放大统计
+///
+/// 覆盖写会追加新的 extent 而不是原地修改, 在回收旧数据之前
+/// `physical_written / logical_written` 反映了写放大的程度。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogStats {
+    /// 写入后新增的文件内容字节数, 覆盖已有数据的部分不计入
+    pub logical_written: u64,
+    /// 实际追加到设备上的字节数
+    pub physical_written: u64,
+    /// 追加次数
+    pub appends: u64,
+}
+
+/// 日志中预留的一段区域, 见 [`LogManager::stage`]
+pub struct LogSlot<D: BlockDevice> {
+    device: Arc<D>,
+    retry: RetryPolicy,
+    pos: u64,
+    len: u64,
+}
+
+impl<D: BlockDevice> LogSlot<D> {
+    /// 区域的物理偏移
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// 把 `data` 写入区域, 长度必须与预留的长度相同
+    pub fn write(&self, data: &[u8]) -> DbfsResult<()> {
+        if data.len() as u64 != self.len {
+            return Err(DbfsError::InvalidArgument);
+        }
+        self.retry.run(|| self.device.write_at(self.pos, data))?;
+        Ok(())
+    }
+}
+
+/// 块设备读写的重试策略
+///
+/// 只有 `Io` 错误会重试, 其他错误 (如越界) 重试也不会成功, 直接返回。第 n 次重试前
+/// 自旋 `backoff_spins << (n - 1)` 次。默认不重试。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 首次失败之后最多重试的次数
+    pub retries: u32,
+    /// 第一次重试前自旋的次数, 之后每次翻倍
+    pub backoff_spins: u32,
+}
+
+impl RetryPolicy {
+    /// 执行 `op`, 按策略重试 `Io` 错误, 重试用尽后返回最后一次的错误
+    pub fn run<T>(&self, mut op: impl FnMut() -> DbfsResult<T>) -> DbfsResult<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(DbfsError::Io) if attempt < self.retries => {
+                    let spins = self.backoff_spins.checked_shl(attempt).unwrap_or(u32::MAX);
+                    for _ in 0..spins {
+                        core::hint::spin_loop();
+                    }
+                    attempt += 1;
+                    log::warn!(
+                        "block device I/O failed, retry {}/{}",
+                        attempt,
+                        self.retries
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 pub struct LogManager<D: BlockDevice> {
-    device: D,
+    device: Arc<D>,
+    start: u64,           // 日志区的起点
     next_append_pos: u64, // 下一个追加位置
+    /// 上次 flush 之后是否追加过数据
+    unflushed: bool,
+    stats: LogStats,
+    retry: RetryPolicy,
+    metrics: Option<Metrics>,
 }
 
 impl<D: BlockDevice> LogManager<D> {
     pub fn new(device: D, next_append_pos: u64) -> Self {
         Self {
-            device,
+            device: Arc::new(device),
+            start: next_append_pos,
             next_append_pos,
+            unflushed: false,
+            stats: LogStats::default(),
+            retry: RetryPolicy::default(),
+            metrics: None,
         }
     }
 
+    /// 日志区从创建时的位置开始, 从 `pos` 继续追加 (重新挂载时使用)
+    pub fn resume_at(mut self, pos: u64) -> Self {
+        self.next_append_pos = pos.max(self.start);
+        self
+    }
+
+    /// 设置设备读写的重试策略
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 安装或移除计时回调, 计时 `append_data`
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Option<Metrics>) {
+        self.metrics = metrics;
+    }
+
     /// 核心操作：追加数据并返回物理偏移
-    pub fn append_data(&mut self, data: &[u8]) -> DbfsResult<u64> {
+    ///
+    /// `data` 中有 `logical` 字节是文件的新内容, 其余部分覆盖了已有数据。
+    pub fn append_data(&mut self, data: &[u8], logical: u64) -> DbfsResult<u64> {
+        let timer = Timer::start(self.metrics);
         let current_pos = self.next_append_pos;
-        
+
         // 1. 计算校验和
         let _checksum = crc32(data);
-        
+
         // 2. 写入数据负载到磁盘
-        self.device.write_at(current_pos, data)?;
-        
+        self.retry.run(|| self.device.write_at(current_pos, data))?;
+
         // 3. 更新指针
         self.next_append_pos += data.len() as u64;
-        
+        self.unflushed = true;
+        self.stats.logical_written += logical;
+        self.stats.physical_written += data.len() as u64;
+        self.stats.appends += 1;
+        timer.stop(ops::APPEND_DATA);
+
         Ok(current_pos)
     }
 
+    /// 在日志中预留 `len` 字节而不写入数据, 返回预留区域的物理偏移
+    pub fn reserve(&mut self, len: u64) -> DbfsResult<u64> {
+        let current_pos = self.next_append_pos;
+        if current_pos
+            .checked_add(len)
+            .map_or(true, |end| end > self.device.size())
+        {
+            return Err(DbfsError::NoSpace);
+        }
+        self.next_append_pos += len;
+        Ok(current_pos)
+    }
+
+    /// 为 `len` 字节的数据预留日志空间, 数据之后通过返回的 [`LogSlot`] 写入
+    ///
+    /// 预留只移动写指针, 写入设备不需要借用日志管理器, 多个写者可以同时写各自预留的区域。
+    /// 统计与 `append_data` 相同, `logical` 是其中新增的文件内容字节数。
+    pub fn stage(&mut self, len: u64, logical: u64) -> DbfsResult<LogSlot<D>> {
+        let pos = self.reserve(len)?;
+        self.stats.logical_written += logical;
+        self.stats.physical_written += len;
+        self.stats.appends += 1;
+        Ok(LogSlot {
+            device: self.device.clone(),
+            retry: self.retry,
+            pos,
+            len,
+        })
+    }
+
+    /// 记录有数据在日志管理器之外 (通过 [`LogSlot`]) 写入, 下次 `flush` 需要让设备落盘
+    pub fn mark_unflushed(&mut self) {
+        self.unflushed = true;
+    }
+
+    /// 让已追加的数据落盘, 之后才能提交指向它们的元数据
+    pub fn flush(&mut self) -> DbfsResult<()> {
+        if self.unflushed {
+            self.device.flush()?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+
+    /// 创建共享同一块设备、写指针停在 `next_append_pos` 的日志管理器, 供快照视图读取
+    pub fn fork(&self, next_append_pos: u64) -> Self {
+        Self {
+            device: self.device.clone(),
+            start: self.start,
+            next_append_pos,
+            unflushed: false,
+            stats: LogStats::default(),
+            retry: self.retry,
+            metrics: self.metrics,
+        }
+    }
+
     pub fn next_append_pos(&self) -> u64 {
         self.next_append_pos
     }
 
+    /// 日志区的起点
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// 块设备的大小, 日志区不能超出它
+    pub fn device_size(&self) -> u64 {
+        self.device.size()
+    }
+
+    /// 把写指针移回 `pos`, 调用者保证 `pos` 之后的数据都不再被引用
+    pub(crate) fn rewind(&mut self, pos: u64) {
+        self.next_append_pos = pos.max(self.start);
+    }
+
+    /// 自创建以来的写放大统计
+    pub fn stats(&self) -> LogStats {
+        self.stats
+    }
+
     /// 从指定物理位置读取数据
     pub fn read_data(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
-        self.device.read_at(pos, buf)
+        self.retry.run(|| self.device.read_at(pos, buf))
     }
-}
 
-/// 简单的 CRC32 实现
-pub fn crc32(data: &[u8]) -> u32 {
-    let mut crc = 0xFFFFFFFFu32;
-    for &byte in data {
-        crc ^= byte as u32;
-        for _ in 0..8 {
-            if crc & 1 != 0 {
-                crc = (crc >> 1) ^ 0xEDB88320;
-            } else {
-                crc >>= 1;
-            }
-        }
+    /// 一次读取多个物理位置的数据
+    pub fn read_data_vectored(&self, reqs: &mut [(u64, &mut [u8])]) -> DbfsResult<usize> {
+        self.retry.run(|| self.device.read_vectored(reqs))
+    }
+
+    /// 借用指定物理位置的数据, 切片只在回调期间有效
+    pub fn with_data(&self, pos: u64, len: usize, f: &mut dyn FnMut(&[u8])) -> DbfsResult<()> {
+        self.retry.run(|| self.device.with_slice(pos, len, &mut *f))
     }
-    !crc
 }
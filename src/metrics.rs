@@ -0,0 +1,46 @@
+//! DBFS-T 的操作计时
+//!
+//! 库本身不依赖任何时间源: 嵌入方通过 [`Metrics`] 提供时钟和接收耗时的回调, 在
+//! `metrics` feature 下用 `TransactionEngine::set_metrics` 安装。没有安装时计时点只检查一次
+//! `None`, 不读时钟。
+
+/// 计时使用的时钟和回调
+#[derive(Clone, Copy)]
+pub struct Metrics {
+    /// 单调时钟, 单位为纳秒
+    pub clock: fn() -> u64,
+    /// 每个计时的操作成功结束时调用, 参数为操作名和耗时 (纳秒)
+    pub observe: fn(&str, u64),
+}
+
+/// 计时的操作名
+pub mod ops {
+    /// `TransactionEngine::write_file_transactional`
+    pub const WRITE_FILE: &str = "write_file_transactional";
+    /// `TransactionEngine::read_file`
+    pub const READ_FILE: &str = "read_file";
+    /// `LogManager::append_data`
+    pub const APPEND_DATA: &str = "append_data";
+    /// 批处理的 jammdb 提交
+    pub const DB_COMMIT: &str = "db_commit";
+}
+
+/// 一次进行中的计时, 没有安装 [`Metrics`] 时什么也不做
+pub(crate) struct Timer {
+    started: Option<(Metrics, u64)>,
+}
+
+impl Timer {
+    pub(crate) fn start(metrics: Option<Metrics>) -> Self {
+        Self {
+            started: metrics.map(|m| (m, (m.clock)())),
+        }
+    }
+
+    /// 结束计时, 以 `op` 为名上报耗时
+    pub(crate) fn stop(self, op: &str) {
+        if let Some((m, start)) = self.started {
+            (m.observe)(op, (m.clock)().saturating_sub(start));
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use serde::{Serialize, Deserialize};
 use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
 
 /// 物理数据块描述符
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -8,6 +8,23 @@ pub struct Extent {
     pub physical_ptr: u64, // 磁盘数据区的绝对偏移
     pub len: u64,          // 数据长度
     pub crc: u32,          // 用于崩溃后校验数据完整性
+    #[serde(default)]
+    pub reserved: bool, // 预分配的 extent: 只占用日志空间, 读取时返回 0
+    #[serde(default)]
+    pub compressed: bool, // 日志中存放的是压缩后的数据, 读取时整体解压
+    #[serde(default)]
+    pub stored_len: u64, // 压缩后在日志中占用的字节数; 未压缩时为 0
+}
+
+impl Extent {
+    /// 在日志中占用的字节数
+    pub fn physical_len(&self) -> u64 {
+        if self.compressed {
+            self.stored_len
+        } else {
+            self.len
+        }
+    }
 }
 
 /// 存储在 jammdb Value 中的 Inode 元数据
@@ -15,9 +32,37 @@ pub struct Extent {
 pub struct InodeMetadata {
     pub ino: u64,
     pub size: u64,
-    pub mode: u32,         // 权限与类型
-    pub nlink: u32,        // 硬链接计数
+    pub mode: u32,            // 权限与类型
+    pub nlink: u32,           // 硬链接计数
     pub extents: Vec<Extent>, // 物理块映射表（索引核心）
     pub atime: i64,
     pub mtime: i64,
+    #[serde(default)]
+    pub uid: u32, // 属主, 用于配额统计; 旧记录没有此字段, 按 0 处理
+    #[serde(default)]
+    pub entry_count: Option<u64>, // 目录中 `.` 和 `..` 以外的目录项数; 非目录和旧记录为 None
+    #[serde(default = "legacy_version")]
+    pub version: u16, // 记录的格式版本; 没有此字段的记录是第 1 版
+}
+
+/// 当前写入的 `InodeMetadata` 格式版本
+///
+/// - 1: 最初的格式, 没有 `version` 字段
+/// - 2: 增加 `version`; `uid` 和 `entry_count` 在旧记录中按默认值处理
+pub const INODE_METADATA_VERSION: u16 = 2;
+
+fn legacy_version() -> u16 {
+    1
+}
+
+impl InodeMetadata {
+    /// 把旧版本的记录升级到当前格式; 比当前版本更新的记录无法理解, 返回 false
+    pub fn migrate(&mut self) -> bool {
+        if self.version > INODE_METADATA_VERSION {
+            return false;
+        }
+        // 1 -> 2: 新字段已由 serde 的默认值补齐, 只需要更新版本号
+        self.version = INODE_METADATA_VERSION;
+        true
+    }
 }
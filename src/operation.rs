@@ -1,7 +1,10 @@
 use alloc::string::String;
 use alloc::vec::Vec;
+use jammdb::{Bucket, Data, DB};
 use serde::{Deserialize, Serialize};
 
+use crate::{clone_db, common::decode_dentry};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionOperation {
     Write {
@@ -40,61 +43,538 @@ pub enum TransactionOperation {
     },
 }
 
+/// Reads the fields `TransactionOperation::encode` wrote, in order
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        self.take(len).map(|b| b.to_vec())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?).ok()
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Raw key/value pairs copied out of an inode bucket
+type Snapshot = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// State captured just before an operation is applied, enough for `undo` to reverse it.
+#[derive(Debug, Clone)]
+pub enum UndoState {
+    /// Previous `size` and `data_*` blocks of the file (`Write`, `Truncate`)
+    Data {
+        size: Option<Vec<u8>>,
+        blocks: Snapshot,
+    },
+    /// The entry did not exist before (`Create`, `Mkdir`)
+    NewEntry { parent: ParentAttrs },
+    /// The removed entry, the inode it pointed to and a copy of that inode (`Delete`)
+    Entry {
+        entry: Vec<u8>,
+        ino: usize,
+        inode: Snapshot,
+        parent: ParentAttrs,
+    },
+    /// The entry a rename overwrote with its inode number and a copy of it, if any (`Rename`)
+    Replaced {
+        entry: Option<(Vec<u8>, usize, Snapshot)>,
+    },
+}
+
+/// Attributes of a directory that adding or removing one of its entries changes
+const PARENT_KEYS: [&str; 3] = ["hard_links", "mtime", "ctime"];
+
+/// The parent's [`PARENT_KEYS`] before an operation, `None` where the key was absent
+pub type ParentAttrs = Vec<(&'static str, Option<Vec<u8>>)>;
+
+fn parent_attrs(bucket: &Bucket) -> ParentAttrs {
+    PARENT_KEYS
+        .iter()
+        .map(|&key| (key, bucket.get_kv(key).map(|kv| kv.value().to_vec())))
+        .collect()
+}
+
+fn restore_parent_attrs(bucket: &Bucket, attrs: &ParentAttrs) -> Result<(), String> {
+    for (key, value) in attrs {
+        match value {
+            Some(value) => bucket
+                .put(*key, value.clone())
+                .map(|_| ())
+                .map_err(|e| alloc::format!("Undo error: {:?}", e))?,
+            None => {
+                let _ = bucket.delete(*key);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn snapshot(bucket: &Bucket, data_only: bool) -> Snapshot {
+    let mut kvs = Vec::new();
+    bucket.cursor().for_each(|data| {
+        if let Data::KeyValue(kv) = data {
+            if !data_only || kv.key().starts_with(b"data_") {
+                kvs.push((kv.key().to_vec(), kv.value().to_vec()));
+            }
+        }
+    });
+    kvs
+}
+
+/// `snapshot` of inode `ino` as it will be once its dirty cached blocks are written back
+fn snapshot_cached(db: &DB, ino: usize, bucket: &Bucket, data_only: bool) -> Snapshot {
+    #[allow(unused_mut)]
+    let mut kvs = snapshot(bucket, data_only);
+    #[cfg(feature = "rvfs2")]
+    for (key, value) in crate::rvfs2::cache::dirty_blocks(db, ino) {
+        match kvs.iter_mut().find(|(k, _)| *k == key) {
+            Some(kv) => kv.1 = value,
+            None => kvs.push((key, value)),
+        }
+    }
+    #[cfg(not(feature = "rvfs2"))]
+    let _ = (db, ino);
+    kvs
+}
+
+/// Put a saved inode back, recreating its bucket if the operation removed it
+fn restore_inode(tx: &jammdb::Tx, ino: usize, inode: &Snapshot) -> Result<(), String> {
+    let bucket = tx
+        .get_or_create_bucket(ino.to_be_bytes())
+        .map_err(|e| alloc::format!("Undo error: {:?}", e))?;
+    for (key, value) in inode {
+        bucket
+            .put(key.clone(), value.clone())
+            .map_err(|e| alloc::format!("Undo error: {:?}", e))?;
+    }
+    Ok(())
+}
+
 impl TransactionOperation {
+    /// The inode this operation modifies: the file itself, or the parent directory for
+    /// namespace operations.
+    pub fn target_ino(&self) -> usize {
+        match self {
+            TransactionOperation::Write { ino, .. }
+            | TransactionOperation::Truncate { ino, .. } => *ino,
+            TransactionOperation::Create { parent_ino, .. }
+            | TransactionOperation::Delete { parent_ino, .. }
+            | TransactionOperation::Mkdir { parent_ino, .. } => *parent_ino,
+            TransactionOperation::Rename { old_parent_ino, .. } => *old_parent_ino,
+        }
+    }
+
+    /// Append the compact binary form of the operation to `out`.
+    ///
+    /// A tag byte is followed by the fields in declaration order: integers little-endian,
+    /// strings and data as a `u32` length and the bytes, an optional device as a presence
+    /// byte and the value.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            TransactionOperation::Write { ino, offset, data } => {
+                out.push(0);
+                out.extend_from_slice(&(*ino as u64).to_le_bytes());
+                out.extend_from_slice(&offset.to_le_bytes());
+                put_bytes(out, data);
+            }
+            TransactionOperation::Create {
+                parent_ino,
+                name,
+                uid,
+                gid,
+                perm,
+                dev,
+            } => {
+                out.push(1);
+                out.extend_from_slice(&(*parent_ino as u64).to_le_bytes());
+                put_bytes(out, name.as_bytes());
+                out.extend_from_slice(&uid.to_le_bytes());
+                out.extend_from_slice(&gid.to_le_bytes());
+                out.extend_from_slice(&perm.to_le_bytes());
+                match dev {
+                    Some(dev) => {
+                        out.push(1);
+                        out.extend_from_slice(&dev.to_le_bytes());
+                    }
+                    None => out.push(0),
+                }
+            }
+            TransactionOperation::Delete { parent_ino, name } => {
+                out.push(2);
+                out.extend_from_slice(&(*parent_ino as u64).to_le_bytes());
+                put_bytes(out, name.as_bytes());
+            }
+            TransactionOperation::Rename {
+                old_parent_ino,
+                old_name,
+                new_parent_ino,
+                new_name,
+            } => {
+                out.push(3);
+                out.extend_from_slice(&(*old_parent_ino as u64).to_le_bytes());
+                put_bytes(out, old_name.as_bytes());
+                out.extend_from_slice(&(*new_parent_ino as u64).to_le_bytes());
+                put_bytes(out, new_name.as_bytes());
+            }
+            TransactionOperation::Mkdir {
+                parent_ino,
+                name,
+                uid,
+                gid,
+                perm,
+            } => {
+                out.push(4);
+                out.extend_from_slice(&(*parent_ino as u64).to_le_bytes());
+                put_bytes(out, name.as_bytes());
+                out.extend_from_slice(&uid.to_le_bytes());
+                out.extend_from_slice(&gid.to_le_bytes());
+                out.extend_from_slice(&perm.to_le_bytes());
+            }
+            TransactionOperation::Truncate { ino, length } => {
+                out.push(5);
+                out.extend_from_slice(&(*ino as u64).to_le_bytes());
+                out.extend_from_slice(&length.to_le_bytes());
+            }
+        }
+    }
+
+    /// Inverse of [`TransactionOperation::encode`]; `None` if `buf` is not exactly one operation.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader(buf);
+        let op = match r.u8()? {
+            0 => TransactionOperation::Write {
+                ino: r.u64()? as usize,
+                offset: r.u64()?,
+                data: r.bytes()?,
+            },
+            1 => TransactionOperation::Create {
+                parent_ino: r.u64()? as usize,
+                name: r.string()?,
+                uid: r.u32()?,
+                gid: r.u32()?,
+                perm: r.u32()?,
+                dev: match r.u8()? {
+                    0 => None,
+                    1 => Some(r.u32()?),
+                    _ => return None,
+                },
+            },
+            2 => TransactionOperation::Delete {
+                parent_ino: r.u64()? as usize,
+                name: r.string()?,
+            },
+            3 => TransactionOperation::Rename {
+                old_parent_ino: r.u64()? as usize,
+                old_name: r.string()?,
+                new_parent_ino: r.u64()? as usize,
+                new_name: r.string()?,
+            },
+            4 => TransactionOperation::Mkdir {
+                parent_ino: r.u64()? as usize,
+                name: r.string()?,
+                uid: r.u32()?,
+                gid: r.u32()?,
+                perm: r.u32()?,
+            },
+            5 => TransactionOperation::Truncate {
+                ino: r.u64()? as usize,
+                length: r.u64()?,
+            },
+            _ => return None,
+        };
+        r.0.is_empty().then_some(op)
+    }
+
+    /// Whether the operation only changes metadata, so `fdatasync` may skip it.
+    pub fn is_metadata_only(&self) -> bool {
+        !matches!(
+            self,
+            TransactionOperation::Write { .. } | TransactionOperation::Truncate { .. }
+        )
+    }
+
+    /// Capture the state `undo` needs, before the operation is applied.
+    pub fn capture(&self) -> Result<UndoState, String> {
+        self.capture_in(&clone_db())
+    }
+
+    /// `capture` against `db` rather than the global database.
+    pub fn capture_in(&self, db: &DB) -> Result<UndoState, String> {
+        let tx = db
+            .tx(false)
+            .map_err(|e| alloc::format!("Capture error: {:?}", e))?;
+        let bucket = |ino: usize| {
+            tx.get_bucket(ino.to_be_bytes())
+                .map_err(|e| alloc::format!("Capture error: {:?}", e))
+        };
+
+        let state = match self {
+            TransactionOperation::Write { ino, .. }
+            | TransactionOperation::Truncate { ino, .. } => {
+                let inode = bucket(*ino)?;
+                UndoState::Data {
+                    size: inode.get_kv("size").map(|kv| kv.value().to_vec()),
+                    blocks: snapshot_cached(db, *ino, &inode, true),
+                }
+            }
+            TransactionOperation::Create { parent_ino, .. }
+            | TransactionOperation::Mkdir { parent_ino, .. } => UndoState::NewEntry {
+                parent: parent_attrs(&bucket(*parent_ino)?),
+            },
+            TransactionOperation::Delete { parent_ino, name } => {
+                let parent_bucket = bucket(*parent_ino)?;
+                let parent = parent_attrs(&parent_bucket);
+                let entry = parent_bucket
+                    .get_kv(name.as_bytes())
+                    .map(|kv| kv.value().to_vec())
+                    .ok_or_else(|| alloc::format!("Capture error: {} not found", name))?;
+                let (ino, _) = decode_dentry(&entry);
+                let inode = tx
+                    .get_bucket(ino.to_be_bytes())
+                    .map(|b| snapshot_cached(db, ino, &b, false))
+                    .unwrap_or_default();
+                UndoState::Entry {
+                    entry,
+                    ino,
+                    inode,
+                    parent,
+                }
+            }
+            TransactionOperation::Rename {
+                new_parent_ino,
+                new_name,
+                ..
+            } => {
+                let entry = bucket(*new_parent_ino)?
+                    .get_kv(new_name.as_bytes())
+                    .map(|kv| kv.value().to_vec())
+                    .map(|entry| {
+                        let (ino, _) = decode_dentry(&entry);
+                        let inode = tx
+                            .get_bucket(ino.to_be_bytes())
+                            .map(|b| snapshot_cached(db, ino, &b, false))
+                            .unwrap_or_default();
+                        (entry, ino, inode)
+                    });
+                UndoState::Replaced { entry }
+            }
+        };
+        Ok(state)
+    }
+
+    /// Reverse an applied operation using the state captured before it ran.
+    pub fn undo(&self, state: &UndoState) -> Result<(), String> {
+        self.undo_in(&clone_db(), state)
+    }
+
+    /// `undo` against `db` rather than the global database.
+    pub fn undo_in(&self, db: &DB, state: &UndoState) -> Result<(), String> {
+        let tx = db
+            .tx(true)
+            .map_err(|e| alloc::format!("Undo error: {:?}", e))?;
+        let err = |e: jammdb::Error| alloc::format!("Undo error: {:?}", e);
+
+        match (self, state) {
+            (
+                TransactionOperation::Write { ino, .. }
+                | TransactionOperation::Truncate { ino, .. },
+                UndoState::Data { size, blocks },
+            ) => {
+                let inode = tx.get_bucket(ino.to_be_bytes()).map_err(err)?;
+                for (key, _) in snapshot(&inode, true) {
+                    inode.delete(key).map_err(err)?;
+                }
+                for (key, value) in blocks {
+                    inode.put(key.clone(), value.clone()).map_err(err)?;
+                }
+                match size {
+                    Some(size) => inode.put("size", size.clone()).map(|_| ()).map_err(err)?,
+                    None => {
+                        let _ = inode.delete("size");
+                    }
+                }
+                // Cached pages would otherwise write the undone data back later
+                #[cfg(feature = "rvfs2")]
+                crate::rvfs2::cache::discard(db, *ino);
+            }
+            (
+                TransactionOperation::Create {
+                    parent_ino, name, ..
+                }
+                | TransactionOperation::Mkdir {
+                    parent_ino, name, ..
+                },
+                UndoState::NewEntry { parent: attrs },
+            ) => {
+                let parent = tx.get_bucket(parent_ino.to_be_bytes()).map_err(err)?;
+                if let Some(kv) = parent.get_kv(name.as_bytes()) {
+                    let (ino, _) = decode_dentry(kv.value());
+                    parent.delete(name.as_bytes()).map_err(err)?;
+                    let _ = tx.delete_bucket(ino.to_be_bytes());
+                }
+                restore_parent_attrs(&parent, attrs)?;
+            }
+            (
+                TransactionOperation::Delete { parent_ino, name },
+                UndoState::Entry {
+                    entry,
+                    ino,
+                    inode,
+                    parent: attrs,
+                },
+            ) => {
+                let parent = tx.get_bucket(parent_ino.to_be_bytes()).map_err(err)?;
+                parent.put(name.as_bytes(), entry.clone()).map_err(err)?;
+                restore_parent_attrs(&parent, attrs)?;
+                restore_inode(&tx, *ino, inode)?;
+            }
+            (
+                TransactionOperation::Rename {
+                    old_parent_ino,
+                    old_name,
+                    new_parent_ino,
+                    new_name,
+                },
+                UndoState::Replaced { entry },
+            ) => {
+                let new_parent = tx.get_bucket(new_parent_ino.to_be_bytes()).map_err(err)?;
+                let moved = new_parent
+                    .get_kv(new_name.as_bytes())
+                    .map(|kv| kv.value().to_vec())
+                    .ok_or_else(|| alloc::format!("Undo error: {} not found", new_name))?;
+                new_parent.delete(new_name.as_bytes()).map_err(err)?;
+                if let Some((entry, ino, inode)) = entry {
+                    new_parent
+                        .put(new_name.as_bytes(), entry.clone())
+                        .map_err(err)?;
+                    restore_inode(&tx, *ino, inode)?;
+                }
+                let old_parent = tx.get_bucket(old_parent_ino.to_be_bytes()).map_err(err)?;
+                old_parent.put(old_name.as_bytes(), moved).map_err(err)?;
+            }
+            _ => return Err("Undo error: state does not match operation".into()),
+        }
+
+        tx.commit().map_err(err)?;
+        Ok(())
+    }
+
     /// Apply the operation to the underlying filesystem.
     pub fn apply(&self) -> Result<(), String> {
-        use crate::common::DbfsTimeSpec;
-        // In a real system, we'd take the current time or a timestamp from the operation.
-        let now = DbfsTimeSpec { sec: 0, nsec: 0 };
+        self.apply_in(&clone_db())
+    }
+
+    /// `apply` against `db` rather than the global database.
+    ///
+    /// Data operations use the block size `db` was formatted with.
+    #[cfg(feature = "rvfs2")]
+    pub fn apply_in(&self, db: &DB) -> Result<(), String> {
+        use crate::common::{DbfsFileType, DbfsPermission};
+        use crate::rvfs2::common as rvfs2;
+
+        let block_size = || -> Result<u64, String> {
+            let tx = db
+                .tx(false)
+                .map_err(|e| alloc::format!("Apply error: {:?}", e))?;
+            let super_blk = tx
+                .get_bucket("super_blk")
+                .map_err(|e| alloc::format!("Apply error: {:?}", e))?;
+            Ok(crate::rvfs2::superblock::stored_block_size(&super_blk) as u64)
+        };
 
         match self {
             TransactionOperation::Write { ino, offset, data } => {
-                crate::common::dbfs_write(*ino, data, *offset)
+                rvfs2::dbfs_write(db, *ino, data, *offset, block_size()?)
                     .map_err(|e| alloc::format!("Write error: {:?}", e))?;
             }
-            TransactionOperation::Create { parent_ino, name, uid, gid, perm, dev } => {
-                crate::common::dbfs_create(
+            TransactionOperation::Create {
+                parent_ino,
+                name,
+                uid,
+                gid,
+                perm,
+                dev,
+            } => {
+                let perm = DbfsPermission::from_bits_truncate(*perm);
+                rvfs2::dbfs_create(
+                    db,
                     *parent_ino,
                     name,
+                    DbfsFileType::from(perm),
                     *uid,
                     *gid,
-                    now,
-                    crate::common::DbfsPermission::from_bits_truncate(*perm),
-                    None,
+                    perm,
                     *dev,
-                ).map_err(|e| alloc::format!("Create error: {:?}", e))?;
+                )
+                .map_err(|e| alloc::format!("Create error: {:?}", e))?;
             }
             TransactionOperation::Delete { parent_ino, name } => {
-                crate::common::dbfs_unlink(0, 0, *parent_ino, name, None, now)
+                rvfs2::dbfs_unlink(db, *parent_ino, name)
                     .map_err(|e| alloc::format!("Delete error: {:?}", e))?;
             }
-            TransactionOperation::Rename { old_parent_ino, old_name, new_parent_ino, new_name } => {
-                crate::common::dbfs_rename(
-                    *old_parent_ino,
-                    old_name,
-                    *new_parent_ino,
-                    new_name,
-                    0, // flags
-                    now,
-                ).map_err(|e| alloc::format!("Rename error: {:?}", e))?;
+            TransactionOperation::Rename {
+                old_parent_ino,
+                old_name,
+                new_parent_ino,
+                new_name,
+            } => {
+                rvfs2::dbfs_rename(db, *old_parent_ino, old_name, *new_parent_ino, new_name, 0)
+                    .map_err(|e| alloc::format!("Rename error: {:?}", e))?;
             }
-            TransactionOperation::Mkdir { parent_ino, name, uid, gid, perm } => {
-                crate::common::dbfs_create(
-                    *parent_ino,
-                    name,
-                    *uid,
-                    *gid,
-                    now,
-                    crate::common::DbfsPermission::from_bits_truncate(*perm | crate::common::DbfsPermission::S_IFDIR.bits()),
-                    None,
-                    None,
-                ).map_err(|e| alloc::format!("Mkdir error: {:?}", e))?;
+            TransactionOperation::Mkdir {
+                parent_ino,
+                name,
+                uid,
+                gid,
+                perm,
+            } => {
+                let perm =
+                    DbfsPermission::from_bits_truncate(*perm | DbfsPermission::S_IFDIR.bits());
+                rvfs2::dbfs_mkdir(db, *parent_ino, name, *uid, *gid, perm)
+                    .map_err(|e| alloc::format!("Mkdir error: {:?}", e))?;
             }
             TransactionOperation::Truncate { ino, length } => {
-                crate::common::dbfs_truncate(*ino, *length, now)
+                rvfs2::dbfs_truncate(db, *ino, *length, block_size()?)
                     .map_err(|e| alloc::format!("Truncate error: {:?}", e))?;
             }
         }
         Ok(())
     }
+
+    /// Without a filesystem backend there is nothing to apply the operation to.
+    #[cfg(not(feature = "rvfs2"))]
+    pub fn apply_in(&self, db: &DB) -> Result<(), String> {
+        let _ = db;
+        Err("Apply error: no filesystem backend".into())
+    }
 }
@@ -0,0 +1,394 @@
+//! Write-back page cache for rvfs2 data blocks
+//!
+//! Pages are allocated from `BUDDY_ALLOCATOR`, keyed by `(database, inode, block number)` and
+//! hold the same bytes as the `data_N` value they shadow. Writes only dirty the page; dirty
+//! pages reach jammdb on `fsync`, `sync_fs`, or when they are evicted to make room. If the
+//! allocator has no memory at all (for example `init_cache` was never called) the cache is
+//! bypassed.
+
+use alloc::{collections::BTreeMap, format, vec::Vec};
+use core::{alloc::Layout, ptr::NonNull};
+
+use jammdb::DB;
+use spin::Mutex;
+
+use crate::{common::DbfsResult, copy_data, BUDDY_ALLOCATOR};
+
+/// Cache counters, for embedders tuning the cache size
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Block reads served from the cache
+    pub hits: u64,
+    /// Block reads that went to the database
+    pub misses: u64,
+    /// Pages currently cached
+    pub pages: usize,
+    /// Cached pages not yet written back
+    pub dirty_pages: usize,
+    /// Blocks loaded ahead of a sequential reader
+    pub prefetched: u64,
+}
+
+struct Page {
+    ptr: NonNull<u8>,
+    /// Allocated size, the block size
+    cap: usize,
+    /// Valid bytes, the length of the `data_N` value
+    len: usize,
+    dirty: bool,
+    /// Bumped on every write, so a flush only cleans the version it wrote back
+    version: u64,
+    last_use: u64,
+}
+
+// Pages are only reached through the cache mutex
+unsafe impl Send for Page {}
+
+impl Page {
+    fn alloc(cap: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(cap, 8).ok()?;
+        let ptr = BUDDY_ALLOCATOR.lock().alloc(layout).ok()?;
+        Some(Self {
+            ptr,
+            cap,
+            len: 0,
+            dirty: false,
+            version: 0,
+            last_use: 0,
+        })
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Copy `src` to `at`, zero-filling any gap past the current end
+    fn copy_in(&mut self, at: usize, src: &[u8]) {
+        let end = at + src.len();
+        assert!(end <= self.cap, "page write past block size");
+        if at > self.len {
+            unsafe {
+                self.ptr
+                    .as_ptr()
+                    .add(self.len)
+                    .write_bytes(0, at - self.len)
+            };
+        }
+        copy_data(
+            src.as_ptr(),
+            unsafe { self.ptr.as_ptr().add(at) },
+            src.len(),
+        );
+        self.len = self.len.max(end);
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.cap, 8).unwrap();
+        BUDDY_ALLOCATOR.lock().dealloc(self.ptr, layout);
+    }
+}
+
+/// `(database, inode, block number)`
+type PageKey = (usize, usize, u64);
+
+/// A dirty page's key, version and contents at the time it was written back
+type DirtyPage = (PageKey, u64, Vec<u8>);
+
+struct PageCache {
+    pages: BTreeMap<PageKey, Page>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    prefetched: u64,
+}
+
+static CACHE: Mutex<PageCache> = Mutex::new(PageCache {
+    pages: BTreeMap::new(),
+    tick: 0,
+    hits: 0,
+    misses: 0,
+    prefetched: 0,
+});
+
+/// Inode whose pages fail to write back, standing in for a device error in tests
+#[cfg(test)]
+pub(crate) static FAIL_WRITEBACK: Mutex<Option<usize>> = Mutex::new(None);
+
+fn data_key(blk: u64) -> Vec<u8> {
+    format!("data_{}", blk).into_bytes()
+}
+
+/// Identifies a mounted database for as long as it is open
+pub fn db_id(db: &DB) -> usize {
+    db as *const DB as usize
+}
+
+impl PageCache {
+    fn touch(&mut self, key: PageKey) -> Option<&mut Page> {
+        self.tick += 1;
+        let tick = self.tick;
+        let page = self.pages.get_mut(&key)?;
+        page.last_use = tick;
+        Some(page)
+    }
+
+    /// Evict the least recently used page, writing it back through `tx` if it is dirty.
+    /// Without a transaction only clean pages can go, and dirty pages of other databases than
+    /// `db` never do. Returns false if nothing was evicted.
+    fn evict(&mut self, db: usize, tx: Option<&jammdb::Tx>) -> DbfsResult<bool> {
+        let victim = self
+            .pages
+            .iter()
+            .filter(|((page_db, ..), page)| !page.dirty || (tx.is_some() && *page_db == db))
+            .min_by_key(|(_, page)| page.last_use)
+            .map(|(key, _)| *key);
+        let Some((page_db, ino, blk)) = victim else {
+            return Ok(false);
+        };
+
+        let page = self.pages.remove(&(page_db, ino, blk)).unwrap();
+        if let (true, Some(tx)) = (page.dirty, tx) {
+            // The inode may be gone already, then so is the data
+            if let Ok(bucket) = tx.get_bucket(ino.to_be_bytes()) {
+                bucket.put(data_key(blk), page.data().to_vec())?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Allocate a page, evicting others until the allocator has room
+    fn alloc(
+        &mut self,
+        db: usize,
+        cap: usize,
+        tx: Option<&jammdb::Tx>,
+    ) -> DbfsResult<Option<Page>> {
+        loop {
+            if let Some(page) = Page::alloc(cap) {
+                return Ok(Some(page));
+            }
+            if !self.evict(db, tx)? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Whether block `blk` of `ino` is cached
+pub fn contains(db: &DB, ino: usize, blk: u64) -> bool {
+    CACHE.lock().pages.contains_key(&(db_id(db), ino, blk))
+}
+
+/// Copy from offset `in_block` of a cached block into `dst`
+///
+/// Returns the bytes copied, or `None` on a miss.
+pub fn read(db: &DB, ino: usize, blk: u64, in_block: usize, dst: &mut [u8]) -> Option<usize> {
+    let mut cache = CACHE.lock();
+    let Some(page) = cache.touch((db_id(db), ino, blk)) else {
+        cache.misses += 1;
+        return None;
+    };
+    let avail = dst.len().min(page.len.saturating_sub(in_block));
+    copy_data(
+        unsafe { page.ptr.as_ptr().add(in_block) },
+        dst.as_mut_ptr(),
+        avail,
+    );
+    cache.hits += 1;
+    Some(avail)
+}
+
+/// Cache a block just read from the database; skipped if only dirty pages could make room
+///
+/// Returns whether a page was added.
+pub fn fill(db: &DB, ino: usize, blk: u64, data: &[u8], block_size: u64) -> bool {
+    let key = (db_id(db), ino, blk);
+    let mut cache = CACHE.lock();
+    if cache.pages.contains_key(&key) || data.len() > block_size as usize {
+        return false;
+    }
+    if let Ok(Some(mut page)) = cache.alloc(key.0, block_size as usize, None) {
+        page.copy_in(0, data);
+        cache.tick += 1;
+        page.last_use = cache.tick;
+        cache.pages.insert(key, page);
+        return true;
+    }
+    false
+}
+
+/// Like `fill`, for a block read before anyone asked for it
+pub fn prefetch(db: &DB, ino: usize, blk: u64, data: &[u8], block_size: u64) -> bool {
+    let added = fill(db, ino, blk, data, block_size);
+    if added {
+        CACHE.lock().prefetched += 1;
+    }
+    added
+}
+
+/// Write `src` at offset `in_block` of a block, leaving the page dirty
+///
+/// On a miss the block is first loaded with `load`. Pages evicted to make room are written
+/// back through `tx`, a write transaction on `db`. Returns false if the cache has no memory
+/// and the caller must write the block itself.
+#[allow(clippy::too_many_arguments)]
+pub fn write<F: FnOnce() -> Option<Vec<u8>>>(
+    db: &DB,
+    tx: &jammdb::Tx,
+    ino: usize,
+    blk: u64,
+    in_block: usize,
+    src: &[u8],
+    block_size: u64,
+    load: F,
+) -> DbfsResult<bool> {
+    let key = (db_id(db), ino, blk);
+    let mut cache = CACHE.lock();
+    if cache.touch(key).is_none() {
+        let Some(mut page) = cache.alloc(key.0, block_size as usize, Some(tx))? else {
+            return Ok(false);
+        };
+        if let Some(old) = load() {
+            page.copy_in(0, &old[..old.len().min(block_size as usize)]);
+        }
+        cache.tick += 1;
+        page.last_use = cache.tick;
+        cache.pages.insert(key, page);
+    }
+
+    let page = cache.pages.get_mut(&key).unwrap();
+    page.copy_in(in_block, src);
+    page.dirty = true;
+    page.version += 1;
+    Ok(true)
+}
+
+/// Write dirty pages of `db` back to it, those of `ino` only if given
+///
+/// Must not be called while holding a write transaction.
+pub fn flush(db: &DB, ino: Option<usize>) -> DbfsResult<()> {
+    let dirty = dirty_pages(db, ino)?;
+    if dirty.is_empty() {
+        return Ok(());
+    }
+
+    // The cache lock is not held across the transaction, writers take them the other way round
+    let tx = db.tx(true)?;
+    put_pages(&tx, &dirty)?;
+    tx.commit()?;
+    mark_clean(WrittenBack(dirty));
+    Ok(())
+}
+
+/// Dirty pages copied into a transaction by [`write_back`], not yet known to be committed
+pub struct WrittenBack(Vec<DirtyPage>);
+
+/// Copy the dirty pages of `ino` into `tx`, a write transaction on `db`
+///
+/// The pages stay dirty until the caller has committed `tx` and passed the result to
+/// [`mark_clean`].
+pub fn write_back(db: &DB, tx: &jammdb::Tx, ino: usize) -> DbfsResult<WrittenBack> {
+    let dirty = dirty_pages(db, Some(ino))?;
+    put_pages(tx, &dirty)?;
+    Ok(WrittenBack(dirty))
+}
+
+/// Mark pages clean once the transaction they were written back in has committed
+///
+/// Pages written again meanwhile stay dirty.
+pub fn mark_clean(written: WrittenBack) {
+    let mut cache = CACHE.lock();
+    for (key, version, _) in written.0 {
+        if let Some(page) = cache.pages.get_mut(&key) {
+            if page.version == version {
+                page.dirty = false;
+            }
+        }
+    }
+}
+
+/// Snapshot the dirty pages of `db`, those of `ino` only if given, with their versions
+fn dirty_pages(db: &DB, ino: Option<usize>) -> DbfsResult<Vec<DirtyPage>> {
+    let id = db_id(db);
+    let dirty: Vec<DirtyPage> = CACHE
+        .lock()
+        .pages
+        .iter()
+        .filter(|((page_db, page_ino, _), page)| {
+            page.dirty && *page_db == id && ino.map_or(true, |ino| ino == *page_ino)
+        })
+        .map(|(key, page)| (*key, page.version, page.data().to_vec()))
+        .collect();
+    #[cfg(test)]
+    if let Some(fail) = *FAIL_WRITEBACK.lock() {
+        if dirty.iter().any(|((_, page_ino, _), ..)| *page_ino == fail) {
+            return Err(crate::common::DbfsError::Io);
+        }
+    }
+    Ok(dirty)
+}
+
+fn put_pages(tx: &jammdb::Tx, dirty: &[DirtyPage]) -> DbfsResult<()> {
+    for ((_, page_ino, blk), _, data) in dirty {
+        if let Ok(bucket) = tx.get_bucket(page_ino.to_be_bytes()) {
+            bucket.put(data_key(*blk), data.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop every page of `ino` without writing it back
+pub fn discard(db: &DB, ino: usize) {
+    let id = db_id(db);
+    CACHE
+        .lock()
+        .pages
+        .retain(|(page_db, page_ino, _), _| (*page_db, *page_ino) != (id, ino));
+}
+
+/// Drop every page of `db`, once it is unmounted
+///
+/// Ids are addresses, so a database opened later may get the same one; nothing of this one
+/// may be left to serve it. Dirty pages are lost, so flush first.
+pub fn forget(db: &DB) {
+    let id = db_id(db);
+    CACHE.lock().pages.retain(|(page_db, ..), _| *page_db != id);
+}
+
+/// Block numbers of the dirty pages of `ino`
+pub fn dirty_block_numbers(db: &DB, ino: usize) -> Vec<u64> {
+    let id = db_id(db);
+    CACHE
+        .lock()
+        .pages
+        .range((id, ino, 0)..=(id, ino, u64::MAX))
+        .filter(|(_, page)| page.dirty)
+        .map(|((.., blk), _)| *blk)
+        .collect()
+}
+
+/// The `data_N` keys and contents of the dirty pages of `ino`
+pub fn dirty_blocks(db: &DB, ino: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let id = db_id(db);
+    CACHE
+        .lock()
+        .pages
+        .range((id, ino, 0)..=(id, ino, u64::MAX))
+        .filter(|(_, page)| page.dirty)
+        .map(|((.., blk), page)| (data_key(*blk), page.data().to_vec()))
+        .collect()
+}
+
+/// Current cache counters
+pub fn stats() -> CacheStats {
+    let cache = CACHE.lock();
+    CacheStats {
+        hits: cache.hits,
+        misses: cache.misses,
+        pages: cache.pages.len(),
+        dirty_pages: cache.pages.values().filter(|page| page.dirty).count(),
+        prefetched: cache.prefetched,
+    }
+}
@@ -3,72 +3,422 @@
 //! These are re-implementations of common DBFS operations that work
 //! with the new vfscore API, independent of the old rvfs crate.
 
-use alloc::{string::String, vec::Vec};
-use jammdb::{Bucket, Data};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use jammdb::{Bucket, Data, DB};
 use log::debug;
+use spin::Mutex;
 
+use super::cache;
 use crate::{
-    clone_db,
     common::{
-        DbfsAttr, DbfsFileType, DbfsPermission, DbfsResult, DbfsTimeSpec, RENAME_EXCHANGE,
+        dbfs_check_name, decode_dentry, encode_dentry, DbfsAttr, DbfsError, DbfsFileType,
+        DbfsPermission, DbfsResult, DbfsTimeSpec, ExtentFlags, ExtentMapping, RENAME_EXCHANGE,
     },
-    inode_common::DBFS_INODE_NUMBER,
-    u32, u64, usize,
+    fast_copy, fs_common, u32, u64, usize,
 };
 
-/// Read data from a file
-pub fn dbfs_read(number: usize, buf: &mut [u8], offset: u64) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
+/// Block size written by [`fs_common::dbfs_common_format`](crate::fs_common::dbfs_common_format)
+/// callers that have no preference: the `sli*` feature if one is enabled, otherwise 4K.
+///
+/// This is only a format-time default. A mounted image always uses the `blk_size` stored in
+/// its `super_blk`, which is why the data functions below take the block size as an argument.
+#[cfg(any(
+    feature = "sli512",
+    feature = "sli1k",
+    feature = "sli4k",
+    feature = "sli8k",
+    feature = "sli32k"
+))]
+pub const DEFAULT_BLOCK_SIZE: u64 = crate::SLICE_SIZE as u64;
+#[cfg(not(any(
+    feature = "sli512",
+    feature = "sli1k",
+    feature = "sli4k",
+    feature = "sli8k",
+    feature = "sli32k"
+)))]
+pub const DEFAULT_BLOCK_SIZE: u64 = 4096;
+
+/// `offset + len`, or `InvalidArgument` if it does not fit in a `u64`
+fn checked_end(offset: u64, len: usize) -> DbfsResult<u64> {
+    offset
+        .checked_add(len as u64)
+        .ok_or(DbfsError::InvalidArgument)
+}
+
+/// Read data from a file stored in `block_size`-byte blocks
+pub fn dbfs_read(
+    db: &DB,
+    number: usize,
+    buf: &mut [u8],
+    offset: u64,
+    block_size: u64,
+) -> DbfsResult<usize> {
+    checked_end(offset, buf.len())?;
+    let tx = db.tx(false)?;
 
     let bucket = tx.get_bucket(number.to_be_bytes())?;
-    let data_key = format!("data_{}", offset / 4096);
 
-    match bucket.get(data_key.as_bytes()) {
-        Some(Data::KeyValue(kv)) => {
-            let value = kv.value();
-            let bytes_to_read = core::cmp::min(buf.len(), value.len());
-            buf[..bytes_to_read].copy_from_slice(&value[..bytes_to_read]);
-            Ok(bytes_to_read)
+    // A symlink keeps its target outside the data blocks
+    if let Some(kv) = bucket.get_kv("symlink_target") {
+        let target = kv.value();
+        if offset >= target.len() as u64 {
+            return Ok(0);
         }
-        _ => Ok(0),
+        let len = core::cmp::min(buf.len(), target.len() - offset as usize);
+        buf[..len].copy_from_slice(&target[offset as usize..offset as usize + len]);
+        return Ok(len);
     }
+
+    let size = bucket
+        .get_kv("size")
+        .map(|kv| crate::u64!(kv.value()))
+        .unwrap_or(0);
+    if offset >= size {
+        return Ok(0);
+    }
+    let total = core::cmp::min(buf.len() as u64, size - offset) as usize;
+
+    // Blocks that were never written, or written only partly, read back as zeros up to `size`
+    let mut count = 0;
+    while count < total {
+        let pos = offset + count as u64;
+        let data_key = format!("data_{}", pos / block_size);
+        let in_block = (pos % block_size) as usize;
+        let len = core::cmp::min(total - count, block_size as usize - in_block);
+        let dst = &mut buf[count..count + len];
+
+        let blk = pos / block_size;
+        let copied = match cache::read(db, number, blk, in_block, dst) {
+            Some(copied) => copied,
+            None => match bucket.get_kv(data_key.as_bytes()) {
+                Some(kv) => {
+                    let value = kv.value();
+                    cache::fill(db, number, blk, value, block_size);
+                    let avail = core::cmp::min(len, value.len().saturating_sub(in_block));
+                    fast_copy(&mut dst[..avail], &value[in_block..in_block + avail]);
+                    avail
+                }
+                None => 0,
+            },
+        };
+        dst[copied..].fill(0);
+        count += len;
+    }
+    Ok(count)
 }
 
-/// Write data to a file
-pub fn dbfs_write(number: usize, buf: &[u8], offset: u64) -> DbfsResult<usize> {
-    let db = clone_db();
-    let tx = db.tx(true)?;
+/// Load blocks `first..first + count` of inode `number` into the page cache
+///
+/// Blocks that are already cached, were never written, or lie past `size` are skipped.
+/// Returns the number of blocks loaded.
+pub fn dbfs_read_ahead(
+    db: &DB,
+    number: usize,
+    first: u64,
+    count: u64,
+    block_size: u64,
+) -> DbfsResult<u64> {
+    let tx = db.tx(false)?;
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    let size = bucket
+        .get_kv("size")
+        .map(|kv| crate::u64!(kv.value()))
+        .unwrap_or(0);
+
+    let mut loaded = 0;
+    for blk in first..first.saturating_add(count) {
+        if blk.saturating_mul(block_size) >= size {
+            break;
+        }
+        if cache::contains(db, number, blk) {
+            continue;
+        }
+        if let Some(kv) = bucket.get_kv(format!("data_{}", blk).as_bytes()) {
+            if cache::prefetch(db, number, blk, kv.value(), block_size) {
+                loaded += 1;
+            }
+        }
+    }
+    Ok(loaded)
+}
 
+/// Physical layout of the first `size` bytes of inode `number`
+///
+/// Block N lives under the `data_N` key, so the physical address reported for it is
+/// `N * block_size`. Blocks never written are holes and blocks only dirty in the page cache
+/// are `DELALLOC`; neighbouring blocks with the same flags are merged into one entry.
+pub fn dbfs_extent_map(
+    db: &DB,
+    number: usize,
+    size: u64,
+    block_size: u64,
+) -> DbfsResult<Vec<ExtentMapping>> {
+    let tx = db.tx(false)?;
     let bucket = tx.get_bucket(number.to_be_bytes())?;
-    let data_key = format!("data_{}", offset / 4096);
+    let dirty = cache::dirty_block_numbers(db, number);
+
+    let mut map: Vec<ExtentMapping> = Vec::new();
+    for blk in 0..size.div_ceil(block_size) {
+        let start = blk * block_size;
+        let len = block_size.min(size - start);
+        let flags = if dirty.contains(&blk) {
+            ExtentFlags::DELALLOC
+        } else if bucket.get_kv(format!("data_{}", blk).as_bytes()).is_some() {
+            ExtentFlags::empty()
+        } else {
+            ExtentFlags::HOLE
+        };
+        match map.last_mut() {
+            Some(last) if last.3 == flags => last.2 += len,
+            _ => map.push((
+                start,
+                if flags == ExtentFlags::HOLE { 0 } else { start },
+                len,
+                flags,
+            )),
+        }
+    }
+    if let Some(last) = map.last_mut() {
+        last.3 |= ExtentFlags::LAST;
+    }
+    Ok(map)
+}
 
-    bucket.put(data_key.as_bytes(), buf)?;
-    tx.commit()?;
+/// Write `buf` at `offset` into the `data_N` blocks of inode `number`
+///
+/// Blocks go to the page cache when it has room and straight to `bucket` otherwise. At most
+/// `free` missing blocks are allocated; the write stops short at the first block that would
+/// exceed that. Returns the bytes written and the number of blocks allocated.
+fn write_blocks(
+    db: &DB,
+    tx: &jammdb::Tx,
+    bucket: &Bucket,
+    number: usize,
+    buf: &[u8],
+    offset: u64,
+    free: u64,
+    block_size: u64,
+) -> DbfsResult<(usize, u64)> {
+    let mut count = 0;
+    let mut new_blocks = 0;
+    while count < buf.len() {
+        let pos = offset + count as u64;
+        let data_key = format!("data_{}", pos / block_size);
+        let in_block = (pos % block_size) as usize;
+        let len = core::cmp::min(buf.len() - count, block_size as usize - in_block);
+
+        let blk = pos / block_size;
+        let stored = bucket.get_kv(data_key.as_bytes());
+        if stored.is_none() && !cache::contains(db, number, blk) {
+            if new_blocks == free {
+                break;
+            }
+            new_blocks += 1;
+        }
 
-    // Update file size
+        let src = &buf[count..count + len];
+        let load = || stored.as_ref().map(|kv| kv.value().to_vec());
+        if cache::write(db, tx, number, blk, in_block, src, block_size, load)? {
+            count += len;
+            continue;
+        }
+
+        let mut block = bucket
+            .get_kv(data_key.as_bytes())
+            .map(|kv| kv.value().to_vec())
+            .unwrap_or_default();
+        if block.len() < in_block + len {
+            block.resize(in_block + len, 0);
+        }
+        fast_copy(&mut block[in_block..in_block + len], src);
+        bucket.put(data_key.as_bytes(), block)?;
+        count += len;
+    }
+    Ok((count, new_blocks))
+}
+
+/// Move the persisted `used_blocks` counter by `added - removed`
+///
+/// Called in the transaction that allocates or frees the blocks, so the stored counter never
+/// lags behind the blocks after a crash. Images without a superblock keep no counter.
+fn account_blocks(tx: &jammdb::Tx, added: u64, removed: u64) -> DbfsResult<()> {
+    if added == removed {
+        return Ok(());
+    }
+    let Ok(super_blk) = tx.get_bucket("super_blk") else {
+        return Ok(());
+    };
+    let used = super_blk
+        .get_kv("used_blocks")
+        .map(|kv| crate::u64!(kv.value()))
+        .unwrap_or(0);
+    super_blk.put(
+        "used_blocks",
+        used.saturating_add(added)
+            .saturating_sub(removed)
+            .to_be_bytes(),
+    )?;
+    Ok(())
+}
+
+/// Number of `data_N` blocks stored in an inode bucket
+fn stored_blocks(bucket: &Bucket) -> u64 {
+    let mut blocks = 0;
+    bucket.cursor().for_each(|data| {
+        if let Data::KeyValue(kv) = data {
+            if kv.key().starts_with(b"data_") {
+                blocks += 1;
+            }
+        }
+    });
+    blocks
+}
+
+/// Grow the `size` of an inode bucket to at least `end`
+fn grow_size(bucket: &Bucket, end: u64) -> DbfsResult<()> {
     let current_size = bucket
         .get_kv("size")
         .map(|kv| crate::u64!(kv.value()))
         .unwrap_or(0);
+    if end > current_size {
+        bucket.put("size", end.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write data to a file stored in `block_size`-byte blocks
+///
+/// The blocks, the block counter and a grown size commit in one transaction. A write that
+/// grows the file also copies the inode's dirty cached pages into that transaction, so the
+/// data-before-size barrier holds without a second commit: a crash leaves either the old size
+/// and none of the new bytes or the new size with all of them. Writes within the current size
+/// leave their pages dirty in the cache.
+pub fn dbfs_write(
+    db: &DB,
+    number: usize,
+    buf: &[u8],
+    offset: u64,
+    block_size: u64,
+) -> DbfsResult<usize> {
+    checked_end(offset, buf.len())?;
+    let tx = db.tx(true)?;
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    let (count, new_blocks) =
+        write_blocks(db, &tx, &bucket, number, buf, offset, u64::MAX, block_size)?;
+    account_blocks(&tx, new_blocks, 0)?;
+    let size = bucket
+        .get_kv("size")
+        .map(|kv| crate::u64!(kv.value()))
+        .unwrap_or(0);
 
-    let new_size = core::cmp::max(current_size, offset as u64 + buf.len() as u64);
-    bucket.put("size", new_size.to_be_bytes())?;
+    let end = offset + count as u64;
+    let written = if end > size {
+        // Barrier: the blocks the new size exposes go into the same commit
+        let written = cache::write_back(db, &tx, number)?;
+        grow_size(&bucket, end)?;
+        Some(written)
+    } else {
+        None
+    };
+    tx.commit()?;
 
-    Ok(buf.len())
+    if let Some(written) = written {
+        cache::mark_clean(written);
+    }
+    Ok(count)
 }
 
-/// Get file attributes
-pub fn dbfs_get_attr(number: usize) -> DbfsResult<DbfsAttr> {
-    let db = clone_db();
+/// Count the data blocks stored for an inode
+pub fn dbfs_block_count(db: &DB, number: usize) -> DbfsResult<u64> {
+    // Blocks only the page cache holds count too
+    cache::flush(db, Some(number))?;
+
+    let tx = db.tx(false)?;
+
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+    Ok(stored_blocks(&bucket))
+}
+
+/// Work out how much of a `len`-byte write at `offset` fits when at most `free` new blocks
+/// may be allocated.
+///
+/// Returns the number of bytes that fit and the number of new blocks they need. Blocks that
+/// already exist are overwritten in place and cost nothing.
+pub fn dbfs_writable_len(
+    db: &DB,
+    number: usize,
+    offset: u64,
+    len: usize,
+    free: u64,
+    block_size: u64,
+) -> DbfsResult<(usize, u64)> {
+    checked_end(offset, len)?;
+    let tx = db.tx(false)?;
+
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+
+    let mut count = 0;
+    let mut new_blocks = 0;
+    while count < len {
+        let pos = offset + count as u64;
+        let data_key = format!("data_{}", pos / block_size);
+        if bucket.get_kv(data_key.as_bytes()).is_none()
+            && !cache::contains(db, number, pos / block_size)
+        {
+            if new_blocks == free {
+                break;
+            }
+            new_blocks += 1;
+        }
+        count += core::cmp::min(
+            len - count,
+            block_size as usize - (pos % block_size) as usize,
+        );
+    }
+    Ok((count, new_blocks))
+}
+
+/// Update the access time of an inode in place, leaving data blocks untouched
+pub fn dbfs_update_atime(db: &DB, number: usize, atime: DbfsTimeSpec) -> DbfsResult<()> {
     let tx = db.tx(true)?;
 
     let bucket = tx.get_bucket(number.to_be_bytes())?;
+    bucket.put("atime", atime.to_be_bytes())?;
 
-    let mode = bucket
+    tx.commit()?;
+    Ok(())
+}
+
+/// Mode bits stored in an inode bucket, 0o755 for images that predate them
+fn inode_mode(bucket: &Bucket) -> DbfsPermission {
+    bucket
         .get_kv("mode")
         .map(|kv| DbfsPermission::from_bits_truncate(crate::u16!(kv.value())))
-        .unwrap_or(DbfsPermission::from_bits_truncate(0o755));
+        .unwrap_or(DbfsPermission::from_bits_truncate(0o755))
+}
+
+/// File type of an inode bucket; a mode without type bits is a regular file
+fn inode_kind(bucket: &Bucket) -> DbfsFileType {
+    let mode = inode_mode(bucket);
+    if (mode & DbfsPermission::S_IFMT).is_empty() {
+        DbfsFileType::RegularFile
+    } else {
+        DbfsFileType::from(mode & DbfsPermission::S_IFMT)
+    }
+}
+
+/// Get file attributes
+///
+/// `blksize` is the mount's `block_size`; `blocks` counts the allocated data blocks, including
+/// ones only the page cache holds, in 512-byte units like `st_blocks`. Holes are not counted.
+pub fn dbfs_get_attr(db: &DB, number: usize, block_size: u64) -> DbfsResult<DbfsAttr> {
+    let tx = db.tx(false)?;
+
+    let bucket = tx.get_bucket(number.to_be_bytes())?;
+
+    let mode = inode_mode(&bucket);
+    let kind = inode_kind(&bucket);
 
     let size = bucket
         .get_kv("size")
@@ -100,27 +450,66 @@ pub fn dbfs_get_attr(number: usize) -> DbfsResult<DbfsAttr> {
         .map(|kv| DbfsTimeSpec::from(kv.value()))
         .unwrap_or_default();
 
-    let hard_links = bucket
+    let nlink = bucket
         .get_kv("hard_links")
         .map(|kv| crate::u32!(kv.value()))
         .unwrap_or(1);
 
+    // Only device nodes carry a device number
+    let rdev = match kind {
+        DbfsFileType::CharDevice | DbfsFileType::BlockDevice => bucket
+            .get_kv("dev")
+            .map(|kv| crate::u32!(kv.value()))
+            .unwrap_or(0),
+        _ => 0,
+    };
+
+    // Directories keep entries, not data blocks, in their bucket; don't walk them
+    let allocated = match kind {
+        DbfsFileType::RegularFile | DbfsFileType::Symlink => {
+            let mut allocated = 0u64;
+            bucket.cursor().for_each(|data| {
+                if let Data::KeyValue(kv) = data {
+                    if kv.key().starts_with(b"data_") {
+                        allocated += 1;
+                    }
+                }
+            });
+            let cached_only = cache::dirty_block_numbers(db, number)
+                .into_iter()
+                .filter(|blk| bucket.get_kv(format!("data_{}", blk).as_bytes()).is_none())
+                .count();
+            allocated + cached_only as u64
+        }
+        _ => 0,
+    };
+
     Ok(DbfsAttr {
         ino: number,
-        size,
-        mode,
-        uid,
-        gid,
+        size: size as usize,
+        blocks: (allocated * block_size / 512) as usize,
         atime,
         mtime,
         ctime,
-        hard_links,
+        crtime: DbfsTimeSpec::default(),
+        kind,
+        perm: (mode - DbfsPermission::S_IFMT).bits(),
+        nlink,
+        uid,
+        gid,
+        rdev,
+        blksize: block_size as u32,
+        padding: 0,
+        flags: 0,
     })
 }
 
-/// Truncate a file to a specific size
-pub fn dbfs_truncate(number: usize, size: u64) -> DbfsResult<()> {
-    let db = clone_db();
+/// Truncate a file stored in `block_size`-byte blocks to a specific size
+pub fn dbfs_truncate(db: &DB, number: usize, size: u64, block_size: u64) -> DbfsResult<()> {
+    // Cut the written-back blocks, then reload whatever is read next
+    cache::flush(db, Some(number))?;
+    cache::discard(db, number);
+
     let tx = db.tx(true)?;
 
     let bucket = tx.get_bucket(number.to_be_bytes())?;
@@ -129,8 +518,7 @@ pub fn dbfs_truncate(number: usize, size: u64) -> DbfsResult<()> {
     bucket.put("size", size.to_be_bytes())?;
 
     // Remove data blocks beyond the new size
-    let block_size = 4096u64;
-    let start_block = (size + block_size - 1) / block_size;
+    let start_block = size.div_ceil(block_size);
 
     // Find and remove blocks
     let mut blocks_to_remove = Vec::new();
@@ -147,6 +535,7 @@ pub fn dbfs_truncate(number: usize, size: u64) -> DbfsResult<()> {
         }
     });
 
+    account_blocks(&tx, 0, blocks_to_remove.len() as u64)?;
     for key in blocks_to_remove {
         bucket.delete(key.as_bytes())?;
     }
@@ -157,26 +546,79 @@ pub fn dbfs_truncate(number: usize, size: u64) -> DbfsResult<()> {
 
 /// Create a new file or directory
 pub fn dbfs_create(
+    db: &DB,
     parent: usize,
     name: &str,
     file_type: DbfsFileType,
     uid: u32,
     gid: u32,
     mode: DbfsPermission,
+    rdev: Option<u32>,
+) -> DbfsResult<usize> {
+    dbfs_check_name(name)?;
+
+    let tx = db.tx(true)?;
+    let ino = dbfs_create_in(&tx, parent, name, file_type, uid, gid, mode, rdev)?;
+    tx.commit()?;
+    Ok(ino)
+}
+
+/// Create a directory with its `.` and `..` entries
+///
+/// The directory and both entries are written in one transaction, and the new directory
+/// starts with two links: its entry in `parent` and its own `.`.
+pub fn dbfs_mkdir(
+    db: &DB,
+    parent: usize,
+    name: &str,
+    uid: u32,
+    gid: u32,
+    mode: DbfsPermission,
 ) -> DbfsResult<usize> {
-    let db = clone_db();
+    dbfs_check_name(name)?;
+
     let tx = db.tx(true)?;
+    let ino = dbfs_create_in(
+        &tx,
+        parent,
+        name,
+        DbfsFileType::Directory,
+        uid,
+        gid,
+        mode,
+        None,
+    )?;
 
+    let dir = tx.get_bucket(ino.to_be_bytes())?;
+    dir.put(".", encode_dentry(ino, DbfsFileType::Directory))?;
+    dir.put("..", encode_dentry(parent, DbfsFileType::Directory))?;
+
+    tx.commit()?;
+    Ok(ino)
+}
+
+/// Create an inode and link it into `parent` within the caller's transaction
+#[allow(clippy::too_many_arguments)]
+fn dbfs_create_in(
+    tx: &jammdb::Tx,
+    parent: usize,
+    name: &str,
+    file_type: DbfsFileType,
+    uid: u32,
+    gid: u32,
+    mode: DbfsPermission,
+    rdev: Option<u32>,
+) -> DbfsResult<usize> {
     // Get parent bucket
     let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
 
     // Check if name already exists
     if parent_bucket.get(name.as_bytes()).is_some() {
-        return Err(DbfsResult::Err("File exists"));
+        return Err(DbfsError::FileExists);
     }
 
     // Allocate new inode number
-    let ino = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+    let ino = fs_common::dbfs_common_alloc_ino(tx)?;
 
     // Create new inode bucket
     let new_inode = tx.create_bucket(ino.to_be_bytes())?;
@@ -184,27 +626,44 @@ pub fn dbfs_create(
     // Set attributes
     let mut final_mode = mode;
     match file_type {
-        DbfsFileType::File => final_mode |= DbfsPermission::S_IFREG,
-        DbfsFileType::Dir => final_mode |= DbfsPermission::S_IFDIR,
+        DbfsFileType::RegularFile => final_mode |= DbfsPermission::S_IFREG,
+        DbfsFileType::Directory => final_mode |= DbfsPermission::S_IFDIR,
         DbfsFileType::Symlink => final_mode |= DbfsPermission::S_IFLNK,
+        DbfsFileType::CharDevice => final_mode |= DbfsPermission::S_IFCHR,
+        DbfsFileType::BlockDevice => final_mode |= DbfsPermission::S_IFBLK,
+        DbfsFileType::NamedPipe => final_mode |= DbfsPermission::S_IFIFO,
+        DbfsFileType::Socket => final_mode |= DbfsPermission::S_IFSOCK,
     }
 
     let now = DbfsTimeSpec::default();
 
+    // A directory is linked from its parent entry and from its own "."
+    let hard_links = if file_type == DbfsFileType::Directory {
+        2u32
+    } else {
+        1u32
+    };
+
     new_inode.put("mode", final_mode.bits().to_be_bytes())?;
     new_inode.put("size", 0u64.to_be_bytes())?;
-    new_inode.put("hard_links", 1u32.to_be_bytes())?;
+    new_inode.put("hard_links", hard_links.to_be_bytes())?;
     new_inode.put("uid", uid.to_be_bytes())?;
     new_inode.put("gid", gid.to_be_bytes())?;
     new_inode.put("atime", now.to_be_bytes())?;
     new_inode.put("mtime", now.to_be_bytes())?;
     new_inode.put("ctime", now.to_be_bytes())?;
+    if matches!(
+        file_type,
+        DbfsFileType::CharDevice | DbfsFileType::BlockDevice
+    ) {
+        new_inode.put("dev", rdev.unwrap_or(0).to_be_bytes())?;
+    }
 
     // Add to parent directory
-    parent_bucket.put(name.as_bytes(), ino.to_be_bytes())?;
+    parent_bucket.put(name.as_bytes(), encode_dentry(ino, file_type))?;
 
-    // Update parent's hard_links count if it's a directory
-    if file_type == DbfsFileType::Dir {
+    // The new directory's ".." links back to the parent
+    if file_type == DbfsFileType::Directory {
         let parent_links = parent_bucket
             .get_kv("hard_links")
             .map(|kv| crate::u32!(kv.value()))
@@ -212,20 +671,23 @@ pub fn dbfs_create(
         parent_bucket.put("hard_links", (parent_links + 1).to_be_bytes())?;
     }
 
-    tx.commit()?;
     Ok(ino)
 }
 
 /// Lookup a file in a directory
-pub fn dbfs_lookup(parent: usize, name: &str) -> DbfsResult<Option<usize>> {
-    let db = clone_db();
+pub fn dbfs_lookup(db: &DB, parent: usize, name: &str) -> DbfsResult<Option<usize>> {
+    dbfs_lookup_raw(db, parent, name.as_bytes())
+}
+
+/// Look up a directory entry by its raw name, which need not be UTF-8
+pub fn dbfs_lookup_raw(db: &DB, parent: usize, name: &[u8]) -> DbfsResult<Option<usize>> {
     let tx = db.tx(false)?;
 
     let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
 
-    match parent_bucket.get(name.as_bytes()) {
+    match parent_bucket.get(name) {
         Some(Data::KeyValue(kv)) => {
-            let ino = crate::usize!(kv.value());
+            let (ino, _) = decode_dentry(kv.value());
             Ok(Some(ino))
         }
         _ => Ok(None),
@@ -233,30 +695,36 @@ pub fn dbfs_lookup(parent: usize, name: &str) -> DbfsResult<Option<usize>> {
 }
 
 /// Create a hard link
-pub fn dbfs_link(old_parent: usize, old_name: &str, new_parent: usize, new_name: &str) -> DbfsResult<()> {
-    let db = clone_db();
+pub fn dbfs_link(
+    db: &DB,
+    old_parent: usize,
+    old_name: &str,
+    new_parent: usize,
+    new_name: &str,
+) -> DbfsResult<()> {
+    dbfs_check_name(new_name)?;
+
     let tx = db.tx(true)?;
 
     // Get old entry
     let old_bucket = tx.get_bucket(old_parent.to_be_bytes())?;
-    let old_entry = old_bucket.get(old_name.as_bytes());
-
-    if old_entry.is_none() {
-        return Err(DbfsResult::Err("Old file not found"));
-    }
-
-    let ino = crate::usize!(old_entry.unwrap().value());
+    let old_value = old_bucket
+        .get_kv(old_name.as_bytes())
+        .ok_or(DbfsError::NotFound)?
+        .value()
+        .to_vec();
+    let (ino, _) = decode_dentry(&old_value);
 
     // Get new parent bucket
     let new_bucket = tx.get_bucket(new_parent.to_be_bytes())?;
 
     // Check if new name already exists
     if new_bucket.get(new_name.as_bytes()).is_some() {
-        return Err(DbfsResult::Err("New file already exists"));
+        return Err(DbfsError::FileExists);
     }
 
-    // Add link
-    new_bucket.put(new_name.as_bytes(), ino.to_be_bytes())?;
+    // Add link, carrying over the entry's type
+    new_bucket.put(new_name.as_bytes(), old_value)?;
 
     // Increment hard_links count
     let inode_bucket = tx.get_bucket(ino.to_be_bytes())?;
@@ -270,45 +738,253 @@ pub fn dbfs_link(old_parent: usize, old_name: &str, new_parent: usize, new_name:
     Ok(())
 }
 
+/// Give inode `ino` another name `new_name` in directory `new_parent`
+///
+/// Raises the link count and sets ctime to `ctime` in the same transaction. Directories
+/// cannot be hard linked.
+pub fn dbfs_link_ino(
+    db: &DB,
+    ino: usize,
+    new_parent: usize,
+    new_name: &str,
+    ctime: DbfsTimeSpec,
+) -> DbfsResult<()> {
+    dbfs_check_name(new_name)?;
+
+    let tx = db.tx(true)?;
+
+    let inode_bucket = tx.get_bucket(ino.to_be_bytes())?;
+    let kind = inode_kind(&inode_bucket);
+    if kind == DbfsFileType::Directory {
+        return Err(DbfsError::PermissionDenied);
+    }
+
+    let new_bucket = tx.get_bucket(new_parent.to_be_bytes())?;
+    if new_bucket.get(new_name.as_bytes()).is_some() {
+        return Err(DbfsError::FileExists);
+    }
+    new_bucket.put(new_name.as_bytes(), encode_dentry(ino, kind))?;
+
+    let links = inode_bucket
+        .get_kv("hard_links")
+        .map(|kv| crate::u32!(kv.value()))
+        .unwrap_or(1);
+    inode_bucket.put("hard_links", (links + 1).to_be_bytes())?;
+    inode_bucket.put("ctime", ctime.to_be_bytes())?;
+
+    tx.commit()?;
+    Ok(())
+}
+
 /// Unlink (delete) a file
-pub fn dbfs_unlink(parent: usize, name: &str) -> DbfsResult<()> {
-    let db = clone_db();
+pub fn dbfs_unlink(db: &DB, parent: usize, name: &str) -> DbfsResult<()> {
     let tx = db.tx(true)?;
 
     let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
 
     // Get inode number
-    let entry = parent_bucket.get(name.as_bytes());
-    if entry.is_none() {
-        return Err(DbfsResult::Err("File not found"));
-    }
-
-    let ino = crate::usize!(entry.unwrap().value());
+    let entry = parent_bucket
+        .get_kv(name.as_bytes())
+        .ok_or(DbfsError::NotFound)?;
+    let (ino, _) = decode_dentry(entry.value());
 
     // Remove entry from parent
     parent_bucket.delete(name.as_bytes())?;
+    drop_link(db, &tx, ino, false)?;
 
-    // Decrement hard_links count
+    tx.commit()?;
+    Ok(())
+}
+
+/// Drop one link to `ino` whose entry was just removed, deleting the inode with its last link
+///
+/// An inode that is still open keeps its data with a link count of 0 until the last handle
+/// goes away. A directory only ever has the one link.
+fn drop_link(db: &DB, tx: &jammdb::Tx, ino: usize, is_dir: bool) -> DbfsResult<()> {
     let inode_bucket = tx.get_bucket(ino.to_be_bytes())?;
     let links = inode_bucket
         .get_kv("hard_links")
         .map(|kv| crate::u32!(kv.value()))
         .unwrap_or(1);
 
-    if links <= 1 {
+    if (is_dir || links <= 1) && dbfs_orphan_if_open(db, ino) {
+        // Last link but still open: keep the data until the last handle goes away
+        inode_bucket.put("hard_links", 0u32.to_be_bytes())?;
+    } else if is_dir || links <= 1 {
         // Last link, delete the inode
+        account_blocks(tx, 0, stored_blocks(&inode_bucket))?;
         tx.delete_bucket(ino.to_be_bytes())?;
+        cache::discard(db, ino);
     } else {
         inode_bucket.put("hard_links", (links - 1).to_be_bytes())?;
     }
+    Ok(())
+}
+
+/// Open handles per `(database, inode)`, and whether the inode lost its last link while open
+static OPEN_INODES: Mutex<BTreeMap<(usize, usize), (usize, bool)>> = Mutex::new(BTreeMap::new());
+
+/// Record a new in-memory handle on `ino`
+pub fn dbfs_open_inode(db: &DB, ino: usize) {
+    OPEN_INODES
+        .lock()
+        .entry((cache::db_id(db), ino))
+        .or_insert((0, false))
+        .0 += 1;
+}
+
+/// Drop a handle on `ino`; returns true if it was the last handle on an orphaned inode,
+/// which the caller must then remove with [`dbfs_reap_orphan`]
+pub fn dbfs_close_inode(db: &DB, ino: usize) -> bool {
+    let key = (cache::db_id(db), ino);
+    let mut open = OPEN_INODES.lock();
+    let Some(state) = open.get_mut(&key) else {
+        return false;
+    };
+    state.0 -= 1;
+    if state.0 > 0 {
+        return false;
+    }
+    let orphaned = state.1;
+    open.remove(&key);
+    orphaned
+}
+
+/// Number of in-memory handles on `ino`
+pub fn dbfs_open_count(db: &DB, ino: usize) -> usize {
+    OPEN_INODES
+        .lock()
+        .get(&(cache::db_id(db), ino))
+        .map_or(0, |state| state.0)
+}
+
+/// Mark `ino` orphaned if any handle is open on it; returns whether it was
+fn dbfs_orphan_if_open(db: &DB, ino: usize) -> bool {
+    match OPEN_INODES.lock().get_mut(&(cache::db_id(db), ino)) {
+        Some(state) if state.0 > 0 => {
+            state.1 = true;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Delete an inode whose last link was removed while it was open; returns whether it was
+/// deleted
+pub fn dbfs_reap_orphan(db: &DB, ino: usize) -> DbfsResult<bool> {
+    let tx = db.tx(true)?;
+
+    let bucket = tx.get_bucket(ino.to_be_bytes())?;
+    let links = bucket
+        .get_kv("hard_links")
+        .map(|kv| crate::u32!(kv.value()))
+        .unwrap_or(0);
+    // A rename or link may have given it a name again
+    if links == 0 {
+        account_blocks(&tx, 0, stored_blocks(&bucket))?;
+        tx.delete_bucket(ino.to_be_bytes())?;
+        cache::discard(db, ino);
+    }
 
     tx.commit()?;
-    Ok(())
+    Ok(links == 0)
+}
+
+/// Remove every inode left with no links and no open handle, returning how many went
+///
+/// An inode unlinked while open keeps its data until the last handle closes; a crash before
+/// then leaves it with a link count of 0 that nothing would ever reclaim. Mount calls this.
+pub fn dbfs_reap_orphans(db: &DB) -> DbfsResult<u64> {
+    let tx = db.tx(true)?;
+
+    let mut orphans = Vec::new();
+    for (name, bucket) in tx.buckets() {
+        let Ok(key) = <[u8; 8]>::try_from(name.name()) else {
+            continue;
+        };
+        let ino = usize::from_be_bytes(key);
+        let links = bucket
+            .get_kv("hard_links")
+            .map(|kv| crate::u32!(kv.value()));
+        if ino != 1 && links == Some(0) && dbfs_open_count(db, ino) == 0 {
+            orphans.push((ino, stored_blocks(&bucket)));
+        }
+    }
+
+    for &(ino, blocks) in &orphans {
+        account_blocks(&tx, 0, blocks)?;
+        tx.delete_bucket(ino.to_be_bytes())?;
+        cache::discard(db, ino);
+    }
+
+    tx.commit()?;
+    Ok(orphans.len() as u64)
+}
+
+/// Whether directory `ancestor` is `dir` itself or one of its ancestors
+///
+/// Walks up through the `..` entries `dbfs_mkdir` writes, stopping at the root or at a
+/// directory without one.
+pub fn dbfs_is_ancestor(db: &DB, ancestor: usize, dir: usize) -> DbfsResult<bool> {
+    let tx = db.tx(false)?;
+    is_ancestor_in(&tx, ancestor, dir)
+}
+
+fn is_ancestor_in(tx: &jammdb::Tx, ancestor: usize, mut dir: usize) -> DbfsResult<bool> {
+    for _ in 0..crate::common::MAX_TREE_DEPTH {
+        if dir == ancestor {
+            return Ok(true);
+        }
+        let Some(kv) = tx.get_bucket(dir.to_be_bytes())?.get_kv("..") else {
+            return Ok(false);
+        };
+        let (parent, _) = decode_dentry(kv.value());
+        if parent == dir {
+            return Ok(false);
+        }
+        dir = parent;
+    }
+    Err(DbfsError::Loop)
+}
+
+/// Whether a directory bucket holds no entries besides `.` and `..`
+fn is_empty_dir(bucket: &Bucket) -> bool {
+    let mut empty = true;
+    bucket.cursor().for_each(|data| {
+        if let Data::KeyValue(kv) = data {
+            if is_dentry_key(kv.key()) && kv.key() != b"." && kv.key() != b".." {
+                empty = false;
+            }
+        }
+    });
+    empty
+}
+
+/// Whether a key in an inode bucket is a directory entry rather than an attribute or block
+fn is_dentry_key(name: &[u8]) -> bool {
+    !name.starts_with(b"data_")
+        && !name.starts_with(b"mode")
+        && !name.starts_with(b"size")
+        && !name.starts_with(b"uid")
+        && !name.starts_with(b"gid")
+        && !name.starts_with(b"atime")
+        && !name.starts_with(b"mtime")
+        && !name.starts_with(b"ctime")
+        && !name.starts_with(b"hard_links")
 }
 
 /// Read directory entries
-pub fn dbfs_readdir(parent: usize) -> DbfsResult<Vec<(String, usize)>> {
-    let db = clone_db();
+///
+/// Names that are not UTF-8 are converted lossily; use [`dbfs_readdir_raw`] to get them intact.
+pub fn dbfs_readdir(db: &DB, parent: usize) -> DbfsResult<Vec<(String, usize)>> {
+    Ok(dbfs_readdir_raw(db, parent)?
+        .into_iter()
+        .map(|(name, ino)| (String::from_utf8_lossy(&name).into_owned(), ino))
+        .collect())
+}
+
+/// Read directory entries with their names as stored, which need not be UTF-8
+pub fn dbfs_readdir_raw(db: &DB, parent: usize) -> DbfsResult<Vec<(Vec<u8>, usize)>> {
     let tx = db.tx(false)?;
 
     let bucket = tx.get_bucket(parent.to_be_bytes())?;
@@ -316,22 +992,10 @@ pub fn dbfs_readdir(parent: usize) -> DbfsResult<Vec<(String, usize)>> {
 
     bucket.cursor().for_each(|data| {
         if let Data::KeyValue(kv) = data {
-            let key = core::str::from_utf8(kv.key()).ok();
-            if let Some(name) = key {
-                // Skip non-entries
-                if !name.starts_with("data_")
-                    && !name.starts_with("mode")
-                    && !name.starts_with("size")
-                    && !name.starts_with("uid")
-                    && !name.starts_with("gid")
-                    && !name.starts_with("atime")
-                    && !name.starts_with("mtime")
-                    && !name.starts_with("ctime")
-                    && !name.starts_with("hard_links")
-                {
-                    let ino = crate::usize!(kv.value());
-                    entries.push((name.to_string(), ino));
-                }
+            // Skip non-entries
+            if is_dentry_key(kv.key()) {
+                let (ino, _) = decode_dentry(kv.value());
+                entries.push((kv.key().to_vec(), ino));
             }
         }
     });
@@ -339,38 +1003,163 @@ pub fn dbfs_readdir(parent: usize) -> DbfsResult<Vec<(String, usize)>> {
     Ok(entries)
 }
 
+/// Return the first directory entry whose name sorts after `after` (the first entry if `None`),
+/// with its type if the entry records one
+///
+/// Entries come back in key byte order. Resuming from the last returned name keeps a walk
+/// stable while the directory changes: every entry present for the whole walk is returned
+/// exactly once, whatever is inserted or removed around it.
+pub fn dbfs_readdir_after(
+    db: &DB,
+    parent: usize,
+    after: Option<&[u8]>,
+) -> DbfsResult<Option<(Vec<u8>, usize, Option<DbfsFileType>)>> {
+    let tx = db.tx(false)?;
+
+    let bucket = tx.get_bucket(parent.to_be_bytes())?;
+    let mut cursor = bucket.cursor();
+    // Keys are sorted, so the walk starts at `after` instead of the first key
+    if let Some(after) = after {
+        cursor.seek(after);
+    }
+    let next = cursor.find_map(|data| match data {
+        Data::KeyValue(kv) => {
+            let name = kv.key();
+            let past = after.map_or(true, |after| name > after);
+            if past && is_dentry_key(name) {
+                let (ino, kind) = decode_dentry(kv.value());
+                Some((name.to_vec(), ino, kind))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    });
+
+    Ok(next)
+}
+
+/// Record `kind` in the entry `name` of `parent` if it still points at `ino` without a type
+pub fn dbfs_tag_dentry(
+    db: &DB,
+    parent: usize,
+    name: &[u8],
+    ino: usize,
+    kind: DbfsFileType,
+) -> DbfsResult<()> {
+    let tx = db.tx(true)?;
+    let bucket = tx.get_bucket(parent.to_be_bytes())?;
+    let legacy = match bucket.get_kv(name) {
+        Some(kv) => decode_dentry(kv.value()) == (ino, None),
+        None => false,
+    };
+    if legacy {
+        bucket.put(name, encode_dentry(ino, kind))?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
 /// Rename a file
 pub fn dbfs_rename(
+    db: &DB,
     old_parent: usize,
     old_name: &str,
     new_parent: usize,
     new_name: &str,
     _flags: u32,
 ) -> DbfsResult<()> {
-    let db = clone_db();
+    dbfs_check_name(new_name)?;
+
     let tx = db.tx(true)?;
 
     let old_bucket = tx.get_bucket(old_parent.to_be_bytes())?;
 
     // Get old entry
-    let old_entry = old_bucket.get(old_name.as_bytes());
-    if old_entry.is_none() {
-        return Err(DbfsResult::Err("Old file not found"));
+    let old_value = old_bucket
+        .get_kv(old_name.as_bytes())
+        .ok_or(DbfsError::NotFound)?
+        .value()
+        .to_vec();
+    let (ino, _) = decode_dentry(&old_value);
+    let is_dir_ino = |ino: usize| -> DbfsResult<bool> {
+        Ok(tx
+            .get_bucket(ino.to_be_bytes())?
+            .get_kv("mode")
+            .map(|kv| DbfsPermission::from_bits_truncate(crate::u16!(kv.value())))
+            .map_or(false, |mode| {
+                DbfsFileType::from(mode) == DbfsFileType::Directory
+            }))
+    };
+    let is_dir = is_dir_ino(ino)?;
+
+    // A directory cannot move below itself
+    if is_dir && old_parent != new_parent && is_ancestor_in(&tx, ino, new_parent)? {
+        return Err(DbfsError::InvalidArgument);
     }
 
-    let ino = crate::usize!(old_entry.unwrap().value());
+    // An existing target is replaced: it loses this link, and an empty directory goes entirely
+    let target = tx
+        .get_bucket(new_parent.to_be_bytes())?
+        .get_kv(new_name.as_bytes())
+        .map(|kv| decode_dentry(kv.value()).0);
+    if let Some(target_ino) = target {
+        if target_ino == ino {
+            return Ok(());
+        }
+        let target_is_dir = is_dir_ino(target_ino)?;
+        match (is_dir, target_is_dir) {
+            (true, false) => return Err(DbfsError::NotDir),
+            (false, true) => return Err(DbfsError::IsDir),
+            _ => {}
+        }
+        if target_is_dir {
+            if !is_empty_dir(&tx.get_bucket(target_ino.to_be_bytes())?) {
+                return Err(DbfsError::NotEmpty);
+            }
+            // The replaced directory's ".." no longer links the target parent
+            let new_bucket = tx.get_bucket(new_parent.to_be_bytes())?;
+            let links = new_bucket
+                .get_kv("hard_links")
+                .map(|kv| crate::u32!(kv.value()))
+                .unwrap_or(2);
+            new_bucket.put("hard_links", links.saturating_sub(1).to_be_bytes())?;
+        }
+        drop_link(db, &tx, target_ino, target_is_dir)?;
+    }
 
     // Remove old entry
     old_bucket.delete(old_name.as_bytes())?;
 
-    // Add new entry
+    // Add new entry, keeping the stored type
     if old_parent == new_parent {
         // Same directory
-        old_bucket.put(new_name.as_bytes(), ino.to_be_bytes())?;
+        old_bucket.put(new_name.as_bytes(), old_value)?;
     } else {
         // Different directory
         let new_bucket = tx.get_bucket(new_parent.to_be_bytes())?;
-        new_bucket.put(new_name.as_bytes(), ino.to_be_bytes())?;
+        new_bucket.put(new_name.as_bytes(), old_value)?;
+
+        // A moved directory takes its ".." link with it
+        if is_dir {
+            // Directories made by `dbfs_mkdir` carry a ".." entry that must follow the move
+            let dir_bucket = tx.get_bucket(ino.to_be_bytes())?;
+            if dir_bucket.get_kv("..").is_some() {
+                dir_bucket.put("..", encode_dentry(new_parent, DbfsFileType::Directory))?;
+            }
+
+            let old_links = old_bucket
+                .get_kv("hard_links")
+                .map(|kv| crate::u32!(kv.value()))
+                .unwrap_or(2);
+            old_bucket.put("hard_links", old_links.saturating_sub(1).to_be_bytes())?;
+
+            let new_links = new_bucket
+                .get_kv("hard_links")
+                .map(|kv| crate::u32!(kv.value()))
+                .unwrap_or(2);
+            new_bucket.put("hard_links", (new_links + 1).to_be_bytes())?;
+        }
     }
 
     tx.commit()?;
@@ -378,12 +1167,26 @@ pub fn dbfs_rename(
 }
 
 /// Create a symbolic link
-pub fn dbfs_symlink(parent: usize, name: &str, target: &str, uid: u32, gid: u32) -> DbfsResult<usize> {
-    let db = clone_db();
+pub fn dbfs_symlink(
+    db: &DB,
+    parent: usize,
+    name: &str,
+    target: &str,
+    uid: u32,
+    gid: u32,
+) -> DbfsResult<usize> {
+    dbfs_check_name(name)?;
+
     let tx = db.tx(true)?;
 
+    // An existing entry is never replaced, its inode would be left without a name
+    let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
+    if parent_bucket.get(name.as_bytes()).is_some() {
+        return Err(DbfsError::FileExists);
+    }
+
     // Allocate new inode number
-    let ino = DBFS_INODE_NUMBER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+    let ino = fs_common::dbfs_common_alloc_ino(&tx)?;
 
     // Create new symlink inode
     let new_inode = tx.create_bucket(ino.to_be_bytes())?;
@@ -392,7 +1195,7 @@ pub fn dbfs_symlink(parent: usize, name: &str, target: &str, uid: u32, gid: u32)
     let now = DbfsTimeSpec::default();
 
     new_inode.put("mode", mode.bits().to_be_bytes())?;
-    new_inode.put("size", target.len() as u64.to_be_bytes())?;
+    new_inode.put("size", (target.len() as u64).to_be_bytes())?;
     new_inode.put("hard_links", 1u32.to_be_bytes())?;
     new_inode.put("uid", uid.to_be_bytes())?;
     new_inode.put("gid", gid.to_be_bytes())?;
@@ -402,81 +1205,64 @@ pub fn dbfs_symlink(parent: usize, name: &str, target: &str, uid: u32, gid: u32)
     new_inode.put("symlink_target", target.as_bytes())?;
 
     // Add to parent directory
-    let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
-    parent_bucket.put(name.as_bytes(), ino.to_be_bytes())?;
+    parent_bucket.put(name.as_bytes(), encode_dentry(ino, DbfsFileType::Symlink))?;
 
     tx.commit()?;
     Ok(ino)
 }
 
 /// Read symbolic link target
-pub fn dbfs_readlink(ino: usize) -> DbfsResult<String> {
-    let db = clone_db();
+pub fn dbfs_readlink(db: &DB, ino: usize) -> DbfsResult<String> {
     let tx = db.tx(false)?;
 
     let bucket = tx.get_bucket(ino.to_be_bytes())?;
 
     match bucket.get("symlink_target") {
         Some(Data::KeyValue(kv)) => {
-            let target = core::str::from_utf8(kv.value())
-                .map_err(|_| DbfsResult::Err("Invalid symlink target"))?;
+            let target =
+                core::str::from_utf8(kv.value()).map_err(|_| DbfsError::InvalidArgument)?;
             Ok(target.to_string())
         }
-        _ => Err(DbfsResult::Err("Not a symlink")),
+        _ => Err(DbfsError::InvalidArgument),
     }
 }
 
-/// Remove a directory
-pub fn dbfs_rmdir(parent: usize, name: &str) -> DbfsResult<()> {
-    let db = clone_db();
+/// Remove an empty directory
+///
+/// Fails with `NotDir` if `name` is not a directory and `NotEmpty` if it still has entries.
+/// A directory that is still open keeps its inode until the last handle goes away.
+pub fn dbfs_rmdir(db: &DB, parent: usize, name: &str) -> DbfsResult<()> {
     let tx = db.tx(true)?;
 
     let parent_bucket = tx.get_bucket(parent.to_be_bytes())?;
 
     // Get inode number
-    let entry = parent_bucket.get(name.as_bytes());
-    if entry.is_none() {
-        return Err(DbfsResult::Err("Directory not found"));
-    }
+    let entry = parent_bucket
+        .get_kv(name.as_bytes())
+        .ok_or(DbfsError::NotFound)?;
+    let (ino, _) = decode_dentry(entry.value());
 
-    let ino = crate::usize!(entry.unwrap().value());
-
-    // Check if directory is empty
     let dir_bucket = tx.get_bucket(ino.to_be_bytes())?;
-    let mut is_empty = true;
-    dir_bucket.cursor().for_each(|data| {
-        if let Data::KeyValue(kv) = data {
-            let key = core::str::from_utf8(kv.key()).unwrap_or("");
-            if !key.starts_with("mode")
-                && !key.starts_with("size")
-                && !key.starts_with("uid")
-                && !key.starts_with("gid")
-                && !key.starts_with("atime")
-                && !key.starts_with("mtime")
-                && !key.starts_with("ctime")
-                && !key.starts_with("hard_links")
-            {
-                is_empty = false;
-            }
-        }
-    });
-
-    if !is_empty {
-        return Err(DbfsResult::Err("Directory not empty"));
+    if inode_kind(&dir_bucket) != DbfsFileType::Directory {
+        return Err(DbfsError::NotDir);
+    }
+    if !is_empty_dir(&dir_bucket) {
+        return Err(DbfsError::NotEmpty);
     }
 
     // Remove entry from parent
     parent_bucket.delete(name.as_bytes())?;
+    drop_link(db, &tx, ino, true)?;
 
-    // Delete the directory inode
-    tx.delete_bucket(ino.to_be_bytes())?;
-
-    // Update parent's hard_links count
+    // Drop the link the removed directory's ".." held on the parent
     let parent_links = parent_bucket
         .get_kv("hard_links")
         .map(|kv| crate::u32!(kv.value()))
         .unwrap_or(2);
-    parent_bucket.put("hard_links", (parent_links - 1).to_be_bytes())?;
+    parent_bucket.put(
+        "hard_links",
+        parent_links.saturating_sub(1).max(2).to_be_bytes(),
+    )?;
 
     tx.commit()?;
     Ok(())
@@ -1,5 +1,6 @@
 use alloc::{string::String, string::ToString, sync::Arc, vec::Vec};
-use log::info;
+use jammdb::DB;
+use log::{error, info};
 
 use vfscore::{
     dentry::VfsDentry,
@@ -10,8 +11,15 @@ use vfscore::{
     VfsResult,
 };
 
-use super::{dentry::DbfsDentry, inode::DbfsInode, superblock::DbfsSuperBlock};
-use crate::{clone_db, common::DbfsTimeSpec, fs_common};
+use super::{
+    dentry::DbfsDentry,
+    inode::DbfsInode,
+    superblock::{CallerIds, DbfsSuperBlock, MountFlags, PollHandler},
+};
+use crate::{
+    common::{DbfsError, DbfsTimeSpec},
+    fs_common, try_clone_db, SafeDb,
+};
 
 /// DBFS Filesystem Type
 pub struct DbfsFsType {
@@ -19,6 +27,18 @@ pub struct DbfsFsType {
     db_path: String,
     /// Transaction manager
     pub tm: Arc<crate::transaction::TransactionManager>,
+    /// Time source handed to every superblock mounted from this type, `None` if there is none
+    clock: Option<fn() -> VfsTimeSpec>,
+    /// Capacity of the backing device in blocks, `None` if unbounded
+    block_count: Option<u64>,
+    /// Read-ahead window in blocks, 0 if off
+    read_ahead: u64,
+    /// Readiness of special nodes, `None` if nothing is ever ready on them
+    poll_handler: Option<PollHandler>,
+    /// Caller identity that permissions are enforced against, `None` to allow every access
+    enforce_permissions: Option<CallerIds>,
+    /// Database this type mounts, the global one from `init_dbfs` if `None`
+    db: Option<Arc<SafeDb>>,
 }
 
 impl DbfsFsType {
@@ -27,14 +47,66 @@ impl DbfsFsType {
         Self {
             db_path,
             tm: Arc::new(crate::transaction::TransactionManager::new()),
+            clock: None,
+            block_count: None,
+            read_ahead: 0,
+            poll_handler: None,
+            enforce_permissions: None,
+            db: None,
+        }
+    }
+
+    /// Create a filesystem type mounting `db` instead of the global database
+    ///
+    /// Types created this way are independent of each other and of `init_dbfs`, so several
+    /// images can be mounted at once.
+    pub fn new_with_db(db: DB, db_path: String) -> Self {
+        Self {
+            db: Some(Arc::new(SafeDb(db))),
+            ..Self::new(db_path)
         }
     }
+
+    /// Use `clock` as the time source for inode timestamps
+    ///
+    /// Without a clock, timestamps are written as 0 and reads leave atime alone.
+    pub fn with_clock(mut self, clock: fn() -> VfsTimeSpec) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Limit data blocks to `block_count`, the capacity of the backing device
+    pub fn with_block_count(mut self, block_count: u64) -> Self {
+        self.block_count = Some(block_count);
+        self
+    }
+
+    /// Load `blocks` blocks into the page cache ahead of sequential readers; off by default
+    pub fn with_read_ahead(mut self, blocks: u64) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    /// Report the readiness of device, fifo and socket nodes through `handler`
+    pub fn with_poll_handler(mut self, handler: PollHandler) -> Self {
+        self.poll_handler = Some(handler);
+        self
+    }
+
+    /// Check the mode, owner and group of inodes against the identity `caller` returns
+    ///
+    /// Off by default, in which case every access is allowed. With it on, new inodes are
+    /// owned by the caller.
+    pub fn with_enforce_permissions(mut self, caller: CallerIds) -> Self {
+        self.enforce_permissions = Some(caller);
+        self
+    }
 }
 
 impl VfsFsType for DbfsFsType {
     fn mount(
         self: Arc<Self>,
-        _flags: u32,
+        flags: u32,
         _ab_mnt: &str,
         _dev: Option<Arc<dyn VfsInode>>,
         _data: &[u8],
@@ -43,30 +115,39 @@ impl VfsFsType for DbfsFsType {
 
         // Set up WAL storage if a device (Bottom FS) is provided
         if let Some(ref dev) = _dev {
-            let wal_inode = dev.create(".dbfs.wal", VfsNodeType::File, VfsNodePerm::from_bits_truncate(0o644), None)
-                .map_err(|_| VfsError::IoError)?;
+            let wal_inode = dev.create(
+                ".dbfs.wal",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )?;
             let storage = Arc::new(super::VfsWalStorage::new(wal_inode));
             self.tm.set_wal_storage(storage);
             info!("WAL storage initialized on Bottom FS");
         }
 
         // Open database
-        let db = clone_db();
-
-        // Initialize root inode if needed
-        let ctime = DbfsTimeSpec::default();
-        fs_common::dbfs_common_root_inode(0, 0, ctime).map_err(|_| VfsError::IoError)?;
+        let db = match self.db.clone() {
+            Some(db) => db,
+            None => try_clone_db()?,
+        };
+        self.tm.set_db(db.clone());
+        crate::init_cache(crate::cache_size())?;
+
+        // Initialize root inode if needed; a read-only mount must find an existing image
+        let flags = MountFlags::from_bits_truncate(flags);
+        if !flags.contains(MountFlags::RDONLY) {
+            let ctime = DbfsTimeSpec::default();
+            fs_common::dbfs_common_root_inode_in(&db, 0, 0, ctime)?;
+        }
 
         // Get superblock metadata
-        let tx = db.tx(false).map_err(|_| VfsError::IoError)?;
+        let tx = db.tx(false).map_err(DbfsError::from)?;
         let bucket = tx
             .get_bucket("super_blk".as_bytes())
-            .map_err(|_| VfsError::IoError)?;
+            .map_err(DbfsError::from)?;
 
-        let blk_size = bucket
-            .get_kv("blk_size")
-            .ok_or_else(|| VfsError::IoError)?;
-        let blk_size = crate::u32!(blk_size.value());
+        let blk_size = super::superblock::stored_block_size(&bucket);
 
         let magic = bucket.get_kv("magic").ok_or_else(|| VfsError::IoError)?;
         let magic = crate::u32!(magic.value());
@@ -75,7 +156,20 @@ impl VfsFsType for DbfsFsType {
         drop(tx);
 
         // Create superblock
-        let sb = Arc::new(DbfsSuperBlock::new(db, blk_size, magic, 0, self.tm.clone())?) as Arc<dyn vfscore::superblock::VfsSuperBlock>;
+        let sb = Arc::new(
+            DbfsSuperBlock::new(
+                db,
+                blk_size,
+                magic,
+                flags,
+                self.clock,
+                self.block_count,
+                self.tm.clone(),
+            )?
+            .with_read_ahead(self.read_ahead)
+            .with_poll_handler(self.poll_handler)
+            .with_enforce_permissions(self.enforce_permissions),
+        ) as Arc<dyn vfscore::superblock::VfsSuperBlock>;
 
         // Get root inode
         let root_inode = sb.root_inode()?;
@@ -88,12 +182,28 @@ impl VfsFsType for DbfsFsType {
 
     fn kill_sb(&self, sb: Arc<dyn vfscore::superblock::VfsSuperBlock>) -> VfsResult<()> {
         info!("Unmounting DBFS");
+        let db = match self.db.clone() {
+            Some(db) => db,
+            None => try_clone_db()?,
+        };
+
+        // 1. Write back the dirty pages
+        super::cache::flush(&db, None)?;
 
-        // Sync filesystem using the VfsSuperBlock trait
+        // 2. Apply whatever the WAL still holds and truncate it
+        self.tm.force_checkpoint().map_err(|e| {
+            error!("WAL checkpoint failed on unmount: {}", e);
+            VfsError::IoError
+        })?;
+
+        // 3. Persist continue_number/used_blocks, flushing anything the checkpoint cached
         sb.sync_fs(true)?;
 
+        // Only now that everything is on disk may the cache let go of this database
+        super::cache::forget(&db);
+
         // Call common umount
-        crate::fs_common::dbfs_common_umount().map_err(|_| VfsError::IoError)?;
+        crate::fs_common::dbfs_common_umount()?;
 
         Ok(())
     }
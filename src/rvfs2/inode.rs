@@ -1,4 +1,4 @@
-use alloc::{string::String, string::ToString, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, string::ToString, sync::Arc, vec::Vec};
 use core::cmp::min;
 
 use log::warn;
@@ -9,18 +9,28 @@ use vfscore::{
     inode::{InodeAttr, VfsInode},
     superblock::VfsSuperBlock,
     utils::{
-        VfsDirEntry, VfsInodeMode, VfsNodePerm, VfsNodeType, VfsRenameFlag, VfsTime, VfsTimeSpec,
+        VfsDirEntry, VfsInodeMode, VfsNodePerm, VfsNodeType, VfsPollEvents, VfsRenameFlag, VfsTime,
+        VfsTimeSpec,
     },
     VfsResult,
 };
 
-use super::{common as dbfs_common, superblock::DbfsSuperBlock};
+use super::{
+    common as dbfs_common,
+    superblock::{DbfsSuperBlock, MountFlags, RELATIME_THRESHOLD_SECS},
+};
 use crate::{
     clone_db,
-    common::{DbfsFileType, DbfsPermission, DbfsTimeSpec as DbfsTs},
+    common::{
+        dbfs_check_name, DbfsError, DbfsFileType, DbfsPermission, DbfsTimeSpec as DbfsTs,
+        ACCESS_R_OK, ACCESS_W_OK, ACCESS_X_OK,
+    },
     u16, u32, u64, usize,
 };
 
+/// Readdir positions kept per directory, enough for a handful of concurrent walks
+const READDIR_CURSORS: usize = 16;
+
 /// DBFS Inode structure
 pub struct DbfsInode {
     /// Reference to superblock
@@ -37,8 +47,8 @@ pub struct DbfsInode {
     uid: u32,
     /// Group ID
     gid: u32,
-    /// Permissions
-    perm: u16,
+    /// Permission bits, without the file type
+    perm: Mutex<u16>,
     /// Block size
     blksize: u32,
     /// Last access time
@@ -49,6 +59,11 @@ pub struct DbfsInode {
     ctime: Mutex<DbfsTs>,
     /// Symlink target (if symlink)
     symlink_target: Mutex<Option<String>>,
+    /// Raw name of the entry `readdir` last returned at each index, to resume walks from.
+    /// Several readers of the directory each find their own position.
+    readdir_cursor: Mutex<BTreeMap<usize, Vec<u8>>>,
+    /// Where the last read ended, to spot sequential readers
+    read_end: Mutex<u64>,
 }
 
 impl DbfsInode {
@@ -61,8 +76,9 @@ impl DbfsInode {
         gid: u32,
         ctime: DbfsTs,
     ) -> VfsResult<Arc<Self>> {
-        let attr = dbfs_common_attr(ino).map_err(|_| VfsError::IoError)?;
+        let attr = dbfs_common::dbfs_get_attr(&sb.db(), ino, sb.block_size())?;
 
+        dbfs_common::dbfs_open_inode(&sb.db(), ino);
         Ok(Arc::new(Self {
             sb,
             ino,
@@ -71,12 +87,14 @@ impl DbfsInode {
             nlink: Mutex::new(attr.nlink),
             uid: attr.uid,
             gid: attr.gid,
-            perm: attr.perm,
+            perm: Mutex::new(attr.perm),
             blksize: attr.blksize,
             atime: Mutex::new(attr.atime),
             mtime: Mutex::new(attr.mtime),
             ctime: Mutex::new(attr.ctime),
             symlink_target: Mutex::new(None),
+            readdir_cursor: Mutex::new(BTreeMap::new()),
+            read_end: Mutex::new(0),
         }))
     }
 
@@ -89,8 +107,9 @@ impl DbfsInode {
         gid: u32,
         ctime: DbfsTs,
     ) -> VfsResult<Arc<Self>> {
-        let attr = dbfs_common_attr(ino).map_err(|_| VfsError::IoError)?;
+        let attr = dbfs_common::dbfs_get_attr(&sb.db(), ino, sb.block_size())?;
 
+        dbfs_common::dbfs_open_inode(&sb.db(), ino);
         Ok(Arc::new(Self {
             sb,
             ino,
@@ -99,12 +118,14 @@ impl DbfsInode {
             nlink: Mutex::new(attr.nlink),
             uid: attr.uid,
             gid: attr.gid,
-            perm: attr.perm,
+            perm: Mutex::new(attr.perm),
             blksize: attr.blksize,
             atime: Mutex::new(attr.atime),
             mtime: Mutex::new(attr.mtime),
             ctime: Mutex::new(attr.ctime),
             symlink_target: Mutex::new(None),
+            readdir_cursor: Mutex::new(BTreeMap::new()),
+            read_end: Mutex::new(0),
         }))
     }
 
@@ -118,8 +139,9 @@ impl DbfsInode {
         target: String,
         ctime: DbfsTs,
     ) -> VfsResult<Arc<Self>> {
-        let attr = dbfs_common_attr(ino).map_err(|_| VfsError::IoError)?;
+        let attr = dbfs_common::dbfs_get_attr(&sb.db(), ino, sb.block_size())?;
 
+        dbfs_common::dbfs_open_inode(&sb.db(), ino);
         Ok(Arc::new(Self {
             sb,
             ino,
@@ -128,12 +150,42 @@ impl DbfsInode {
             nlink: Mutex::new(attr.nlink),
             uid: attr.uid,
             gid: attr.gid,
-            perm: attr.perm,
+            perm: Mutex::new(attr.perm),
             blksize: attr.blksize,
             atime: Mutex::new(attr.atime),
             mtime: Mutex::new(attr.mtime),
             ctime: Mutex::new(attr.ctime),
             symlink_target: Mutex::new(Some(target)),
+            readdir_cursor: Mutex::new(BTreeMap::new()),
+            read_end: Mutex::new(0),
+        }))
+    }
+
+    /// Create an inode for a device, fifo or socket node, which carries no data
+    fn new_node(
+        sb: Arc<DbfsSuperBlock>,
+        ino: usize,
+        inode_type: VfsNodeType,
+    ) -> VfsResult<Arc<Self>> {
+        let attr = dbfs_common::dbfs_get_attr(&sb.db(), ino, sb.block_size())?;
+
+        dbfs_common::dbfs_open_inode(&sb.db(), ino);
+        Ok(Arc::new(Self {
+            sb,
+            ino,
+            inode_type,
+            size: Mutex::new(attr.size),
+            nlink: Mutex::new(attr.nlink),
+            uid: attr.uid,
+            gid: attr.gid,
+            perm: Mutex::new(attr.perm),
+            blksize: attr.blksize,
+            atime: Mutex::new(attr.atime),
+            mtime: Mutex::new(attr.mtime),
+            ctime: Mutex::new(attr.ctime),
+            symlink_target: Mutex::new(None),
+            readdir_cursor: Mutex::new(BTreeMap::new()),
+            read_end: Mutex::new(0),
         }))
     }
 
@@ -142,12 +194,284 @@ impl DbfsInode {
         self.ino
     }
 
+    /// Inode number behind any handle on this file system
+    ///
+    /// The VFS may hand back a handle wrapped in its own type, so anything that is not a
+    /// `DbfsInode` is identified by its `st_ino`.
+    fn inode_number(inode: &dyn VfsInode) -> VfsResult<usize> {
+        match inode.downcast_ref::<DbfsInode>() {
+            Some(dbfs) => Ok(dbfs.ino),
+            None => Ok(inode.get_attr()?.st_ino as usize),
+        }
+    }
+
+    /// Whether `other` is a handle on this same inode
+    pub fn same_inode(&self, other: &dyn VfsInode) -> bool {
+        Self::inode_number(other).map_or(false, |ino| ino == self.ino)
+    }
+
+    /// Lookups on this mount answered from the negative cache
+    pub fn negative_hits(&self) -> u64 {
+        self.sb.negative_hits()
+    }
+
+    /// Physical layout of the file, see [`dbfs_common::dbfs_extent_map`]
+    pub fn extent_map(&self) -> VfsResult<Vec<crate::common::ExtentMapping>> {
+        if self.inode_type != VfsNodeType::File {
+            return Err(VfsError::Invalid);
+        }
+        let size = *self.size.lock() as u64;
+        Ok(dbfs_common::dbfs_extent_map(
+            &self.sb.db(),
+            self.ino,
+            size,
+            self.sb.block_size(),
+        )?)
+    }
+
+    /// Append `buf` at the current end of file, for a VFS layer implementing `O_APPEND`
+    ///
+    /// The append is logged as a write at the current size, so it is as crash-safe as any
+    /// other write. Appenders on the mount take turns, so concurrent appenders never
+    /// overwrite each other. Returns a short count when the device fills up.
+    pub fn append(&self, buf: &[u8]) -> VfsResult<usize> {
+        self.check_writable()?;
+        match self.inode_type {
+            VfsNodeType::File => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // The write is applied before `write_at_vectored` returns, so the next appender sees the new size
+        let _turn = self.sb.append_lock.lock();
+        let size =
+            dbfs_common::dbfs_get_attr(&self.sb.db(), self.ino, self.sb.block_size())?.size as u64;
+        self.write_at_vectored(size, &[buf])
+    }
+
+    /// Create a subdirectory with its `.` and `..` entries in place
+    ///
+    /// Unlike `create` with `VfsNodeType::Dir`, the new directory resolves `.` and `..`
+    /// through `lookup` and starts with a link count of 2.
+    pub fn mkdir(&self, name: &str, perm: VfsNodePerm) -> VfsResult<Arc<dyn VfsInode>> {
+        self.check_writable()?;
+        if self.inode_type != VfsNodeType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        Self::check_name(name)?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+        let (uid, gid) = self.sb.caller().unwrap_or((0, 0));
+
+        let dbfs_perm = Self::vfs_to_dbfs_perm(perm, VfsNodeType::Dir);
+        let ctime = Self::current_time();
+
+        let ino = {
+            let _guard = self.sb.tm.state_lock.write();
+            dbfs_common::dbfs_mkdir(&self.sb.db(), self.ino, name, uid, gid, dbfs_perm)?
+        };
+        self.sb.inode_created();
+        self.sb.forget_negative(self.ino, name);
+        self.refresh()?;
+
+        let dir = DbfsInode::new_dir(self.sb.clone(), ino, dbfs_perm.bits(), uid, gid, ctime)?;
+        self.sb.insert_inode(ino, dir.clone());
+
+        Ok(dir)
+    }
+
+    /// Write the concatenation of `bufs` at `offset` in one transaction
+    ///
+    /// The buffers are copied, up to what the device can hold, into a single logged write, so
+    /// they commit together as one contiguous range. Returns the bytes written, which is short
+    /// only when the device fills up.
+    pub fn write_at_vectored(&self, offset: u64, bufs: &[&[u8]]) -> VfsResult<usize> {
+        self.check_writable()?;
+        match self.inode_type {
+            VfsNodeType::File => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
+        }
+        self.enforce_access(ACCESS_W_OK)?;
+
+        use crate::operation::TransactionOperation;
+
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+
+        // Only log as much as the device can hold, so the WAL never records bytes that were not stored.
+        // The worst case (an unaligned tail may straddle one extra block) is reserved before
+        // measuring, and whatever the write turns out not to need is handed back.
+        let worst = (total as u64).div_ceil(self.sb.block_size()) + 1;
+        let budget = self.sb.reserve_up_to(worst);
+        let (len, new_blocks) = dbfs_common::dbfs_writable_len(
+            &self.sb.db(),
+            self.ino,
+            offset,
+            total,
+            budget,
+            self.sb.block_size(),
+        )
+        .map_err(|e| {
+            self.sb.release_blocks(budget);
+            VfsError::from(e)
+        })?;
+        self.sb.release_blocks(budget - new_blocks);
+        if len == 0 && total != 0 {
+            return Err(VfsError::NoSpace);
+        }
+
+        let mut data = Vec::with_capacity(len);
+        for buf in bufs {
+            let take = buf.len().min(len - data.len());
+            data.extend_from_slice(&buf[..take]);
+        }
+
+        let mut txn = self.sb.tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino: self.ino,
+            offset,
+            data,
+        });
+
+        self.sb.tm.commit(txn).map_err(|e| {
+            log::error!("Transaction commit failed: {}", e);
+            self.sb.release_blocks(new_blocks);
+            VfsError::IoError
+        })?;
+
+        self.refresh()?;
+        Ok(len)
+    }
+
+    /// Reload the cached size, link count and timestamps from the database
+    ///
+    /// The database is the source of truth; every operation that changes these fields calls
+    /// this afterwards instead of patching the cache by hand.
+    fn refresh(&self) -> VfsResult<()> {
+        let attr = dbfs_common::dbfs_get_attr(&self.sb.db(), self.ino, self.sb.block_size())?;
+        *self.size.lock() = attr.size;
+        *self.nlink.lock() = attr.nlink;
+        *self.atime.lock() = attr.atime;
+        *self.mtime.lock() = attr.mtime;
+        *self.ctime.lock() = attr.ctime;
+        Ok(())
+    }
+
     /// Get current time
     fn current_time() -> DbfsTs {
-        DbfsTs {
-            sec: 0,
-            nsec: 0,
+        DbfsTs { sec: 0, nsec: 0 }
+    }
+
+    /// Validate a new entry name before it reaches the database
+    fn check_name(name: &str) -> VfsResult<()> {
+        Ok(dbfs_check_name(name)?)
+    }
+
+    /// Fail with `PermissionDenied` (the `EROFS` equivalent) on a read-only mount
+    fn check_writable(&self) -> VfsResult<()> {
+        if self.sb.mount_flags().contains(MountFlags::RDONLY) {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Fail with `PermissionDenied` unless `uid`/`gid` hold every `ACCESS_*_OK` bit of `want`
+    ///
+    /// The owner class applies if `uid` owns the inode, else the group class if `gid` matches,
+    /// else the other class. Root may read and write anything, and execute if any class can.
+    pub fn check_access(&self, uid: u32, gid: u32, want: u16) -> VfsResult<()> {
+        let perm = *self.perm.lock();
+        let granted = if uid == 0 {
+            ACCESS_R_OK | ACCESS_W_OK | if perm & 0o111 != 0 { ACCESS_X_OK } else { 0 }
+        } else if uid == self.uid {
+            (perm >> 6) & 0o7
+        } else if gid == self.gid {
+            (perm >> 3) & 0o7
+        } else {
+            perm & 0o7
+        };
+        if want & !granted != 0 {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// [`check_access`](Self::check_access) for the current caller, if the mount enforces
+    /// permissions
+    fn enforce_access(&self, want: u16) -> VfsResult<()> {
+        match self.sb.caller() {
+            Some((uid, gid)) => self.check_access(uid, gid, want),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail with `PermissionDenied` if this directory has the sticky bit and the current caller
+    /// owns neither it nor the entry `name`; always passes when permissions are not enforced
+    fn check_sticky(&self, name: &str) -> VfsResult<()> {
+        let uid = match self.sb.caller() {
+            Some((uid, _)) => uid,
+            None => return Ok(()),
+        };
+        if *self.perm.lock() & DbfsPermission::S_ISVTX.bits() == 0 || uid == 0 || uid == self.uid {
+            return Ok(());
+        }
+        let ino = match dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)? {
+            Some(ino) => ino,
+            None => return Ok(()),
+        };
+        if dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size())?.uid != uid {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// After a read of `len` bytes at `offset` that ends on a block boundary and continues
+    /// where the previous one stopped, load the next blocks into the page cache
+    fn read_ahead(&self, offset: u64, len: u64) {
+        let window = self.sb.read_ahead();
+        let end = offset + len;
+        let sequential = core::mem::replace(&mut *self.read_end.lock(), end) == offset;
+        let block_size = self.sb.block_size();
+        if window == 0 || len == 0 || !sequential || end % block_size != 0 {
+            return;
+        }
+        // A failed prefetch only costs the reader a cache miss later
+        if let Err(e) = dbfs_common::dbfs_read_ahead(
+            &self.sb.db(),
+            self.ino,
+            end / block_size,
+            window,
+            block_size,
+        ) {
+            warn!("Read-ahead of inode {} failed: {:?}", self.ino, e);
+        }
+    }
+
+    /// Refresh atime after a read according to the mount's atime policy
+    fn touch_atime(&self) -> VfsResult<()> {
+        let flags = self.sb.mount_flags();
+        // Without a clock every refresh would write the same zero atime
+        if flags.intersects(MountFlags::NOATIME | MountFlags::RDONLY) || !self.sb.has_clock() {
+            return Ok(());
+        }
+
+        let now = self.sb.now();
+        let mut atime = self.atime.lock();
+        if flags.contains(MountFlags::RELATIME) {
+            let mtime = *self.mtime.lock();
+            let ctime = *self.ctime.lock();
+            let current = (atime.sec, atime.nsec);
+            let stale = atime.sec.saturating_add(RELATIME_THRESHOLD_SECS) <= now.sec;
+            if current > (mtime.sec, mtime.nsec) && current > (ctime.sec, ctime.nsec) && !stale {
+                return Ok(());
+            }
         }
+
+        dbfs_common::dbfs_update_atime(&self.sb.db(), self.ino, now)?;
+        *atime = now;
+        Ok(())
     }
 
     /// Convert VfsNodeType to DbfsFileType
@@ -181,43 +505,47 @@ impl DbfsInode {
     }
 }
 
+impl Drop for DbfsInode {
+    fn drop(&mut self) {
+        // The last handle on an unlinked inode takes its data with it
+        if dbfs_common::dbfs_close_inode(&self.sb.db(), self.ino) {
+            let blocks = dbfs_common::dbfs_block_count(&self.sb.db(), self.ino).unwrap_or(0);
+            match dbfs_common::dbfs_reap_orphan(&self.sb.db(), self.ino) {
+                Ok(removed) => {
+                    self.sb.release_blocks(blocks);
+                    if removed {
+                        self.sb.inode_removed();
+                    }
+                }
+                Err(e) => warn!("Failed to remove orphaned inode {}: {:?}", self.ino, e),
+            }
+        }
+    }
+}
+
 impl VfsFile for DbfsInode {
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
-        if self.inode_type != VfsNodeType::File && self.inode_type != VfsNodeType::SymLink {
-            return Err(VfsError::NoSys);
+        match self.inode_type {
+            VfsNodeType::File | VfsNodeType::SymLink => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
         }
+        self.enforce_access(ACCESS_R_OK)?;
 
         // Acquire read lock to ensure we're not reading while a commit is applying changes
-        let _guard = self.sb.tm.state_lock.read();
+        let guard = self.sb.tm.state_lock.read();
+
+        let len =
+            dbfs_common::dbfs_read(&self.sb.db(), self.ino, buf, offset, self.sb.block_size())?;
+        self.read_ahead(offset, len as u64);
+        drop(guard);
 
-        dbfs_common::dbfs_read(self.ino, buf, offset)
-            .map_err(|_| VfsError::IoError)
+        self.touch_atime()?;
+        Ok(len)
     }
 
     fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
-        if self.inode_type != VfsNodeType::File {
-            return Err(VfsError::NoSys);
-        }
-
-        use crate::operation::TransactionOperation;
-
-        let mut txn = self.sb.tm.begin_transaction();
-        txn.record(TransactionOperation::Write {
-            ino: self.ino,
-            offset,
-            data: buf.to_vec(),
-        });
-
-        self.sb.tm.commit(txn).map_err(|e| {
-            log::error!("Transaction commit failed: {}", e);
-            VfsError::IoError
-        })?;
-
-        // Update size in memory cache
-        let len = buf.len();
-        let new_size = (offset as usize + len).max(*self.size.lock());
-        *self.size.lock() = new_size;
-        Ok(len)
+        self.write_at_vectored(offset, &[buf])
     }
 
     fn readdir(&self, start_index: usize) -> VfsResult<Option<VfsDirEntry>> {
@@ -227,56 +555,105 @@ impl VfsFile for DbfsInode {
 
         let _guard = self.sb.tm.state_lock.read();
 
-        let db = self.sb.db();
-        let tx = db.tx(false).map_err(|_| VfsError::IoError)?;
-        let bucket = tx
-            .get_bucket(self.ino.to_be_bytes())
-            .map_err(|_| VfsError::IoError)?;
-
-        let entries: Vec<(String, usize)> = bucket
-            .kv_pairs()
-            .filter_map(|kv| {
-                let key = kv.key();
-                if key.starts_with(b"data:") {
-                    let name = String::from_utf8_lossy(&key[5..]).to_string();
-                    let value = kv.value();
-                    let ino = core::str::from_utf8(value)
-                        .ok()?
-                        .parse::<usize>()
-                        .ok()?;
-                    Some((name, ino))
-                } else {
-                    None
+        // Resume after the name returned for `start_index - 1` rather than counting entries,
+        // so entries inserted or removed during the walk cannot shift it
+        let mut cursor = self.readdir_cursor.lock();
+        let resumed = start_index
+            .checked_sub(1)
+            .and_then(|index| cursor.get(&index).cloned());
+        let after = match (start_index, resumed) {
+            (0, _) => None,
+            (_, Some(name)) => Some(name),
+            // Not a continuation of a walk in progress, fall back to counting
+            _ => {
+                let entries = dbfs_common::dbfs_readdir_raw(&self.sb.db(), self.ino)?;
+                match entries.into_iter().nth(start_index - 1) {
+                    Some((name, _)) => Some(name),
+                    None => return Ok(None),
                 }
-            })
-            .collect();
+            }
+        };
 
-        if start_index >= entries.len() {
-            return Ok(None);
+        let (name, ino, kind) =
+            match dbfs_common::dbfs_readdir_after(&self.sb.db(), self.ino, after.as_deref())? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+        let name_lossy = String::from_utf8_lossy(&name).into_owned();
+
+        // The entry records its type; older entries need the inode's attributes, and are
+        // tagged on the way so the next walk does not
+        let kind = match kind {
+            Some(kind) => kind,
+            None => {
+                let kind =
+                    dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size())?.kind;
+                if !self.sb.mount_flags().contains(MountFlags::RDONLY) {
+                    let _ = dbfs_common::dbfs_tag_dentry(&self.sb.db(), self.ino, &name, ino, kind);
+                }
+                kind
+            }
+        };
+        // The positions walks have long moved past go first
+        if cursor.insert(start_index, name).is_none() && cursor.len() > READDIR_CURSORS {
+            cursor.pop_first();
         }
+        drop(cursor);
 
-        let (name, ino) = entries[start_index].clone();
-
-        // Get the inode type
-        let attr = dbfs_common_attr(ino).map_err(|_| VfsError::IoError)?;
-        let entry_type = match attr.kind {
+        let entry_type = match kind {
             DbfsFileType::Directory => VfsNodeType::Dir,
             DbfsFileType::RegularFile => VfsNodeType::File,
             DbfsFileType::Symlink => VfsNodeType::SymLink,
-            _ => VfsNodeType::Fifo, // Fallback
+            DbfsFileType::CharDevice => VfsNodeType::CharDevice,
+            DbfsFileType::BlockDevice => VfsNodeType::BlockDevice,
+            DbfsFileType::NamedPipe => VfsNodeType::Fifo,
+            DbfsFileType::Socket => VfsNodeType::Socket,
         };
 
+        // Names are only converted to `String` at the VFS boundary
         Ok(Some(VfsDirEntry {
             ino: ino as u64,
             ty: entry_type,
-            name,
+            name: name_lossy,
         }))
     }
 
+    fn fsync(&self, datasync: bool) -> VfsResult<()> {
+        self.sb.tm.sync_inode(self.ino, datasync).map_err(|e| {
+            log::error!("fsync failed: {}", e);
+            VfsError::IoError
+        })?;
+        super::cache::flush(&self.sb.db(), Some(self.ino))?;
+        Ok(())
+    }
+
+    /// Write the dirty cached pages of this inode back to the database
+    ///
+    /// Unlike `fsync` this leaves the transaction log alone. Nothing is written if no page of
+    /// the inode is dirty; a failed write-back is reported and the pages stay dirty.
     fn flush(&self) -> VfsResult<()> {
-        // Sync the inode data
+        super::cache::flush(&self.sb.db(), Some(self.ino))?;
         Ok(())
     }
+
+    /// Report which of `event` are ready
+    ///
+    /// Files, directories and symlinks are backed by the database and never block, so reads and
+    /// writes are always ready. Device, fifo and socket nodes ask the mount's poll handler and
+    /// report nothing ready without one.
+    fn poll(&self, event: VfsPollEvents) -> VfsResult<VfsPollEvents> {
+        match self.inode_type {
+            VfsNodeType::File | VfsNodeType::Dir | VfsNodeType::SymLink => {
+                Ok(event & (VfsPollEvents::IN | VfsPollEvents::OUT))
+            }
+            ty => Ok(self
+                .sb
+                .poll_handler()
+                .map_or(VfsPollEvents::empty(), |handler| {
+                    handler(self.ino, ty, event)
+                })),
+        }
+    }
 }
 
 impl VfsInode for DbfsInode {
@@ -285,7 +662,7 @@ impl VfsInode for DbfsInode {
     }
 
     fn node_perm(&self) -> VfsNodePerm {
-        VfsNodePerm::from_bits_truncate(self.perm & 0o777)
+        VfsNodePerm::from_bits_truncate(*self.perm.lock() & 0o777)
     }
 
     fn create(
@@ -295,15 +672,28 @@ impl VfsInode for DbfsInode {
         perm: VfsNodePerm,
         rdev: Option<u64>,
     ) -> VfsResult<Arc<dyn VfsInode>> {
+        self.check_writable()?;
         if self.inode_type != VfsNodeType::Dir {
             return Err(VfsError::NotDir);
         }
+        // `create` has no way to carry a link target, so symlinks must go through `symlink()`
+        if ty == VfsNodeType::SymLink {
+            return Err(VfsError::Invalid);
+        }
+        Self::check_name(name)?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+        // Report an existing entry before logging, the commit can only say it failed
+        if dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)?.is_some() {
+            return Err(VfsError::EExist);
+        }
 
         let dbfs_perm = Self::vfs_to_dbfs_perm(perm, ty);
         let ctime = Self::current_time();
+        let (uid, gid) = self.sb.caller().unwrap_or((0, 0));
 
+        // Device numbers are stored in 32 bits, refuse one that would be cut short
         let dev = if ty == VfsNodeType::CharDevice || ty == VfsNodeType::BlockDevice {
-            Some(rdev.unwrap_or(0) as u32)
+            Some(u32::try_from(rdev.unwrap_or(0)).map_err(|_| VfsError::Invalid)?)
         } else {
             None
         };
@@ -314,19 +704,23 @@ impl VfsInode for DbfsInode {
         txn.record(TransactionOperation::Create {
             parent_ino: self.ino,
             name: name.to_string(),
-            uid: 0, // Default for now
-            gid: 0,
+            uid,
+            gid,
             perm: dbfs_perm.bits(),
-            dev: dev,
+            dev,
         });
 
         self.sb.tm.commit(txn).map_err(|e| {
             log::error!("Create transaction failed: {}", e);
             VfsError::IoError
         })?;
+        self.sb.inode_created();
+        self.sb.forget_negative(self.ino, name);
 
         // After commit, the inode should exist. Look it up to return it.
-        let attr = dbfs_common::dbfs_lookup(self.ino, name).map_err(|_| VfsError::IoError)?;
+        let ino =
+            dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)?.ok_or(VfsError::IoError)?;
+        let attr = dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size())?;
 
         // Create the new inode
         let new_inode = match ty {
@@ -346,41 +740,86 @@ impl VfsInode for DbfsInode {
                 attr.gid,
                 ctime,
             )?,
-            VfsNodeType::SymLink => {
-                return Err(VfsError::NoSys);
-            }
+            VfsNodeType::CharDevice
+            | VfsNodeType::BlockDevice
+            | VfsNodeType::Fifo
+            | VfsNodeType::Socket => DbfsInode::new_node(self.sb.clone(), attr.ino, ty)?,
             _ => return Err(VfsError::NoSys),
         };
 
         // Cache the new inode
         self.sb.insert_inode(attr.ino, new_inode.clone());
+        if ty == VfsNodeType::Dir {
+            self.refresh()?;
+        }
 
         Ok(new_inode)
     }
 
     fn link(&self, name: &str, src: Arc<dyn VfsInode>) -> VfsResult<Arc<dyn VfsInode>> {
+        self.check_writable()?;
         if self.inode_type != VfsNodeType::Dir {
             return Err(VfsError::NotDir);
         }
 
-        let src_dbfs = src.downcast_arc::<DbfsInode>().map_err(|_| VfsError::Invalid)?;
+        Self::check_name(name)?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
 
-        let ctime = Self::current_time();
-        dbfs_common::dbfs_link(0, 0, src_dbfs.ino, self.ino, name, ctime).map_err(|_| VfsError::IoError)?;
+        let src_ino = Self::inode_number(src.as_ref())?;
+
+        dbfs_common::dbfs_link_ino(&self.sb.db(), src_ino, self.ino, name, self.sb.now())?;
+        self.sb.forget_negative(self.ino, name);
 
-        // Update link count
-        *src_dbfs.nlink.lock() += 1;
+        // Handles on the source see the raised link count
+        if let Some(dbfs) = src.downcast_ref::<DbfsInode>() {
+            dbfs.refresh()?;
+        }
+        if let Some(cached) = self.sb.get_inode(src_ino) {
+            cached.refresh()?;
+        }
 
-        Ok(src_dbfs.clone())
+        Ok(src)
     }
 
     fn unlink(&self, name: &str) -> VfsResult<()> {
+        self.check_writable()?;
         if self.inode_type != VfsNodeType::Dir {
             return Err(VfsError::NotDir);
         }
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+        self.check_sticky(name)?;
+
+        // Directories go through `rmdir`, which checks that they are empty
+        if let Some(ino) = dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)? {
+            if dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size())?.kind
+                == DbfsFileType::Directory
+            {
+                return Err(VfsError::IsDir);
+            }
+        }
 
         use crate::operation::TransactionOperation;
 
+        // Blocks held by the inode are freed together with its last link, or by the last
+        // handle if it is still open
+        let mut open_handle = None;
+        let freed = match dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name) {
+            Ok(Some(ino)) => {
+                open_handle = self.sb.get_inode(ino);
+                self.sb.remove_inode(ino);
+                match dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size()) {
+                    Ok(attr)
+                        if attr.nlink <= 1
+                            && dbfs_common::dbfs_open_count(&self.sb.db(), ino) == 0 =>
+                    {
+                        Some(dbfs_common::dbfs_block_count(&self.sb.db(), ino).unwrap_or(0))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
         let mut txn = self.sb.tm.begin_transaction();
         txn.record(TransactionOperation::Delete {
             parent_ino: self.ino,
@@ -391,42 +830,48 @@ impl VfsInode for DbfsInode {
             log::error!("Unlink transaction failed: {}", e);
             VfsError::IoError
         })?;
+        if let Some(blocks) = freed {
+            self.sb.release_blocks(blocks);
+            self.sb.inode_removed();
+        }
+
+        // A handle that outlives the entry sees the lowered link count
+        if let Some(inode) = open_handle {
+            inode.refresh()?;
+        }
 
         Ok(())
     }
 
     fn symlink(&self, name: &str, target: &str) -> VfsResult<Arc<dyn VfsInode>> {
+        self.check_writable()?;
         if self.inode_type != VfsNodeType::Dir {
             return Err(VfsError::NotDir);
         }
 
-        let mut perm = DbfsPermission::S_IFLNK;
-        perm |= DbfsPermission::from_bits_truncate(0o777);
+        Self::check_name(name)?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+
+        let perm = DbfsPermission::S_IFLNK | DbfsPermission::from_bits_truncate(0o777);
 
         let ctime = Self::current_time();
-        let attr = dbfs_common::dbfs_create(
-            self.ino,
-            name,
-            0,
-            0,
-            ctime,
-            perm,
-            Some(target),
-            None,
-        )
-        .map_err(|_| VfsError::IoError)?;
+        let (uid, gid) = self.sb.caller().unwrap_or((0, 0));
+        // Persist the inode together with its target so that a later lookup can restore it
+        let ino = dbfs_common::dbfs_symlink(&self.sb.db(), self.ino, name, target, uid, gid)?;
+        self.sb.inode_created();
+        self.sb.forget_negative(self.ino, name);
 
         let symlink = DbfsInode::new_symlink(
             self.sb.clone(),
-            attr.ino,
-            attr.perm,
-            attr.uid,
-            attr.gid,
+            ino,
+            perm.bits(),
+            uid,
+            gid,
             target.to_string(),
             ctime,
         )?;
 
-        self.sb.insert_inode(attr.ino, symlink.clone());
+        self.sb.insert_inode(ino, symlink.clone());
 
         Ok(symlink)
     }
@@ -436,9 +881,21 @@ impl VfsInode for DbfsInode {
             return Err(VfsError::NotDir);
         }
 
+        // Repeated misses are answered without a transaction
+        if self.sb.is_negative(self.ino, name) {
+            return Err(VfsError::NoEntry);
+        }
+
         let _guard = self.sb.tm.state_lock.read();
 
-        let attr = dbfs_common::dbfs_lookup(self.ino, name).map_err(|_| VfsError::NoEntry)?;
+        let ino = match dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)? {
+            Some(ino) => ino,
+            None => {
+                self.sb.remember_negative(self.ino, name);
+                return Err(VfsError::NoEntry);
+            }
+        };
+        let attr = dbfs_common::dbfs_get_attr(&self.sb.db(), ino, self.sb.block_size())?;
 
         // Check if inode is already cached
         if let Some(cached) = self.sb.get_inode(attr.ino) {
@@ -464,11 +921,7 @@ impl VfsInode for DbfsInode {
                 ctime,
             )?,
             DbfsFileType::Symlink => {
-                let mut target = [0u8; 4096];
-                let len = dbfs_common::dbfs_readlink(attr.ino, &mut target).map_err(|_| VfsError::IoError)?;
-                let target_str = core::str::from_utf8(&target[..len])
-                    .map_err(|_| VfsError::Invalid)?
-                    .to_string();
+                let target_str = dbfs_common::dbfs_readlink(&self.sb.db(), attr.ino)?;
 
                 DbfsInode::new_symlink(
                     self.sb.clone(),
@@ -480,7 +933,18 @@ impl VfsInode for DbfsInode {
                     ctime,
                 )?
             }
-            _ => return Err(VfsError::NoSys),
+            DbfsFileType::CharDevice => {
+                DbfsInode::new_node(self.sb.clone(), attr.ino, VfsNodeType::CharDevice)?
+            }
+            DbfsFileType::BlockDevice => {
+                DbfsInode::new_node(self.sb.clone(), attr.ino, VfsNodeType::BlockDevice)?
+            }
+            DbfsFileType::NamedPipe => {
+                DbfsInode::new_node(self.sb.clone(), attr.ino, VfsNodeType::Fifo)?
+            }
+            DbfsFileType::Socket => {
+                DbfsInode::new_node(self.sb.clone(), attr.ino, VfsNodeType::Socket)?
+            }
         };
 
         self.sb.insert_inode(attr.ino, inode.clone());
@@ -489,57 +953,83 @@ impl VfsInode for DbfsInode {
     }
 
     fn rmdir(&self, name: &str) -> VfsResult<()> {
+        self.check_writable()?;
         if self.inode_type != VfsNodeType::Dir {
             return Err(VfsError::NotDir);
         }
-
-        let ctime = Self::current_time();
-        dbfs_common::dbfs_rmdir(0, 0, self.ino, name, ctime).map_err(|e| match e {
-            crate::common::DbfsError::NotEmpty => VfsError::NotEmpty,
-            _ => VfsError::IoError,
-        })?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+        self.check_sticky(name)?;
+
+        // Evict the cached inode first, so only handles held by callers keep it alive
+        let ino = dbfs_common::dbfs_lookup(&self.sb.db(), self.ino, name)?;
+        let open = ino.map_or(false, |ino| {
+            self.sb.remove_inode(ino);
+            dbfs_common::dbfs_open_count(&self.sb.db(), ino) > 0
+        });
+        dbfs_common::dbfs_rmdir(&self.sb.db(), self.ino, name)?;
+        // An open directory is counted out by its last handle
+        if !open {
+            self.sb.inode_removed();
+        }
+        // The removed directory's ".." no longer links here
+        self.refresh()?;
 
         Ok(())
     }
 
-    fn readlink(&self, _buf: &mut [u8]) -> VfsResult<usize> {
-        Err(VfsError::NoSys)
+    fn readlink(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        if self.inode_type != VfsNodeType::SymLink {
+            return Err(VfsError::Invalid);
+        }
+
+        let target = self.symlink_target.lock();
+        let target = target.as_ref().ok_or(VfsError::Invalid)?;
+        let len = min(buf.len(), target.len());
+        buf[..len].copy_from_slice(&target.as_bytes()[..len]);
+        Ok(len)
     }
 
     fn set_attr(&self, attr: InodeAttr) -> VfsResult<()> {
+        self.check_writable()?;
         // Update attributes in database
         let db = self.sb.db();
-        let tx = db.tx(true).map_err(|_| VfsError::IoError)?;
+        let tx = db.tx(true).map_err(DbfsError::from)?;
         let bucket = tx
             .get_bucket(self.ino.to_be_bytes())
-            .map_err(|_| VfsError::IoError)?;
+            .map_err(DbfsError::from)?;
 
         // Update size if changed
         if attr.size as usize != *self.size.lock() {
             bucket
                 .put("size", attr.size.to_be_bytes())
-                .map_err(|_| VfsError::IoError)?;
+                .map_err(DbfsError::from)?;
             *self.size.lock() = attr.size as usize;
         }
 
-        // Update permissions
-        if attr.mode as u16 != self.perm {
+        // Update permissions, keeping the file type bits of the stored mode
+        let perm = attr.mode as u16 & !DbfsPermission::S_IFMT.bits();
+        let mut cached_perm = self.perm.lock();
+        if perm != *cached_perm {
+            let old_mode = bucket
+                .get_kv("mode")
+                .map(|kv| u16!(kv.value()))
+                .unwrap_or(0);
+            let mode = (old_mode & DbfsPermission::S_IFMT.bits()) | perm;
             bucket
-                .put("mode", (attr.mode as u16).to_be_bytes())
-                .map_err(|_| VfsError::IoError)?;
-            // SAFETY: We're updating the permission, which is safe
-            // Note: This modifies through an immutable reference, but that's the design
-            // In production code, this should be refactored
+                .put("mode", mode.to_be_bytes())
+                .map_err(DbfsError::from)?;
         }
 
-        tx.commit().map_err(|_| VfsError::IoError)?;
+        tx.commit().map_err(DbfsError::from)?;
+        *cached_perm = perm;
 
         Ok(())
     }
 
     fn get_attr(&self) -> VfsResult<vfscore::utils::VfsFileStat> {
         let _guard = self.sb.tm.state_lock.read();
-        let attr = dbfs_common_attr(self.ino).map_err(|_| VfsError::IoError)?;
+        let attr = dbfs_common::dbfs_get_attr(&self.sb.db(), self.ino, self.sb.block_size())?;
+        let size = attr.size as u64;
 
         let mode = VfsInodeMode::from(
             VfsNodePerm::from_bits_truncate(attr.perm & 0o777),
@@ -563,7 +1053,7 @@ impl VfsInode for DbfsInode {
             st_gid: attr.gid,
             st_rdev: attr.rdev as u64,
             __pad: 0,
-            st_size: attr.size as u64,
+            st_size: size,
             st_blksize: attr.blksize as u32,
             __pad2: 0,
             st_blocks: attr.blocks as u64,
@@ -594,11 +1084,16 @@ impl VfsInode for DbfsInode {
     }
 
     fn truncate(&self, len: u64) -> VfsResult<()> {
-        if self.inode_type != VfsNodeType::File {
-            return Err(VfsError::NoSys);
+        self.check_writable()?;
+        match self.inode_type {
+            VfsNodeType::File => {}
+            VfsNodeType::Dir => return Err(VfsError::IsDir),
+            _ => return Err(VfsError::NoSys),
         }
 
         use crate::operation::TransactionOperation;
+        let before = dbfs_common::dbfs_block_count(&self.sb.db(), self.ino)?;
+
         let mut txn = self.sb.tm.begin_transaction();
         txn.record(TransactionOperation::Truncate {
             ino: self.ino,
@@ -610,7 +1105,10 @@ impl VfsInode for DbfsInode {
             VfsError::IoError
         })?;
 
-        *self.size.lock() = len as usize;
+        let after = dbfs_common::dbfs_block_count(&self.sb.db(), self.ino)?;
+        self.sb.release_blocks(before.saturating_sub(after));
+
+        self.refresh()?;
 
         Ok(())
     }
@@ -622,16 +1120,49 @@ impl VfsInode for DbfsInode {
         new_name: &str,
         flag: VfsRenameFlag,
     ) -> VfsResult<()> {
-        let new_parent_dbfs = new_parent
-            .downcast_arc::<DbfsInode>()
-            .map_err(|_| VfsError::Invalid)?;
+        self.check_writable()?;
+        Self::check_name(new_name)?;
+        self.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+        self.check_sticky(old_name)?;
+        // Replacing an entry in a sticky target directory is a deletion there too
+        if let Some(target) = new_parent.downcast_ref::<DbfsInode>() {
+            target.enforce_access(ACCESS_W_OK | ACCESS_X_OK)?;
+            target.check_sticky(new_name)?;
+        }
+
+        let new_parent_ino = Self::inode_number(new_parent.as_ref())?;
+        let db = self.sb.db();
+
+        // A directory cannot move below itself
+        let ino = dbfs_common::dbfs_lookup(&db, self.ino, old_name)?.ok_or(VfsError::NoEntry)?;
+        if dbfs_common::dbfs_get_attr(&db, ino, self.sb.block_size())?.kind
+            == DbfsFileType::Directory
+            && dbfs_common::dbfs_is_ancestor(&db, ino, new_parent_ino)?
+        {
+            return Err(VfsError::Invalid);
+        }
+
+        // A replaced target loses a link; its blocks are freed with the last one, as in `unlink`
+        let mut replaced = None;
+        let mut freed = None;
+        if let Some(target) =
+            dbfs_common::dbfs_lookup(&db, new_parent_ino, new_name)?.filter(|&t| t != ino)
+        {
+            replaced = Some((target, self.sb.get_inode(target)));
+            if let Ok(attr) = dbfs_common::dbfs_get_attr(&db, target, self.sb.block_size()) {
+                let last = attr.nlink <= 1 || attr.kind == DbfsFileType::Directory;
+                if last && dbfs_common::dbfs_open_count(&db, target) == 0 {
+                    freed = Some(dbfs_common::dbfs_block_count(&db, target).unwrap_or(0));
+                }
+            }
+        }
 
         use crate::operation::TransactionOperation;
         let mut txn = self.sb.tm.begin_transaction();
         txn.record(TransactionOperation::Rename {
             old_parent_ino: self.ino,
             old_name: old_name.to_string(),
-            new_parent_ino: new_parent_dbfs.ino,
+            new_parent_ino,
             new_name: new_name.to_string(),
         });
 
@@ -639,42 +1170,55 @@ impl VfsInode for DbfsInode {
             log::error!("Rename transaction failed: {}", e);
             VfsError::IoError
         })?;
+        self.sb.forget_negative(new_parent_ino, new_name);
+
+        if let Some((target, open_handle)) = replaced {
+            self.sb.remove_inode(target);
+            if let Some(blocks) = freed {
+                self.sb.release_blocks(blocks);
+                self.sb.inode_removed();
+            }
+            // A handle that outlives the entry sees the lowered link count
+            if let Some(inode) = open_handle {
+                let _ = inode.refresh();
+            }
+        }
 
         Ok(())
     }
 
-    fn update_time(&self, time: VfsTime, _now: VfsTimeSpec) -> VfsResult<()> {
+    fn update_time(&self, time: VfsTime, now: VfsTimeSpec) -> VfsResult<()> {
+        self.check_writable()?;
         let db = self.sb.db();
-        let tx = db.tx(true).map_err(|_| VfsError::IoError)?;
+        let tx = db.tx(true).map_err(DbfsError::from)?;
         let bucket = tx
             .get_bucket(self.ino.to_be_bytes())
-            .map_err(|_| VfsError::IoError)?;
-
-        let ctime = Self::current_time();
+            .map_err(DbfsError::from)?;
+
+        let put = |key: &str, ts: &VfsTimeSpec| -> VfsResult<()> {
+            let mut bytes = [0u8; 16];
+            bytes[0..8].copy_from_slice(&ts.sec.to_be_bytes());
+            bytes[8..16].copy_from_slice(&ts.nsec.to_be_bytes());
+            bucket.put(key, bytes).map_err(DbfsError::from)?;
+            Ok(())
+        };
 
+        // Setting atime or mtime is itself a change to the inode, so ctime moves to `now`
+        #[allow(unreachable_patterns)]
         match time {
             VfsTime::AccessTime(ts) => {
-                let mut bytes = [0u8; 16];
-                bytes[0..8].copy_from_slice(&ts.sec.to_be_bytes());
-                bytes[8..16].copy_from_slice(&ts.nsec.to_be_bytes());
-                bucket.put("atime", bytes).map_err(|_| VfsError::IoError)?;
+                put("atime", &ts)?;
+                put("ctime", &now)?;
             }
             VfsTime::ModifiedTime(ts) => {
-                let mut bytes = [0u8; 16];
-                bytes[0..8].copy_from_slice(&ts.sec.to_be_bytes());
-                bytes[8..16].copy_from_slice(&ts.nsec.to_be_bytes());
-                bucket.put("mtime", bytes).map_err(|_| VfsError::IoError)?;
-            }
-            _ => {
-                // Ctime is not in VfsTime enum, handle separately
-                let mut bytes = [0u8; 16];
-                bytes[0..8].copy_from_slice(&ctime.sec.to_be_bytes());
-                bytes[8..16].copy_from_slice(&ctime.nsec.to_be_bytes());
-                bucket.put("ctime", bytes).map_err(|_| VfsError::IoError)?;
+                put("mtime", &ts)?;
+                put("ctime", &now)?;
             }
+            VfsTime::ChangeTime(ts) => put("ctime", &ts)?,
+            _ => return Err(VfsError::NoSys),
         }
 
-        tx.commit().map_err(|_| VfsError::IoError)?;
+        tx.commit().map_err(DbfsError::from)?;
 
         Ok(())
     }
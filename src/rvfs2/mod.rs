@@ -3,16 +3,20 @@
 //! This module provides an implementation of the new RVFS traits (VfsInode, VfsFile, VfsSuperBlock, VfsFsType)
 //! for DBFS, allowing it to work with the updated VFS layer.
 
-mod common;
+pub(crate) mod cache;
+pub(crate) mod common;
 mod dentry;
 mod fstype;
 mod inode;
-mod superblock;
+pub(crate) mod superblock;
 
-use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::sync::Arc;
 
+pub use cache::{stats as cache_stats, CacheStats};
 pub use fstype::DbfsFsType;
+pub use inode::DbfsInode;
+pub use superblock::{CallerIds, MountFlags, PollHandler, NEGATIVE_CACHE_CAPACITY};
 
 pub struct VfsWalStorage {
     inode: Arc<dyn vfscore::inode::VfsInode>,
@@ -29,19 +33,25 @@ impl crate::wal::WalStorage for VfsWalStorage {
         // In vfscore, VfsInode often also implements VfsFile
         // or has a way to get one. For now, we assume the inode
         // representing the WAL file can be written to.
-        self.inode.write_at(offset, data)
+        self.inode
+            .write_at(offset, data)
             .map(|_| ())
             .map_err(|e| alloc::format!("{:?}", e))
     }
 
     fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
-        self.inode.read_at(offset, buf)
-            .map(|_| ())
-            .map_err(|e| alloc::format!("{:?}", e))
+        // A short read means the log ends here; recovery must not parse the rest of `buf`
+        match self.inode.read_at(offset, buf) {
+            Ok(n) if n == buf.len() => Ok(()),
+            Ok(n) => Err(alloc::format!("Short read: {} of {} bytes", n, buf.len())),
+            Err(e) => Err(alloc::format!("{:?}", e)),
+        }
     }
 
     fn truncate(&self, length: u64) -> Result<(), String> {
-        self.inode.truncate(length).map_err(|e| alloc::format!("{:?}", e))
+        self.inode
+            .truncate(length)
+            .map_err(|e| alloc::format!("{:?}", e))
     }
 
     fn flush(&self) -> Result<(), String> {
@@ -1,13 +1,57 @@
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bitflags::bitflags;
 use spin::Mutex;
 
 use vfscore::{
     superblock::{SuperType, VfsSuperBlock},
-    utils::VfsFsStat,
+    utils::{VfsFsStat, VfsNodeType, VfsPollEvents, VfsTimeSpec},
     VfsResult,
 };
 
-use crate::{clone_db, common::DbfsTimeSpec, fs_common, inode_common::DBFS_INODE_NUMBER};
+use crate::{
+    clone_db,
+    common::{DbfsTimeSpec, MAX_NAME_LEN},
+    fs_common,
+    inode_common::DBFS_INODE_NUMBER,
+};
+
+bitflags! {
+    /// Mount options understood by DBFS (values follow Linux `MS_*`)
+    pub struct MountFlags: u32 {
+        const RDONLY = 1;
+        const NOATIME = 1 << 10;
+        const RELATIME = 1 << 21;
+    }
+}
+
+/// Readiness of a device, fifo or socket node: called with its inode number, type and the
+/// events asked for, returns the events that are ready
+pub type PollHandler = fn(usize, VfsNodeType, VfsPollEvents) -> VfsPollEvents;
+
+/// Identity of the process calling into the mount, as `(uid, gid)`
+pub type CallerIds = fn() -> (u32, u32);
+
+/// Lookup misses remembered per mount; the oldest is forgotten first
+pub const NEGATIVE_CACHE_CAPACITY: usize = 1024;
+
+/// Names recently looked up and found missing, keyed by directory inode
+#[derive(Default)]
+struct NegativeCache {
+    entries: BTreeSet<(usize, String)>,
+    /// Insertion order, for evicting the oldest miss
+    order: VecDeque<(usize, String)>,
+    hits: u64,
+}
+
+/// With `RELATIME`, an atime that is not older than mtime/ctime is still refreshed once per day
+pub const RELATIME_THRESHOLD_SECS: u64 = 24 * 60 * 60;
 
 /// DBFS SuperBlock structure
 pub struct DbfsSuperBlock {
@@ -20,43 +64,94 @@ pub struct DbfsSuperBlock {
     /// Root inode number
     root_ino: usize,
     /// Mount flags
-    mount_flags: u32,
+    mount_flags: MountFlags,
+    /// Time source for inode timestamps, `None` if the mount has none
+    clock: Option<fn() -> VfsTimeSpec>,
+    /// Capacity of the backing device in blocks, `None` if unbounded
+    total_blocks: Option<u64>,
+    /// Data blocks currently allocated, shared by every clone of this superblock
+    used_blocks: Arc<AtomicU64>,
+    /// Inodes currently allocated, shared by every clone of this superblock
+    used_inodes: Arc<AtomicU64>,
+    /// Blocks to load ahead of a sequential reader, 0 to disable read-ahead
+    read_ahead: u64,
+    /// Readiness of special nodes, `None` if nothing is ever ready on them
+    poll_handler: Option<PollHandler>,
+    /// Caller identity that permissions are enforced against, `None` to allow every access
+    enforce_permissions: Option<CallerIds>,
+    /// Recent lookup misses, shared by every clone of this superblock
+    negative: Arc<Mutex<NegativeCache>>,
+    /// Held by an appender from reading the size until its write is applied
+    pub(crate) append_lock: Arc<Mutex<()>>,
     /// Inode cache (inode_number -> Arc<DbfsInode>)
     inode_cache: Mutex<BTreeMap<usize, Arc<super::inode::DbfsInode>>>,
     /// Transaction manager
     pub tm: Arc<crate::transaction::TransactionManager>,
 }
 
+/// Block size recorded in `super_blk`, the compiled `SLICE_SIZE` if the image has none
+pub(crate) fn stored_block_size(super_blk: &jammdb::Bucket) -> u32 {
+    super_blk
+        .get_kv("blk_size")
+        .map(|kv| crate::u32!(kv.value()))
+        .unwrap_or(crate::SLICE_SIZE as u32)
+}
+
 impl DbfsSuperBlock {
     /// Create a new DBFS superblock
     pub fn new(
         db: Arc<crate::SafeDb>,
         block_size: u32,
         magic: u32,
-        mount_flags: u32,
+        mount_flags: MountFlags,
+        clock: Option<fn() -> VfsTimeSpec>,
+        total_blocks: Option<u64>,
         tm: Arc<crate::transaction::TransactionManager>,
     ) -> VfsResult<Self> {
+        // Nobody holds a handle on an inode unlinked while open before a crash, reclaim it now
+        if !mount_flags.contains(MountFlags::RDONLY) {
+            let reaped = super::common::dbfs_reap_orphans(&db)
+                .map_err(|_| vfscore::error::VfsError::IoError)?;
+            if reaped > 0 {
+                log::info!("Reclaimed {} orphaned inodes", reaped);
+            }
+        }
+
         let db_clone = db.clone();
-        let tx = db_clone.tx(false).map_err(|_| vfscore::error::VfsError::IoError)?;
+        let tx = db_clone
+            .tx(false)
+            .map_err(|_| vfscore::error::VfsError::IoError)?;
 
         // Load or create superblock metadata
         let bucket = tx
             .get_bucket("super_blk".as_bytes())
             .map_err(|_| vfscore::error::VfsError::IoError)?;
 
-        let continue_number = bucket
-            .get_kv("continue_number")
-            .ok_or_else(|| vfscore::error::VfsError::IoError)?;
-        let continue_number = crate::usize!(continue_number.value());
+        // Images written by another adapter may lack the counter; start past the highest inode
+        let continue_number = match bucket.get_kv("continue_number") {
+            Some(kv) => crate::usize!(kv.value()),
+            None => crate::fs_common::dbfs_common_highest_ino(&tx) + 1,
+        };
 
-        // Set the next inode number
-        DBFS_INODE_NUMBER.store(continue_number, core::sync::atomic::Ordering::SeqCst);
+        // Set the next inode number, never moving backwards past numbers already handed out
+        DBFS_INODE_NUMBER.fetch_max(continue_number, core::sync::atomic::Ordering::SeqCst);
 
         // Get block size from superblock
-        let blk_size = bucket
-            .get_kv("blk_size")
-            .ok_or_else(|| vfscore::error::VfsError::IoError)?;
-        let blk_size = crate::u32!(blk_size.value());
+        let blk_size = stored_block_size(&bucket);
+        if blk_size == 0 {
+            return Err(vfscore::error::VfsError::Invalid);
+        }
+
+        let used_blocks = bucket
+            .get_kv("used_blocks")
+            .map(|kv| crate::u64!(kv.value()))
+            .unwrap_or(0);
+
+        // Counted once per mount, then kept up to date by create and delete
+        let used_inodes = tx
+            .buckets()
+            .filter(|(name, _)| name.name().len() == 8)
+            .count() as u64;
 
         Ok(Self {
             db,
@@ -64,11 +159,53 @@ impl DbfsSuperBlock {
             magic,
             root_ino: 1, // Root inode is always 1
             mount_flags,
+            clock,
+            total_blocks,
+            used_blocks: Arc::new(AtomicU64::new(used_blocks)),
+            used_inodes: Arc::new(AtomicU64::new(used_inodes)),
+            read_ahead: 0,
+            poll_handler: None,
+            enforce_permissions: None,
+            negative: Arc::new(Mutex::new(NegativeCache::default())),
+            append_lock: Arc::new(Mutex::new(())),
             inode_cache: Mutex::new(BTreeMap::new()),
             tm,
         })
     }
 
+    /// Load `blocks` blocks ahead of sequential readers into the page cache
+    pub fn with_read_ahead(mut self, blocks: u64) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    /// Blocks loaded ahead of a sequential reader, 0 if read-ahead is off
+    pub fn read_ahead(&self) -> u64 {
+        self.read_ahead
+    }
+
+    /// Ask `handler` for the readiness of device, fifo and socket nodes
+    pub fn with_poll_handler(mut self, handler: Option<PollHandler>) -> Self {
+        self.poll_handler = handler;
+        self
+    }
+
+    /// Readiness handler for special nodes, if one is registered
+    pub fn poll_handler(&self) -> Option<PollHandler> {
+        self.poll_handler
+    }
+
+    /// Check inode permissions against the identity `caller` returns; `None` allows everything
+    pub fn with_enforce_permissions(mut self, caller: Option<CallerIds>) -> Self {
+        self.enforce_permissions = caller;
+        self
+    }
+
+    /// Identity of the current caller if permissions are enforced
+    pub fn caller(&self) -> Option<(u32, u32)> {
+        self.enforce_permissions.map(|caller| caller())
+    }
+
     /// Get the database instance
     pub fn db(&self) -> Arc<crate::SafeDb> {
         self.db.clone()
@@ -89,6 +226,95 @@ impl DbfsSuperBlock {
         self.root_ino
     }
 
+    /// Get the mount flags
+    pub fn mount_flags(&self) -> MountFlags {
+        self.mount_flags
+    }
+
+    /// Get the current time from the mount's clock, 0 if the mount has no clock
+    pub fn now(&self) -> DbfsTimeSpec {
+        match self.clock {
+            Some(clock) => {
+                let ts = clock();
+                DbfsTimeSpec::new(ts.sec as u64, ts.nsec as u32)
+            }
+            None => DbfsTimeSpec::new(0, 0),
+        }
+    }
+
+    /// Whether the mount has a clock to take timestamps from
+    pub fn has_clock(&self) -> bool {
+        self.clock.is_some()
+    }
+
+    /// Number of blocks that can still be allocated
+    pub fn free_blocks(&self) -> u64 {
+        match self.total_blocks {
+            Some(total) => total.saturating_sub(self.used_blocks.load(Ordering::SeqCst)),
+            None => u64::MAX,
+        }
+    }
+
+    /// Reserve `count` blocks, failing if the device does not have that many left
+    pub fn reserve_blocks(&self, count: u64) -> bool {
+        self.used_blocks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let new = used.checked_add(count)?;
+                match self.total_blocks {
+                    Some(total) if new > total => None,
+                    _ => Some(new),
+                }
+            })
+            .is_ok()
+    }
+
+    /// Reserve as many of `count` blocks as the device has left, returning how many were taken
+    ///
+    /// The check and the reservation are one atomic step, so concurrent writers never both
+    /// claim the last free blocks.
+    pub fn reserve_up_to(&self, count: u64) -> u64 {
+        let Some(total) = self.total_blocks else {
+            self.used_blocks.fetch_add(count, Ordering::SeqCst);
+            return count;
+        };
+        let mut taken = 0;
+        let _ = self
+            .used_blocks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                taken = count.min(total.saturating_sub(used));
+                Some(used + taken)
+            });
+        taken
+    }
+
+    /// Return `count` blocks to the free pool
+    pub fn release_blocks(&self, count: u64) {
+        let _ = self
+            .used_blocks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(count))
+            });
+    }
+
+    /// Number of inodes currently allocated
+    pub fn used_inodes(&self) -> u64 {
+        self.used_inodes.load(Ordering::SeqCst)
+    }
+
+    /// Account for an inode that was just created
+    pub fn inode_created(&self) {
+        self.used_inodes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Account for an inode whose bucket was just deleted
+    pub fn inode_removed(&self) {
+        let _ = self
+            .used_inodes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(1))
+            });
+    }
+
     /// Insert an inode into the cache
     pub fn insert_inode(&self, ino: usize, inode: Arc<super::inode::DbfsInode>) {
         let mut cache = self.inode_cache.lock();
@@ -106,21 +332,91 @@ impl DbfsSuperBlock {
         let mut cache = self.inode_cache.lock();
         cache.remove(&ino);
     }
+
+    /// Whether `name` in directory `dir` is known to be missing
+    pub fn is_negative(&self, dir: usize, name: &str) -> bool {
+        let mut negative = self.negative.lock();
+        let hit = negative.entries.contains(&(dir, name.to_string()));
+        if hit {
+            negative.hits += 1;
+        }
+        hit
+    }
+
+    /// Remember that `name` in directory `dir` is missing
+    ///
+    /// Callers must hold `tm.state_lock` across the database lookup and this call, so a
+    /// concurrent create cannot slip in between.
+    pub fn remember_negative(&self, dir: usize, name: &str) {
+        let mut negative = self.negative.lock();
+        let key = (dir, name.to_string());
+        if !negative.entries.insert(key.clone()) {
+            return;
+        }
+        negative.order.push_back(key);
+        while negative.order.len() > NEGATIVE_CACHE_CAPACITY {
+            if let Some(old) = negative.order.pop_front() {
+                negative.entries.remove(&old);
+            }
+        }
+    }
+
+    /// Forget a remembered miss once `name` may exist in `dir`
+    pub fn forget_negative(&self, dir: usize, name: &str) {
+        // Wait out lookups that read the database before the name appeared
+        let _guard = self.tm.state_lock.write();
+        let mut negative = self.negative.lock();
+        let key = (dir, name.to_string());
+        if negative.entries.remove(&key) {
+            negative.order.retain(|k| k != &key);
+        }
+    }
+
+    /// Lookups answered from the negative cache without touching the database
+    pub fn negative_hits(&self) -> u64 {
+        self.negative.lock().hits
+    }
+
+    /// Number of live handles on an inode, the cache's own included
+    ///
+    /// An inode unlinked while this is nonzero keeps its data until the last handle drops.
+    pub fn open_handles(&self, ino: usize) -> usize {
+        super::common::dbfs_open_count(&self.db, ino)
+    }
 }
 
 impl VfsSuperBlock for DbfsSuperBlock {
     fn sync_fs(&self, _wait: bool) -> VfsResult<()> {
+        // Nothing may be written to a read-only image
+        if self.mount_flags.contains(MountFlags::RDONLY) {
+            return Ok(());
+        }
+
         let db = self.db();
+        super::cache::flush(&db, None)?;
+
         let tx = db.tx(true).map_err(|_| vfscore::error::VfsError::IoError)?;
         let bucket = tx
             .get_bucket("super_blk".as_bytes())
             .map_err(|_| vfscore::error::VfsError::IoError)?;
 
-        let continue_number =
-            DBFS_INODE_NUMBER.load(core::sync::atomic::Ordering::SeqCst);
+        // Never move the persisted counter backwards past numbers allocated elsewhere
+        let persisted = bucket
+            .get_kv("continue_number")
+            .map(|kv| crate::usize!(kv.value()))
+            .unwrap_or(0);
+        let continue_number = DBFS_INODE_NUMBER
+            .load(core::sync::atomic::Ordering::SeqCst)
+            .max(persisted);
         bucket
             .put("continue_number".as_bytes(), continue_number.to_be_bytes())
             .map_err(|_| vfscore::error::VfsError::IoError)?;
+        bucket
+            .put(
+                "used_blocks".as_bytes(),
+                self.used_blocks.load(Ordering::SeqCst).to_be_bytes(),
+            )
+            .map_err(|_| vfscore::error::VfsError::IoError)?;
 
         tx.commit().map_err(|_| vfscore::error::VfsError::IoError)?;
         Ok(())
@@ -130,23 +426,30 @@ impl VfsSuperBlock for DbfsSuperBlock {
         let _stat = fs_common::dbfs_common_statfs(
             self.block_size as u64,
             self.magic as u64,
-            self.mount_flags as u64,
+            self.mount_flags.bits() as u64,
             0,
             self.block_size,
         )
         .map_err(|_| vfscore::error::VfsError::IoError)?;
 
+        // 容量未知时块数报告为 0
+        let total = self.total_blocks.unwrap_or(0);
+        let free = total.saturating_sub(self.used_blocks.load(Ordering::SeqCst));
+        // 没有回收的 inode 号, 剩余的都来自 continue_number 之后的号段
+        let next_ino = DBFS_INODE_NUMBER.load(Ordering::SeqCst) as u64;
+        let free_inodes = (usize::MAX as u64).saturating_sub(next_ino);
+
         // 手动构建 VfsFsStat（使用默认值）
         Ok(vfscore::utils::VfsFsStat {
             f_type: self.magic as i64,
             f_bsize: self.block_size as i64,
-            f_blocks: 0,
-            f_bfree: 0,
-            f_bavail: 0,
-            f_files: 0,
-            f_ffree: 0,
+            f_blocks: total,
+            f_bfree: free,
+            f_bavail: free,
+            f_files: self.used_inodes(),
+            f_ffree: free_inodes,
             f_fsid: [0; 2],
-            f_namelen: 255,
+            f_namelen: MAX_NAME_LEN as _,
             f_frsize: self.block_size as isize,
             f_flags: 0,
             f_spare: [0; 4],
@@ -192,6 +495,15 @@ impl Clone for DbfsSuperBlock {
             magic: self.magic,
             root_ino: self.root_ino,
             mount_flags: self.mount_flags,
+            clock: self.clock,
+            total_blocks: self.total_blocks,
+            used_blocks: self.used_blocks.clone(),
+            used_inodes: self.used_inodes.clone(),
+            read_ahead: self.read_ahead,
+            poll_handler: self.poll_handler,
+            enforce_permissions: self.enforce_permissions,
+            negative: self.negative.clone(),
+            append_lock: self.append_lock.clone(),
             inode_cache: Mutex::new(self.inode_cache.lock().clone()),
             tm: self.tm.clone(),
         }
@@ -0,0 +1,2244 @@
+use alloc::{string::ToString, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use jammdb::{
+    memfile::{FakeMap, FileOpenOptions},
+    DB,
+};
+use spin::{Once, RwLock};
+use vfscore::{
+    error::VfsError,
+    fstype::VfsFsType,
+    inode::{InodeAttr, VfsInode},
+    utils::{VfsNodePerm, VfsNodeType, VfsPollEvents, VfsRenameFlag, VfsTimeSpec},
+};
+
+use crate::rvfs2::{DbfsFsType, MountFlags};
+
+static ROOT: Once<Arc<dyn VfsInode>> = Once::new();
+static CLOCK: AtomicU64 = AtomicU64::new(1);
+
+const MAGIC: u32 = 0x44424653;
+
+/// 每次调用前进一秒的测试时钟
+fn test_clock() -> VfsTimeSpec {
+    VfsTimeSpec::new(CLOCK.fetch_add(1, Ordering::SeqCst), 0)
+}
+
+/// 所有测试共享同一个全局数据库, 只挂载一次
+fn root() -> Arc<dyn VfsInode> {
+    ROOT.call_once(|| {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-test.db").unwrap();
+        crate::init_dbfs(db);
+
+        // 其他测试模块可能已经初始化了全局数据库, 格式化作用于 clone_db()
+        crate::fs_common::dbfs_common_format(4096, MAGIC).expect("Format failed");
+
+        let fs_type = Arc::new(DbfsFsType::new("rvfs2-test.db".to_string()).with_clock(test_clock));
+        let root_dentry = fs_type.mount(0, "/", None, &[]).expect("Mount failed");
+        root_dentry.inode().expect("Get root inode failed")
+    })
+    .clone()
+}
+
+/// 以指定的挂载选项再次挂载同一个数据库
+fn mount_with(flags: MountFlags) -> Arc<dyn VfsInode> {
+    root();
+    let fs_type = Arc::new(DbfsFsType::new("rvfs2-test.db".to_string()).with_clock(test_clock));
+    let root_dentry = fs_type
+        .mount(flags.bits(), "/", None, &[])
+        .expect("Mount failed");
+    root_dentry.inode().expect("Get root inode failed")
+}
+
+/// 挂载一个只有 `blocks` 个数据块的小设备
+fn mount_small_device(blocks: u64) -> Arc<dyn VfsInode> {
+    root();
+    let fs_type = Arc::new(
+        DbfsFsType::new("rvfs2-test.db".to_string())
+            .with_clock(test_clock)
+            .with_block_count(blocks),
+    );
+    let root_dentry = fs_type.mount(0, "/", None, &[]).expect("Mount failed");
+    root_dentry.inode().expect("Get root inode failed")
+}
+
+/// 打开并格式化一个测试私有的数据库, 不影响全局数据库
+fn private_db(path: &str) -> DB {
+    private_db_with_block_size(path, 4096)
+}
+
+/// 同 [`private_db`], 以 `blk_size` 格式化
+fn private_db_with_block_size(path: &str, blk_size: u32) -> DB {
+    let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), path).unwrap();
+    crate::fs_common::dbfs_common_format_in(&db, blk_size, MAGIC).expect("Format failed");
+    db
+}
+
+/// 挂载 `fs_type`, 返回根目录
+fn mount_root(fs_type: &Arc<DbfsFsType>) -> Arc<dyn VfsInode> {
+    fs_type
+        .mount(0, "/", None, &[])
+        .expect("Mount failed")
+        .inode()
+        .expect("Get root inode failed")
+}
+
+/// 以默认选项和测试时钟挂载一个私有数据库
+fn mount_private(db: &DB, path: &str) -> (Arc<DbfsFsType>, Arc<dyn VfsInode>) {
+    let fs_type =
+        Arc::new(DbfsFsType::new_with_db(db.clone(), path.to_string()).with_clock(test_clock));
+    let root = mount_root(&fs_type);
+    (fs_type, root)
+}
+
+/// 在 `dir` 下创建权限为 0o644 的普通文件
+fn create_file(dir: &Arc<dyn VfsInode>, name: &str) -> Arc<dyn VfsInode> {
+    dir.create(
+        name,
+        VfsNodeType::File,
+        VfsNodePerm::from_bits_truncate(0o644),
+        None,
+    )
+    .expect("Create file failed")
+}
+
+/// 在 `dir` 下创建权限为 0o755 的目录
+fn create_dir(dir: &Arc<dyn VfsInode>, name: &str) -> Arc<dyn VfsInode> {
+    dir.create(
+        name,
+        VfsNodeType::Dir,
+        VfsNodePerm::from_bits_truncate(0o755),
+        None,
+    )
+    .expect("Create dir failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symlink_create_and_readlink() {
+        let root = root();
+
+        let link = root
+            .symlink("sym_link", "/target/path")
+            .expect("Symlink failed");
+        assert_eq!(link.inode_type(), VfsNodeType::SymLink);
+
+        let mut buf = [0u8; 64];
+        let n = link.readlink(&mut buf).expect("Readlink failed");
+        assert_eq!(&buf[..n], b"/target/path");
+
+        let found = root.lookup("sym_link").expect("Lookup symlink failed");
+        let n = found
+            .readlink(&mut buf)
+            .expect("Readlink after lookup failed");
+        assert_eq!(&buf[..n], b"/target/path");
+
+        // 符号链接只能通过 symlink() 创建
+        assert!(root
+            .create(
+                "sym_via_create",
+                VfsNodeType::SymLink,
+                VfsNodePerm::from_bits_truncate(0o777),
+                None,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_mkdir_nlink() {
+        let root = root();
+        let parent = root
+            .create(
+                "nlink_parent",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create parent failed");
+        let before = parent.get_attr().expect("Get parent attr failed").st_nlink;
+
+        let child = parent
+            .create(
+                "child",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create child failed");
+        assert_eq!(child.get_attr().expect("Get child attr failed").st_nlink, 2);
+        assert_eq!(
+            parent.get_attr().expect("Get parent attr failed").st_nlink,
+            before + 1
+        );
+
+        parent.rmdir("child").expect("Rmdir failed");
+        assert_eq!(
+            parent.get_attr().expect("Get parent attr failed").st_nlink,
+            before
+        );
+    }
+
+    #[test]
+    fn test_read_updates_atime() {
+        let root = root();
+        let file = create_file(&root, "atime_default");
+        file.write_at(0, b"atime").expect("Write failed");
+
+        let before = file.get_attr().expect("Get attr failed").st_atime;
+        file.read_at(0, &mut [0u8; 5]).expect("Read failed");
+        let after = file.get_attr().expect("Get attr failed").st_atime;
+        assert!(after.sec > before.sec);
+    }
+
+    #[test]
+    fn test_noatime_keeps_atime() {
+        let root = mount_with(MountFlags::NOATIME);
+        let file = create_file(&root, "atime_noatime");
+        file.write_at(0, b"atime").expect("Write failed");
+
+        let before = file.get_attr().expect("Get attr failed").st_atime;
+        file.read_at(0, &mut [0u8; 5]).expect("Read failed");
+        let after = file.get_attr().expect("Get attr failed").st_atime;
+        assert_eq!(after.sec, before.sec);
+    }
+
+    #[test]
+    fn test_relatime_updates_once() {
+        let root = mount_with(MountFlags::RELATIME);
+        let file = create_file(&root, "atime_relatime");
+        file.write_at(0, b"atime").expect("Write failed");
+
+        // atime 不晚于 mtime, 第一次读取需要更新
+        let before = file.get_attr().expect("Get attr failed").st_atime;
+        file.read_at(0, &mut [0u8; 5]).expect("Read failed");
+        let first = file.get_attr().expect("Get attr failed").st_atime;
+        assert!(first.sec > before.sec);
+
+        // atime 已经晚于 mtime, 且未超过阈值, 不再更新
+        file.read_at(0, &mut [0u8; 5]).expect("Read failed");
+        let second = file.get_attr().expect("Get attr failed").st_atime;
+        assert_eq!(second.sec, first.sec);
+    }
+
+    #[test]
+    fn test_max_name_length() {
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+
+        let ok_name = "a".repeat(255);
+        root.create(&ok_name, VfsNodeType::File, perm, None)
+            .expect("Create with 255-byte name failed");
+
+        // 超过 255 字节的名字在任何创建路径上都会被拒绝
+        let long_name = "b".repeat(256);
+        assert_eq!(
+            root.create(&long_name, VfsNodeType::File, perm, None).err(),
+            Some(VfsError::NameTooLong)
+        );
+        assert_eq!(
+            root.symlink(&long_name, "/target").err(),
+            Some(VfsError::NameTooLong)
+        );
+        let src = root.lookup(&ok_name).expect("Lookup failed");
+        assert_eq!(
+            root.link(&long_name, src).err(),
+            Some(VfsError::NameTooLong)
+        );
+        assert_eq!(
+            root.rename_to(&ok_name, root.clone(), &long_name, VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::NameTooLong)
+        );
+
+        // 空名字和包含 '/' 的名字同样无效
+        assert_eq!(
+            root.create("", VfsNodeType::File, perm, None).err(),
+            Some(VfsError::Invalid)
+        );
+        assert_eq!(
+            root.create("a/b", VfsNodeType::File, perm, None).err(),
+            Some(VfsError::Invalid)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fuse")]
+    fn test_concurrent_readers() {
+        use crate::rvfs2::common as dbfs_common;
+
+        let root = root();
+        let file = create_file(&root, "concurrent_read");
+        file.write_at(0, b"concurrent").expect("Write failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+
+        // 只读事务互不阻塞, 所有读线程都应当顺利完成
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let mut buf = [0u8; 10];
+                        let n = dbfs_common::dbfs_read(&crate::clone_db(), ino, &mut buf, 0, 4096)
+                            .expect("Read failed");
+                        assert_eq!(&buf[..n], b"concurrent");
+                        let attr = dbfs_common::dbfs_get_attr(&crate::clone_db(), ino, 4096)
+                            .expect("Get attr failed");
+                        assert_eq!(attr.ino, ino);
+                        assert_eq!(
+                            dbfs_common::dbfs_lookup(&crate::clone_db(), 1, "concurrent_read")
+                                .ok()
+                                .flatten(),
+                            Some(ino)
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("Reader thread panicked");
+        }
+    }
+
+    #[test]
+    fn test_short_write_on_full_device() {
+        let root = mount_small_device(4);
+        let file = create_file(&root, "full_device");
+
+        // 设备只剩 4 个块, 6 个块的写入只能写入前 4 个块
+        let data: Vec<u8> = (0..6 * 4096).map(|i| (i % 251) as u8).collect();
+        let n = file.write_at(0, &data).expect("Write failed");
+        assert_eq!(n, 4 * 4096);
+
+        // 设备已满, 剩余部分重试时返回 NoSpace
+        assert_eq!(
+            file.write_at(n as u64, &data[n..]).err(),
+            Some(VfsError::NoSpace)
+        );
+
+        // 覆盖已有的块不需要新空间
+        assert_eq!(
+            file.write_at(0, &data[..4096]).expect("Overwrite failed"),
+            4096
+        );
+
+        let mut buf = vec![0u8; 6 * 4096];
+        let read = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(read, n);
+        assert_eq!(&buf[..n], &data[..n]);
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, n as u64);
+
+        // 截断释放块后可以继续写入
+        file.truncate(4096).expect("Truncate failed");
+        assert_eq!(
+            file.write_at(4096, &data[4096..2 * 4096])
+                .expect("Write failed"),
+            4096
+        );
+    }
+
+    #[test]
+    fn test_concurrent_append() {
+        use crate::rvfs2::DbfsInode;
+
+        let root = root();
+        let file = create_file(&root, "concurrent_append");
+        let inode = file
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed");
+
+        // 两个线程同时通过 Inode 追加, 每次追加一个由线程号填充的 100 字节记录
+        let handles: Vec<_> = (0..2u8)
+            .map(|id| {
+                let inode = inode.clone();
+                std::thread::spawn(move || {
+                    let record = [b'a' + id; 100];
+                    for _ in 0..50 {
+                        let n = inode.append(&record).expect("Append failed");
+                        assert_eq!(n, record.len());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("Appender thread panicked");
+        }
+
+        // 没有字节丢失, 每条记录都完整且不被覆盖
+        let mut buf = vec![0u8; 2 * 50 * 100];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(n, buf.len());
+        for record in buf.chunks(100) {
+            assert!(record.iter().all(|&b| b == record[0]));
+        }
+        assert_eq!(buf.iter().filter(|&&b| b == b'a').count(), 50 * 100);
+        assert_eq!(buf.iter().filter(|&&b| b == b'b').count(), 50 * 100);
+    }
+
+    #[test]
+    fn test_chmod_keeps_file_type() {
+        let root = root();
+        let file = create_file(&root, "chmod_file");
+
+        let stat = file.get_attr().expect("Get attr failed");
+        file.set_attr(InodeAttr {
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            size: stat.st_size,
+            mode: 0o600,
+            atime: stat.st_atime,
+            mtime: stat.st_mtime,
+            ctime: stat.st_ctime,
+        })
+        .expect("Set attr failed");
+
+        // node_perm 与数据库中的 mode 一致, 且文件类型位没有被覆盖
+        assert_eq!(file.node_perm(), VfsNodePerm::from_bits_truncate(0o600));
+        let stat = file.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_mode & 0o777, 0o600);
+        assert_eq!(stat.st_mode & 0o170000, 0o100000);
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        use crate::{common::DbfsError, fs_common::resolve_path};
+
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+        let a = root
+            .create("resolve_a", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        let b = a
+            .create("b", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        let file = b
+            .create("file", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        let file_ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        let b_ino = b.get_attr().expect("Get attr failed").st_ino as usize;
+
+        // 普通的嵌套路径, 以及 . 和 ..
+        assert!(matches!(resolve_path(1, "/resolve_a/b/file"), Ok(ino) if ino == file_ino));
+        assert!(matches!(resolve_path(1, "resolve_a/./b/../b/file"), Ok(ino) if ino == file_ino));
+        assert!(matches!(
+            resolve_path(1, "/resolve_a/b/file/x"),
+            Err(DbfsError::NotDir)
+        ));
+
+        // 指向文件的符号链接: 相对目标和绝对目标
+        a.symlink("rel_link", "b/file").expect("Symlink failed");
+        root.symlink("resolve_abs", "/resolve_a/b")
+            .expect("Symlink failed");
+        assert!(matches!(resolve_path(1, "/resolve_a/rel_link"), Ok(ino) if ino == file_ino));
+        assert!(matches!(resolve_path(1, "/resolve_abs"), Ok(ino) if ino == b_ino));
+        assert!(matches!(resolve_path(1, "/resolve_abs/file"), Ok(ino) if ino == file_ino));
+
+        // 自引用的符号链接返回 Loop 而不是死循环
+        root.symlink("resolve_loop1", "resolve_loop2")
+            .expect("Symlink failed");
+        root.symlink("resolve_loop2", "resolve_loop1")
+            .expect("Symlink failed");
+        assert!(matches!(
+            resolve_path(1, "/resolve_loop1"),
+            Err(DbfsError::Loop)
+        ));
+    }
+
+    #[test]
+    fn test_runtime_block_size() {
+        use crate::rvfs2::common as dbfs_common;
+
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+
+        // 两个数据库分别格式化为 1K 和 4K, 挂载时各自读取块大小
+        for blk_size in [1024u32, 4096] {
+            let path = alloc::format!("rvfs2-block-size-{}.db", blk_size);
+            let db = private_db_with_block_size(&path, blk_size);
+            let (_fs_type, root) = mount_private(&db, &path);
+            let file = create_file(&root, "block_size");
+
+            let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+            let n =
+                dbfs_common::dbfs_write(&db, ino, &data, 0, blk_size as u64).expect("Write failed");
+            assert_eq!(n, data.len());
+
+            // 数据按格式化时的块大小切分, 挂载读取时使用同样的块大小
+            let expected = (data.len() as u64).div_ceil(blk_size as u64);
+            assert_eq!(
+                dbfs_common::dbfs_block_count(&db, ino).expect("Block count failed"),
+                expected
+            );
+
+            let mut buf = vec![0u8; data.len()];
+            let mut read = 0;
+            while read < buf.len() {
+                let n = file
+                    .read_at(read as u64, &mut buf[read..])
+                    .expect("Read failed");
+                assert!(n > 0);
+                read += n;
+            }
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[test]
+    fn test_commit_rolls_back_failed_batch() {
+        use crate::{operation::TransactionOperation, transaction::TransactionManager};
+
+        let root = root();
+        let file = create_file(&root, "undo_file");
+        file.write_at(0, b"original").expect("Write failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+
+        // 第三个操作删除不存在的文件而失败, 前两个操作必须被撤销
+        let tm = TransactionManager::new();
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Write {
+            ino,
+            offset: 0,
+            data: b"clobbered data".to_vec(),
+        });
+        txn.record(TransactionOperation::Create {
+            parent_ino: 1,
+            name: "undo_created".to_string(),
+            uid: 0,
+            gid: 0,
+            perm: 0o100644,
+            dev: None,
+        });
+        txn.record(TransactionOperation::Delete {
+            parent_ino: 1,
+            name: "undo_missing".to_string(),
+        });
+        assert!(tm.commit(txn).is_err());
+
+        let mut buf = [0u8; 32];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"original");
+        assert!(root.lookup("undo_created").is_err());
+        // 回滚的事务不会留在 WAL 中等待重放
+        assert_eq!(tm.pending_ops(), 0);
+    }
+
+    #[test]
+    fn test_read_only_mount() {
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let file = create_file(&root, "ro_file");
+        file.write_at(0, b"read only").expect("Write failed");
+
+        let ro_root = mount_with(MountFlags::RDONLY);
+        let ro_file = ro_root.lookup("ro_file").expect("Lookup failed");
+
+        // 读操作正常
+        let mut buf = [0u8; 16];
+        let n = ro_file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"read only");
+
+        // 所有修改操作都被拒绝
+        let denied = Some(VfsError::PermissionDenied);
+        assert_eq!(ro_file.write_at(0, b"x").err(), denied);
+        assert_eq!(ro_file.truncate(0).err(), denied);
+        assert_eq!(
+            ro_root
+                .create("ro_new", VfsNodeType::File, perm, None)
+                .err(),
+            denied
+        );
+        assert_eq!(
+            ro_root.create("ro_dir", VfsNodeType::Dir, perm, None).err(),
+            denied
+        );
+        assert_eq!(ro_root.unlink("ro_file").err(), denied);
+        assert_eq!(ro_root.rmdir("ro_file").err(), denied);
+        assert_eq!(ro_root.symlink("ro_link", "ro_file").err(), denied);
+        assert_eq!(
+            ro_root
+                .rename_to(
+                    "ro_file",
+                    ro_root.clone(),
+                    "ro_moved",
+                    VfsRenameFlag::empty()
+                )
+                .err(),
+            denied
+        );
+        let stat = ro_file.get_attr().expect("Get attr failed");
+        let attr = InodeAttr {
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            size: stat.st_size,
+            mode: 0o600,
+            atime: stat.st_atime,
+            mtime: stat.st_mtime,
+            ctime: stat.st_ctime,
+        };
+        assert_eq!(ro_file.set_attr(attr).err(), denied);
+
+        // 数据没有被修改
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"read only");
+    }
+
+    #[test]
+    fn test_char_device_rdev() {
+        use crate::common::{DbfsFileType, DbfsPermission};
+        use crate::rvfs2::common as dbfs_common;
+
+        let root = root();
+        let rdev: u32 = (4 << 8) | 64; // major 4, minor 64
+
+        dbfs_common::dbfs_create(
+            &crate::clone_db(),
+            1,
+            "chr_dev",
+            DbfsFileType::CharDevice,
+            0,
+            0,
+            DbfsPermission::from_bits_truncate(0o620),
+            Some(rdev),
+        )
+        .expect("Create char device failed");
+
+        // 设备号写入了 Inode 所在的桶
+        let attr = dbfs_common::dbfs_get_attr(
+            &crate::clone_db(),
+            dbfs_common::dbfs_lookup(&crate::clone_db(), 1, "chr_dev")
+                .unwrap()
+                .expect("Lookup failed"),
+            4096,
+        )
+        .expect("Get attr failed");
+        assert!(matches!(attr.kind, DbfsFileType::CharDevice));
+        assert_eq!(attr.rdev, rdev);
+
+        let dev = root.lookup("chr_dev").expect("Lookup failed");
+        assert_eq!(dev.inode_type(), VfsNodeType::CharDevice);
+        let stat = dev.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_rdev, rdev as u64);
+        assert_eq!(stat.st_mode & 0o170000, 0o020000);
+        assert_eq!(stat.st_mode & 0o777, 0o620);
+
+        // 超出 32 位的设备号不会被截断, 而是拒绝创建
+        let perm = VfsNodePerm::from_bits_truncate(0o620);
+        let wide = root.create(
+            "chr_wide",
+            VfsNodeType::CharDevice,
+            perm,
+            Some(1 << 32 | rdev as u64),
+        );
+        assert_eq!(wide.err(), Some(VfsError::Invalid));
+        assert_eq!(root.lookup("chr_wide").err(), Some(VfsError::NoEntry));
+
+        // 普通文件没有设备号
+        let file = create_file(&root, "chr_plain");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_rdev, 0);
+    }
+
+    #[test]
+    fn test_fifo_and_socket_nodes() {
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+
+        for (name, ty) in [
+            ("node_fifo", VfsNodeType::Fifo),
+            ("node_sock", VfsNodeType::Socket),
+        ] {
+            let node = root
+                .create(name, ty, perm, None)
+                .expect("Create node failed");
+            assert_eq!(node.inode_type(), ty);
+
+            // 只有元数据, 没有数据
+            let stat = node.get_attr().expect("Get attr failed");
+            assert_eq!(stat.st_size, 0);
+            assert_eq!(stat.st_rdev, 0);
+
+            let found = root.lookup(name).expect("Lookup failed");
+            assert_eq!(found.inode_type(), ty);
+            assert_eq!(
+                found.get_attr().expect("Get attr failed").st_ino,
+                stat.st_ino
+            );
+        }
+    }
+
+    #[test]
+    fn test_sparse_write() {
+        let root = root();
+        let file = create_file(&root, "sparse_file");
+
+        assert_eq!(file.write_at(1_000_000, b"z").expect("Write failed"), 1);
+        let stat = file.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_size, 1_000_001);
+
+        // 空洞读出零
+        let mut buf = [0xffu8; 1];
+        assert_eq!(file.read_at(500_000, &mut buf).expect("Read failed"), 1);
+        assert_eq!(buf, [0]);
+
+        // 跨过空洞读到写入的字节, 到文件末尾为止
+        let mut buf = [0xffu8; 16];
+        assert_eq!(file.read_at(999_998, &mut buf).expect("Read failed"), 3);
+        assert_eq!(&buf[..3], b"\0\0z");
+
+        // 中间的块没有被物化
+        file.fsync(false).expect("Fsync failed");
+        let db = crate::clone_db();
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.get_bucket((stat.st_ino as usize).to_be_bytes()).unwrap();
+        let blocks = bucket
+            .cursor()
+            .filter(|data| data.key().starts_with(b"data_"))
+            .count();
+        assert_eq!(blocks, 1);
+    }
+
+    #[test]
+    fn test_write_updates_size() {
+        use crate::rvfs2::common as dbfs_common;
+
+        let root = root();
+        let file = create_file(&root, "size_order");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        // 使用挂载时读到的块大小
+        let blk_size = root
+            .get_super_block()
+            .expect("Get super block failed")
+            .stat_fs()
+            .expect("Statfs failed")
+            .f_bsize as u64;
+
+        // 非零偏移写入后立即可以看到新的大小
+        assert_eq!(
+            dbfs_common::dbfs_write(&crate::clone_db(), ino, b"abc", 100, blk_size)
+                .expect("Write failed"),
+            3
+        );
+        assert_eq!(
+            dbfs_common::dbfs_get_attr(&crate::clone_db(), ino, blk_size)
+                .expect("Get attr failed")
+                .size,
+            103
+        );
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 103);
+
+        // 写在已有数据之内不会缩小文件
+        dbfs_common::dbfs_write(&crate::clone_db(), ino, b"x", 10, blk_size).expect("Write failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 103);
+    }
+
+    #[test]
+    fn test_symlink_size() {
+        let root = root();
+        let target = "/some/where/else.txt";
+        let link = root.symlink("sym_size", target).expect("Symlink failed");
+
+        // lstat 报告的大小是目标路径的长度
+        assert_eq!(
+            link.get_attr().expect("Get attr failed").st_size,
+            target.len() as u64
+        );
+        let found = root.lookup("sym_size").expect("Lookup failed");
+        assert_eq!(
+            found.get_attr().expect("Get attr failed").st_size,
+            target.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_readdir_stable_under_insert() {
+        let root = root();
+        let dir = create_dir(&root, "readdir_dir");
+        for name in ["f1", "f3", "f5", "f7"] {
+            create_file(&dir, name);
+        }
+
+        let mut seen = Vec::new();
+        let mut index = 0;
+        while let Some(entry) = dir.readdir(index).expect("Readdir failed") {
+            seen.push(entry.name);
+            index += 1;
+            // 遍历途中在游标之前和之后各插入一个目录项
+            if index == 2 {
+                create_file(&dir, "f0");
+                create_file(&dir, "f6");
+            }
+        }
+
+        // 已有的目录项不丢失也不重复; 游标之前插入的不会出现, 之后插入的会出现
+        assert_eq!(seen, vec!["f1", "f3", "f5", "f6", "f7"]);
+
+        // 重新从头遍历能看到全部目录项
+        let mut all = Vec::new();
+        let mut index = 0;
+        while let Some(entry) = dir.readdir(index).expect("Readdir failed") {
+            all.push(entry.name);
+            index += 1;
+        }
+        assert_eq!(all, vec!["f0", "f1", "f3", "f5", "f6", "f7"]);
+    }
+
+    #[test]
+    fn test_mkdir_dot_entries() {
+        use crate::rvfs2::DbfsInode;
+
+        let root = root();
+        let parent = root
+            .create(
+                "mkdir_parent",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o755),
+                None,
+            )
+            .expect("Create parent failed");
+        let parent_ino = parent.get_attr().expect("Get attr failed").st_ino;
+
+        let dbfs_parent = parent
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed");
+        let dir = dbfs_parent
+            .mkdir("child", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+        let stat = dir.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_nlink, 2);
+        assert_eq!(stat.st_mode & 0o170000, 0o040000);
+
+        // "." 指向自身, ".." 指向父目录
+        let dot = dir.lookup(".").expect("Lookup . failed");
+        assert_eq!(dot.get_attr().expect("Get attr failed").st_ino, stat.st_ino);
+        let dotdot = dir.lookup("..").expect("Lookup .. failed");
+        assert_eq!(
+            dotdot.get_attr().expect("Get attr failed").st_ino,
+            parent_ino
+        );
+
+        assert_eq!(
+            dbfs_parent
+                .mkdir("child", VfsNodePerm::from_bits_truncate(0o755))
+                .err(),
+            Some(VfsError::EExist)
+        );
+    }
+
+    #[test]
+    fn test_precise_error_codes() {
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        root.create("errno_file", VfsNodeType::File, perm, None)
+            .expect("Create failed");
+
+        // 已存在的文件报告 EExist, 而不是笼统的 IoError
+        assert_eq!(
+            root.create("errno_file", VfsNodeType::File, perm, None)
+                .err(),
+            Some(VfsError::EExist)
+        );
+        assert_eq!(root.lookup("errno_missing").err(), Some(VfsError::NoEntry));
+        assert_eq!(
+            VfsError::from(crate::common::DbfsError::NoSpace),
+            VfsError::NoSpace
+        );
+        assert_eq!(
+            VfsError::from(crate::common::DbfsError::NotEmpty),
+            VfsError::NotEmpty
+        );
+        assert_eq!(
+            VfsError::from(crate::common::DbfsError::NoData),
+            VfsError::NoEntry
+        );
+    }
+
+    #[test]
+    fn test_unlink_while_open() {
+        let root = root();
+        let file = create_file(&root, "open_unlinked");
+        file.write_at(0, b"still readable").expect("Write failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+
+        root.unlink("open_unlinked").expect("Unlink failed");
+        assert_eq!(root.lookup("open_unlinked").err(), Some(VfsError::NoEntry));
+
+        // 打开的句柄仍然可以读取数据
+        let mut buf = [0u8; 32];
+        let n = file
+            .read_at(0, &mut buf)
+            .expect("Read through open handle failed");
+        assert_eq!(&buf[..n], b"still readable");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_nlink, 0);
+
+        // 最后一个句柄释放后 Inode 才被删除
+        let db = crate::clone_db();
+        assert!(db.tx(false).unwrap().get_bucket(ino.to_be_bytes()).is_ok());
+        drop(file);
+        assert!(db.tx(false).unwrap().get_bucket(ino.to_be_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_nlink_matches_database() {
+        let root = root();
+        let file = create_file(&root, "nlink_src");
+        let handle = root.link("nlink_dst", file.clone()).expect("Link failed");
+
+        // 另一个挂载没有缓存, 直接从数据库加载
+        let fresh = mount_with(MountFlags::empty())
+            .lookup("nlink_src")
+            .expect("Lookup failed");
+        let nlink = fresh.get_attr().expect("Get attr failed").st_nlink;
+        assert_eq!(nlink, 2);
+        assert_eq!(handle.get_attr().expect("Get attr failed").st_nlink, nlink);
+
+        root.unlink("nlink_dst").expect("Unlink failed");
+        let fresh = mount_with(MountFlags::empty())
+            .lookup("nlink_src")
+            .expect("Lookup failed");
+        assert_eq!(fresh.get_attr().expect("Get attr failed").st_nlink, 1);
+        assert_eq!(file.get_attr().expect("Get attr failed").st_nlink, 1);
+
+        file.write_at(0, b"size").expect("Write failed");
+        assert_eq!(fresh.get_attr().expect("Get attr failed").st_size, 4);
+    }
+
+    #[test]
+    fn test_write_back_cache() {
+        let root = root();
+        let file = create_file(&root, "cached");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        file.write_at(0, b"write back").expect("Write failed");
+
+        // 提交的数据块在日志记录清除之前已写回数据库, 缓存中仍保留一份
+        let db = crate::clone_db();
+        let stored = || {
+            let tx = db.tx(false).unwrap();
+            let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
+            bucket.get_kv("data_0").map(|kv| kv.value().to_vec())
+        };
+        assert_eq!(stored().as_deref(), Some(&b"write back"[..]));
+        assert!(crate::rvfs2::cache::dirty_block_numbers(&db, ino).is_empty());
+
+        // 读取命中缓存
+        let hits = crate::rvfs2::cache_stats().hits;
+        let mut buf = [0u8; 16];
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"write back");
+        assert!(crate::rvfs2::cache_stats().hits > hits);
+
+        // fsync 后写回数据库
+        file.fsync(false).expect("Fsync failed");
+        assert_eq!(stored().as_deref(), Some(&b"write back"[..]));
+    }
+
+    #[test]
+    fn test_dir_read_write_is_dir() {
+        let root = root();
+        let dir = create_dir(&root, "rw_dir");
+
+        let mut buf = [0u8; 8];
+        assert_eq!(dir.read_at(0, &mut buf).err(), Some(VfsError::IsDir));
+        assert_eq!(dir.write_at(0, b"data").err(), Some(VfsError::IsDir));
+        assert_eq!(dir.truncate(0).err(), Some(VfsError::IsDir));
+    }
+
+    #[test]
+    fn test_mount_independent_dbs() {
+        use crate::rvfs2::DbfsInode;
+
+        // 两个互不相关的内存数据库, 都不经过全局的 init_dbfs
+        let mount = |path: &str| {
+            mount_private(&private_db(path), path)
+                .1
+                .downcast_arc::<DbfsInode>()
+                .ok()
+                .expect("Downcast failed")
+        };
+        let a = mount("rvfs2-iso-a.db");
+        let b = mount("rvfs2-iso-b.db");
+
+        // 只在 a 中创建的目录在 b 中不可见
+        a.mkdir("iso_dir", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+        assert!(a.lookup("iso_dir").is_ok());
+        assert_eq!(b.lookup("iso_dir").err(), Some(VfsError::NoEntry));
+
+        // 同名的条目各自保存自己的内容
+        a.symlink("iso_link", "/in/a").expect("Symlink failed");
+        b.symlink("iso_link", "/in/b").expect("Symlink failed");
+        let mut buf = [0u8; 16];
+        let n = a
+            .lookup("iso_link")
+            .unwrap()
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"/in/a");
+        let n = b
+            .lookup("iso_link")
+            .unwrap()
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"/in/b");
+    }
+
+    #[test]
+    fn test_get_attr_blocks() {
+        let root = root();
+        let file = create_file(&root, "blocks_file");
+
+        let stat = file.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_blocks, 0);
+        assert_eq!(stat.st_blksize, 4096);
+
+        // 10KB 占用 3 个 4K 块, st_blocks 以 512 字节为单位; 只在页缓存中的块也计入
+        let data = alloc::vec![0x5au8; 10 * 1024];
+        assert_eq!(file.write_at(0, &data).expect("Write failed"), data.len());
+        let stat = file.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_size, 10 * 1024);
+        assert_eq!(stat.st_blocks, 3 * 4096 / 512);
+
+        file.fsync(false).expect("Fsync failed");
+        assert_eq!(
+            file.get_attr().expect("Get attr failed").st_blocks,
+            3 * 4096 / 512
+        );
+
+        // 空洞不占用块
+        file.write_at(1 << 20, b"z").expect("Write failed");
+        assert_eq!(
+            file.get_attr().expect("Get attr failed").st_blocks,
+            4 * 4096 / 512
+        );
+    }
+
+    #[test]
+    fn test_unmount_flushes_cache() {
+        let db = private_db("rvfs2-umount.db");
+        let mount = || mount_private(&db, "rvfs2-umount.db");
+
+        let (fs_type, root) = mount();
+        let file = create_file(&root, "kept");
+        file.write_at(0, b"written back on unmount")
+            .expect("Write failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        fs_type
+            .kill_sb(root.get_super_block().expect("Get super block failed"))
+            .expect("Unmount failed");
+
+        // 脏页已写回数据库, 缓存中不再保留这个数据库的页
+        let tx = db.tx(false).unwrap();
+        let bucket = tx.get_bucket(ino.to_be_bytes()).unwrap();
+        assert_eq!(
+            bucket
+                .get_kv("data_0")
+                .expect("Block not written back")
+                .value()[..23],
+            *b"written back on unmount"
+        );
+        let super_blk = tx.get_bucket("super_blk").unwrap();
+        assert!(crate::usize!(super_blk.get_kv("continue_number").unwrap().value()) > ino);
+        drop(tx);
+
+        // 重新挂载后数据仍在
+        let (_fs_type, root) = mount();
+        let mut buf = [0u8; 32];
+        let n = root
+            .lookup("kept")
+            .expect("Lookup failed")
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"written back on unmount");
+    }
+
+    #[test]
+    fn test_update_time_variants() {
+        use vfscore::utils::VfsTime;
+
+        let root = root();
+        let file = create_file(&root, "times");
+
+        // atime 和 mtime 各自独立设置, ctime 取调用者给出的当前时间
+        file.update_time(
+            VfsTime::AccessTime(VfsTimeSpec::new(1_000, 1)),
+            VfsTimeSpec::new(5_000, 0),
+        )
+        .expect("Update atime failed");
+        file.update_time(
+            VfsTime::ModifiedTime(VfsTimeSpec::new(2_000, 2)),
+            VfsTimeSpec::new(6_000, 0),
+        )
+        .expect("Update mtime failed");
+
+        let stat = file.get_attr().expect("Get attr failed");
+        assert_eq!((stat.st_atime.sec, stat.st_atime.nsec), (1_000, 1));
+        assert_eq!((stat.st_mtime.sec, stat.st_mtime.nsec), (2_000, 2));
+        assert_eq!(stat.st_ctime.sec, 6_000);
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        use crate::rvfs2::cache_stats;
+
+        let db = private_db("rvfs2-readahead.db");
+        let mount = |read_ahead: u64| {
+            let fs_type = Arc::new(
+                DbfsFsType::new_with_db(db.clone(), "rvfs2-readahead.db".to_string())
+                    .with_clock(test_clock)
+                    .with_read_ahead(read_ahead),
+            );
+            let root = mount_root(&fs_type);
+            (fs_type, root)
+        };
+
+        // 写入 4 个块后卸载, 重新挂载时缓存是空的
+        let (fs_type, root) = mount(0);
+        let file = create_file(&root, "seq");
+        file.write_at(0, &[7u8; 4 * 4096]).expect("Write failed");
+        fs_type
+            .kill_sb(root.get_super_block().expect("Get super block failed"))
+            .expect("Unmount failed");
+
+        // 1. 默认不预读
+        let (fs_type, root) = mount(0);
+        let file = root.lookup("seq").expect("Lookup failed");
+        let mut buf = [0u8; 4096];
+        let before = cache_stats().prefetched;
+        file.read_at(0, &mut buf).expect("Read failed");
+        file.read_at(4096, &mut buf).expect("Read failed");
+        assert_eq!(cache_stats().prefetched, before);
+        fs_type
+            .kill_sb(root.get_super_block().expect("Get super block failed"))
+            .expect("Unmount failed");
+
+        // 2. 顺序读到块边界时预读之后的 2 个块
+        let (_fs_type, root) = mount(2);
+        let file = root.lookup("seq").expect("Lookup failed");
+        let before = cache_stats().prefetched;
+        file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(cache_stats().prefetched, before + 2);
+        // 块 2 已经在缓存中, 只需要加载块 3
+        file.read_at(4096, &mut buf).expect("Read failed");
+        assert_eq!(cache_stats().prefetched, before + 3);
+        assert!(buf.iter().all(|&b| b == 7));
+
+        // 3. 非顺序的读取不触发预读
+        file.read_at(0, &mut buf).expect("Read failed");
+        file.read_at(100, &mut buf[..3996]).expect("Read failed");
+        assert_eq!(cache_stats().prefetched, before + 3);
+    }
+
+    #[test]
+    fn test_flush_reports_writeback_error() {
+        use crate::rvfs2::cache::FAIL_WRITEBACK;
+
+        let db = private_db("rvfs2-flush.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-flush.db");
+        let file = create_file(&root, "dirty");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+
+        // 1. 回写失败时写入和 flush 都返回错误, 页面仍然是脏的
+        *FAIL_WRITEBACK.lock() = Some(ino);
+        let written = file.write_at(0, &[3u8; 4096]);
+        let result = file.flush();
+        *FAIL_WRITEBACK.lock() = None;
+        assert_eq!(written.err(), Some(VfsError::IoError));
+        assert!(result.is_err());
+
+        // 2. 设备恢复后 flush 把数据写入数据库
+        file.flush().expect("Flush failed");
+        let tx = db.tx(false).expect("Tx failed");
+        let bucket = tx.get_bucket(ino.to_be_bytes()).expect("Get bucket failed");
+        let data = bucket.get_kv("data_0").expect("Block not written back");
+        assert_eq!(data.value(), &[3u8; 4096][..]);
+        drop(tx);
+
+        // 3. 没有脏页时直接返回
+        file.flush().expect("Flush failed");
+    }
+
+    #[test]
+    fn test_poll_readiness() {
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let both = VfsPollEvents::IN | VfsPollEvents::OUT;
+
+        // 1. 普通文件和目录总是可读可写
+        let root = root();
+        let file = create_file(&root, "poll_file");
+        assert_eq!(file.poll(both).expect("Poll failed"), both);
+        assert_eq!(
+            file.poll(VfsPollEvents::IN).expect("Poll failed"),
+            VfsPollEvents::IN
+        );
+        assert_eq!(root.poll(both).expect("Poll failed"), both);
+
+        // 2. 没有注册处理函数时特殊节点什么也不就绪
+        let fifo = root
+            .create("poll_fifo", VfsNodeType::Fifo, perm, None)
+            .expect("Create fifo failed");
+        assert_eq!(
+            fifo.poll(both).expect("Poll failed"),
+            VfsPollEvents::empty()
+        );
+
+        // 3. 注册的处理函数决定特殊节点的就绪状态
+        fn readable_fifo(_ino: usize, ty: VfsNodeType, events: VfsPollEvents) -> VfsPollEvents {
+            match ty {
+                VfsNodeType::Fifo => events & VfsPollEvents::IN,
+                _ => VfsPollEvents::empty(),
+            }
+        }
+        let db = private_db("rvfs2-poll.db");
+        let fs_type = Arc::new(
+            DbfsFsType::new_with_db(db, "rvfs2-poll.db".to_string())
+                .with_clock(test_clock)
+                .with_poll_handler(readable_fifo),
+        );
+        let root = mount_root(&fs_type);
+        let fifo = root
+            .create("fifo", VfsNodeType::Fifo, perm, None)
+            .expect("Create fifo failed");
+        assert_eq!(fifo.poll(both).expect("Poll failed"), VfsPollEvents::IN);
+        let sock = root
+            .create("sock", VfsNodeType::Socket, perm, None)
+            .expect("Create socket failed");
+        assert_eq!(
+            sock.poll(both).expect("Poll failed"),
+            VfsPollEvents::empty()
+        );
+    }
+
+    #[test]
+    fn test_write_data_with_size() {
+        use crate::rvfs2::{cache::FAIL_WRITEBACK, common as dbfs_common};
+
+        let db = private_db("rvfs2-barrier.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-barrier.db");
+        let file = create_file(&root, "barrier");
+        file.write_at(0, &[1u8; 4096]).expect("Write failed");
+        file.flush().expect("Flush failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        let size = || {
+            dbfs_common::dbfs_get_attr(&db, ino, 4096)
+                .expect("Get attr failed")
+                .size
+        };
+
+        // 1. 数据写不进去时整个写入回滚: 丢掉缓存 (内存中的状态), 文件仍是原来的大小和内容
+        *FAIL_WRITEBACK.lock() = Some(ino);
+        let result = dbfs_common::dbfs_write(&db, ino, &[2u8; 4096], 4096, 4096);
+        *FAIL_WRITEBACK.lock() = None;
+        assert!(result.is_err());
+        crate::rvfs2::cache::forget(&db);
+        assert_eq!(size(), 4096);
+        let mut buf = [0u8; 8192];
+        assert_eq!(
+            dbfs_common::dbfs_read(&db, ino, &mut buf, 0, 4096).expect("Read failed"),
+            4096
+        );
+        assert!(buf[..4096].iter().all(|&b| b == 1));
+
+        // 2. 大小和新的块在同一次提交中, 丢掉缓存也能完整读出
+        assert_eq!(
+            dbfs_common::dbfs_write(&db, ino, &[2u8; 4096], 4096, 4096).expect("Write failed"),
+            4096
+        );
+        crate::rvfs2::cache::forget(&db);
+        assert_eq!(size(), 8192);
+        assert_eq!(
+            dbfs_common::dbfs_read(&db, ino, &mut buf, 0, 4096).expect("Read failed"),
+            8192
+        );
+        assert!(buf[4096..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_huge_offsets_rejected() {
+        use crate::{common::DbfsError, rvfs2::common as dbfs_common};
+
+        let root = root();
+        let file = create_file(&root, "huge_offset");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        let db = crate::clone_db();
+
+        assert!(matches!(
+            dbfs_common::dbfs_write(&db, ino, b"wrap", u64::MAX - 1, 4096),
+            Err(DbfsError::InvalidArgument)
+        ));
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            dbfs_common::dbfs_read(&db, ino, &mut buf, u64::MAX, 4096),
+            Err(DbfsError::InvalidArgument)
+        ));
+        assert!(matches!(
+            dbfs_common::dbfs_writable_len(&db, ino, u64::MAX, 2, u64::MAX, 4096),
+            Err(DbfsError::InvalidArgument)
+        ));
+        assert!(file.read_at(u64::MAX - 3, &mut buf).is_err());
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 0);
+    }
+
+    #[test]
+    fn test_uninitialized_db() {
+        use crate::common::DbfsError;
+
+        // 1. 未初始化的槽位返回错误而不是 panic
+        let slot = RwLock::new(None);
+        assert!(matches!(
+            crate::try_clone_db_from(&slot),
+            Err(DbfsError::NotInitialized)
+        ));
+        assert_eq!(VfsError::from(DbfsError::NotInitialized), VfsError::IoError);
+
+        // 2. 全局 DB 已初始化时正常返回
+        root();
+        assert!(crate::try_clone_db().is_ok());
+    }
+
+    #[test]
+    fn test_reset_db_slot() {
+        let slot = RwLock::new(None);
+
+        // 1. 第一个数据库, 格式化后写入 super_blk
+        let first = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-reset-a.db").unwrap();
+        crate::init_dbfs_in(&slot, first);
+        let a = crate::try_clone_db_from(&slot).expect("Clone db failed");
+        crate::fs_common::dbfs_common_format_in(&a, 4096, MAGIC).expect("Format failed");
+
+        // 2. 未重置时再次初始化被忽略
+        let ignored =
+            DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-reset-b.db").unwrap();
+        crate::init_dbfs_in(&slot, ignored);
+        assert!(Arc::ptr_eq(
+            &crate::try_clone_db_from(&slot).expect("Clone db failed"),
+            &a
+        ));
+
+        // 3. 重置后槽位为空, 新数据库看不到上一个的状态
+        let old = crate::reset_dbfs_in(&slot).expect("Reset failed");
+        assert!(Arc::ptr_eq(&old, &a));
+        assert!(crate::try_clone_db_from(&slot).is_err());
+        let second = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-reset-c.db").unwrap();
+        crate::init_dbfs_in(&slot, second);
+        let b = crate::try_clone_db_from(&slot).expect("Clone db failed");
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert!(b
+            .tx(false)
+            .expect("Tx failed")
+            .get_bucket("super_blk")
+            .is_err());
+        assert!(a
+            .tx(false)
+            .expect("Tx failed")
+            .get_bucket("super_blk")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_statfs_counts_inodes() {
+        let db = private_db("rvfs2-statfs-inodes.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-statfs-inodes.db");
+        let sb = root.get_super_block().expect("Get super block failed");
+
+        // 1. 创建 N 个文件, f_files 增加 N
+        let before = sb.stat_fs().expect("Statfs failed");
+        assert!(before.f_files >= 1);
+        assert!(before.f_ffree > 0);
+        for i in 0..8 {
+            create_file(&root, &alloc::format!("f{}", i));
+        }
+        assert_eq!(
+            sb.stat_fs().expect("Statfs failed").f_files,
+            before.f_files + 8
+        );
+
+        // 2. 删除后计数回落
+        root.unlink("f0").expect("Unlink failed");
+        assert_eq!(
+            sb.stat_fs().expect("Statfs failed").f_files,
+            before.f_files + 7
+        );
+    }
+
+    #[test]
+    fn test_get_attr_after_write() {
+        let root = root();
+        let file = create_file(&root, "stat_after_write");
+
+        // 1. 写入后立即 stat, 大小已经是最新的
+        file.write_at(0, &[1u8; 100]).expect("Write failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 100);
+        file.write_at(5000, b"tail").expect("Write failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 5004);
+
+        // 2. 截断缩小后不会被之前写入的末尾掩盖
+        file.truncate(10).expect("Truncate failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 10);
+    }
+
+    #[test]
+    fn test_negative_lookup_cache() {
+        use crate::rvfs2::DbfsInode;
+
+        let db = private_db("rvfs2-negative.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-negative.db");
+        let dbfs_root = root
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed");
+
+        // 1. 只有第一次查找访问数据库
+        for _ in 0..50 {
+            assert_eq!(root.lookup("ghost").err(), Some(VfsError::NoEntry));
+        }
+        assert_eq!(dbfs_root.negative_hits(), 49);
+
+        // 2. 创建后缓存失效, 查找成功
+        create_file(&root, "ghost");
+        assert!(root.lookup("ghost").is_ok());
+
+        // 3. 重命名到目录中同样使缓存失效
+        assert!(root.lookup("moved").is_err());
+        root.rename_to("ghost", root.clone(), "moved", VfsRenameFlag::empty())
+            .expect("Rename failed");
+        assert!(root.lookup("moved").is_ok());
+        assert_eq!(dbfs_root.negative_hits(), 49);
+    }
+
+    #[test]
+    fn test_mount_without_counter_or_block_size() {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-minimal.db").unwrap();
+
+        // 1. 只有根目录, 一个编号为 7 的 inode 和 magic, 没有 continue_number 和 blk_size
+        crate::fs_common::dbfs_common_root_inode_in(
+            &db,
+            0,
+            0,
+            crate::common::DbfsTimeSpec::default(),
+        )
+        .expect("Create root failed");
+        {
+            let tx = db.tx(true).expect("Tx failed");
+            let super_blk = tx.get_bucket("super_blk").expect("Get super_blk failed");
+            super_blk
+                .delete("continue_number")
+                .expect("Delete counter failed");
+            super_blk
+                .put("magic", MAGIC.to_be_bytes())
+                .expect("Put magic failed");
+            let inode = tx
+                .create_bucket(7usize.to_be_bytes())
+                .expect("Create inode failed");
+            inode
+                .put("hard_links", 1u32.to_be_bytes())
+                .expect("Put links failed");
+            tx.commit().expect("Commit failed");
+        }
+
+        // 2. 挂载成功, 块大小取编译时的 SLICE_SIZE, 新 inode 编号在已有的之后
+        let (_fs_type, root) = mount_private(&db, "rvfs2-minimal.db");
+        let file = create_file(&root, "fresh");
+        let attr = file.get_attr().expect("Get attr failed");
+        assert!(attr.st_ino > 7);
+        assert_eq!(attr.st_blksize as usize, crate::SLICE_SIZE);
+    }
+
+    #[test]
+    fn test_rename_through_wrapped_handle() {
+        use crate::rvfs2::DbfsInode;
+        use vfscore::{file::VfsFile, superblock::VfsSuperBlock, utils::VfsFileStat, VfsResult};
+
+        /// VFS 包装过的句柄, 不能向下转换为 DbfsInode
+        struct Wrapped(Arc<dyn VfsInode>);
+
+        impl VfsFile for Wrapped {}
+
+        impl VfsInode for Wrapped {
+            fn get_attr(&self) -> VfsResult<VfsFileStat> {
+                self.0.get_attr()
+            }
+            fn inode_type(&self) -> VfsNodeType {
+                self.0.inode_type()
+            }
+            fn get_super_block(&self) -> VfsResult<Arc<dyn VfsSuperBlock>> {
+                self.0.get_super_block()
+            }
+            fn node_perm(&self) -> VfsNodePerm {
+                self.0.node_perm()
+            }
+        }
+
+        let root = root();
+        create_dir(&root, "same_parent");
+
+        // 1. 两次查找得到同一父目录的两个句柄, 其中一个被包装
+        let first = root.lookup("same_parent").expect("Lookup failed");
+        let second: Arc<dyn VfsInode> =
+            Arc::new(Wrapped(root.lookup("same_parent").expect("Lookup failed")));
+        let dbfs_first = first
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed");
+        assert!(dbfs_first.same_inode(second.as_ref()));
+        assert!(!dbfs_first.same_inode(root.as_ref()));
+
+        // 2. 通过包装的句柄重命名和建立硬链接
+        let file = create_file(&first, "before");
+        first
+            .rename_to("before", second.clone(), "after", VfsRenameFlag::empty())
+            .expect("Rename failed");
+        assert!(first.lookup("before").is_err());
+        assert!(second.get_attr().is_ok());
+        let renamed = first.lookup("after").expect("Lookup failed");
+        assert!(renamed
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed")
+            .same_inode(file.as_ref()));
+
+        first
+            .link("linked", Arc::new(Wrapped(renamed)))
+            .expect("Link failed");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_nlink, 2);
+    }
+
+    #[test]
+    fn test_readdir_types_without_attr_reads() {
+        use crate::common::DbfsPermission;
+
+        let db = private_db("rvfs2-dtype.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-dtype.db");
+        let dir = create_dir(&root, "typed");
+        let file = create_file(&dir, "file");
+        let sub = create_dir(&dir, "sub");
+        let link = dir.symlink("link", "file").expect("Symlink failed");
+        let inos: Vec<usize> = [&file, &sub, &link]
+            .iter()
+            .map(|inode| inode.get_attr().expect("Get attr failed").st_ino as usize)
+            .collect();
+        // 直接改写 inode 的 mode, 遍历读到的类型能看出它来自目录项还是属性
+        let set_mode = |ino: usize, mode: DbfsPermission| {
+            let tx = db.tx(true).expect("Tx failed");
+            let bucket = tx.get_bucket(ino.to_be_bytes()).expect("Get inode failed");
+            bucket
+                .put("mode", mode.bits().to_be_bytes())
+                .expect("Put mode failed");
+            tx.commit().expect("Commit failed");
+        };
+        let fifo = DbfsPermission::S_IFIFO | DbfsPermission::from_bits_truncate(0o644);
+        let walk = || {
+            let mut entries = Vec::new();
+            let mut index = 0;
+            while let Some(entry) = dir.readdir(index).expect("Readdir failed") {
+                entries.push((entry.name, entry.ty));
+                index += 1;
+            }
+            entries
+        };
+
+        // 1. 目录项自带类型, 遍历不读取各 inode 的属性
+        for &ino in &inos {
+            set_mode(ino, fifo);
+        }
+        let entries = walk();
+        assert!(entries.contains(&("file".to_string(), VfsNodeType::File)));
+        assert!(entries.contains(&("sub".to_string(), VfsNodeType::Dir)));
+        assert!(entries.contains(&("link".to_string(), VfsNodeType::SymLink)));
+
+        // 2. 旧格式的目录项只有 inode 编号, 遍历时回退到属性并补上类型
+        let dir_ino = dir.get_attr().expect("Get attr failed").st_ino as usize;
+        {
+            let tx = db.tx(true).expect("Tx failed");
+            let bucket = tx
+                .get_bucket(dir_ino.to_be_bytes())
+                .expect("Get dir failed");
+            bucket
+                .put("file", inos[0].to_be_bytes())
+                .expect("Put entry failed");
+            tx.commit().expect("Commit failed");
+        }
+        assert!(walk().contains(&("file".to_string(), VfsNodeType::Fifo)));
+        {
+            let tx = db.tx(false).expect("Tx failed");
+            let bucket = tx
+                .get_bucket(dir_ino.to_be_bytes())
+                .expect("Get dir failed");
+            assert_eq!(
+                bucket
+                    .get_kv("file")
+                    .expect("Get entry failed")
+                    .value()
+                    .len(),
+                9
+            );
+        }
+
+        // 3. 补上类型之后不再读取属性
+        set_mode(
+            inos[0],
+            DbfsPermission::S_IFREG | DbfsPermission::from_bits_truncate(0o644),
+        );
+        assert!(walk().contains(&("file".to_string(), VfsNodeType::Fifo)));
+    }
+
+    #[test]
+    fn test_enforce_permissions() {
+        use core::sync::atomic::AtomicU32;
+
+        static CALLER: AtomicU32 = AtomicU32::new(0);
+        fn caller() -> (u32, u32) {
+            let uid = CALLER.load(Ordering::SeqCst);
+            (uid, uid)
+        }
+
+        let db = private_db("rvfs2-perm.db");
+        let fs_type = Arc::new(
+            DbfsFsType::new_with_db(db.clone(), "rvfs2-perm.db".to_string())
+                .with_clock(test_clock)
+                .with_enforce_permissions(caller),
+        );
+        let root = mount_root(&fs_type);
+
+        // 1. root 建立 1000 可写的目录, 1000 在其中创建的文件归 1000 所有
+        let shared = root
+            .create(
+                "shared",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o777),
+                None,
+            )
+            .expect("Create dir failed");
+        CALLER.store(1000, Ordering::SeqCst);
+        let file = create_file(&shared, "owned");
+        assert_eq!(file.get_attr().expect("Get attr failed").st_uid, 1000);
+        assert_eq!(file.write_at(0, b"mine").expect("Write failed"), 4);
+
+        // 2. 其他用户只能读, 不能写
+        CALLER.store(2000, Ordering::SeqCst);
+        let denied = Some(VfsError::PermissionDenied);
+        assert_eq!(file.write_at(0, b"theirs").err(), denied);
+        let mut buf = [0u8; 4];
+        assert_eq!(file.read_at(0, &mut buf).expect("Read failed"), 4);
+        assert_eq!(&buf, b"mine");
+
+        // 3. 不能在 root 的 0755 目录中创建、链接或删除
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        assert_eq!(
+            root.create("intruder", VfsNodeType::File, perm, None).err(),
+            denied
+        );
+        assert_eq!(root.link("intruder", file.clone()).err(), denied);
+        assert_eq!(root.symlink("intruder", "shared/owned").err(), denied);
+        assert_eq!(root.lookup("intruder").err(), Some(VfsError::NoEntry));
+        assert_eq!(root.unlink("shared").err(), denied);
+
+        // 4. 属主仍然可以写
+        CALLER.store(1000, Ordering::SeqCst);
+        assert_eq!(file.write_at(0, b"MINE").expect("Write failed"), 4);
+        shared.unlink("owned").expect("Unlink failed");
+    }
+
+    #[test]
+    fn test_sticky_directory() {
+        use core::sync::atomic::AtomicU32;
+
+        static CALLER: AtomicU32 = AtomicU32::new(0);
+        fn caller() -> (u32, u32) {
+            let uid = CALLER.load(Ordering::SeqCst);
+            (uid, uid)
+        }
+
+        let db = private_db("rvfs2-sticky.db");
+        let fs_type = Arc::new(
+            DbfsFsType::new_with_db(db.clone(), "rvfs2-sticky.db".to_string())
+                .with_clock(test_clock)
+                .with_enforce_permissions(caller),
+        );
+        let root = mount_root(&fs_type);
+
+        // 1. root 建立 /tmp 式的目录: 所有人可写, 带粘滞位
+        let tmp = root
+            .create(
+                "tmp",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o777),
+                None,
+            )
+            .expect("Create dir failed");
+        let stat = tmp.get_attr().expect("Get attr failed");
+        tmp.set_attr(InodeAttr {
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            size: stat.st_size,
+            mode: 0o1777,
+            atime: stat.st_atime,
+            mtime: stat.st_mtime,
+            ctime: stat.st_ctime,
+        })
+        .expect("Set attr failed");
+
+        // 2. 1000 在其中创建文件
+        CALLER.store(1000, Ordering::SeqCst);
+        let perm = VfsNodePerm::from_bits_truncate(0o666);
+        tmp.create("a", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        tmp.create("b", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+
+        // 3. 2000 不能删除或重命名 1000 的文件, 也不能用重命名覆盖它
+        CALLER.store(2000, Ordering::SeqCst);
+        let denied = Some(VfsError::PermissionDenied);
+        assert_eq!(tmp.unlink("a").err(), denied);
+        assert_eq!(
+            tmp.rename_to("a", tmp.clone(), "c", VfsRenameFlag::empty())
+                .err(),
+            denied
+        );
+        tmp.create("mine", VfsNodeType::File, perm, None)
+            .expect("Create file failed");
+        assert_eq!(
+            tmp.rename_to("mine", tmp.clone(), "b", VfsRenameFlag::empty())
+                .err(),
+            denied
+        );
+        tmp.unlink("mine").expect("Unlink own file failed");
+
+        // 4. 属主可以删除和重命名
+        CALLER.store(1000, Ordering::SeqCst);
+        tmp.rename_to("a", tmp.clone(), "c", VfsRenameFlag::empty())
+            .expect("Rename failed");
+        tmp.unlink("c").expect("Unlink failed");
+
+        // 5. 目录属主 root 也可以
+        CALLER.store(0, Ordering::SeqCst);
+        tmp.unlink("b").expect("Unlink failed");
+    }
+
+    #[test]
+    fn test_write_at_vectored() {
+        use crate::rvfs2::DbfsInode;
+
+        let root = root();
+        let file = create_file(&root, "vectored");
+        let dbfs_file = file
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed");
+
+        // 1. 三个缓冲区一次写入, 返回总长度
+        let n = dbfs_file
+            .write_at_vectored(4, &[b"hello", b", ", b"vectored world"])
+            .expect("Vectored write failed");
+        assert_eq!(n, 21);
+        assert_eq!(file.get_attr().expect("Get attr failed").st_size, 25);
+
+        // 2. 读回的是按顺序拼接的内容, 写入点之前是空洞
+        let mut buf = [0xffu8; 25];
+        assert_eq!(file.read_at(0, &mut buf).expect("Read failed"), 25);
+        assert_eq!(&buf[..4], &[0; 4]);
+        assert_eq!(&buf[4..], b"hello, vectored world");
+
+        // 3. 空缓冲区不影响结果
+        let n = dbfs_file
+            .write_at_vectored(4, &[b"", b"HELLO", b""])
+            .expect("Vectored write failed");
+        assert_eq!(n, 5);
+        assert_eq!(file.read_at(4, &mut buf[..7]).expect("Read failed"), 7);
+        assert_eq!(&buf[..7], b"HELLO, ");
+    }
+
+    #[test]
+    fn test_transactions_apply_to_own_db() {
+        // 两个挂载各自有自己的数据库和块大小, 日志中的操作只应用到自己的数据库
+        let mount = |path: &str, blk_size: u32| {
+            mount_private(&private_db_with_block_size(path, blk_size), path).1
+        };
+        let a = mount("rvfs2-tm-a.db", 4096);
+        let b = mount("rvfs2-tm-b.db", 512);
+
+        // 一、创建和写入经过各自的事务管理器
+        let fa = create_file(&a, "tm_file");
+        assert_eq!(b.lookup("tm_file").err(), Some(VfsError::NoEntry));
+        let fb = create_file(&b, "tm_file");
+        fa.write_at(0, &[b'a'; 5000]).expect("Write failed");
+        fb.write_at(0, &[b'b'; 1000]).expect("Write failed");
+
+        // 二、数据按各自的块大小存储
+        assert_eq!(fa.get_attr().expect("Get attr failed").st_size, 5000);
+        assert_eq!(fb.get_attr().expect("Get attr failed").st_size, 1000);
+        assert_eq!(fa.get_attr().unwrap().st_blocks, 2 * 4096 / 512);
+        assert_eq!(fb.get_attr().unwrap().st_blocks, 2);
+        let mut buf = [0u8; 1000];
+        assert_eq!(fb.read_at(0, &mut buf).expect("Read failed"), 1000);
+        assert!(buf.iter().all(|&c| c == b'b'));
+
+        // 三、截断、改名和删除也只作用于自己的数据库
+        fa.truncate(10).expect("Truncate failed");
+        assert_eq!(fb.get_attr().unwrap().st_size, 1000);
+        a.rename_to("tm_file", a.clone(), "tm_moved", VfsRenameFlag::empty())
+            .expect("Rename failed");
+        assert!(b.lookup("tm_file").is_ok());
+        a.unlink("tm_moved").expect("Unlink failed");
+        assert!(b.lookup("tm_file").is_ok());
+        assert_eq!(a.lookup("tm_moved").err(), Some(VfsError::NoEntry));
+    }
+
+    #[test]
+    fn test_rollback_restores_unlinked_file() {
+        use crate::{operation::TransactionOperation, transaction::TransactionManager};
+
+        let root = root();
+        let file = create_file(&root, "undo_unlinked");
+        file.write_at(0, b"keep me").expect("Write failed");
+
+        // 第二个操作失败, 已经执行的删除必须被撤销
+        let tm = TransactionManager::new();
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Delete {
+            parent_ino: 1,
+            name: "undo_unlinked".to_string(),
+        });
+        txn.record(TransactionOperation::Delete {
+            parent_ino: 1,
+            name: "undo_unlinked_missing".to_string(),
+        });
+        assert!(tm.commit(txn).is_err());
+
+        // 条目、链接数和文件内容都恢复原样
+        let restored = root.lookup("undo_unlinked").expect("Lookup failed");
+        assert_eq!(restored.inode_type(), VfsNodeType::File);
+        let stat = restored.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_nlink, 1);
+        assert_eq!(stat.st_size, 7);
+        let mut buf = [0u8; 16];
+        let n = restored.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"keep me");
+    }
+
+    #[test]
+    fn test_committed_write_survives_lost_cache() {
+        let db = private_db("rvfs2-lost-cache.db");
+        let mount = || mount_private(&db, "rvfs2-lost-cache.db").1;
+
+        // 一、提交写入后不 fsync
+        let root = mount();
+        let file = create_file(&root, "committed");
+        file.write_at(0, b"committed data").expect("Write failed");
+
+        // 二、模拟崩溃: 缓存中的页全部丢失, 然后重新挂载
+        crate::rvfs2::cache::forget(&db);
+        let root = mount();
+
+        // 三、日志已清除, 数据必须已经在数据库中
+        let mut buf = [0u8; 32];
+        let n = root
+            .lookup("committed")
+            .expect("Lookup failed")
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"committed data");
+    }
+
+    #[test]
+    fn test_dot_names_and_unlink_dir() {
+        let root = root();
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+
+        // `.` 和 `..` 不能作为新条目的名字
+        assert_eq!(
+            root.create(".", VfsNodeType::File, perm, None).err(),
+            Some(VfsError::Invalid)
+        );
+        assert_eq!(
+            root.create("..", VfsNodeType::Dir, perm, None).err(),
+            Some(VfsError::Invalid)
+        );
+        assert_eq!(root.symlink("..", "/target").err(), Some(VfsError::Invalid));
+
+        // unlink 不删除目录, 目录只能通过 rmdir 删除
+        root.create("unlink_dir", VfsNodeType::Dir, perm, None)
+            .expect("Create dir failed");
+        assert_eq!(root.unlink("unlink_dir").err(), Some(VfsError::IsDir));
+        assert!(root.lookup("unlink_dir").is_ok());
+        root.rmdir("unlink_dir").expect("Rmdir failed");
+    }
+
+    #[test]
+    fn test_alloc_ino_without_counter() {
+        // 没有 continue_number 的镜像: 新的 Inode 号从已有的最大 Inode 号之后开始
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "rvfs2-no-counter.db").unwrap();
+        let existing = 1usize << 32;
+        {
+            let tx = db.tx(true).unwrap();
+            tx.create_bucket("super_blk").unwrap();
+            tx.create_bucket(existing.to_be_bytes()).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let tx = db.tx(true).unwrap();
+        let ino = crate::fs_common::dbfs_common_alloc_ino(&tx).expect("Alloc failed");
+        assert!(ino > existing);
+        let next = crate::fs_common::dbfs_common_alloc_ino(&tx).expect("Alloc failed");
+        assert_eq!(next, ino + 1);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_rollback_restores_parent_links() {
+        use crate::{operation::TransactionOperation, transaction::TransactionManager};
+
+        let root = root();
+        let parent = create_dir(&root, "undo_parent");
+        let before = parent.get_attr().expect("Get attr failed");
+
+        // 子目录的 ".." 增加父目录的链接数; 事务失败后链接数和时间戳必须恢复
+        let tm = TransactionManager::new();
+        let mut txn = tm.begin_transaction();
+        txn.record(TransactionOperation::Mkdir {
+            parent_ino: before.st_ino as usize,
+            name: "undo_sub".to_string(),
+            uid: 0,
+            gid: 0,
+            perm: 0o755,
+        });
+        txn.record(TransactionOperation::Delete {
+            parent_ino: before.st_ino as usize,
+            name: "undo_sub_missing".to_string(),
+        });
+        assert!(tm.commit(txn).is_err());
+
+        let after = parent.get_attr().expect("Get attr failed");
+        assert_eq!(parent.lookup("undo_sub").err(), Some(VfsError::NoEntry));
+        assert_eq!(after.st_nlink, before.st_nlink);
+        assert_eq!(
+            (after.st_mtime.sec, after.st_mtime.nsec),
+            (before.st_mtime.sec, before.st_mtime.nsec)
+        );
+    }
+
+    #[test]
+    fn test_read_without_clock_keeps_atime() {
+        use vfscore::utils::VfsTime;
+
+        let db = private_db("rvfs2-no-clock.db");
+        let root = mount_root(&Arc::new(DbfsFsType::new_with_db(
+            db,
+            "rvfs2-no-clock.db".to_string(),
+        )));
+        let file = create_file(&root, "no_clock");
+        file.write_at(0, b"data").expect("Write failed");
+        file.update_time(
+            VfsTime::AccessTime(VfsTimeSpec::new(1_000, 0)),
+            VfsTimeSpec::new(1_000, 0),
+        )
+        .expect("Update atime failed");
+
+        // 没有时钟的挂载不更新 atime, 读取不会把它写成 0
+        let mut buf = [0u8; 8];
+        file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(
+            file.get_attr().expect("Get attr failed").st_atime.sec,
+            1_000
+        );
+    }
+
+    #[test]
+    fn test_block_reservation_is_atomic_and_persisted() {
+        let db = private_db("rvfs2-reserve.db");
+        let root = mount_root(&Arc::new(
+            DbfsFsType::new_with_db(db.clone(), "rvfs2-reserve.db".to_string())
+                .with_clock(test_clock)
+                .with_block_count(6),
+        ));
+
+        // 一、两个写者同时争抢 6 个块, 写入的块数之和不超过设备容量
+        let data = Arc::new(vec![7u8; 4 * 4096]);
+        let writers: Vec<_> = (0..2)
+            .map(|i| {
+                let file = create_file(&root, &alloc::format!("reserve_{}", i));
+                let data = data.clone();
+                std::thread::spawn(move || file.write_at(0, &data).unwrap_or(0))
+            })
+            .collect();
+        let written: usize = writers.into_iter().map(|w| w.join().unwrap()).sum();
+        assert!(written <= 6 * 4096);
+
+        // 二、不经过 sync_fs, 已用块数随写入一起保存在数据库中
+        let tx = db.tx(false).unwrap();
+        let used = tx
+            .get_bucket("super_blk")
+            .unwrap()
+            .get_kv("used_blocks")
+            .map(|kv| crate::u64!(kv.value()));
+        assert_eq!(used, Some((written / 4096) as u64));
+    }
+
+    #[test]
+    fn test_rename_replaces_target_and_rejects_descendant() {
+        use crate::rvfs2::{common as dbfs_common, DbfsInode};
+
+        let root = root();
+        let src = create_file(&root, "rename_src");
+        src.write_at(0, b"source").expect("Write failed");
+        let dst = create_file(&root, "rename_dst");
+        let dst_ino = dst.get_attr().expect("Get attr failed").st_ino as usize;
+        drop(dst);
+
+        // 一、覆盖已有的目标: 目标 Inode 被删除, 名字指向源文件
+        root.rename_to(
+            "rename_src",
+            root.clone(),
+            "rename_dst",
+            VfsRenameFlag::empty(),
+        )
+        .expect("Rename failed");
+        assert!(dbfs_common::dbfs_get_attr(&crate::clone_db(), dst_ino, 4096).is_err());
+        let mut buf = [0u8; 16];
+        let n = root
+            .lookup("rename_dst")
+            .expect("Lookup failed")
+            .read_at(0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"source");
+
+        // 二、目录不能移动到自己或自己的子目录下
+        let outer = root
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed")
+            .mkdir("rename_outer", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+        let inner = outer
+            .clone()
+            .downcast_arc::<DbfsInode>()
+            .ok()
+            .expect("Downcast failed")
+            .mkdir("inner", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+        assert_eq!(
+            root.rename_to("rename_outer", inner, "moved", VfsRenameFlag::empty())
+                .err(),
+            Some(VfsError::Invalid)
+        );
+        assert_eq!(
+            root.rename_to(
+                "rename_outer",
+                outer.clone(),
+                "moved",
+                VfsRenameFlag::empty()
+            )
+            .err(),
+            Some(VfsError::Invalid)
+        );
+        assert!(root.lookup("rename_outer").is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_readdir_walks() {
+        let root = root();
+        let dir = create_dir(&root, "readdir_walks");
+        for name in ["f1", "f3", "f5"] {
+            create_file(&dir, name);
+        }
+
+        // 两个遍历交替进行, 途中插入排在最前面的目录项, 各自的位置互不覆盖
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        a.push(
+            dir.readdir(0)
+                .expect("Readdir failed")
+                .expect("No entry")
+                .name,
+        );
+        b.push(
+            dir.readdir(0)
+                .expect("Readdir failed")
+                .expect("No entry")
+                .name,
+        );
+        a.push(
+            dir.readdir(1)
+                .expect("Readdir failed")
+                .expect("No entry")
+                .name,
+        );
+        create_file(&dir, "f0");
+        let mut index = 1;
+        while let Some(entry) = dir.readdir(index).expect("Readdir failed") {
+            b.push(entry.name);
+            index += 1;
+        }
+        assert_eq!(a, vec!["f1", "f3"]);
+        assert_eq!(b, vec!["f1", "f3", "f5"]);
+    }
+
+    #[test]
+    fn test_mount_reaps_unlinked_inodes() {
+        let db = private_db("rvfs2-reap.db");
+        let mount = || mount_private(&db, "rvfs2-reap.db");
+
+        let (fs_type, root) = mount();
+        let file = create_file(&root, "crashed");
+        file.write_at(0, &[7u8; 4096]).expect("Write failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino as usize;
+        drop(file);
+        fs_type
+            .kill_sb(root.get_super_block().expect("Get super block failed"))
+            .expect("Unmount failed");
+
+        // 模拟打开时被删除后崩溃: 目录项已删除, 链接数为 0, Inode 仍留在数据库中
+        let tx = db.tx(true).unwrap();
+        tx.get_bucket(1usize.to_be_bytes())
+            .unwrap()
+            .delete("crashed")
+            .unwrap();
+        tx.get_bucket(ino.to_be_bytes())
+            .unwrap()
+            .put("hard_links", 0u32.to_be_bytes())
+            .unwrap();
+        tx.commit().unwrap();
+
+        // 重新挂载时回收该 Inode 及其数据块
+        let (_fs_type, root) = mount();
+        let tx = db.tx(false).unwrap();
+        assert!(tx.get_bucket(ino.to_be_bytes()).is_err());
+        let super_blk = tx.get_bucket("super_blk").unwrap();
+        assert_eq!(
+            crate::u64!(super_blk.get_kv("used_blocks").unwrap().value()),
+            0
+        );
+        drop(tx);
+        assert_eq!(
+            root.get_super_block()
+                .expect("Get super block failed")
+                .stat_fs()
+                .expect("Statfs failed")
+                .f_files,
+            1
+        );
+    }
+
+    #[test]
+    fn test_link_by_inode() {
+        let root = root();
+        let file = create_file(&root, "link_by_ino");
+
+        // 1. 新名字指向同一个 inode, 链接数加一, ctime 取挂载的时钟
+        let linked = root
+            .link("link_by_ino_2", file.clone())
+            .expect("Link failed");
+        let stat = linked.get_attr().expect("Get attr failed");
+        assert_eq!(stat.st_nlink, 2);
+        assert!(stat.st_ctime.sec > 0);
+        let found = root.lookup("link_by_ino_2").expect("Lookup failed");
+        assert_eq!(found.inode_type(), VfsNodeType::File);
+        assert_eq!(
+            found.get_attr().expect("Get attr failed").st_ino,
+            stat.st_ino
+        );
+
+        // 2. 已存在的名字和目录都不能作为链接
+        assert!(root.link("link_by_ino_2", file).is_err());
+        let dir = create_dir(&root, "link_dir");
+        assert!(root.link("link_dir_2", dir).is_err());
+        assert_eq!(root.lookup("link_dir_2").err(), Some(VfsError::NoEntry));
+    }
+
+    #[test]
+    fn test_rmdir_checks_type_and_open_handles() {
+        let db = private_db("rvfs2-rmdir.db");
+        let (_fs_type, root) = mount_private(&db, "rvfs2-rmdir.db");
+        let used = || {
+            root.get_super_block()
+                .expect("Get super block failed")
+                .stat_fs()
+                .expect("Statfs failed")
+                .f_files
+        };
+
+        // 1. 普通文件不能用 rmdir 删除, 非空目录也不能
+        create_file(&root, "plain");
+        assert_eq!(root.rmdir("plain").err(), Some(VfsError::NotDir));
+        assert!(root.lookup("plain").is_ok());
+        let dir = create_dir(&root, "full");
+        create_file(&dir, "inner");
+        assert_eq!(root.rmdir("full").err(), Some(VfsError::NotEmpty));
+        dir.unlink("inner").expect("Unlink failed");
+
+        // 2. 仍被打开的空目录删除后保留到最后一个句柄释放
+        let ino = dir.get_attr().expect("Get attr failed").st_ino as usize;
+        let before = used();
+        root.rmdir("full").expect("Rmdir failed");
+        assert_eq!(root.lookup("full").err(), Some(VfsError::NoEntry));
+        assert!(db.tx(false).unwrap().get_bucket(ino.to_be_bytes()).is_ok());
+        assert_eq!(
+            dir.get_attr().expect("Get attr failed").st_ino as usize,
+            ino
+        );
+        drop(dir);
+        assert!(db.tx(false).unwrap().get_bucket(ino.to_be_bytes()).is_err());
+        assert_eq!(used(), before - 1);
+
+        // 3. 没有句柄的目录立即删除, 不会从缓存中重新出现
+        create_dir(&root, "closed");
+        root.rmdir("closed").expect("Rmdir failed");
+        assert_eq!(root.lookup("closed").err(), Some(VfsError::NoEntry));
+        assert_eq!(used(), before - 1);
+    }
+
+    #[test]
+    fn test_symlink_keeps_existing_entry_and_owner() {
+        use core::sync::atomic::AtomicU32;
+
+        static CALLER: AtomicU32 = AtomicU32::new(0);
+        fn caller() -> (u32, u32) {
+            let uid = CALLER.load(Ordering::SeqCst);
+            (uid, uid / 10)
+        }
+
+        let db = private_db("rvfs2-symlink-owner.db");
+        let root = mount_root(&Arc::new(
+            DbfsFsType::new_with_db(db, "rvfs2-symlink-owner.db".to_string())
+                .with_clock(test_clock)
+                .with_enforce_permissions(caller),
+        ));
+        let shared = root
+            .create(
+                "shared",
+                VfsNodeType::Dir,
+                VfsNodePerm::from_bits_truncate(0o777),
+                None,
+            )
+            .expect("Create dir failed");
+        CALLER.store(1000, Ordering::SeqCst);
+
+        // 1. 符号链接归调用者所有
+        let link = shared.symlink("link", "target").expect("Symlink failed");
+        let stat = link.get_attr().expect("Get attr failed");
+        assert_eq!((stat.st_uid, stat.st_gid), (1000, 100));
+        let found = shared.lookup("link").expect("Lookup failed");
+        let stat = found.get_attr().expect("Get attr failed");
+        assert_eq!((stat.st_uid, stat.st_gid), (1000, 100));
+
+        // 2. 已存在的名字不会被覆盖, 原来的文件仍然可以访问
+        let file = create_file(&shared, "taken");
+        file.write_at(0, b"kept").expect("Write failed");
+        assert_eq!(
+            shared.symlink("taken", "elsewhere").err(),
+            Some(VfsError::EExist)
+        );
+        let found = shared.lookup("taken").expect("Lookup failed");
+        assert_eq!(found.inode_type(), VfsNodeType::File);
+        let mut buf = [0u8; 4];
+        assert_eq!(found.read_at(0, &mut buf).expect("Read failed"), 4);
+        assert_eq!(&buf, b"kept");
+    }
+}
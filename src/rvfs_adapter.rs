@@ -1,12 +1,18 @@
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    string::ToString,
+    sync::{Arc, Weak},
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use vfscore::fstype::VfsMountPoint;
 use vfscore::{
-    VfsResult, VfsInode, VfsSuperBlock, VfsFsType, VfsDentry, VfsError, VfsNodeType,
-    VfsFile, utils::{VfsFileStat, VfsNodePerm, VfsTimeSpec, VfsDirEntry, VfsFsStat, VfsTime},
-    inode::{InodeAttr},
+    inode::InodeAttr,
     superblock::SuperType,
+    utils::{VfsDirEntry, VfsFileStat, VfsFsStat, VfsNodePerm, VfsTime, VfsTimeSpec},
+    VfsDentry, VfsError, VfsFile, VfsFsType, VfsInode, VfsNodeType, VfsResult, VfsSuperBlock,
 };
-use alloc::{sync::{Arc, Weak}, string::String, collections::BTreeMap, string::ToString};
-use spin::Mutex;
-use vfscore::fstype::VfsMountPoint;
 
 pub struct DbfsDentry<D: BlockDevice> {
     inner: Mutex<DbfsDentryInner<D>>,
@@ -59,7 +65,11 @@ impl<D: BlockDevice + 'static> VfsDentry for DbfsDentry<D> {
         self.inner.lock().mnt = None;
     }
 
-    fn to_mount_point(self: Arc<Self>, sub_fs_root: Arc<dyn VfsDentry>, mount_flag: u32) -> VfsResult<()> {
+    fn to_mount_point(
+        self: Arc<Self>,
+        sub_fs_root: Arc<dyn VfsDentry>,
+        mount_flag: u32,
+    ) -> VfsResult<()> {
         let mut inner = self.inner.lock();
         inner.mnt = Some(VfsMountPoint {
             root: sub_fs_root,
@@ -71,13 +81,26 @@ impl<D: BlockDevice + 'static> VfsDentry for DbfsDentry<D> {
 
     fn find(&self, path: &str) -> Option<Arc<dyn VfsDentry>> {
         let inner = self.inner.lock();
-        inner.children.get(path).map(|c| c.clone() as Arc<dyn VfsDentry>)
+        inner
+            .children
+            .get(path)
+            .map(|c| c.clone() as Arc<dyn VfsDentry>)
     }
 
-    fn insert(self: Arc<Self>, name: &str, child: Arc<dyn VfsInode>) -> VfsResult<Arc<dyn VfsDentry>> {
+    fn insert(
+        self: Arc<Self>,
+        name: &str,
+        child: Arc<dyn VfsInode>,
+    ) -> VfsResult<Arc<dyn VfsDentry>> {
         let mut inner = self.inner.lock();
-        let dbfs_inode = child.downcast_arc::<DbfsInode<D>>().map_err(|_| VfsError::Invalid)?;
-        let dentry = DbfsDentry::new(dbfs_inode, Arc::downgrade(&(self.clone() as Arc<dyn VfsDentry>)), name.to_string());
+        let dbfs_inode = child
+            .downcast_arc::<DbfsInode<D>>()
+            .map_err(|_| VfsError::Invalid)?;
+        let dentry = DbfsDentry::new(
+            dbfs_inode,
+            Arc::downgrade(&(self.clone() as Arc<dyn VfsDentry>)),
+            name.to_string(),
+        );
         inner.children.insert(name.to_string(), dentry.clone());
         Ok(dentry as Arc<dyn VfsDentry>)
     }
@@ -89,9 +112,9 @@ impl<D: BlockDevice + 'static> VfsDentry for DbfsDentry<D> {
 }
 use crate::common::DbfsResult;
 use crate::log_manager::BlockDevice;
-use crate::tx_engine::TransactionEngine;
 use crate::models::InodeMetadata;
-use jammdb::{DbFile, FileExt, IOResult, MetaData, OpenOption, File as JammFile};
+use crate::tx_engine::TransactionEngine;
+use jammdb::{DbFile, File as JammFile, FileExt, IOResult, MetaData, OpenOption};
 
 /// 桥接 vfscore 的 VfsInode 到 jammdb 的 DbFile trait
 pub struct JammdbFileAdapter {
@@ -101,19 +124,41 @@ pub struct JammdbFileAdapter {
 
 impl core2::io::Read for JammdbFileAdapter {
     fn read(&mut self, buf: &mut [u8]) -> core2::io::Result<usize> {
+        // 底层设备可能返回短读, 循环直到填满缓冲区; 读到 0 字节视为 EOF
         let mut pos = self.pos.lock();
-        let n = self.inode.read_at(*pos, buf).map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "read error"))?;
-        *pos += n as u64;
-        Ok(n)
+        let mut total = 0;
+        while total < buf.len() {
+            let n = self
+                .inode
+                .read_at(*pos, &mut buf[total..])
+                .map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "read error"))?;
+            if n == 0 {
+                break;
+            }
+            *pos += n as u64;
+            total += n;
+        }
+        Ok(total)
     }
 }
 
 impl core2::io::Write for JammdbFileAdapter {
     fn write(&mut self, buf: &[u8]) -> core2::io::Result<usize> {
+        // 短写时继续写剩余部分; 没有进展时停止, 避免死循环
         let mut pos = self.pos.lock();
-        let n = self.inode.write_at(*pos, buf).map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "write error"))?;
-        *pos += n as u64;
-        Ok(n)
+        let mut total = 0;
+        while total < buf.len() {
+            let n = self
+                .inode
+                .write_at(*pos, &buf[total..])
+                .map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "write error"))?;
+            if n == 0 {
+                break;
+            }
+            *pos += n as u64;
+            total += n;
+        }
+        Ok(total)
     }
     fn flush(&mut self) -> core2::io::Result<()> {
         Ok(())
@@ -124,33 +169,53 @@ impl core2::io::Seek for JammdbFileAdapter {
     fn seek(&mut self, pos: core2::io::SeekFrom) -> core2::io::Result<u64> {
         let mut current_pos = self.pos.lock();
         let size = self.inode.get_attr().map(|a| a.st_size).unwrap_or(0);
+        // 落在文件开头之前或溢出 u64 的位置都是无效的
         let new_pos = match pos {
-            core2::io::SeekFrom::Start(s) => s,
-            core2::io::SeekFrom::End(e) => (size as i64 + e) as u64,
-            core2::io::SeekFrom::Current(c) => (*current_pos as i64 + c) as u64,
-        };
+            core2::io::SeekFrom::Start(s) => Some(s),
+            core2::io::SeekFrom::End(e) => size.checked_add_signed(e),
+            core2::io::SeekFrom::Current(c) => current_pos.checked_add_signed(c),
+        }
+        .ok_or_else(|| {
+            core2::io::Error::new(core2::io::ErrorKind::InvalidInput, "invalid seek position")
+        })?;
         *current_pos = new_pos;
         Ok(new_pos)
     }
 }
 
 impl FileExt for JammdbFileAdapter {
-    fn lock_exclusive(&self) -> IOResult<()> { Ok(()) }
-    fn unlock(&self) -> IOResult<()> { Ok(()) }
+    fn lock_exclusive(&self) -> IOResult<()> {
+        Ok(())
+    }
+    fn unlock(&self) -> IOResult<()> {
+        Ok(())
+    }
     fn size(&self) -> usize {
-        self.inode.get_attr().map(|a| a.st_size as usize).unwrap_or(0)
+        self.inode
+            .get_attr()
+            .map(|a| a.st_size as usize)
+            .unwrap_or(0)
     }
     fn metadata(&self) -> IOResult<MetaData> {
-        let attr = self.inode.get_attr().map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "stat error"))?;
+        let attr = self
+            .inode
+            .get_attr()
+            .map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "stat error"))?;
         Ok(MetaData { len: attr.st_size })
     }
     fn sync_all(&self) -> IOResult<()> {
-        self.inode.fsync().map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "fsync error"))
+        self.inode
+            .fsync(false)
+            .map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "fsync error"))
     }
     fn allocate(&mut self, new_size: u64) -> IOResult<()> {
-        self.inode.truncate(new_size).map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "truncate error"))
+        self.inode
+            .truncate(new_size)
+            .map_err(|_| core2::io::Error::new(core2::io::ErrorKind::Other, "truncate error"))
+    }
+    fn addr(&self) -> usize {
+        0
     }
-    fn addr(&self) -> usize { 0 }
 }
 
 impl DbFile for JammdbFileAdapter {}
@@ -160,10 +225,18 @@ pub struct JammdbOpenOptions {
 }
 
 impl OpenOption for JammdbOpenOptions {
-    fn new() -> Self { panic!("Use JammdbOpenOptions::with_dev") }
-    fn read(&mut self, _read: bool) -> &mut Self { self }
-    fn write(&mut self, _write: bool) -> &mut Self { self }
-    fn create(&mut self, _create: bool) -> &mut Self { self }
+    fn new() -> Self {
+        panic!("Use JammdbOpenOptions::with_dev")
+    }
+    fn read(&mut self, _read: bool) -> &mut Self {
+        self
+    }
+    fn write(&mut self, _write: bool) -> &mut Self {
+        self
+    }
+    fn create(&mut self, _create: bool) -> &mut Self {
+        self
+    }
     fn open<T: ToString + jammdb::PathLike>(&mut self, _path: &T) -> IOResult<JammFile> {
         let adapter = JammdbFileAdapter {
             inode: self.dev.clone(),
@@ -180,14 +253,21 @@ pub struct VfsBlockDeviceAdapter {
 
 impl BlockDevice for VfsBlockDeviceAdapter {
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
-        self.inode.read_at(pos, buf).map_err(|_| crate::common::DbfsError::Io)
+        self.inode
+            .read_at(pos, buf)
+            .map_err(|_| crate::common::DbfsError::Io)
     }
     fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
-        self.inode.write_at(pos, buf).map_err(|_| crate::common::DbfsError::Io)
+        self.inode
+            .write_at(pos, buf)
+            .map_err(|_| crate::common::DbfsError::Io)
     }
     fn size(&self) -> u64 {
         self.inode.get_attr().map(|a| a.st_size).unwrap_or(0)
     }
+    fn flush(&self) -> DbfsResult<()> {
+        self.inode.flush().map_err(|_| crate::common::DbfsError::Io)
+    }
 }
 
 impl BlockDevice for Arc<VfsBlockDeviceAdapter> {
@@ -200,11 +280,68 @@ impl BlockDevice for Arc<VfsBlockDeviceAdapter> {
     fn size(&self) -> u64 {
         (**self).size()
     }
+    fn flush(&self) -> DbfsResult<()> {
+        (**self).flush()
+    }
 }
 
 unsafe impl Send for VfsBlockDeviceAdapter {}
 unsafe impl Sync for VfsBlockDeviceAdapter {}
 
+/// ioctl: 回收日志空间 ([`TransactionEngine::gc`])
+pub const DBFS_IOC_GC: u32 = 0x4442_0001;
+/// ioctl: 让所有修改落盘 ([`TransactionEngine::force_checkpoint`])
+pub const DBFS_IOC_CHECKPOINT: u32 = 0x4442_0002;
+/// ioctl: 检查文件系统 ([`TransactionEngine::fsck`]), `arg` 非 0 时修复
+pub const DBFS_IOC_FSCK: u32 = 0x4442_0003;
+
+/// 没有记录保留区大小的旧文件系统使用的保留区大小
+const LEGACY_RESERVED_SIZE: u64 = 32 * 1024 * 1024;
+/// 保留区至少要容纳 jammdb 的元数据页
+const MIN_RESERVED_SIZE: u64 = 64 * 1024;
+/// 保留区之后至少要留给日志的空间
+const MIN_LOG_SIZE: u64 = 64 * 1024;
+/// 能够挂载的最小设备: 最小的保留区加上最小的日志区
+pub const MIN_DEVICE_SIZE: u64 = MIN_RESERVED_SIZE + MIN_LOG_SIZE;
+
+/// 检查保留区和其后的日志区能否放进 `disk_size` 字节的设备
+fn check_layout(disk_size: u64, reserved_size: u64) -> VfsResult<()> {
+    if reserved_size < MIN_RESERVED_SIZE {
+        log::error!(
+            "DBFS reserved size {} is below the minimum of {}",
+            reserved_size,
+            MIN_RESERVED_SIZE
+        );
+        return Err(VfsError::Invalid);
+    }
+    if disk_size < reserved_size.saturating_add(MIN_LOG_SIZE) {
+        log::error!(
+            "DBFS device of {} bytes cannot hold a {}-byte reserved region and a {}-byte log",
+            disk_size,
+            reserved_size,
+            MIN_LOG_SIZE
+        );
+        return Err(VfsError::Invalid);
+    }
+    Ok(())
+}
+
+/// 默认保留设备的 1/4 给 jammdb, 最多 32MB, 按 4KB 对齐
+fn default_reserved_size(disk_size: u64) -> u64 {
+    core::cmp::min(disk_size / 4, LEGACY_RESERVED_SIZE) & !4095
+}
+
+/// 从挂载参数中解析 `reserved_size=<字节数>`, 参数以逗号分隔
+fn parse_reserved_size(data: &[u8]) -> VfsResult<Option<u64>> {
+    let options = core::str::from_utf8(data).map_err(|_| VfsError::Invalid)?;
+    for option in options.split(',') {
+        if let Some(value) = option.trim().strip_prefix("reserved_size=") {
+            return value.parse().map(Some).map_err(|_| VfsError::Invalid);
+        }
+    }
+    Ok(None)
+}
+
 /// DBFS-T 文件系统类型定义
 pub struct DbfsFsType;
 
@@ -214,7 +351,7 @@ impl VfsFsType for DbfsFsType {
         _flags: u32,
         _ab_mnt: &str,
         dev: Option<Arc<dyn VfsInode>>,
-        _data: &[u8],
+        data: &[u8],
     ) -> VfsResult<Arc<dyn VfsDentry>> {
         let dev = dev.ok_or(VfsError::Invalid)?;
         if dev.inode_type() != VfsNodeType::BlockDevice {
@@ -222,58 +359,66 @@ impl VfsFsType for DbfsFsType {
         }
 
         let adapter = Arc::new(VfsBlockDeviceAdapter { inode: dev.clone() });
-        
+        // 在 jammdb 写入任何内容之前拒绝过小的设备
+        let disk_size = adapter.size();
+        if disk_size < MIN_DEVICE_SIZE {
+            log::error!(
+                "DBFS device of {} bytes is smaller than the minimum of {}",
+                disk_size,
+                MIN_DEVICE_SIZE
+            );
+            return Err(VfsError::Invalid);
+        }
+
         // 1. 初始化数据库打开选项
         let mut options = JammdbOpenOptions { dev: dev.clone() };
-        
-        // 2. 尝试打开数据库，如果失败且磁盘足够大，则尝试初始化
-        let db = match jammdb::DB::open(&mut options, &"dbfs.db".to_string()) {
-            Ok(db) => db,
-            Err(_) => {
-                if adapter.size() < 4096 { 
-                    return Err(VfsError::Invalid);
-                }
-                // 强制初始化 (模拟 mkfs)
-                // 注意：实际生产中应有更严格的 magic number 检查
-                jammdb::DB::open(&mut options, &"dbfs.db".to_string()).map_err(|_| VfsError::IoError)?
-            }
-        };
 
-        // 3. 初始化 LogManager
-        // 假设数据库文件前 32MB 为 jammdb 使用，之后为日志追加区
-        let db_reserved_size = 32 * 1024 * 1024; 
-        if adapter.size() < db_reserved_size {
-            // 如果磁盘太小，根据实际大小调整或报错
-            // 这里简单处理为至少 32MB
-        }
-        let log_manager = LogManager::new(adapter.clone() as Arc<dyn BlockDevice>, db_reserved_size);
+        // 2. 打开数据库, 设备上还没有数据库时由 jammdb 初始化 (模拟 mkfs)
+        // 注意：实际生产中应有更严格的 magic number 检查
+        let db = jammdb::DB::open(&mut options, &"dbfs.db".to_string())
+            .map_err(|_| VfsError::IoError)?;
 
-        // 4. 初始化文件系统结构 (如果尚未初始化)
+        // 3. 初始化文件系统结构 (如果尚未初始化)
         {
             let tx = db.begin_batch();
             if tx.get_bucket("inodes").is_err() {
+                // 格式化时确定 jammdb 保留区的大小, 之后的挂载都从超级块读取
+                let reserved_size = match parse_reserved_size(data)? {
+                    Some(size) => size,
+                    None => default_reserved_size(disk_size).max(MIN_RESERVED_SIZE),
+                };
+                check_layout(disk_size, reserved_size)?;
+
                 // 初始化元数据 bucket
                 tx.create_bucket("inodes").map_err(|_| VfsError::IoError)?;
-                
+
                 // 初始化超级块信息 bucket
-                let sb_bucket = tx.create_bucket("super_blk").map_err(|_| VfsError::IoError)?;
+                let sb_bucket = tx
+                    .create_bucket("super_blk")
+                    .map_err(|_| VfsError::IoError)?;
                 sb_bucket.put("magic", 0x44424653u32.to_be_bytes()).unwrap(); // "DBFS"
-                sb_bucket.put("disk_size", adapter.size().to_be_bytes()).unwrap();
-                
+                sb_bucket.put("disk_size", disk_size.to_be_bytes()).unwrap();
+                sb_bucket
+                    .put("reserved_size", reserved_size.to_be_bytes())
+                    .unwrap();
+
                 // 初始化根目录元数据 (Inode 1)
                 let root_meta = crate::models::InodeMetadata {
                     ino: 1,
                     size: 0,
-                    mode: 0o040755, 
+                    mode: 0o040755,
                     nlink: 2,
                     extents: alloc::vec::Vec::new(),
                     atime: 0,
                     mtime: 0,
+                    uid: 0,
+                    entry_count: Some(0),
+                    version: crate::models::INODE_METADATA_VERSION,
                 };
                 let bucket = tx.get_bucket("inodes").unwrap();
                 let meta_data = serde_json::to_vec(&root_meta).unwrap();
                 bucket.put(1u64.to_be_bytes(), meta_data).unwrap();
-                
+
                 // 创建根目录的目录项 bucket
                 let root_dir = tx.create_bucket("dir_1").map_err(|_| VfsError::IoError)?;
                 root_dir.put(".", 1u64.to_be_bytes()).unwrap();
@@ -282,17 +427,38 @@ impl VfsFsType for DbfsFsType {
             tx.commit().map_err(|_| VfsError::IoError)?;
         }
 
+        // 4. 初始化 LogManager: 保留区之前归 jammdb 使用，之后为日志追加区
+        let reserved_size = {
+            let tx = db.tx(false).map_err(|_| VfsError::IoError)?;
+            let sb_bucket = tx.get_bucket("super_blk").map_err(|_| VfsError::IoError)?;
+            // 早期格式化的文件系统没有记录保留区大小, 固定为 32MB
+            match sb_bucket.get_kv("reserved_size") {
+                Some(kv) => {
+                    u64::from_be_bytes(kv.value().try_into().map_err(|_| VfsError::Invalid)?)
+                }
+                None => LEGACY_RESERVED_SIZE,
+            }
+        };
+        check_layout(disk_size, reserved_size)?;
+        // 从上次卸载时记录的写指针继续追加; 异常关机后扫描已有的 extent 重新确定
+        let next_append_pos = crate::tx_engine::recover_append_pos(&db, reserved_size)
+            .map_err(|_| VfsError::IoError)?;
+        let log_manager =
+            LogManager::new(adapter.clone(), reserved_size).resume_at(next_append_pos);
+
         let engine = Arc::new(Mutex::new(TransactionEngine::new(db, log_manager)));
-        
+
         // 使用 Arc::new_cyclic 处理自引用弱指针
         let sb = Arc::new_cyclic(|weak| DbfsSuperBlock {
             engine: engine.clone(),
+            locks: Arc::default(),
             self_weak: weak.clone(),
         });
-        
+
         let root_inode = Arc::new(DbfsInode {
             ino: 1,
             engine,
+            locks: sb.locks.clone(),
             sb: Arc::downgrade(&sb),
         });
 
@@ -301,7 +467,11 @@ impl VfsFsType for DbfsFsType {
 
     fn kill_sb(&self, sb: Arc<dyn VfsSuperBlock>) -> VfsResult<()> {
         sb.sync_fs(true)?;
-        Ok(())
+        match sb.downcast_arc::<DbfsSuperBlock<Arc<VfsBlockDeviceAdapter>>>() {
+            Ok(sb) => sb.unmount(),
+            // 不是由本类型挂载的超级块, 没有需要写回的状态
+            Err(_) => Ok(()),
+        }
     }
 
     fn fs_flag(&self) -> vfscore::fstype::FileSystemFlags {
@@ -313,82 +483,186 @@ impl VfsFsType for DbfsFsType {
     }
 }
 
+/// 按 Inode 号分片的锁的分片数
+pub const INODE_LOCK_SHARDS: usize = 64;
+
+/// 按 Inode 号分片的读写锁
+///
+/// 数据读写期间只持有所在 Inode 的分片锁, 引擎的锁只在元数据步骤中短暂持有, 不同文件的读写
+/// 因此可以并发。分片数固定, 号码对分片数同余的 Inode 共用一把锁。
+pub struct InodeLocks {
+    shards: [RwLock<()>; INODE_LOCK_SHARDS],
+    /// 需要等待其他持有者的加锁次数
+    contended: AtomicU64,
+}
+
+impl Default for InodeLocks {
+    fn default() -> Self {
+        Self {
+            shards: core::array::from_fn(|_| RwLock::new(())),
+            contended: AtomicU64::new(0),
+        }
+    }
+}
+
+impl InodeLocks {
+    fn shard(&self, ino: u64) -> &RwLock<()> {
+        &self.shards[(ino % INODE_LOCK_SHARDS as u64) as usize]
+    }
+
+    /// 以共享方式锁住 `ino`
+    pub fn read(&self, ino: u64) -> RwLockReadGuard<'_, ()> {
+        let shard = self.shard(ino);
+        shard.try_read().unwrap_or_else(|| {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            shard.read()
+        })
+    }
+
+    /// 以独占方式锁住 `ino`
+    pub fn write(&self, ino: u64) -> RwLockWriteGuard<'_, ()> {
+        let shard = self.shard(ino);
+        shard.try_write().unwrap_or_else(|| {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            shard.write()
+        })
+    }
+
+    /// 至今需要等待的加锁次数
+    pub fn contended(&self) -> u64 {
+        self.contended.load(Ordering::Relaxed)
+    }
+}
+
 /// 适配 rvfs 的 Inode 实现
 pub struct DbfsInode<D: BlockDevice> {
     pub ino: u64,
     pub engine: Arc<Mutex<TransactionEngine<D>>>,
+    pub locks: Arc<InodeLocks>,
     pub sb: Weak<DbfsSuperBlock<D>>,
 }
 
+impl<D: BlockDevice + 'static> DbfsInode<D> {
+    /// 文件的物理布局, 参见 [`TransactionEngine::extent_map`]
+    pub fn extent_map(&self) -> VfsResult<alloc::vec::Vec<crate::common::ExtentMapping>> {
+        let _guard = self.locks.read(self.ino);
+        self.engine
+            .lock()
+            .extent_map(self.ino)
+            .map_err(|_| VfsError::IoError)
+    }
+}
+
 impl<D: BlockDevice + 'static> VfsFile for DbfsInode<D> {
     /// 翻译 rvfs 的写操作
+    ///
+    /// 引擎的锁只在预留日志空间和提交 extent 时持有, 写设备期间只持有本文件的锁。
     fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
-        let mut engine = self.engine.lock();
-        
-        engine.write_file_transactional(self.ino, offset, buf)
+        let _guard = self.locks.write(self.ino);
+
+        let mut staged = self
+            .engine
+            .lock()
+            .stage_write(self.ino, offset, buf)
+            .map_err(|_| vfscore::VfsError::IoError)?;
+        staged
+            .write_data(buf)
+            .map_err(|_| vfscore::VfsError::IoError)?;
+        self.engine
+            .lock()
+            .commit_staged(staged)
             .map_err(|_| vfscore::VfsError::IoError)?;
-            
+
         Ok(buf.len())
     }
 
     /// 翻译 rvfs 的读操作
+    ///
+    /// 通过分离的只读句柄读取, 读设备期间不持有引擎的锁。
     fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
-        let engine = self.engine.lock();
-        
-        engine.read_file(self.ino, offset, buf)
+        let _guard = self.locks.read(self.ino);
+        let reader = self.engine.lock().reader();
+
+        reader
+            .read_snapshot(self.ino, offset, buf)
             .map_err(|_| vfscore::VfsError::IoError)
     }
 
     /// 读取目录项
     fn readdir(&self, start_index: usize) -> VfsResult<Option<VfsDirEntry>> {
         let engine = self.engine.lock();
-        
+
         // 1. 获取当前 Inode 元数据确认是目录
-        let meta = engine.get_metadata(self.ino)
+        let meta = engine
+            .get_metadata(self.ino)
             .map_err(|_| VfsError::IoError)?;
-            
+
         if (meta.mode & 0o170000) != 0o040000 {
             return Err(VfsError::NotDir);
         }
 
         // 2. 调用 tx_engine 获取目录项
-        let entry = engine.list_dentries(self.ino, start_index)
+        let entry = engine
+            .list_dentries(self.ino, start_index)
             .map_err(|_| VfsError::IoError)?;
-            
+
         if let Some((name, ino)) = entry {
             // 获取子节点元数据以确定类型
-            let child_meta = engine.get_metadata(ino)
-                .map_err(|_| VfsError::IoError)?;
-            
+            let child_meta = engine.get_metadata(ino).map_err(|_| VfsError::IoError)?;
+
             let ty = if (child_meta.mode & 0o170000) == 0o040000 {
                 VfsNodeType::Dir
             } else {
                 VfsNodeType::File
             };
-            
-            Ok(Some(VfsDirEntry {
-                ino,
-                ty,
-                name,
-            }))
+
+            Ok(Some(VfsDirEntry { ino, ty, name }))
         } else {
             Ok(None)
         }
     }
 
-    fn fsync(&self) -> VfsResult<()> {
-        // DBFS-T 的写操作已经是事务性的，每次 write_at 都会 commit
-        Ok(())
+    fn fsync(&self, datasync: bool) -> VfsResult<()> {
+        // DBFS-T 的写操作已经是事务性的，每次 write_at 都会 commit; 完整同步时补上延迟的 atime
+        self.engine
+            .lock()
+            .fsync(self.ino, datasync)
+            .map_err(|_| VfsError::IoError)
     }
 
     fn flush(&self) -> VfsResult<()> {
         Ok(())
     }
+
+    /// DBFS 专用的命令, 作用于整个文件系统, 可以对任意文件或目录发出
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        let mut engine = self.engine.lock();
+        match cmd {
+            // 返回日志写指针后退的字节数
+            DBFS_IOC_GC => Ok(engine.gc()? as usize),
+            DBFS_IOC_CHECKPOINT => {
+                engine.force_checkpoint()?;
+                Ok(0)
+            }
+            // `arg` 非 0 时修复; 返回发现的问题数
+            DBFS_IOC_FSCK => {
+                let report = engine.fsck(arg != 0)?;
+                Ok(report.dangling_dentries.len()
+                    + report.orphaned_inodes.len()
+                    + report.nlink_mismatches.len()
+                    + report.bad_extents.len())
+            }
+            _ => Err(VfsError::NoSys),
+        }
+    }
 }
 
 impl<D: BlockDevice + 'static> VfsInode for DbfsInode<D> {
     fn get_super_block(&self) -> VfsResult<Arc<dyn VfsSuperBlock>> {
-        self.sb.upgrade().map(|sb| sb as Arc<dyn VfsSuperBlock>).ok_or(VfsError::Invalid)
+        self.sb
+            .upgrade()
+            .map(|sb| sb as Arc<dyn VfsSuperBlock>)
+            .ok_or(VfsError::Invalid)
     }
 
     fn node_perm(&self) -> VfsNodePerm {
@@ -401,9 +675,10 @@ impl<D: BlockDevice + 'static> VfsInode for DbfsInode<D> {
 
     fn get_attr(&self) -> VfsResult<VfsFileStat> {
         let engine = self.engine.lock();
-        let meta = engine.get_metadata(self.ino)
+        let meta = engine
+            .get_metadata(self.ino)
             .map_err(|_| vfscore::VfsError::IoError)?;
-        
+
         let mut attr = VfsFileStat::default();
         attr.st_size = meta.size;
         attr.st_ino = self.ino;
@@ -411,35 +686,51 @@ impl<D: BlockDevice + 'static> VfsInode for DbfsInode<D> {
         attr.st_nlink = meta.nlink;
         attr.st_uid = 0;
         attr.st_gid = 0;
-        attr.st_atime = VfsTimeSpec::new(meta.atime, 0);
+        attr.st_atime = VfsTimeSpec::new(engine.pending_atime(self.ino).unwrap_or(meta.atime), 0);
         attr.st_mtime = VfsTimeSpec::new(meta.mtime, 0);
         attr.st_ctime = VfsTimeSpec::new(meta.mtime, 0); // 暂用 mtime
-        
+
         Ok(attr)
     }
 
     fn set_attr(&self, attr: InodeAttr) -> VfsResult<()> {
         let mut engine = self.engine.lock();
-        let mut meta = engine.get_metadata(self.ino)
+        let mut meta = engine
+            .get_metadata(self.ino)
             .map_err(|_| VfsError::IoError)?;
-            
+
         meta.mode = attr.mode;
         meta.size = attr.size;
         meta.atime = attr.atime.tv_sec;
         meta.mtime = attr.mtime.tv_sec;
-        
-        engine.update_metadata(&meta)
+
+        engine
+            .update_metadata(&meta)
             .map_err(|_| VfsError::IoError)?;
         Ok(())
     }
 
+    fn update_time(&self, time: VfsTime, _now: VfsTimeSpec) -> VfsResult<()> {
+        let mut engine = self.engine.lock();
+        match time {
+            // atime 延迟到 fsync 时提交
+            VfsTime::AccessTime(ts) => engine.touch_atime(self.ino, ts.sec as i64),
+            VfsTime::ModifiedTime(ts) => engine.get_metadata(self.ino).and_then(|mut meta| {
+                meta.mtime = ts.sec as i64;
+                engine.update_metadata(&meta)
+            }),
+            _ => Ok(()),
+        }
+        .map_err(|_| VfsError::IoError)
+    }
+
     fn inode_type(&self) -> VfsNodeType {
         let engine = self.engine.lock();
         let meta = match engine.get_metadata(self.ino) {
             Ok(m) => m,
             Err(_) => return VfsNodeType::Unknown,
         };
-        
+
         if (meta.mode & 0o170000) == 0o040000 {
             VfsNodeType::Dir
         } else {
@@ -447,155 +738,258 @@ impl<D: BlockDevice + 'static> VfsInode for DbfsInode<D> {
         }
     }
 
-    fn create(&self, name: &str, _ty: VfsNodeType, perm: VfsNodePerm, _rdev: Option<u64>) -> VfsResult<Arc<dyn VfsInode>> {
+    fn create(
+        &self,
+        name: &str,
+        _ty: VfsNodeType,
+        perm: VfsNodePerm,
+        _rdev: Option<u64>,
+    ) -> VfsResult<Arc<dyn VfsInode>> {
         let mut engine = self.engine.lock();
-        
-        // 1. 分配新的 Inode 号 (普通文件)
+
+        // 分配新的 Inode 号 (普通文件) 并创建目录项, 名字已存在时失败
         let mode = 0o100000 | (perm.bits() as u32);
-        let new_ino = engine.allocate_inode(mode)
-            .map_err(|_| VfsError::IoError)?;
-            
-        // 2. 在数据库中创建目录项
-        engine.add_dentry(self.ino, name, new_ino)
-            .map_err(|_| VfsError::IoError)?;
-            
+        let new_ino = engine
+            .create_exclusive(self.ino, name, mode)
+            .map_err(|e| match e {
+                crate::common::DbfsError::FileExists => VfsError::EExist,
+                _ => VfsError::IoError,
+            })?;
+
         Ok(Arc::new(DbfsInode {
             ino: new_ino,
             engine: self.engine.clone(),
+            locks: self.locks.clone(),
             sb: self.sb.clone(),
         }))
     }
 
     fn mkdir(&self, name: &str, perm: VfsNodePerm) -> VfsResult<Arc<dyn VfsInode>> {
         let mut engine = self.engine.lock();
-        
+
         let mode = 0o040000 | (perm.bits() as u32);
-        let new_ino = engine.allocate_inode(mode)
-            .map_err(|_| VfsError::IoError)?;
-            
-        engine.add_dentry(self.ino, name, new_ino)
+        let new_ino = engine.allocate_inode(mode).map_err(|_| VfsError::IoError)?;
+
+        engine
+            .add_dentry(self.ino, name, new_ino)
             .map_err(|_| VfsError::IoError)?;
-            
+
         Ok(Arc::new(DbfsInode {
             ino: new_ino,
             engine: self.engine.clone(),
+            locks: self.locks.clone(),
             sb: self.sb.clone(),
         }))
     }
 
+    fn link(&self, name: &str, src: Arc<dyn VfsInode>) -> VfsResult<Arc<dyn VfsInode>> {
+        let src = src
+            .downcast_arc::<DbfsInode<D>>()
+            .map_err(|_| VfsError::Invalid)?;
+        let mut engine = self.engine.lock();
+
+        // 1. 不允许对目录建立硬链接
+        let src_meta = engine
+            .get_metadata(src.ino)
+            .map_err(|_| VfsError::IoError)?;
+        if (src_meta.mode & 0o170000) == 0o040000 {
+            return Err(VfsError::PermissionDenied);
+        }
+        if engine.lookup_dentry(self.ino, name).is_ok() {
+            return Err(VfsError::EExist);
+        }
+
+        // 2. 添加目录项并增加 nlink, 在同一个事务中提交
+        engine
+            .batch(|ctx| {
+                let mut meta = ctx.get_metadata(src.ino)?;
+                meta.nlink += 1;
+                ctx.update_metadata(&meta)?;
+                ctx.add_dentry(self.ino, name, src.ino)
+            })
+            .map_err(|_| VfsError::IoError)?;
+
+        Ok(src)
+    }
+
     fn lookup(&self, name: &str) -> VfsResult<Arc<dyn VfsInode>> {
         let engine = self.engine.lock();
-        let ino = engine.lookup_dentry(self.ino, name)
+        let ino = engine
+            .lookup_dentry(self.ino, name)
             .map_err(|_| VfsError::NoEntry)?;
-            
+
         Ok(Arc::new(DbfsInode {
             ino,
             engine: self.engine.clone(),
+            locks: self.locks.clone(),
             sb: self.sb.clone(),
         }))
     }
 
     fn unlink(&self, name: &str) -> VfsResult<()> {
         let mut engine = self.engine.lock();
-        
+
         // 1. 查找子节点 Inode
-        let child_ino = engine.lookup_dentry(self.ino, name)
+        let child_ino = engine
+            .lookup_dentry(self.ino, name)
             .map_err(|_| VfsError::NoEntry)?;
-            
+
         // 2. 删除目录项
-        engine.delete_dentry(self.ino, name)
+        engine
+            .delete_dentry(self.ino, name)
             .map_err(|_| VfsError::IoError)?;
-            
+
         // 3. 更新子节点 nlink
-        let mut child_meta = engine.get_metadata(child_ino)
+        let mut child_meta = engine
+            .get_metadata(child_ino)
             .map_err(|_| VfsError::IoError)?;
-        
+
         if child_meta.nlink > 0 {
             child_meta.nlink -= 1;
         }
-        
+
         if child_meta.nlink == 0 {
             // 如果链接数为 0，删除 Inode (简单处理，实际可能需要延迟删除)
-            engine.delete_inode(child_ino)
+            engine
+                .delete_inode(child_ino)
                 .map_err(|_| VfsError::IoError)?;
         } else {
-            engine.update_metadata(&child_meta)
+            engine
+                .update_metadata(&child_meta)
                 .map_err(|_| VfsError::IoError)?;
         }
-        
+
         Ok(())
     }
 
     fn rmdir(&self, name: &str) -> VfsResult<()> {
         let mut engine = self.engine.lock();
-        
+
         // 1. 查找子节点
-        let child_ino = engine.lookup_dentry(self.ino, name)
+        let child_ino = engine
+            .lookup_dentry(self.ino, name)
             .map_err(|_| VfsError::NoEntry)?;
-            
+
         // 2. 检查是否为目录
-        let child_meta = engine.get_metadata(child_ino)
+        let child_meta = engine
+            .get_metadata(child_ino)
             .map_err(|_| VfsError::IoError)?;
         if (child_meta.mode & 0o170000) != 0o040000 {
             return Err(VfsError::NotDir);
         }
-        
+
         // 3. 检查目录是否为空
-        if engine.list_dentries(child_ino, 0).map_err(|_| VfsError::IoError)?.is_some() {
+        if !engine
+            .is_dir_empty(child_ino)
+            .map_err(|_| VfsError::IoError)?
+        {
             return Err(VfsError::NotEmpty);
         }
-        
+
         // 4. 删除目录项和 Inode
-        engine.delete_dentry(self.ino, name)
+        engine
+            .delete_dentry(self.ino, name)
             .map_err(|_| VfsError::IoError)?;
-        engine.delete_inode(child_ino)
+        engine
+            .delete_inode(child_ino)
             .map_err(|_| VfsError::IoError)?;
-            
+
         Ok(())
     }
 
     fn truncate(&self, len: u64) -> VfsResult<()> {
+        let _guard = self.locks.write(self.ino);
         let mut engine = self.engine.lock();
-        engine.truncate_file(self.ino, len)
+        engine
+            .truncate_file(self.ino, len)
             .map_err(|_| VfsError::IoError)?;
         Ok(())
     }
 
-    fn rename_to(&self, old_name: &str, new_parent: Arc<dyn VfsInode>, new_name: &str, _flag: vfscore::utils::VfsRenameFlag) -> VfsResult<()> {
+    fn rename_to(
+        &self,
+        old_name: &str,
+        new_parent: Arc<dyn VfsInode>,
+        new_name: &str,
+        _flag: vfscore::utils::VfsRenameFlag,
+    ) -> VfsResult<()> {
         let mut engine = self.engine.lock();
-        
+
         // 1. 查找旧节点
-        let ino = engine.lookup_dentry(self.ino, old_name)
+        let ino = engine
+            .lookup_dentry(self.ino, old_name)
             .map_err(|_| VfsError::NoEntry)?;
-            
+
         // 2. 获取新父节点的 Inode (假定它是 DbfsInode)
-        let new_parent_dbfs = new_parent.downcast_ref::<DbfsInode<D>>()
+        let new_parent_dbfs = new_parent
+            .downcast_ref::<DbfsInode<D>>()
             .ok_or(VfsError::Invalid)?;
-            
+
         // 3. 在新位置添加目录项
-        engine.add_dentry(new_parent_dbfs.ino, new_name, ino)
+        engine
+            .add_dentry(new_parent_dbfs.ino, new_name, ino)
             .map_err(|_| VfsError::IoError)?;
-            
+
         // 4. 删除旧位置目录项
-        engine.delete_dentry(self.ino, old_name)
+        engine
+            .delete_dentry(self.ino, old_name)
             .map_err(|_| VfsError::IoError)?;
-            
+
         Ok(())
     }
 }
 
-
 /// 适配 rvfs 的超级块实现
 pub struct DbfsSuperBlock<D: BlockDevice> {
     pub engine: Arc<Mutex<TransactionEngine<D>>>,
+    pub locks: Arc<InodeLocks>,
     pub self_weak: Weak<DbfsSuperBlock<D>>,
 }
 
+impl<D: BlockDevice + 'static> DbfsSuperBlock<D> {
+    /// 卸载: 写回未持久化的状态并记录日志写指针, 参见 [`TransactionEngine::unmount`]
+    pub fn unmount(&self) -> VfsResult<()> {
+        self.engine.lock().unmount().map_err(|e| {
+            log::error!("DBFS unmount failed: {:?}", e);
+            VfsError::IoError
+        })
+    }
+
+    /// 把文件系统此刻的状态记录为名为 `name` 的快照
+    pub fn snapshot(&self, name: &str) -> VfsResult<()> {
+        self.engine.lock().snapshot(name).map_err(|e| match e {
+            crate::common::DbfsError::FileExists => VfsError::EExist,
+            crate::common::DbfsError::NameTooLong => VfsError::NameTooLong,
+            _ => VfsError::IoError,
+        })
+    }
+
+    /// 以只读方式挂载快照 `name`, 返回快照的根目录
+    pub fn mount_snapshot(&self, name: &str) -> VfsResult<Arc<dyn VfsInode>> {
+        let view = self
+            .engine
+            .lock()
+            .open_snapshot(name)
+            .map_err(|e| match e {
+                crate::common::DbfsError::NotFound => VfsError::NoEntry,
+                _ => VfsError::IoError,
+            })?;
+        let engine = Arc::new(Mutex::new(view));
+        let sb = Arc::new_cyclic(|weak| DbfsSuperBlock {
+            engine: engine.clone(),
+            locks: Arc::default(),
+            self_weak: weak.clone(),
+        });
+        sb.root_inode()
+    }
+}
+
 impl<D: BlockDevice + 'static> VfsSuperBlock for DbfsSuperBlock<D> {
     fn root_inode(&self) -> VfsResult<Arc<dyn VfsInode>> {
         Ok(Arc::new(DbfsInode {
             ino: 1, // 根目录约定为 1
             engine: self.engine.clone(),
+            locks: self.locks.clone(),
             sb: self.self_weak.clone(),
         }))
     }
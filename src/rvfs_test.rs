@@ -1,10 +1,17 @@
+use crate::common::DbfsResult;
+use crate::log_manager::{BlockDevice, LogManager};
+use crate::rvfs_adapter::{DbfsFsType, DbfsInode};
+use crate::tx_engine::TransactionEngine;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use jammdb::{
+    memfile::{FakeMap, FileOpenOptions},
+    DB,
+};
 use spin::Mutex;
-use vfscore::{VfsInode, VfsNodeType, VfsResult, VfsFile, VfsDentry};
-use vfscore::utils::{VfsFileStat, VfsNodePerm, VfsTimeSpec};
-use crate::rvfs_adapter::{DbfsFsType, DbfsInode};
 use vfscore::fstype::VfsFsType;
+use vfscore::utils::{VfsFileStat, VfsNodePerm, VfsTimeSpec};
+use vfscore::{VfsDentry, VfsFile, VfsInode, VfsNodeType, VfsResult};
 
 /// 模拟块设备
 pub struct RamDisk {
@@ -54,11 +61,66 @@ impl VfsInode for RamDisk {
     fn inode_type(&self) -> VfsNodeType {
         VfsNodeType::BlockDevice
     }
-    
+
     fn get_super_block(&self) -> VfsResult<Arc<dyn vfscore::VfsSuperBlock>> {
         Err(vfscore::VfsError::Invalid)
     }
-    fn node_perm(&self) -> VfsNodePerm { VfsNodePerm::all() }
+    fn node_perm(&self) -> VfsNodePerm {
+        VfsNodePerm::all()
+    }
+}
+
+/// 直接供 LogManager 使用的内存块设备
+pub struct MemDevice {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemDevice {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: Mutex::new(alloc::vec![0u8; size]),
+        }
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        let data = self.data.lock();
+        let end = core::cmp::min(pos as usize + buf.len(), data.len());
+        let len = end.saturating_sub(pos as usize);
+        buf[..len].copy_from_slice(&data[pos as usize..end]);
+        Ok(len)
+    }
+
+    fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+        let mut data = self.data.lock();
+        let end = core::cmp::min(pos as usize + buf.len(), data.len());
+        let len = end.saturating_sub(pos as usize);
+        data[pos as usize..end].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> u64 {
+        self.data.lock().len() as u64
+    }
+
+    fn with_slice(&self, pos: u64, len: usize, f: &mut dyn FnMut(&[u8])) -> DbfsResult<()> {
+        let data = self.data.lock();
+        let end = core::cmp::min(pos as usize + len, data.len());
+        f(&data[core::cmp::min(pos as usize, end)..end]);
+        Ok(())
+    }
+}
+
+/// 使用独立内存数据库和 1MB 日志设备的事务引擎
+fn new_engine() -> TransactionEngine<MemDevice> {
+    let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "engine-test.db").unwrap();
+    {
+        let tx = db.tx(true).unwrap();
+        tx.get_or_create_bucket("inodes").unwrap();
+        tx.commit().unwrap();
+    }
+    TransactionEngine::new(db, LogManager::new(MemDevice::new(1024 * 1024), 0))
 }
 
 #[cfg(test)]
@@ -70,32 +132,49 @@ mod tests {
     fn test_dbfs_vfs_basic() {
         let ram_disk = Arc::new(RamDisk::new(64 * 1024 * 1024)); // 64MB
         let fs_type = Arc::new(DbfsFsType);
-        
+
         // 1. 挂载
-        let root_dentry = fs_type.mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[]).expect("Mount failed");
+        let root_dentry = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed");
         let root_inode = root_dentry.inode().expect("Get root inode failed");
-        
+
         // 2. 创建目录
-        root_inode.mkdir("test_dir", VfsNodePerm::from_bits_truncate(0o755)).expect("Mkdir failed");
-        
+        root_inode
+            .mkdir("test_dir", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+
         // 3. 查找目录
         let dir_inode = root_inode.lookup("test_dir").expect("Lookup dir failed");
         assert_eq!(dir_inode.inode_type(), VfsNodeType::Dir);
-        
+
         // 4. 在目录中创建文件
-        dir_inode.create("test_file.txt", VfsNodeType::File, VfsNodePerm::from_bits_truncate(0o644), None).expect("Create file failed");
-        
+        dir_inode
+            .create(
+                "test_file.txt",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+
         // 5. 查找文件并写入
-        let file_inode = dir_inode.lookup("test_file.txt").expect("Lookup file failed");
+        let file_inode = dir_inode
+            .lookup("test_file.txt")
+            .expect("Lookup file failed");
         let test_data = b"Hello DBFS-T via VFS!";
-        file_inode.write_at(0, test_data).expect("Write file failed");
-        
+        file_inode
+            .write_at(0, test_data)
+            .expect("Write file failed");
+
         // 6. 读取文件并验证
         let mut read_buf = [0u8; 32];
-        let n = file_inode.read_at(0, &mut read_buf).expect("Read file failed");
+        let n = file_inode
+            .read_at(0, &mut read_buf)
+            .expect("Read file failed");
         assert_eq!(n, test_data.len());
         assert_eq!(&read_buf[..n], test_data);
-        
+
         // 7. 遍历目录 (readdir)
         let mut entries = Vec::new();
         let mut idx = 0;
@@ -108,13 +187,2445 @@ mod tests {
         assert!(entries.contains(&".".to_string()));
         assert!(entries.contains(&"..".to_string()));
         assert!(entries.contains(&"test_file.txt".to_string()));
-        
+
         // 8. 测试截断
         file_inode.truncate(5).expect("Truncate failed");
         assert_eq!(file_inode.get_attr().unwrap().st_size, 5);
         let mut read_buf_small = [0u8; 10];
-        let n = file_inode.read_at(0, &mut read_buf_small).expect("Read truncated file failed");
+        let n = file_inode
+            .read_at(0, &mut read_buf_small)
+            .expect("Read truncated file failed");
         assert_eq!(n, 5);
         assert_eq!(&read_buf_small[..n], b"Hello");
     }
+
+    #[test]
+    fn test_batch_single_commit() {
+        let mut engine = new_engine();
+        let before = engine.commit_count();
+
+        // 创建文件并写入数据, 只提交一次
+        engine
+            .batch(|ctx| {
+                let ino = ctx.allocate_inode(0o100644)?;
+                ctx.add_dentry(1, "batched.txt", ino)?;
+                ctx.write(ino, 0, b"batched data")?;
+                Ok(())
+            })
+            .expect("Batch failed");
+        assert_eq!(engine.commit_count(), before + 1);
+
+        let ino = engine
+            .lookup_dentry(1, "batched.txt")
+            .expect("Lookup failed");
+        let mut buf = [0u8; 16];
+        let n = engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"batched data");
+
+        // 批处理失败时不提交任何修改
+        let result = engine.batch(|ctx| {
+            let ino = ctx.allocate_inode(0o100644)?;
+            ctx.add_dentry(1, "aborted.txt", ino)?;
+            Err(crate::common::DbfsError::Io)
+        });
+        assert!(result.is_err());
+        assert_eq!(engine.commit_count(), before + 1);
+        assert!(engine.lookup_dentry(1, "aborted.txt").is_err());
+
+        // 只统计 jammdb 真正完成的提交
+        use crate::tx_engine::CrashPoint;
+        engine.set_crash_hook(|p| p == CrashPoint::BeforeCommit);
+        assert!(engine.truncate_file(ino, 4).is_err());
+        assert_eq!(engine.commit_count(), before + 1);
+        engine.set_crash_hook(|p| p == CrashPoint::AfterCommit);
+        assert!(engine.truncate_file(ino, 4).is_err());
+        assert_eq!(engine.commit_count(), before + 2);
+        engine.clear_crash_hook();
+    }
+
+    #[test]
+    fn test_append_file() {
+        let mut engine = new_engine();
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+
+        assert_eq!(
+            engine.append_file(ino, b"hello ").expect("Append failed"),
+            0
+        );
+        assert_eq!(engine.append_file(ino, b"world").expect("Append failed"), 6);
+
+        let mut buf = [0u8; 16];
+        let n = engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"hello world");
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            11
+        );
+    }
+
+    #[test]
+    fn test_with_block_zero_copy() {
+        let mut engine = new_engine();
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        engine
+            .write_file_transactional(ino, 0, b"zero copy read")
+            .expect("Write failed");
+        // 覆盖中间的一段, 借出的切片不能越过新 extent 的起点
+        engine
+            .write_file_transactional(ino, 5, b"COPY")
+            .expect("Write failed");
+
+        let mut copied = [0u8; 32];
+        let n = engine.read_file(ino, 0, &mut copied).expect("Read failed");
+        assert_eq!(&copied[..n], b"zero COPY read");
+
+        // 逐段借用并拼接, 结果应与拷贝读取一致
+        let mut borrowed = Vec::new();
+        let mut offset = 0;
+        loop {
+            let len = engine
+                .with_block(ino, offset, |slice| {
+                    borrowed.extend_from_slice(slice);
+                    slice.len()
+                })
+                .expect("With block failed");
+            if len == 0 {
+                break;
+            }
+            offset += len as u64;
+        }
+        assert_eq!(borrowed.as_slice(), &copied[..n]);
+
+        // 文件末尾之后得到空切片
+        let len = engine
+            .with_block(ino, 100, |slice| slice.len())
+            .expect("With block failed");
+        assert_eq!(len, 0);
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let ram_disk = Arc::new(RamDisk::new(64 * 1024 * 1024));
+        let fs_type = Arc::new(DbfsFsType);
+        let root_dentry = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed");
+        let root_inode = root_dentry.inode().expect("Get root inode failed");
+
+        // 1. 创建文件并建立硬链接
+        let file = root_inode
+            .create(
+                "link_src.txt",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        root_inode
+            .link("link_dst.txt", file.clone())
+            .expect("Link failed");
+        assert_eq!(file.get_attr().unwrap().st_nlink, 2);
+
+        // 2. 通过一个名字写入, 通过另一个名字读到修改
+        let dst = root_inode
+            .lookup("link_dst.txt")
+            .expect("Lookup link failed");
+        dst.write_at(0, b"written via link").expect("Write failed");
+        let src = root_inode
+            .lookup("link_src.txt")
+            .expect("Lookup file failed");
+        let mut buf = [0u8; 32];
+        let n = src.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"written via link");
+
+        // 3. 不允许对目录建立硬链接
+        let dir = root_inode
+            .mkdir("link_dir", VfsNodePerm::from_bits_truncate(0o755))
+            .expect("Mkdir failed");
+        assert!(root_inode.link("link_dir2", dir).is_err());
+
+        // 4. 删除一个名字后数据仍可通过另一个名字访问
+        root_inode.unlink("link_src.txt").expect("Unlink failed");
+        let n = dst.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"written via link");
+        assert_eq!(dst.get_attr().unwrap().st_nlink, 1);
+    }
+
+    #[test]
+    fn test_rmdir_empty_check() {
+        let ram_disk = Arc::new(RamDisk::new(64 * 1024 * 1024));
+        let fs_type = Arc::new(DbfsFsType);
+        let root_dentry = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed");
+        let root_inode = root_dentry.inode().expect("Get root inode failed");
+        let perm = VfsNodePerm::from_bits_truncate(0o755);
+
+        // 1. 空目录可以删除
+        root_inode.mkdir("empty_dir", perm).expect("Mkdir failed");
+        root_inode
+            .rmdir("empty_dir")
+            .expect("Rmdir empty dir failed");
+        assert!(root_inode.lookup("empty_dir").is_err());
+
+        // 2. 非空目录返回 NotEmpty
+        let dir = root_inode.mkdir("full_dir", perm).expect("Mkdir failed");
+        dir.create(
+            "child.txt",
+            VfsNodeType::File,
+            VfsNodePerm::from_bits_truncate(0o644),
+            None,
+        )
+        .expect("Create file failed");
+        assert_eq!(
+            root_inode.rmdir("full_dir").err(),
+            Some(vfscore::VfsError::NotEmpty)
+        );
+
+        // 3. 清空后再次删除成功
+        dir.unlink("child.txt").expect("Unlink failed");
+        root_inode
+            .rmdir("full_dir")
+            .expect("Rmdir emptied dir failed");
+    }
+
+    #[test]
+    fn test_is_dir_empty_ignores_dot_entries() {
+        let mut engine = new_engine();
+        let ino = engine
+            .allocate_inode(0o040755)
+            .expect("Allocate inode failed");
+        assert!(engine.is_dir_empty(ino).expect("Check failed"));
+
+        // `.` 和 `..` 不能作为普通目录项添加
+        assert!(matches!(
+            engine.add_dentry(ino, ".", ino),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+        assert!(matches!(
+            engine.add_dentry(ino, "..", 1),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+        assert!(engine.is_dir_empty(ino).expect("Check failed"));
+
+        engine.add_dentry(ino, "a", 7).expect("Add dentry failed");
+        assert!(!engine.is_dir_empty(ino).expect("Check failed"));
+    }
+
+    #[test]
+    fn test_lookup_or_create_race() {
+        let engine = Arc::new(Mutex::new(new_engine()));
+
+        // 两个线程同时创建同一个名字, 只有一个线程真正创建
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let engine = engine.clone();
+                std::thread::spawn(move || {
+                    engine
+                        .lock()
+                        .lookup_or_create(1, "raced.txt", 0o100644)
+                        .expect("Lookup or create failed")
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles
+            .into_iter()
+            .map(|h| h.join().expect("Creator thread panicked"))
+            .collect();
+
+        assert_eq!(results.iter().filter(|(_, created)| *created).count(), 1);
+        assert_eq!(results[0].0, results[1].0);
+
+        // O_EXCL: 名字已存在时失败
+        let mut engine = engine.lock();
+        assert!(matches!(
+            engine.create_exclusive(1, "raced.txt", 0o100644),
+            Err(crate::common::DbfsError::FileExists)
+        ));
+        let ino = engine
+            .create_exclusive(1, "fresh.txt", 0o100644)
+            .expect("Create failed");
+        assert_eq!(
+            engine.lookup_dentry(1, "fresh.txt").expect("Lookup failed"),
+            ino
+        );
+    }
+
+    #[cfg(feature = "rvfs2")]
+    #[test]
+    fn test_inode_numbers_shared_across_adapters() {
+        use crate::common::{DbfsFileType, DbfsPermission, DbfsTimeSpec};
+
+        // 两个适配器挂载同一个全局数据库
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "shared-ino-test.db").unwrap();
+        crate::init_dbfs(db);
+        crate::fs_common::dbfs_common_root_inode(0, 0, DbfsTimeSpec::default())
+            .expect("Root inode failed");
+        let db = (**crate::clone_db()).clone();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let mut engine = TransactionEngine::new(db, LogManager::new(MemDevice::new(64 * 1024), 0));
+
+        // 交替通过两个适配器分配 Inode 号, 不能出现重复
+        let mut inos = Vec::new();
+        for i in 0..8 {
+            inos.push(
+                engine
+                    .allocate_inode(0o100644)
+                    .expect("Allocate inode failed") as usize,
+            );
+            let name = alloc::format!("shared_ino_{}", i);
+            inos.push(
+                crate::rvfs2::common::dbfs_create(
+                    &crate::clone_db(),
+                    1,
+                    &name,
+                    DbfsFileType::RegularFile,
+                    0,
+                    0,
+                    DbfsPermission::from_bits_truncate(0o644),
+                    None,
+                )
+                .expect("Create failed"),
+            );
+        }
+        let mut unique = inos.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), inos.len());
+        assert!(!inos.contains(&1));
+    }
+
+    #[test]
+    fn test_fsck_detects_and_repairs() {
+        let mut engine = new_engine();
+        let file = engine
+            .create_exclusive(1, "fsck.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(file, 0, b"fsck data")
+            .expect("Write failed");
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+
+        // 手动破坏数据库: 悬空目录项、错误的 nlink、越界 extent、孤儿 Inode
+        engine
+            .add_dentry(1, "ghost", 999)
+            .expect("Add dentry failed");
+        let mut meta = engine.get_metadata(file).expect("Get metadata failed");
+        meta.nlink = 5;
+        meta.extents.push(crate::models::Extent {
+            logical_off: 0,
+            physical_ptr: 1 << 30,
+            len: 16,
+            crc: 0,
+            reserved: false,
+            compressed: false,
+            stored_len: 0,
+        });
+        engine
+            .update_metadata(&meta)
+            .expect("Update metadata failed");
+        let orphan = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+
+        let report = engine.fsck(false).expect("Fsck failed");
+        assert_eq!(
+            report.dangling_dentries,
+            alloc::vec![(1, "ghost".to_string(), 999)]
+        );
+        assert_eq!(report.nlink_mismatches, alloc::vec![(file, 5, 1)]);
+        assert_eq!(report.bad_extents, alloc::vec![(file, 1)]);
+        assert_eq!(report.orphaned_inodes, vec![orphan]);
+        assert!(!report.repaired);
+        // 只检查不修改
+        assert_eq!(
+            engine.lookup_dentry(1, "ghost").expect("Lookup failed"),
+            999
+        );
+
+        // 修复悬空目录项和 nlink
+        let report = engine.fsck(true).expect("Fsck failed");
+        assert!(report.repaired);
+        assert!(engine.lookup_dentry(1, "ghost").is_err());
+        assert_eq!(
+            engine
+                .get_metadata(file)
+                .expect("Get metadata failed")
+                .nlink,
+            1
+        );
+
+        let report = engine.fsck(false).expect("Fsck failed");
+        assert!(report.dangling_dentries.is_empty());
+        assert!(report.nlink_mismatches.is_empty());
+        assert_eq!(report.bad_extents, alloc::vec![(file, 1)]);
+        assert_eq!(report.orphaned_inodes, vec![orphan]);
+
+        // 名字不是 UTF-8 的悬空目录项同样按原始键删除
+        engine
+            .add_dentry(1, b"ghost\xff", 998)
+            .expect("Add dentry failed");
+        assert_eq!(
+            engine
+                .fsck(false)
+                .expect("Fsck failed")
+                .dangling_dentries
+                .len(),
+            1
+        );
+        assert!(engine.fsck(true).expect("Fsck failed").repaired);
+        assert!(engine
+            .fsck(false)
+            .expect("Fsck failed")
+            .dangling_dentries
+            .is_empty());
+    }
+
+    /// 记录写入与 flush 顺序的设备, flush 可以被设置为失败
+    struct BarrierDevice {
+        inner: MemDevice,
+        events: Arc<Mutex<Vec<&'static str>>>,
+        fail_flush: Arc<core::sync::atomic::AtomicBool>,
+    }
+
+    impl BlockDevice for BarrierDevice {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+            self.inner.read_at(pos, buf)
+        }
+        fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+            self.events.lock().push("write");
+            self.inner.write_at(pos, buf)
+        }
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+        fn flush(&self) -> DbfsResult<()> {
+            if self.fail_flush.load(core::sync::atomic::Ordering::SeqCst) {
+                return Err(crate::common::DbfsError::Io);
+            }
+            self.events.lock().push("flush");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_before_commit() {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "barrier-test.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let fail_flush = Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let device = BarrierDevice {
+            inner: MemDevice::new(64 * 1024),
+            events: events.clone(),
+            fail_flush: fail_flush.clone(),
+        };
+        let mut engine = TransactionEngine::new(db, LogManager::new(device, 0));
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+
+        // 没有追加数据的事务不需要 flush
+        assert!(events.lock().is_empty());
+
+        engine
+            .write_file_transactional(ino, 0, b"barrier")
+            .expect("Write failed");
+        assert_eq!(*events.lock(), alloc::vec!["write", "flush"]);
+        let commits = engine.commit_count();
+
+        // flush 失败时元数据不能提交
+        fail_flush.store(true, core::sync::atomic::Ordering::SeqCst);
+        assert!(engine.write_file_transactional(ino, 7, b" lost").is_err());
+        assert_eq!(engine.commit_count(), commits);
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            7
+        );
+    }
+
+    /// 记录收到的 vectored 请求的设备
+    struct VectoredDevice {
+        inner: MemDevice,
+        /// 每次 read_vectored 调用包含的请求数
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl BlockDevice for VectoredDevice {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+            self.inner.read_at(pos, buf)
+        }
+        fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+            self.inner.write_at(pos, buf)
+        }
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+        fn read_vectored(&self, reqs: &mut [(u64, &mut [u8])]) -> DbfsResult<usize> {
+            self.batches.lock().push(reqs.len());
+            let mut total = 0;
+            for (pos, buf) in reqs.iter_mut() {
+                total += self.inner.read_at(*pos, buf)?;
+            }
+            Ok(total)
+        }
+    }
+
+    #[test]
+    fn test_read_from_log_vectored() {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "vectored-test.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let device = VectoredDevice {
+            inner: MemDevice::new(64 * 1024),
+            batches: batches.clone(),
+        };
+        let mut engine = TransactionEngine::new(db, LogManager::new(device, 0));
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        let other = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+
+        // 交替写入两个文件, 让同一文件的 extent 在日志中物理上不连续
+        for (i, chunk) in [b"aaaaaaaa", b"bbbbbbbb", b"cccccccc"].iter().enumerate() {
+            engine
+                .write_file_transactional(ino, i as u64 * 8, *chunk)
+                .expect("Write failed");
+            engine
+                .write_file_transactional(other, i as u64 * 8, b"--------")
+                .expect("Write failed");
+        }
+
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        let mut buf = [0u8; 24];
+        let n = engine
+            .read_from_log(&meta, 0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(n, 24);
+        assert_eq!(&buf, b"aaaaaaaabbbbbbbbcccccccc");
+
+        // 三个 extent 合并为一次 vectored 请求
+        assert_eq!(*batches.lock(), alloc::vec![3]);
+    }
+
+    #[test]
+    fn test_iter_inodes() {
+        let mut engine = new_engine();
+        let file = engine
+            .create_exclusive(1, "export.txt", 0o100644)
+            .expect("Create failed");
+        let dir = engine
+            .create_exclusive(1, "export_dir", 0o040755)
+            .expect("Create failed");
+        let link = engine
+            .create_exclusive(dir, "export_link", 0o120777)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(file, 0, b"export me")
+            .expect("Write failed");
+
+        let inodes: Vec<_> = engine.iter_inodes().expect("Iterate failed").collect();
+        let inos: Vec<_> = inodes.iter().map(|(ino, _)| *ino).collect();
+        assert_eq!(inos, alloc::vec![file, dir, link]);
+        for (ino, meta) in &inodes {
+            assert_eq!(meta.ino, *ino);
+            let expected = engine.get_metadata(*ino).expect("Get metadata failed");
+            assert_eq!(meta.mode, expected.mode);
+            assert_eq!(meta.size, expected.size);
+        }
+        assert_eq!(inodes[0].1.size, 9);
+
+        // 导出钩子收到每个 Inode, 普通文件带有内容
+        let mut exported = Vec::new();
+        engine
+            .export_stream(|meta, data| {
+                exported.push((meta.ino, data.to_vec()));
+                Ok(())
+            })
+            .expect("Export failed");
+        assert_eq!(
+            exported,
+            alloc::vec![
+                (file, b"export me".to_vec()),
+                (dir, Vec::new()),
+                (link, Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clone_file() {
+        let mut engine = new_engine();
+        let src = engine
+            .create_exclusive(1, "origin.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(src, 0, b"original data")
+            .expect("Write failed");
+
+        let clone = engine.clone_file(src).expect("Clone failed");
+        engine
+            .add_dentry(1, "clone.txt", clone)
+            .expect("Add dentry failed");
+        let src_meta = engine.get_metadata(src).expect("Get metadata failed");
+        let clone_meta = engine.get_metadata(clone).expect("Get metadata failed");
+        assert_eq!(clone_meta.size, src_meta.size);
+        assert_eq!(clone_meta.mode, src_meta.mode);
+        assert_eq!(clone_meta.nlink, 1);
+
+        // 共享同一段物理数据, 没有拷贝
+        assert_eq!(clone_meta.extents.len(), src_meta.extents.len());
+        assert_eq!(
+            clone_meta.extents[0].physical_ptr,
+            src_meta.extents[0].physical_ptr
+        );
+
+        // 修改克隆不影响原文件
+        engine
+            .write_file_transactional(clone, 0, b"CLONED")
+            .expect("Write failed");
+        engine
+            .write_file_transactional(clone, 13, b" + more")
+            .expect("Write failed");
+        let mut buf = [0u8; 32];
+        let n = engine.read_file(src, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"original data");
+        let n = engine.read_file(clone, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"CLONEDal data + more");
+        assert_eq!(
+            engine
+                .get_metadata(src)
+                .expect("Get metadata failed")
+                .extents
+                .len(),
+            1
+        );
+
+        // 目录不能克隆
+        let dir = engine
+            .create_exclusive(1, "clone_dir", 0o040755)
+            .expect("Create failed");
+        assert!(matches!(
+            engine.clone_file(dir),
+            Err(crate::common::DbfsError::IsDir)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_mount() {
+        use crate::rvfs_adapter::DbfsSuperBlock;
+        use vfscore::VfsSuperBlock;
+
+        let engine = Arc::new(Mutex::new(new_engine()));
+        let sb = Arc::new_cyclic(|weak| DbfsSuperBlock {
+            engine: engine.clone(),
+            locks: Arc::default(),
+            self_weak: weak.clone(),
+        });
+        let root = sb.root_inode().expect("Get root failed");
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+
+        let file = root
+            .create("snap.txt", VfsNodeType::File, perm, None)
+            .expect("Create failed");
+        file.write_at(0, b"before").expect("Write failed");
+        sb.snapshot("s1").expect("Snapshot failed");
+        assert_eq!(sb.snapshot("s1").err(), Some(vfscore::VfsError::EExist));
+
+        // 快照之后修改活动文件系统
+        file.write_at(0, b"AFTER!").expect("Write failed");
+        file.write_at(6, b" and more").expect("Write failed");
+        root.create("new.txt", VfsNodeType::File, perm, None)
+            .expect("Create failed");
+
+        // 快照中仍是修改之前的状态
+        let snap_root = sb.mount_snapshot("s1").expect("Mount snapshot failed");
+        let snap_file = snap_root.lookup("snap.txt").expect("Lookup failed");
+        let mut buf = [0u8; 32];
+        let n = snap_file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"before");
+        assert!(snap_root.lookup("new.txt").is_err());
+
+        // 快照是只读的
+        assert!(snap_file.write_at(0, b"x").is_err());
+        assert!(snap_root
+            .create("other.txt", VfsNodeType::File, perm, None)
+            .is_err());
+
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"AFTER! and more");
+        assert_eq!(
+            sb.mount_snapshot("missing").err(),
+            Some(vfscore::VfsError::NoEntry)
+        );
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        let mut engine = new_engine();
+        let name: &[u8] = b"caf\xe9.txt"; // Latin-1 编码, 不是合法的 UTF-8
+        let ino = engine
+            .create_exclusive(7, name, 0o100644)
+            .expect("Create failed");
+
+        // 用相同的字节可以查找到, 有损转换后的名字查找不到
+        assert_eq!(engine.lookup_dentry(7, name).expect("Lookup failed"), ino);
+        assert!(engine.lookup_dentry(7, "caf\u{fffd}.txt").is_err());
+        assert!(matches!(
+            engine.create_exclusive(7, name, 0o100644),
+            Err(crate::common::DbfsError::FileExists)
+        ));
+
+        // 原始名字原样返回, 字符串接口做有损转换
+        assert_eq!(
+            engine.list_dentries_raw(7, 0).expect("List failed"),
+            Some((name.to_vec(), ino))
+        );
+        assert_eq!(
+            engine.list_dentries(7, 0).expect("List failed"),
+            Some(("caf\u{fffd}.txt".to_string(), ino))
+        );
+
+        engine.delete_dentry(7, name).expect("Delete failed");
+        assert!(engine.lookup_dentry(7, name).is_err());
+    }
+
+    #[test]
+    fn test_datasync_skips_atime() {
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "datasync.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"payload")
+            .expect("Write failed");
+
+        // atime 只记录在内存中
+        engine.touch_atime(ino, 42).expect("Touch atime failed");
+        assert_eq!(engine.pending_atime(ino), Some(42));
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").atime,
+            0
+        );
+
+        // fdatasync 不提交 atime
+        let commits = engine.commit_count();
+        engine.fsync(ino, true).expect("Datasync failed");
+        assert_eq!(engine.commit_count(), commits);
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").atime,
+            0
+        );
+        assert_eq!(engine.pending_atime(ino), Some(42));
+
+        // 完整的 fsync 提交 atime
+        engine.fsync(ino, false).expect("Fsync failed");
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert_eq!(meta.atime, 42);
+        assert_eq!(meta.size, 7);
+        assert_eq!(engine.pending_atime(ino), None);
+
+        // 删除文件时丢弃尚未写入的 atime
+        engine.touch_atime(ino, 43).expect("Touch atime failed");
+        engine.delete_inode(ino).expect("Delete inode failed");
+        assert_eq!(engine.pending_atime(ino), None);
+        engine.fsync(ino, false).expect("Fsync failed");
+    }
+
+    #[test]
+    fn test_custom_reserved_size() {
+        let ram_disk = Arc::new(RamDisk::new(16 * 1024 * 1024));
+        let fs_type = Arc::new(DbfsFsType);
+        let reserved = 4 * 1024 * 1024;
+
+        // 保留区不小于设备大小时拒绝格式化
+        assert!(matches!(
+            fs_type.clone().mount(
+                0,
+                "/",
+                Some(Arc::new(RamDisk::new(1024 * 1024)) as Arc<dyn VfsInode>),
+                b"reserved_size=1048576"
+            ),
+            Err(vfscore::VfsError::Invalid)
+        ));
+
+        let root_dentry = fs_type
+            .clone()
+            .mount(
+                0,
+                "/",
+                Some(ram_disk.clone() as Arc<dyn VfsInode>),
+                b"reserved_size=4194304",
+            )
+            .expect("Mount failed");
+        let root_inode = root_dentry.inode().expect("Get root inode failed");
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        let file = root_inode
+            .create("log.txt", VfsNodeType::File, perm, None)
+            .expect("Create failed");
+        file.write_at(0, b"first").expect("Write failed");
+
+        // 日志从保留区之后开始追加
+        assert_eq!(&ram_disk.data.lock()[reserved..reserved + 5], b"first");
+
+        // 重新挂载时以超级块记录的大小为准, 忽略新的挂载参数
+        let root_dentry = fs_type
+            .mount(
+                0,
+                "/",
+                Some(ram_disk.clone() as Arc<dyn VfsInode>),
+                b"reserved_size=8388608",
+            )
+            .expect("Remount failed");
+        let root_inode = root_dentry.inode().expect("Get root inode failed");
+        let file = root_inode
+            .create("log2.txt", VfsNodeType::File, perm, None)
+            .expect("Create failed");
+        file.write_at(0, b"again").expect("Write failed");
+        assert_eq!(&ram_disk.data.lock()[reserved..reserved + 5], b"again");
+    }
+
+    #[test]
+    fn test_log_write_amplification() {
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "amplify.txt", 0o100644)
+            .expect("Create failed");
+
+        engine
+            .write_file_transactional(ino, 0, &[1u8; 100])
+            .expect("Write failed");
+        let stats = engine.log_stats();
+        assert_eq!(stats.logical_written, 100);
+        assert_eq!(stats.physical_written, 100);
+
+        // 反复覆盖同一区域: 每次都追加新的 extent, 但文件内容没有增加
+        for i in 0..4u8 {
+            engine
+                .write_file_transactional(ino, 0, &[i; 100])
+                .expect("Overwrite failed");
+        }
+        // 部分覆盖, 只有超出原有内容的 50 字节是新的
+        engine
+            .write_file_transactional(ino, 50, &[9u8; 100])
+            .expect("Write failed");
+
+        let stats = engine.log_stats();
+        assert_eq!(stats.appends, 6);
+        assert_eq!(stats.logical_written, 150);
+        assert_eq!(stats.physical_written, 600);
+        assert!(stats.physical_written > stats.logical_written);
+    }
+
+    #[test]
+    fn test_truncate_mid_extent_crc() {
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "trunc.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"0123456789")
+            .expect("Write failed");
+
+        // 在 extent 中间截断
+        engine.truncate_file(ino, 4).expect("Truncate failed");
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert_eq!(meta.size, 4);
+        assert_eq!(meta.extents.len(), 1);
+        assert_eq!(meta.extents[0].len, 4);
+        assert!(engine
+            .verify_extent(&meta.extents[0])
+            .expect("Verify failed"));
+
+        let mut buf = [0u8; 16];
+        let n = engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"0123");
+    }
+
+    #[test]
+    fn test_jammdb_adapter_seek_bounds() {
+        use crate::rvfs_adapter::JammdbFileAdapter;
+        use core2::io::{ErrorKind, Seek, SeekFrom};
+
+        let mut file = JammdbFileAdapter {
+            inode: Arc::new(RamDisk::new(4096)),
+            pos: Mutex::new(0),
+        };
+
+        // 相对末尾定位到有效位置
+        assert_eq!(file.seek(SeekFrom::End(-96)).expect("Seek failed"), 4000);
+        assert_eq!(
+            file.seek(SeekFrom::Current(-1000)).expect("Seek failed"),
+            3000
+        );
+
+        // 定位到文件开头之前返回错误, 位置保持不变
+        let err = file
+            .seek(SeekFrom::End(-4097))
+            .expect_err("Seek before start succeeded");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = file
+            .seek(SeekFrom::Current(-3001))
+            .expect_err("Seek before start succeeded");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(*file.pos.lock(), 3000);
+
+        // 溢出 u64 同样是无效位置
+        file.seek(SeekFrom::Start(u64::MAX)).expect("Seek failed");
+        let err = file
+            .seek(SeekFrom::Current(1))
+            .expect_err("Seek overflow succeeded");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_jammdb_adapter_short_io() {
+        use crate::rvfs_adapter::JammdbFileAdapter;
+        use core2::io::{Read, Write};
+
+        /// 每次读写最多处理 7 字节的设备
+        struct ChunkedDisk(RamDisk);
+
+        impl VfsFile for ChunkedDisk {
+            fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+                let len = core::cmp::min(buf.len(), 7);
+                self.0.read_at(offset, &mut buf[..len])
+            }
+
+            fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+                self.0
+                    .write_at(offset, &buf[..core::cmp::min(buf.len(), 7)])
+            }
+        }
+
+        impl VfsInode for ChunkedDisk {
+            fn get_attr(&self) -> VfsResult<VfsFileStat> {
+                self.0.get_attr()
+            }
+            fn inode_type(&self) -> VfsNodeType {
+                VfsNodeType::BlockDevice
+            }
+            fn get_super_block(&self) -> VfsResult<Arc<dyn vfscore::VfsSuperBlock>> {
+                Err(vfscore::VfsError::Invalid)
+            }
+            fn node_perm(&self) -> VfsNodePerm {
+                VfsNodePerm::all()
+            }
+        }
+
+        let mut file = JammdbFileAdapter {
+            inode: Arc::new(ChunkedDisk(RamDisk::new(150))),
+            pos: Mutex::new(0),
+        };
+        let data: Vec<u8> = (0..100u8).collect();
+        assert_eq!(file.write(&data).expect("Write failed"), 100);
+
+        *file.pos.lock() = 0;
+        let mut buf = [0u8; 100];
+        assert_eq!(file.read(&mut buf).expect("Read failed"), 100);
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(*file.pos.lock(), 100);
+
+        // 到达设备末尾时返回已读的字节数
+        let mut buf = [0u8; 100];
+        assert_eq!(file.read(&mut buf).expect("Read failed"), 50);
+        assert_eq!(file.read(&mut buf).expect("Read failed"), 0);
+    }
+
+    #[test]
+    fn test_quota_enforcement() {
+        use crate::tx_engine::QUOTA_BLOCK_SIZE;
+
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "quota.txt", 0o100644)
+            .expect("Create failed");
+        let mut meta = engine.get_metadata(ino).expect("Get metadata failed");
+        meta.uid = 1000;
+        engine
+            .update_metadata(&meta)
+            .expect("Update metadata failed");
+        engine.set_quota(1000, 2).expect("Set quota failed");
+
+        // 两个块以内的写入成功
+        let block = alloc::vec![7u8; QUOTA_BLOCK_SIZE as usize];
+        engine
+            .write_file_transactional(ino, 0, &block)
+            .expect("Write failed");
+        engine
+            .write_file_transactional(ino, QUOTA_BLOCK_SIZE, &block)
+            .expect("Write failed");
+        assert_eq!(
+            engine
+                .get_quota(1000)
+                .expect("Get quota failed")
+                .blocks_used,
+            2
+        );
+
+        // 超出配额的写入被拒绝, 文件和用量都不变
+        assert!(matches!(
+            engine.write_file_transactional(ino, 2 * QUOTA_BLOCK_SIZE, b"x"),
+            Err(crate::common::DbfsError::NoSpace)
+        ));
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            2 * QUOTA_BLOCK_SIZE
+        );
+        let quota = engine.get_quota(1000).expect("Get quota failed");
+        assert_eq!(quota.blocks_used, 2);
+        assert_eq!(quota.blocks_limit, 2);
+
+        // 覆盖已有的块不占用新的配额
+        engine
+            .write_file_transactional(ino, 10, b"overwrite")
+            .expect("Write failed");
+
+        // 截断和删除归还配额
+        engine.truncate_file(ino, 100).expect("Truncate failed");
+        assert_eq!(
+            engine
+                .get_quota(1000)
+                .expect("Get quota failed")
+                .blocks_used,
+            1
+        );
+        engine.delete_inode(ino).expect("Delete failed");
+        assert_eq!(
+            engine
+                .get_quota(1000)
+                .expect("Get quota failed")
+                .blocks_used,
+            0
+        );
+    }
+
+    #[test]
+    fn test_crash_points() {
+        use crate::tx_engine::CrashPoint;
+
+        for (point, committed) in [
+            (CrashPoint::BeforeDataWrite, false),
+            (CrashPoint::BeforeCommit, false),
+            (CrashPoint::AfterCommit, true),
+        ] {
+            let mut engine = new_engine();
+            let ino = engine
+                .create_exclusive(1, "crash.txt", 0o100644)
+                .expect("Create failed");
+            engine
+                .write_file_transactional(ino, 0, b"old data")
+                .expect("Write failed");
+
+            // 在指定的点模拟崩溃, 写操作中止
+            engine.set_crash_hook(move |p| p == point);
+            assert!(engine
+                .write_file_transactional(ino, 0, b"new data!")
+                .is_err());
+            engine.clear_crash_hook();
+
+            // 恢复后的状态要么是旧数据, 要么是完整的新数据
+            let meta = engine.get_metadata(ino).expect("Get metadata failed");
+            let mut buf = [0u8; 16];
+            let n = engine.read_file(ino, 0, &mut buf).expect("Read failed");
+            if committed {
+                assert_eq!(meta.size, 9);
+                assert_eq!(&buf[..n], b"new data!");
+            } else {
+                assert_eq!(meta.size, 8);
+                assert_eq!(&buf[..n], b"old data");
+            }
+            assert!(
+                engine.fsck(false).expect("Fsck failed").is_clean(),
+                "{:?}",
+                point
+            );
+
+            // 钩子移除之后写操作恢复正常
+            engine
+                .write_file_transactional(ino, 0, b"after")
+                .expect("Write failed");
+        }
+    }
+
+    #[test]
+    fn test_crash_during_truncate_and_delete() {
+        use crate::tx_engine::CrashPoint;
+
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "crash.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"hello world")
+            .expect("Write failed");
+
+        engine.set_crash_hook(|p| p == CrashPoint::BeforeCommit);
+        assert!(engine.truncate_file(ino, 5).is_err());
+        assert!(engine.delete_inode(ino).is_err());
+        engine.clear_crash_hook();
+
+        // 未提交的截断和删除都没有生效
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            11
+        );
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+
+        engine.set_crash_hook(|p| p == CrashPoint::AfterCommit);
+        assert!(engine.truncate_file(ino, 5).is_err());
+        engine.clear_crash_hook();
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            5
+        );
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+    }
+
+    #[cfg(feature = "async_device")]
+    #[test]
+    fn test_async_block_device() {
+        use crate::log_manager::{AsyncBlockDevice, BlockOn};
+        use core::{
+            future::Future,
+            pin::Pin,
+            task::{Context, Poll},
+        };
+
+        // 第一次轮询返回 Pending, 模拟等待中断的驱动
+        struct YieldOnce(bool);
+
+        impl Future for YieldOnce {
+            type Output = ();
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                if self.0 {
+                    Poll::Ready(())
+                } else {
+                    self.0 = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        }
+
+        struct AsyncMemDevice(MemDevice);
+
+        impl AsyncBlockDevice for AsyncMemDevice {
+            async fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+                YieldOnce(false).await;
+                self.0.read_at(pos, buf)
+            }
+
+            async fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+                YieldOnce(false).await;
+                self.0.write_at(pos, buf)
+            }
+
+            fn size(&self) -> u64 {
+                self.0.size()
+            }
+        }
+
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "engine-async.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let device = BlockOn(AsyncMemDevice(MemDevice::new(1024 * 1024)));
+        let mut engine = TransactionEngine::new(db, LogManager::new(device, 0));
+
+        let ino = engine
+            .create_exclusive(1, "async.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"hello async")
+            .expect("Write failed");
+        engine
+            .write_file_transactional(ino, 6, b"world")
+            .expect("Write failed");
+
+        let mut buf = [0u8; 16];
+        let n = engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"hello world");
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "engine-prealloc.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let mut engine =
+            TransactionEngine::new(db, LogManager::new(MemDevice::new(4 * 1024 * 1024), 0));
+        let ino = engine
+            .create_exclusive(1, "prealloc.bin", 0o100644)
+            .expect("Create failed");
+
+        // 预分配 1MB, 文件大小随之增长, 内容全为 0
+        const LEN: u64 = 1024 * 1024;
+        engine.preallocate(ino, 0, LEN).expect("Preallocate failed");
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            LEN
+        );
+        let mut buf = alloc::vec![0xffu8; LEN as usize];
+        assert_eq!(
+            engine.read_file(ino, 0, &mut buf).expect("Read failed"),
+            LEN as usize
+        );
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // 覆盖其中一部分, 覆盖的数据生效, 其余部分仍为 0
+        engine
+            .write_file_transactional(ino, 4096, b"overwrite")
+            .expect("Write failed");
+        let mut buf = [0xffu8; 32];
+        engine.read_file(ino, 4090, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..6], &[0; 6]);
+        assert_eq!(&buf[6..15], b"overwrite");
+        assert!(buf[15..].iter().all(|&b| b == 0));
+
+        // 预分配不会覆盖已有数据
+        engine
+            .preallocate(ino, 0, 8192)
+            .expect("Preallocate failed");
+        let mut buf = [0u8; 9];
+        engine.read_file(ino, 4096, &mut buf).expect("Read failed");
+        assert_eq!(&buf, b"overwrite");
+
+        // 超出日志容量的预分配失败
+        assert!(matches!(
+            engine.preallocate(ino, 0, 8 * LEN),
+            Err(crate::common::DbfsError::NoSpace)
+        ));
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+    }
+
+    #[test]
+    fn test_remove_tree() {
+        let mut engine = new_engine();
+        const DIR: u32 = 0o040755;
+        const FILE: u32 = 0o100644;
+
+        // top/{a.txt, sub/{b.txt, deep/{c.txt}}}
+        let top = engine
+            .create_exclusive(1, "top", DIR)
+            .expect("Create failed");
+        let a = engine
+            .create_exclusive(top, "a.txt", FILE)
+            .expect("Create failed");
+        let sub = engine
+            .create_exclusive(top, "sub", DIR)
+            .expect("Create failed");
+        let b = engine
+            .create_exclusive(sub, "b.txt", FILE)
+            .expect("Create failed");
+        let deep = engine
+            .create_exclusive(sub, "deep", DIR)
+            .expect("Create failed");
+        let c = engine
+            .create_exclusive(deep, "c.txt", FILE)
+            .expect("Create failed");
+        for ino in [a, b, c] {
+            engine
+                .write_file_transactional(ino, 0, b"data")
+                .expect("Write failed");
+        }
+
+        // b.txt 在树外还有一个硬链接
+        engine
+            .add_dentry(1, "b-link", b)
+            .expect("Add dentry failed");
+        let mut meta = engine.get_metadata(b).expect("Get metadata failed");
+        meta.nlink = 2;
+        engine
+            .update_metadata(&meta)
+            .expect("Update metadata failed");
+
+        engine.remove_tree(1, "top").expect("Remove tree failed");
+
+        assert!(matches!(
+            engine.lookup_dentry(1, "top"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+        for ino in [top, a, sub, deep, c] {
+            assert!(engine.get_metadata(ino).is_err(), "inode {} not freed", ino);
+        }
+        for dir in [top, sub, deep] {
+            assert_eq!(engine.list_dentries(dir, 0).expect("List failed"), None);
+        }
+
+        // 有硬链接的文件只减少 nlink
+        let meta = engine.get_metadata(b).expect("Get metadata failed");
+        assert_eq!(meta.nlink, 1);
+        assert_eq!(engine.lookup_dentry(1, "b-link").expect("Lookup failed"), b);
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+
+        assert!(matches!(
+            engine.remove_tree(1, "top"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_metrics_callback() {
+        use crate::metrics::{ops, Metrics};
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        // 假时钟每读一次前进 10ns
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        static SEEN: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+        fn clock() -> u64 {
+            NOW.fetch_add(10, Ordering::SeqCst)
+        }
+        fn observe(op: &str, nanos: u64) {
+            SEEN.lock().push((op.to_string(), nanos));
+        }
+
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "timed", 0o100644)
+            .expect("Create failed");
+        engine.set_metrics(Some(Metrics { clock, observe }));
+
+        engine
+            .write_file_transactional(ino, 0, b"hello")
+            .expect("Write failed");
+        let mut buf = [0u8; 5];
+        engine.read_file(ino, 0, &mut buf).expect("Read failed");
+
+        let seen = SEEN.lock().clone();
+        let ops_seen: Vec<&str> = seen.iter().map(|(op, _)| op.as_str()).collect();
+        assert_eq!(
+            ops_seen,
+            vec![
+                ops::APPEND_DATA,
+                ops::DB_COMMIT,
+                ops::WRITE_FILE,
+                ops::READ_FILE
+            ]
+        );
+        assert!(seen.iter().all(|(_, nanos)| *nanos > 0));
+        // 写入包含追加和提交, 耗时不小于两者之和
+        assert!(seen[2].1 >= seen[0].1 + seen[1].1);
+
+        // 移除回调后不再上报
+        engine.set_metrics(None);
+        engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(SEEN.lock().len(), 4);
+    }
+
+    #[test]
+    fn test_large_write_splits_extents() {
+        use crate::tx_engine::MAX_EXTENT_LEN;
+
+        let db =
+            DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "engine-large-write.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let mut engine =
+            TransactionEngine::new(db, LogManager::new(MemDevice::new(4 * 1024 * 1024), 0));
+        let ino = engine
+            .create_exclusive(1, "big", 0o100644)
+            .expect("Create failed");
+        let data: Vec<u8> = (0..1024 * 1024u32)
+            .map(|i| (i * 7 + i / 251) as u8)
+            .collect();
+        let offset = 1000;
+
+        // 1. 一次写入 1MB, 从未对齐的偏移开始
+        engine
+            .write_file_transactional(ino, offset, &data)
+            .expect("Write failed");
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert_eq!(meta.size, offset + data.len() as u64);
+        assert!(meta.extents.len() > 1);
+        for (i, e) in meta.extents.iter().enumerate() {
+            assert!(e.len <= MAX_EXTENT_LEN);
+            // 除第一段外都从对齐的位置开始
+            if i > 0 {
+                assert_eq!(e.logical_off % MAX_EXTENT_LEN, 0);
+            }
+        }
+        assert_eq!(
+            meta.extents.iter().map(|e| e.len).sum::<u64>(),
+            data.len() as u64
+        );
+
+        // 2. 任意子区间都能正确读回, 包括跨 extent 边界的
+        for (start, len) in [
+            (0, 4096),
+            (MAX_EXTENT_LEN - 1000 - 10, 20),
+            (12_345, 300_000),
+            (data.len() as u64 - 7, 7),
+        ] {
+            let mut buf = alloc::vec![0u8; len as usize];
+            let n = engine
+                .read_file(ino, offset + start, &mut buf)
+                .expect("Read failed");
+            assert_eq!(n, len as usize);
+            assert_eq!(&buf[..], &data[start as usize..(start + len) as usize]);
+        }
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+    }
+
+    #[test]
+    fn test_put_metadata() {
+        use crate::common::DbfsError;
+
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "imported", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"0123456789")
+            .expect("Write failed");
+        engine
+            .write_file_transactional(ino, 20, b"abcdefghij")
+            .expect("Write failed");
+
+        // 1. 合法的元数据整体写入后原样读回
+        let mut meta = engine.get_metadata(ino).expect("Get metadata failed");
+        meta.size = 30;
+        meta.mode = 0o100600;
+        meta.nlink = 1;
+        meta.atime = 1_000;
+        meta.mtime = 2_000;
+        meta.uid = 42;
+        engine.put_metadata(&meta).expect("Put metadata failed");
+        let read = engine.get_metadata(ino).expect("Get metadata failed");
+        assert_eq!(
+            (read.size, read.mode, read.atime, read.mtime, read.uid),
+            (30, 0o100600, 1_000, 2_000, 42)
+        );
+        assert_eq!(read.extents.len(), 2);
+        let mut buf = [0u8; 30];
+        engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..10], b"0123456789");
+        assert_eq!(&buf[20..], b"abcdefghij");
+
+        // 2. 重叠的 extent 被拒绝, 原记录不变
+        let mut bad = engine.get_metadata(ino).expect("Get metadata failed");
+        bad.extents[1].logical_off = 5;
+        bad.size = 999;
+        assert!(matches!(
+            engine.put_metadata(&bad),
+            Err(DbfsError::InvalidArgument)
+        ));
+
+        // 3. 乱序的 extent 被拒绝
+        let mut bad = engine.get_metadata(ino).expect("Get metadata failed");
+        bad.extents.swap(0, 1);
+        assert!(matches!(
+            engine.put_metadata(&bad),
+            Err(DbfsError::InvalidArgument)
+        ));
+
+        // 4. 超出日志写指针的 extent 被拒绝
+        let mut bad = engine.get_metadata(ino).expect("Get metadata failed");
+        bad.extents[1].physical_ptr = engine.next_append_pos();
+        assert!(matches!(
+            engine.put_metadata(&bad),
+            Err(DbfsError::InvalidArgument)
+        ));
+
+        assert_eq!(
+            engine.get_metadata(ino).expect("Get metadata failed").size,
+            30
+        );
+    }
+
+    #[test]
+    fn test_remove_deep_tree() {
+        let mut engine = new_engine();
+        const DIR: u32 = 0o040755;
+
+        // 10000 层嵌套的目录链, 最深处有一个文件
+        let mut parent = 1;
+        let mut chain = Vec::new();
+        for _ in 0..10_000 {
+            parent = engine
+                .create_exclusive(parent, "d", DIR)
+                .expect("Create failed");
+            chain.push(parent);
+        }
+        let leaf = engine
+            .create_exclusive(parent, "leaf", 0o100644)
+            .expect("Create failed");
+
+        engine.remove_tree(1, "d").expect("Remove tree failed");
+
+        assert!(matches!(
+            engine.lookup_dentry(1, "d"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+        for ino in [chain[0], chain[5_000], chain[9_999], leaf] {
+            assert!(engine.get_metadata(ino).is_err(), "inode {} not freed", ino);
+        }
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+    }
+
+    #[test]
+    fn test_dir_entry_count() {
+        let mut engine = new_engine();
+        let dir = engine
+            .create_exclusive(1, "dir", 0o040755)
+            .expect("Create failed");
+        let check = |engine: &TransactionEngine<MemDevice>, expected: u64| {
+            assert_eq!(
+                engine.entry_count(dir).expect("Entry count failed"),
+                expected
+            );
+            assert_eq!(engine.scan_entry_count(dir).expect("Scan failed"), expected);
+            assert_eq!(
+                engine.is_dir_empty(dir).expect("Is empty failed"),
+                expected == 0
+            );
+        };
+        check(&engine, 0);
+
+        // `.` 和 `..` 被拒绝, 不计入
+        assert!(engine.add_dentry(dir, ".", dir).is_err());
+        assert!(engine.add_dentry(dir, "..", 1).is_err());
+        check(&engine, 0);
+
+        let mut files = Vec::new();
+        for i in 0..10 {
+            let name = alloc::format!("f{}", i);
+            files.push(
+                engine
+                    .create_exclusive(dir, &name, 0o100644)
+                    .expect("Create failed"),
+            );
+            check(&engine, i + 1);
+        }
+
+        // 覆盖已有的目录项不改变计数
+        engine
+            .add_dentry(dir, "f0", files[1])
+            .expect("Add dentry failed");
+        check(&engine, 10);
+
+        // 同一个批次中的增删也保持一致
+        engine
+            .batch(|ctx| {
+                ctx.delete_dentry(dir, "f0")?;
+                ctx.delete_dentry(dir, "f1")?;
+                ctx.add_dentry(dir, "g", files[2])
+            })
+            .expect("Batch failed");
+        check(&engine, 9);
+
+        for i in 2..10 {
+            engine
+                .delete_dentry(dir, alloc::format!("f{}", i))
+                .expect("Delete dentry failed");
+            check(&engine, 9 - (i - 1));
+        }
+        engine
+            .delete_dentry(dir, "g")
+            .expect("Delete dentry failed");
+        check(&engine, 0);
+    }
+
+    #[test]
+    fn test_unmount_remount() {
+        let ram_disk = Arc::new(RamDisk::new(64 * 1024 * 1024));
+        let fs_type = Arc::new(DbfsFsType);
+
+        let root = fs_type
+            .mount(0, "/", Some(ram_disk.clone() as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed")
+            .inode()
+            .expect("Get root inode failed");
+        let file = root
+            .create(
+                "kept.txt",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        file.write_at(0, b"survives unmount").expect("Write failed");
+        fs_type
+            .kill_sb(root.get_super_block().expect("Get super block failed"))
+            .expect("Unmount failed");
+
+        // 重新挂载后日志从上次的写指针继续, 新写入的数据不会覆盖旧文件
+        let root = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Remount failed")
+            .inode()
+            .expect("Get root inode failed");
+        let other = root
+            .create(
+                "new.txt",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+        other
+            .write_at(0, b"XXXXXXXXXXXXXXXXXXXX")
+            .expect("Write failed");
+
+        let mut buf = [0u8; 32];
+        let file = root.lookup("kept.txt").expect("Lookup failed");
+        let n = file.read_at(0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"survives unmount");
+    }
+
+    #[test]
+    fn test_recover_append_pos_after_crash() {
+        use crate::tx_engine::recover_append_pos;
+
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "engine-recover.db").unwrap();
+        {
+            let tx = db.tx(true).unwrap();
+            tx.get_or_create_bucket("inodes").unwrap();
+            tx.commit().unwrap();
+        }
+        let mut engine =
+            TransactionEngine::new(db.clone(), LogManager::new(MemDevice::new(1024 * 1024), 0));
+        let ino = engine
+            .create_exclusive(1, "crash.txt", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, b"0123456789")
+            .expect("Write failed");
+
+        // 没有正常卸载: 扫描 extent 得到写指针
+        assert_eq!(recover_append_pos(&db, 0).expect("Recover failed"), 10);
+
+        // 正常卸载后取回记录的写指针, 预留的空间也算在内
+        engine
+            .preallocate(ino, 100, 50)
+            .expect("Preallocate failed");
+        engine.unmount().expect("Unmount failed");
+        assert_eq!(recover_append_pos(&db, 0).expect("Recover failed"), 60);
+
+        // 记录只能取回一次, 之后的异常关机仍然扫描 extent
+        assert_eq!(recover_append_pos(&db, 0).expect("Recover failed"), 60);
+        assert_eq!(recover_append_pos(&db, 4096).expect("Recover failed"), 4096);
+    }
+
+    /// 接下来 `failures` 次读写返回 `Io` 错误的设备
+    struct FlakyDevice {
+        inner: MemDevice,
+        failures: Arc<core::sync::atomic::AtomicU32>,
+    }
+
+    impl FlakyDevice {
+        fn fail(&self) -> DbfsResult<()> {
+            use core::sync::atomic::Ordering;
+            match self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(crate::common::DbfsError::Io),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    impl BlockDevice for FlakyDevice {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+            self.fail()?;
+            self.inner.read_at(pos, buf)
+        }
+        fn write_at(&self, pos: u64, buf: &[u8]) -> DbfsResult<usize> {
+            self.fail()?;
+            self.inner.write_at(pos, buf)
+        }
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_device_errors() {
+        use crate::log_manager::RetryPolicy;
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        let failures = Arc::new(AtomicU32::new(0));
+        let flaky = || FlakyDevice {
+            inner: MemDevice::new(4096),
+            failures: failures.clone(),
+        };
+
+        // 默认不重试, 第一次错误直接返回
+        let mut log = LogManager::new(flaky(), 0);
+        failures.store(2, Ordering::SeqCst);
+        assert!(matches!(
+            log.append_data(b"data", 4),
+            Err(crate::common::DbfsError::Io)
+        ));
+
+        // 失败两次后成功: 重试两次即可
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff_spins: 4,
+        };
+        let mut log = LogManager::new(flaky(), 0).with_retry_policy(policy);
+        failures.store(2, Ordering::SeqCst);
+        let pos = log.append_data(b"data", 4).expect("Append failed");
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        // 读同样会重试
+        failures.store(2, Ordering::SeqCst);
+        let mut buf = [0u8; 4];
+        log.read_data(pos, &mut buf).expect("Read failed");
+        assert_eq!(&buf, b"data");
+
+        // 重试用尽后返回错误
+        failures.store(3, Ordering::SeqCst);
+        assert!(matches!(
+            log.append_data(b"more", 4),
+            Err(crate::common::DbfsError::Io)
+        ));
+    }
+
+    #[test]
+    fn test_read_snapshot_isolation() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        let mut engine = new_engine();
+        let ino = engine
+            .create_exclusive(1, "isolated.bin", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, &[b'A'; 4096])
+            .expect("Write failed");
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = engine.reader();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::SeqCst) || reads == 0 {
+                        let mut buf = [0u8; 4096];
+                        let n = reader.read_snapshot(ino, 0, &mut buf).expect("Read failed");
+                        // 每次读到的都是某一次完整的写入, 不会是两次写入拼起来的
+                        assert_eq!(n, 4096);
+                        assert!(buf.iter().all(|&b| b == buf[0]), "torn read");
+                        reads += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..200u32 {
+            let byte = b'A' + (i % 26) as u8;
+            engine
+                .write_file_transactional(ino, 0, &[byte; 4096])
+                .expect("Write failed");
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().expect("Reader panicked");
+        }
+
+        let mut buf = [0u8; 4096];
+        engine.read_snapshot(ino, 0, &mut buf).expect("Read failed");
+        assert!(buf.iter().all(|&b| b == b'A' + (199 % 26) as u8));
+    }
+
+    #[test]
+    fn test_ioctl_gc() {
+        use crate::rvfs_adapter::{DBFS_IOC_CHECKPOINT, DBFS_IOC_FSCK, DBFS_IOC_GC};
+
+        let ram_disk = Arc::new(RamDisk::new(64 * 1024 * 1024));
+        let fs_type = Arc::new(DbfsFsType);
+        let root = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed")
+            .inode()
+            .expect("Get root inode failed");
+        let file = root
+            .create(
+                "gc.bin",
+                VfsNodeType::File,
+                VfsNodePerm::from_bits_truncate(0o644),
+                None,
+            )
+            .expect("Create file failed");
+
+        // 反复覆盖同一段数据, 日志中只有最后一次写入仍被引用
+        for i in 0..16u8 {
+            file.write_at(0, &[i; 4096]).expect("Write failed");
+        }
+        let reclaimed = file.ioctl(DBFS_IOC_GC, 0).expect("GC failed");
+        assert_eq!(reclaimed, 15 * 4096);
+
+        // 数据不变, 文件系统一致
+        let mut buf = [0u8; 4096];
+        assert_eq!(file.read_at(0, &mut buf).expect("Read failed"), 4096);
+        assert!(buf.iter().all(|&b| b == 15));
+        assert_eq!(file.ioctl(DBFS_IOC_FSCK, 0).expect("Fsck failed"), 0);
+        assert_eq!(
+            file.ioctl(DBFS_IOC_CHECKPOINT, 0)
+                .expect("Checkpoint failed"),
+            0
+        );
+
+        // 没有可回收的空间
+        assert_eq!(file.ioctl(DBFS_IOC_GC, 0).expect("GC failed"), 0);
+        assert_eq!(file.ioctl(0xdead, 0).err(), Some(vfscore::VfsError::NoSys));
+    }
+
+    /// 构造一个 ustar 头
+    #[cfg(feature = "fuse")]
+    fn tar_header(
+        path: &str,
+        mode: u32,
+        size: usize,
+        mtime: i64,
+        typeflag: u8,
+        link: &str,
+    ) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        let mut field = |range: core::ops::Range<usize>, value: &[u8]| {
+            header[range.start..range.start + value.len()].copy_from_slice(value);
+        };
+        field(0..100, path.as_bytes());
+        field(100..108, alloc::format!("{:07o}\0", mode).as_bytes());
+        field(124..136, alloc::format!("{:011o}\0", size).as_bytes());
+        field(136..148, alloc::format!("{:011o}\0", mtime).as_bytes());
+        field(156..157, &[typeflag]);
+        field(157..257, link.as_bytes());
+        field(257..263, b"ustar\0");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..156].copy_from_slice(alloc::format!("{:06o}\0 ", sum).as_bytes());
+        header
+    }
+
+    #[cfg(feature = "fuse")]
+    #[test]
+    fn test_import_tar_stream() {
+        let mut tar = Vec::new();
+        let mut add = |path: &str, mode: u32, mtime: i64, typeflag: u8, data: &[u8], link: &str| {
+            tar.extend_from_slice(&tar_header(path, mode, data.len(), mtime, typeflag, link));
+            tar.extend_from_slice(data);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        };
+        add("etc/", 0o755, 1_600_000_000, b'5', b"", "");
+        add("etc/hostname", 0o644, 1_600_000_001, b'0', b"dbfs\n", "");
+        add(
+            "etc/localtime",
+            0o777,
+            1_600_000_002,
+            b'2',
+            b"",
+            "/usr/share/zoneinfo/UTC",
+        );
+        // 父目录 bin 没有单独的条目
+        add("bin/sh", 0o755, 1_600_000_003, b'0', &[0x7f; 1000], "");
+        tar.extend_from_slice(&[0u8; 1024]);
+
+        let mut engine = new_engine();
+        let imported = engine.import_stream(1, &tar[..]).expect("Import failed");
+        assert_eq!(imported, 4);
+
+        let etc = engine.lookup_dentry(1, "etc").expect("Lookup failed");
+        let meta = engine.get_metadata(etc).expect("Get metadata failed");
+        assert_eq!(meta.mode, 0o040755);
+        assert_eq!(meta.mtime, 1_600_000_000);
+
+        let hostname = engine
+            .lookup_dentry(etc, "hostname")
+            .expect("Lookup failed");
+        let meta = engine.get_metadata(hostname).expect("Get metadata failed");
+        assert_eq!(meta.mode, 0o100644);
+        assert_eq!(meta.mtime, 1_600_000_001);
+        let mut buf = [0u8; 16];
+        let n = engine
+            .read_file(hostname, 0, &mut buf)
+            .expect("Read failed");
+        assert_eq!(&buf[..n], b"dbfs\n");
+
+        let link = engine
+            .lookup_dentry(etc, "localtime")
+            .expect("Lookup failed");
+        assert_eq!(
+            engine.get_metadata(link).expect("Get metadata failed").mode,
+            0o120777
+        );
+        let mut buf = [0u8; 64];
+        let n = engine.read_file(link, 0, &mut buf).expect("Read failed");
+        assert_eq!(&buf[..n], b"/usr/share/zoneinfo/UTC");
+
+        let bin = engine.lookup_dentry(1, "bin").expect("Lookup failed");
+        let sh = engine.lookup_dentry(bin, "sh").expect("Lookup failed");
+        assert_eq!(
+            engine.get_metadata(sh).expect("Get metadata failed").size,
+            1000
+        );
+        assert!(engine.fsck(false).expect("Fsck failed").is_clean());
+
+        // 损坏的头被拒绝
+        let mut bad = tar.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            engine.import_stream(1, &bad[..]),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+    }
+
+    #[cfg(feature = "fuse")]
+    #[test]
+    fn test_fuse_tx_smoke() {
+        extern crate std;
+        use crate::fuse::txfs::DbfsTxFuse;
+        use std::{format, fs, path::Path};
+
+        // 没有 FUSE 设备的环境 (如容器) 中无法挂载
+        if !Path::new("/dev/fuse").exists() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("dbfs-t-fuse-{}", std::process::id()));
+        let data = dir.join("data");
+        let mnt = dir.join("mnt");
+        fs::create_dir_all(&data).expect("create data dir failed");
+        fs::create_dir_all(&mnt).expect("create mount point failed");
+        let options = [fuser::MountOption::FSName("dbfs-t".into())];
+
+        // 1. 挂载后写入文件并读回
+        let txfs = DbfsTxFuse::open(&data, 16 * 1024 * 1024).expect("open failed");
+        let session = fuser::spawn_mount2(txfs, &mnt, &options).expect("mount failed");
+        fs::create_dir(mnt.join("sub")).expect("mkdir failed");
+        fs::write(mnt.join("sub/hello.txt"), b"hello dbfs-t").expect("write failed");
+        assert_eq!(
+            fs::read(mnt.join("sub/hello.txt")).expect("read failed"),
+            b"hello dbfs-t"
+        );
+
+        // 2. 卸载
+        drop(session);
+
+        // 3. 重新挂载后数据仍在
+        let txfs = DbfsTxFuse::open(&data, 16 * 1024 * 1024).expect("reopen failed");
+        let session = fuser::spawn_mount2(txfs, &mnt, &options).expect("remount failed");
+        assert_eq!(
+            fs::read(mnt.join("sub/hello.txt")).expect("read failed"),
+            b"hello dbfs-t"
+        );
+        fs::remove_file(mnt.join("sub/hello.txt")).expect("unlink failed");
+        fs::remove_dir(mnt.join("sub")).expect("rmdir failed");
+        drop(session);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_per_inode_locks() {
+        use crate::rvfs_adapter::DbfsSuperBlock;
+        use vfscore::VfsSuperBlock;
+
+        let engine = Arc::new(Mutex::new(new_engine()));
+        let sb = Arc::new_cyclic(|weak| DbfsSuperBlock {
+            engine: engine.clone(),
+            locks: Arc::default(),
+            self_weak: weak.clone(),
+        });
+        let root = sb.root_inode().expect("Get root failed");
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+
+        // 1. 两个线程各写一个文件
+        let writers: Vec<_> = [("a.bin", b'a'), ("b.bin", b'b')]
+            .into_iter()
+            .map(|(name, byte)| {
+                let file = root
+                    .create(name, VfsNodeType::File, perm, None)
+                    .expect("Create failed");
+                std::thread::spawn(move || {
+                    for i in 0..64u64 {
+                        file.write_at(i * 4096, &[byte; 4096])
+                            .expect("Write failed");
+                    }
+                    (file, byte)
+                })
+            })
+            .collect();
+        for writer in writers {
+            let (file, byte) = writer.join().expect("Writer panicked");
+            let mut buf = alloc::vec![0u8; 64 * 4096];
+            assert_eq!(file.read_at(0, &mut buf).expect("Read failed"), buf.len());
+            assert!(buf.iter().all(|&b| b == byte));
+        }
+        // 不同文件的读写互不等待
+        assert_eq!(sb.locks.contended(), 0);
+
+        // 2. 同一文件的写入要等待当前持有者
+        let file = root.lookup("a.bin").expect("Lookup failed");
+        let ino = file.get_attr().expect("Get attr failed").st_ino;
+        let guard = sb.locks.write(ino);
+        let writer = std::thread::spawn(move || file.write_at(0, b"x").expect("Write failed"));
+        while sb.locks.contended() == 0 {
+            core::hint::spin_loop();
+        }
+        drop(guard);
+        writer.join().expect("Writer panicked");
+        assert_eq!(sb.locks.contended(), 1);
+    }
+
+    #[test]
+    fn test_max_extents_per_inode() {
+        use crate::tx_engine::{COMPACT_CHUNK, DEFAULT_MAX_EXTENTS_PER_INODE};
+
+        let mut engine = new_engine();
+        assert_eq!(
+            engine.max_extents_per_inode(),
+            DEFAULT_MAX_EXTENTS_PER_INODE
+        );
+        engine.set_max_extents_per_inode(16);
+        let ino = engine
+            .create_exclusive(1, "fragmented.bin", 0o100644)
+            .expect("Create failed");
+        engine
+            .write_file_transactional(ino, 0, &[b'.'; 4096])
+            .expect("Write failed");
+
+        // 1. 大量互不相邻的小块覆盖写, extent 数达到上限之前就被整理
+        let mut expected = alloc::vec![b'.'; 4096];
+        let mut compactions = 0;
+        let mut last = 1;
+        for i in 0..64u64 {
+            let offset = i * 64;
+            engine
+                .write_file_transactional(ino, offset, b"x")
+                .expect("Write failed");
+            expected[offset as usize] = b'x';
+
+            let extents = engine
+                .get_metadata(ino)
+                .expect("Get metadata failed")
+                .extents
+                .len();
+            assert!(extents <= 16);
+            if extents < last {
+                compactions += 1;
+            }
+            last = extents;
+        }
+        assert!(compactions > 0);
+        let mut buf = alloc::vec![0u8; 4096];
+        engine.read_file(ino, 0, &mut buf).expect("Read failed");
+        assert_eq!(buf, expected);
+
+        // 2. 整理之后仍然超出上限时写入失败, 文件保持原样
+        engine.set_max_extents_per_inode(1);
+        let big = engine
+            .create_exclusive(1, "big.bin", 0o100644)
+            .expect("Create failed");
+        let data = alloc::vec![b'B'; 2 * COMPACT_CHUNK as usize];
+        engine
+            .write_file_transactional(big, 0, &data)
+            .expect("Write failed");
+        let res = engine.write_file_transactional(big, 0, b"overwrite");
+        assert!(matches!(res, Err(crate::common::DbfsError::NoSpace)));
+        let meta = engine.get_metadata(big).expect("Get metadata failed");
+        assert_eq!(meta.extents.len(), 1);
+        let mut buf = alloc::vec![0u8; data.len()];
+        engine.read_file(big, 0, &mut buf).expect("Read failed");
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_name_index_large_dir() {
+        let mut engine = new_engine();
+        let dir = engine
+            .allocate_inode(0o040755)
+            .expect("Allocate inode failed");
+        engine
+            .batch(|ctx| {
+                for i in 0..10_000u64 {
+                    ctx.add_dentry(dir, alloc::format!("file_{}", i), 100 + i)?;
+                }
+                Ok(())
+            })
+            .expect("Add dentries failed");
+
+        // 1. 没有索引时查找不经过索引
+        assert!(!engine.has_name_index(dir).expect("Check failed"));
+        assert!(matches!(
+            engine.lookup_dentry(dir, "missing"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+        assert_eq!(engine.name_index_misses(), 0);
+
+        // 2. 建立索引后, 不存在的名字由索引直接判定, 不读取目录项
+        engine
+            .enable_name_index(dir)
+            .expect("Enable name index failed");
+        assert!(engine.has_name_index(dir).expect("Check failed"));
+        for i in 0..100 {
+            let name = alloc::format!("missing_{}", i);
+            assert!(matches!(
+                engine.lookup_dentry(dir, &name),
+                Err(crate::common::DbfsError::NotFound)
+            ));
+        }
+        assert_eq!(engine.name_index_misses(), 100);
+        assert_eq!(
+            engine
+                .lookup_dentry(dir, "file_9999")
+                .expect("Lookup failed"),
+            10_099
+        );
+
+        // 3. 插入和删除同步更新索引
+        engine
+            .add_dentry(dir, "late", 42)
+            .expect("Add dentry failed");
+        assert_eq!(
+            engine.lookup_dentry(dir, "late").expect("Lookup failed"),
+            42
+        );
+        engine
+            .delete_dentry(dir, "file_0")
+            .expect("Delete dentry failed");
+        assert!(matches!(
+            engine.lookup_dentry(dir, "file_0"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+        assert_eq!(engine.name_index_misses(), 101);
+
+        // 4. 批处理中的查找也能看到索引的修改
+        engine
+            .batch(|ctx| {
+                ctx.add_dentry(dir, "in_batch", 43)?;
+                assert_eq!(ctx.lookup_dentry(dir, "in_batch")?, Some(43));
+                assert_eq!(ctx.lookup_dentry(dir, "file_0")?, None);
+                Ok(())
+            })
+            .expect("Batch failed");
+
+        // 5. 删除索引后回到直接查找目录项
+        engine
+            .disable_name_index(dir)
+            .expect("Disable name index failed");
+        assert!(!engine.has_name_index(dir).expect("Check failed"));
+        assert_eq!(
+            engine
+                .lookup_dentry(dir, "in_batch")
+                .expect("Lookup failed"),
+            43
+        );
+        assert!(matches!(
+            engine.lookup_dentry(dir, "missing"),
+            Err(crate::common::DbfsError::NotFound)
+        ));
+        assert_eq!(engine.name_index_misses(), 101);
+    }
+
+    #[test]
+    fn test_dedup_identical_blocks() {
+        let mut engine = new_engine();
+        engine.set_dedup(true);
+        let block: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+        // 1. 同一个块写入两个文件, 日志只追加一次
+        let a = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        let b = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        let start = engine.next_append_pos();
+        engine
+            .write_file_transactional(a, 0, &block)
+            .expect("Write failed");
+        assert_eq!(engine.next_append_pos(), start + 4096);
+        engine
+            .write_file_transactional(b, 4096, &block)
+            .expect("Write failed");
+        assert_eq!(engine.next_append_pos(), start + 4096);
+
+        let mut buf = alloc::vec![0u8; 4096];
+        engine.read_file(b, 4096, &mut buf).expect("Read failed");
+        assert_eq!(buf, block);
+
+        // 2. 删除一个文件后共享块仍被另一个文件引用, 之后的写入继续共享
+        engine.delete_inode(a).expect("Delete inode failed");
+        let c = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        engine
+            .write_file_transactional(c, 0, &block)
+            .expect("Write failed");
+        assert_eq!(engine.next_append_pos(), start + 4096);
+
+        // 3. 所有引用都删除后块移出去重表, 相同内容重新追加
+        engine.delete_inode(b).expect("Delete inode failed");
+        engine.delete_inode(c).expect("Delete inode failed");
+        let d = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        engine
+            .write_file_transactional(d, 0, &block)
+            .expect("Write failed");
+        assert_eq!(engine.next_append_pos(), start + 2 * 4096);
+
+        // 4. 内容不同或未对齐的写入照常追加
+        engine
+            .write_file_transactional(d, 4096, &[1u8; 4096])
+            .expect("Write failed");
+        engine
+            .write_file_transactional(d, 100, &block[..100])
+            .expect("Write failed");
+        assert_eq!(engine.next_append_pos(), start + 3 * 4096 + 100);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compression_roundtrip() {
+        let mut engine = new_engine();
+        engine.set_compression(true);
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+
+        // 1. 高度可压缩的数据在日志中只占很少的空间
+        let text: Vec<u8> = b"dbfs compresses repetitive text. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(64 * 1024)
+            .collect();
+        engine
+            .write_file_transactional(ino, 0, &text)
+            .expect("Write failed");
+        let stats = engine.log_stats();
+        assert_eq!(stats.logical_written, text.len() as u64);
+        assert!(stats.physical_written * 10 < stats.logical_written);
+
+        let mut buf = alloc::vec![0u8; text.len()];
+        assert_eq!(
+            engine.read_file(ino, 0, &mut buf).expect("Read failed"),
+            text.len()
+        );
+        assert_eq!(buf, text);
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert!(meta.extents[0].compressed);
+        assert!(engine
+            .verify_extent(&meta.extents[0])
+            .expect("Verify failed"));
+
+        // 2. 从压缩的 extent 中间读取、截断之后读取
+        let mut part = [0u8; 100];
+        engine.read_file(ino, 1000, &mut part).expect("Read failed");
+        assert_eq!(&part[..], &text[1000..1100]);
+        engine.truncate_file(ino, 5000).expect("Truncate failed");
+        let mut buf = alloc::vec![0u8; 5000];
+        assert_eq!(
+            engine.read_file(ino, 0, &mut buf).expect("Read failed"),
+            5000
+        );
+        assert_eq!(&buf[..], &text[..5000]);
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert!(engine
+            .verify_extent(&meta.extents[0])
+            .expect("Verify failed"));
+
+        // 3. 压缩后不会变小的数据按原样保存
+        let noise: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let before = engine.log_stats().physical_written;
+        engine
+            .write_file_transactional(ino, 8192, &noise)
+            .expect("Write failed");
+        assert_eq!(
+            engine.log_stats().physical_written - before,
+            noise.len() as u64
+        );
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert!(!meta.extents.last().unwrap().compressed);
+        let mut buf = alloc::vec![0u8; 4096];
+        engine.read_file(ino, 8192, &mut buf).expect("Read failed");
+        assert_eq!(buf, noise);
+    }
+
+    #[test]
+    fn test_extent_map() {
+        use crate::common::ExtentFlags;
+
+        let mut engine = new_engine();
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        let s = engine.next_append_pos();
+        engine
+            .write_file_transactional(ino, 0, &[1u8; 4096])
+            .expect("Write failed");
+        engine
+            .write_file_transactional(ino, 8192, &[2u8; 4096])
+            .expect("Write failed");
+        engine
+            .write_file_transactional(ino, 1000, &[3u8; 1000])
+            .expect("Write failed");
+        engine
+            .preallocate(ino, 12288, 4096)
+            .expect("Preallocate failed");
+
+        let inode = DbfsInode {
+            ino,
+            engine: Arc::new(Mutex::new(engine)),
+            locks: Arc::new(Default::default()),
+            sb: alloc::sync::Weak::new(),
+        };
+        let empty = ExtentFlags::empty();
+        assert_eq!(
+            inode.extent_map().expect("Extent map failed"),
+            alloc::vec![
+                (0, s, 1000, empty),
+                (1000, s + 8192, 1000, empty),
+                (2000, s + 2000, 2096, empty),
+                (4096, 0, 4096, ExtentFlags::HOLE),
+                (8192, s + 4096, 4096, empty),
+                (
+                    12288,
+                    s + 9192,
+                    4096,
+                    ExtentFlags::UNWRITTEN | ExtentFlags::LAST
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_huge_offsets_rejected() {
+        let mut engine = new_engine();
+        let ino = engine
+            .allocate_inode(0o100644)
+            .expect("Allocate inode failed");
+        engine
+            .write_file_transactional(ino, 0, b"hello")
+            .expect("Write failed");
+        let before = engine.next_append_pos();
+
+        // 写入范围超出 u64 时返回错误, 不会回绕到文件开头
+        assert!(matches!(
+            engine.write_file_transactional(ino, u64::MAX - 2, b"wrap"),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+        assert!(matches!(
+            engine.stage_write(ino, u64::MAX, b"x"),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+        assert_eq!(engine.next_append_pos(), before);
+
+        // 读取同样检查
+        let mut buf = [0u8; 16];
+        assert!(matches!(
+            engine.read_file(ino, u64::MAX - 4, &mut buf),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+        let meta = engine.get_metadata(ino).expect("Get metadata failed");
+        assert!(matches!(
+            engine.read_from_log(&meta, u64::MAX, &mut buf),
+            Err(crate::common::DbfsError::InvalidArgument)
+        ));
+
+        // 文件开头的数据没有被改动, 末尾之后的普通偏移仍然读到 0 字节
+        assert_eq!(engine.read_file(ino, 0, &mut buf).expect("Read failed"), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(
+            engine
+                .read_file(ino, u64::MAX - 16, &mut buf)
+                .expect("Read failed"),
+            0
+        );
+    }
+
+    #[test]
+    fn test_mount_rejects_small_device() {
+        use crate::rvfs_adapter::MIN_DEVICE_SIZE;
+
+        let fs_type = Arc::new(DbfsFsType);
+
+        // 1KB 的设备放不下保留区和日志区
+        let tiny = Arc::new(RamDisk::new(1024));
+        assert!(matches!(
+            fs_type
+                .clone()
+                .mount(0, "/", Some(tiny.clone() as Arc<dyn VfsInode>), &[]),
+            Err(vfscore::VfsError::Invalid)
+        ));
+        // 拒绝时没有写入设备
+        assert!(tiny.data.lock().iter().all(|&b| b == 0));
+
+        // 保留区之后不足最小日志区时同样拒绝
+        let small = Arc::new(RamDisk::new(MIN_DEVICE_SIZE as usize * 2));
+        assert!(matches!(
+            fs_type.clone().mount(
+                0,
+                "/",
+                Some(small as Arc<dyn VfsInode>),
+                alloc::format!("reserved_size={}", MIN_DEVICE_SIZE * 2 - 4096).as_bytes()
+            ),
+            Err(vfscore::VfsError::Invalid)
+        ));
+
+        // 足够大的设备正常挂载
+        let ram_disk = Arc::new(RamDisk::new(1024 * 1024));
+        let root_dentry = fs_type
+            .mount(0, "/", Some(ram_disk as Arc<dyn VfsInode>), &[])
+            .expect("Mount failed");
+        root_dentry.inode().expect("Get root inode failed");
+    }
+
+    #[test]
+    fn test_inode_metadata_v1_blob() {
+        use crate::models::{InodeMetadata, INODE_METADATA_VERSION};
+
+        // 1. 第 1 版的记录: 没有 version/uid/entry_count, extent 没有压缩相关字段
+        let v1 = br#"{"ino":5,"size":10,"mode":33188,"nlink":1,"extents":[{"logical_off":0,"physical_ptr":4096,"len":10,"crc":7}],"atime":1,"mtime":2}"#;
+        let mut meta: InodeMetadata = serde_json::from_slice(v1).expect("Decode v1 failed");
+        assert_eq!(meta.version, 1);
+        assert!(meta.migrate());
+        assert_eq!(meta.version, INODE_METADATA_VERSION);
+        assert_eq!(
+            (meta.ino, meta.size, meta.uid, meta.entry_count),
+            (5, 10, 0, None)
+        );
+        assert!(!meta.extents[0].reserved && !meta.extents[0].compressed);
+
+        // 2. 写回时带上当前版本号
+        let encoded = serde_json::to_vec(&meta).expect("Encode failed");
+        let again: InodeMetadata = serde_json::from_slice(&encoded).expect("Decode failed");
+        assert_eq!(again.version, INODE_METADATA_VERSION);
+
+        // 3. 更新版本的记录无法理解
+        let future = br#"{"ino":5,"size":0,"mode":33188,"nlink":1,"extents":[],"atime":0,"mtime":0,"version":99}"#;
+        let mut meta: InodeMetadata = serde_json::from_slice(future).expect("Decode failed");
+        assert!(!meta.migrate());
+    }
 }
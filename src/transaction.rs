@@ -1,8 +1,52 @@
-use crate::wal::WriteAheadLog;
-use alloc::vec::Vec;
+use crate::operation::TransactionOperation;
+use crate::wal::{RecoveryStatus, WalStats, WriteAheadLog};
 use alloc::string::String;
 use alloc::sync::Arc;
-use spin::{Mutex, RwLock};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard, Once, RwLock};
+
+use crate::{clone_db, SafeDb};
+
+/// A group flush starts as soon as this many transactions are waiting for it.
+pub const GROUP_COMMIT_MAX: u64 = 64;
+
+/// Spins the flushing commit waits for other commits to join, by default.
+pub const DEFAULT_GROUP_COMMIT_SPINS: usize = 1024;
+
+/// Write the dirty cached pages of `db` back to it, those of `ino` only if given
+fn write_back(db: &jammdb::DB, ino: Option<usize>) -> Result<(), String> {
+    #[cfg(feature = "rvfs2")]
+    crate::rvfs2::cache::flush(db, ino).map_err(|e| alloc::format!("Write back error: {:?}", e))?;
+    #[cfg(not(feature = "rvfs2"))]
+    let _ = (db, ino);
+    Ok(())
+}
+
+/// A commit's place in the apply order
+///
+/// Dropping it lets the next sequence number apply, so a commit that fails or panics
+/// anywhere after appending never leaves later commits spinning.
+struct ApplyTurn<'a> {
+    apply_seq: &'a AtomicU64,
+    seq: u64,
+}
+
+impl ApplyTurn<'_> {
+    /// Wait until every earlier commit has applied
+    fn wait(&self) {
+        while self.apply_seq.load(Ordering::SeqCst) != self.seq {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for ApplyTurn<'_> {
+    fn drop(&mut self) {
+        self.wait();
+        self.apply_seq.store(self.seq + 1, Ordering::SeqCst);
+    }
+}
 
 pub struct Transaction {
     pub id: u64,
@@ -27,6 +71,18 @@ pub struct TransactionManager {
     next_txn_id: Mutex<u64>,
     /// Ensures that while operations are being applied, no one is reading.
     pub state_lock: RwLock<()>,
+    /// Database the operations run against, the global one if unset
+    db: Once<Arc<SafeDb>>,
+    /// Sequence number of the last transaction appended to the WAL
+    appended_seq: AtomicU64,
+    /// Every transaction up to this sequence number has been flushed
+    durable_seq: AtomicU64,
+    /// The only sequence number allowed to apply next, so operations apply in WAL order
+    apply_seq: AtomicU64,
+    /// Held by the commit that flushes on behalf of the group
+    flush_lock: Mutex<()>,
+    /// Spins the flushing commit waits for others to join
+    group_window: AtomicUsize,
 }
 
 impl TransactionManager {
@@ -35,6 +91,12 @@ impl TransactionManager {
             wal: Mutex::new(WriteAheadLog::new()),
             next_txn_id: Mutex::new(1),
             state_lock: RwLock::new(()),
+            db: Once::new(),
+            appended_seq: AtomicU64::new(0),
+            durable_seq: AtomicU64::new(0),
+            apply_seq: AtomicU64::new(1),
+            flush_lock: Mutex::new(()),
+            group_window: AtomicUsize::new(DEFAULT_GROUP_COMMIT_SPINS),
         }
     }
 
@@ -48,7 +110,82 @@ impl TransactionManager {
     pub fn set_wal_storage(&self, storage: Arc<dyn crate::wal::WalStorage>) {
         self.wal.lock().set_storage(storage);
     }
-}
+
+    /// Run operations against `db` instead of the global database; only the first call counts.
+    pub(crate) fn set_db(&self, db: Arc<SafeDb>) {
+        self.db.call_once(|| db);
+    }
+
+    fn db(&self) -> Arc<SafeDb> {
+        self.db.get().cloned().unwrap_or_else(clone_db)
+    }
+
+    /// How long, in spins, a flushing commit waits for concurrent commits to join it.
+    /// 0 flushes right away; commits arriving during a flush still share the next one.
+    pub fn set_group_commit_window(&self, spins: usize) {
+        self.group_window.store(spins, Ordering::SeqCst);
+    }
+
+    /// Lock the WAL once no commit is between appending and applying.
+    ///
+    /// Appends only happen under the WAL lock, so none can start while the guard is held.
+    fn quiesce(&self) -> MutexGuard<'_, WriteAheadLog> {
+        loop {
+            let wal = self.wal.lock();
+            if self.apply_seq.load(Ordering::SeqCst) == self.appended_seq.load(Ordering::SeqCst) + 1
+            {
+                return wal;
+            }
+            drop(wal);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait until the WAL records of transaction `seq` are on disk.
+    ///
+    /// The first waiter to take `flush_lock` gives others a short window to append, then
+    /// flushes once for everything appended so far and wakes them all.
+    fn wait_durable(&self, seq: u64) -> Result<(), String> {
+        loop {
+            if self.durable_seq.load(Ordering::SeqCst) >= seq {
+                return Ok(());
+            }
+            let Some(_leader) = self.flush_lock.try_lock() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            if self.durable_seq.load(Ordering::SeqCst) >= seq {
+                return Ok(());
+            }
+
+            // Only worth waiting if someone else is committing too
+            let in_flight = |tm: &Self| {
+                tm.appended_seq.load(Ordering::SeqCst) + 1 - tm.apply_seq.load(Ordering::SeqCst)
+            };
+            if in_flight(self) > 1 {
+                let mut spins = self.group_window.load(Ordering::SeqCst);
+                while spins > 0
+                    && self.appended_seq.load(Ordering::SeqCst)
+                        - self.durable_seq.load(Ordering::SeqCst)
+                        < GROUP_COMMIT_MAX
+                {
+                    core::hint::spin_loop();
+                    spins -= 1;
+                }
+            }
+
+            // Every record up to `target` was written under the WAL lock before this flush
+            let (target, storage) = {
+                let wal = self.wal.lock();
+                (self.appended_seq.load(Ordering::SeqCst), wal.storage())
+            };
+            if let Some(storage) = storage {
+                storage.flush()?;
+            }
+            self.durable_seq.fetch_max(target, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
 
     /// Simulates a crash scenario: writes to WAL but does not apply ops.
     pub fn commit_into_wal_only(&self, txn: Transaction) -> Result<(), String> {
@@ -61,45 +198,181 @@ impl TransactionManager {
     }
 
     pub fn commit(&self, txn: Transaction) -> Result<(), String> {
-        let mut wal = self.wal.lock();
-        
         // 1. Write all ops to WAL
-        for op in &txn.ops {
-            wal.append(txn.id, op.clone())?;
+        let seq = {
+            let mut wal = self.wal.lock();
+            for op in &txn.ops {
+                wal.append(txn.id, op.clone())?;
+            }
+            self.appended_seq.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        // Passes the turn on when dropped, even if this commit panics
+        let turn = ApplyTurn {
+            apply_seq: &self.apply_seq,
+            seq,
+        };
+
+        // 2. Flush WAL together with concurrent commits (ensures durability)
+        let durable = self.wait_durable(seq);
+
+        // 3. Apply in WAL order, so replay after a crash reaches the same state
+        turn.wait();
+        match durable {
+            Ok(()) => self.apply_committed(txn),
+            Err(e) => {
+                // Not known to be durable, so not applied: it must not be replayed either
+                self.wal.lock().clear_txn(txn.id);
+                Err(e)
+            }
         }
-        
-        // 2. Flush WAL (ensures durability)
-        wal.flush()?;
-        
+    }
+
+    /// Apply a transaction whose WAL records are durable, then drop them from the log.
+    fn apply_committed(&self, txn: Transaction) -> Result<(), String> {
         // --- Atomic Point ---
-        // 3. Acquire exclusive lock before applying operations to Bottom FS
+        // Acquire exclusive lock before applying operations to Bottom FS
         let _guard = self.state_lock.write();
-        
-        // 4. Apply operations to Bottom FS (Deferred Execution)
+
+        // Apply operations to Bottom FS (Deferred Execution), unwinding on failure
+        let db = self.db();
+        let mut applied = Vec::new();
         for op in txn.ops {
-            op.apply()?;
+            match op
+                .capture_in(&db)
+                .and_then(|state| op.apply_in(&db).map(|_| state))
+            {
+                Ok(state) => applied.push((op, state)),
+                Err(e) => {
+                    for (op, state) in applied.into_iter().rev() {
+                        if let Err(undo) = op.undo_in(&db, &state) {
+                            log::error!("Rollback of {:?} failed: {}", op, undo);
+                        }
+                    }
+                    // The transaction was rolled back, so it must not be replayed
+                    self.wal.lock().clear_txn(txn.id);
+                    return Err(e);
+                }
+            }
+        }
+
+        // Data may still sit in dirty cached pages; it must reach the database before the
+        // records leave the log, or a crash loses committed writes. On failure they stay
+        // logged for replay.
+        for (op, _) in &applied {
+            if !op.is_metadata_only() {
+                write_back(&db, Some(op.target_ino()))?;
+            }
+        }
+
+        // Clear from WAL (Checkpoint)
+        self.wal.lock().clear_txn(txn.id);
+
+        Ok(())
+    }
+
+    /// Make every pending operation on `ino` durable (`fsync`).
+    ///
+    /// Operations that were logged but not yet applied are applied to the Bottom FS and the
+    /// WAL is flushed; once nothing is pending the log is checkpointed. With `datasync`,
+    /// metadata-only operations stay pending. Operations on other inodes logged before the
+    /// last one on `ino` are applied with it, in log order, so none is overtaken. If one fails
+    /// to apply, it and everything after it stay pending.
+    pub fn sync_inode(&self, ino: usize, datasync: bool) -> Result<(), String> {
+        let mut wal = self.quiesce();
+        let mut entries = wal
+            .take_through(|e| {
+                e.operation.target_ino() == ino && !(datasync && e.operation.is_metadata_only())
+            })
+            .into_iter();
+
+        let db = self.db();
+        {
+            let _guard = self.state_lock.write();
+            while let Some(entry) = entries.next() {
+                if let Err(e) = entry.operation.apply_in(&db) {
+                    let mut remaining = alloc::vec![entry];
+                    remaining.extend(entries);
+                    wal.requeue(remaining);
+                    return Err(e);
+                }
+            }
+        }
+
+        wal.flush()?;
+        if wal.pending() == 0 {
+            write_back(&db, None)?;
+            wal.checkpoint()?;
         }
-        
-        // 5. Clear from WAL (Checkpoint)
-        wal.clear_txn(txn.id);
-        
         Ok(())
     }
 
+    /// Number of logged operations not yet applied to the Bottom FS.
+    pub fn pending_ops(&self) -> usize {
+        self.wal.lock().pending()
+    }
+
+    /// WAL statistics, so an embedder can decide when to force a checkpoint.
+    pub fn wal_stats(&self) -> WalStats {
+        self.wal.lock().stats()
+    }
+
+    /// Apply every pending operation and truncate the WAL.
+    ///
+    /// Waiting out in-flight commits and holding the WAL lock keeps concurrent commits out, so
+    /// no transaction is half-logged while the log is reset. If an operation fails to apply, it and everything after it stay
+    /// pending and the log is left untouched.
+    pub fn force_checkpoint(&self) -> Result<(), String> {
+        let mut wal = self.quiesce();
+        let mut entries = wal.take_pending(|_| true).into_iter();
+
+        let db = self.db();
+        {
+            let _guard = self.state_lock.write();
+            while let Some(entry) = entries.next() {
+                if let Err(e) = entry.operation.apply_in(&db) {
+                    let mut remaining = alloc::vec![entry];
+                    remaining.extend(entries);
+                    wal.requeue(remaining);
+                    return Err(e);
+                }
+            }
+        }
+
+        wal.flush()?;
+        write_back(&db, None)?;
+        wal.checkpoint()
+    }
+
+    /// Checkpoint once the WAL has grown past `max_bytes`. Returns whether it checkpointed.
+    pub fn checkpoint_if_needed(&self, max_bytes: u64) -> Result<bool, String> {
+        if self.wal.lock().stats().next_offset <= max_bytes {
+            return Ok(false);
+        }
+        self.force_checkpoint()?;
+        Ok(true)
+    }
+
     pub fn replay(&self) -> Result<(), String> {
         let mut wal = self.wal.lock();
-        let entries = wal.recover()?;
-        
-        log::info!("Replaying {} transactional operations from WAL", entries.len());
-        
+        let (entries, status) = wal.recover()?;
+        if status == RecoveryStatus::TruncatedTail {
+            log::warn!("Dropped a torn record at the end of the WAL");
+        }
+
+        log::info!(
+            "Replaying {} transactional operations from WAL",
+            entries.len()
+        );
+
+        let db = self.db();
         for entry in entries {
-            entry.operation.apply()?;
+            entry.operation.apply_in(&db)?;
         }
-        
+
         // After replaying all entries, we can clear the memory log
         // Note: The physical log persists until we decide to truncate/checkpoint.
         // For this simple version, we assume replay is equivalent to a checkpoint.
-        
+
         Ok(())
     }
 
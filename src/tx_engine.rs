@@ -1,287 +1,2063 @@
-use crate::models::{InodeMetadata, Extent};
-use crate::log_manager::{LogManager, BlockDevice, crc32};
-use crate::common::{DbfsResult, DbfsError};
-use jammdb::DB;
+use crate::common::{
+    dbfs_check_name, dbfs_check_name_bytes, DbfsError, DbfsResult, ExtentFlags, ExtentMapping,
+    MAX_TREE_DEPTH,
+};
+use crate::log_manager::{crc32, BlockDevice, LogManager, LogSlot, LogStats};
+use crate::metrics::{ops, Metrics, Timer};
+use crate::models::{Extent, InodeMetadata, INODE_METADATA_VERSION};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use jammdb::{Data, DB};
 
-pub struct TransactionEngine<D: BlockDevice> {
+/// `TransactionEngine::fsck` 的检查结果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    /// 指向不存在 Inode 的目录项 (父目录, 名字, 子 Inode)
+    pub dangling_dentries: Vec<(u64, String, u64)>,
+    /// 没有任何目录项引用的 Inode (根目录除外)
+    pub orphaned_inodes: Vec<u64>,
+    /// nlink 与引用它的目录项数不一致的非目录 Inode (Inode, 记录的 nlink, 实际引用数)
+    pub nlink_mismatches: Vec<(u64, u32, u32)>,
+    /// 超出日志写指针的 extent (Inode, extent 下标)
+    pub bad_extents: Vec<(u64, usize)>,
+    /// 是否修复了悬空目录项和 nlink
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// 没有发现任何问题
+    pub fn is_clean(&self) -> bool {
+        self.dangling_dentries.is_empty()
+            && self.orphaned_inodes.is_empty()
+            && self.nlink_mismatches.is_empty()
+            && self.bad_extents.is_empty()
+    }
+}
+
+/// 正常卸载时日志写指针在 `super_blk` 中的 key
+const NEXT_APPEND_POS_KEY: &str = "next_append_pos";
+
+/// `remove_tree` 每个事务最多删除的目录项数
+pub const REMOVE_TREE_BATCH: usize = 64;
+
+/// `import_stream` 每个事务最多导入的条目数
+pub const IMPORT_BATCH: usize = 64;
+
+/// 配额按 4K 块统计, 文件大小向上取整
+pub const QUOTA_BLOCK_SIZE: u64 = 4096;
+
+/// 每个 Inode 默认最多的 extent 数
+pub const DEFAULT_MAX_EXTENTS_PER_INODE: usize = 4096;
+
+/// `compact_extents` 重写数据时每个 extent 的最大长度
+pub const COMPACT_CHUNK: u64 = 64 * 1024;
+
+/// `write` 记录的单个 extent 的最大长度
+///
+/// 更长的写入在文件中按这个大小对齐的位置拆开, 每段单独追加到日志并带有自己的 crc, 与
+/// `compact_extents` 重写后的布局一致。
+pub const MAX_EXTENT_LEN: u64 = COMPACT_CHUNK;
+
+/// 去重的粒度: 开启去重时按这个大小对齐的块按内容共享日志空间
+pub const DEDUP_BLOCK_SIZE: u64 = 4096;
+
+/// 一个 uid 的配额: 已用块数和上限, 没有设置上限时为 `u64::MAX`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub blocks_used: u64,
+    pub blocks_limit: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            blocks_used: 0,
+            blocks_limit: u64::MAX,
+        }
+    }
+}
+
+/// 故障注入点, 按一次写操作中经过的顺序排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// 数据追加到日志之前
+    BeforeDataWrite,
+    /// 数据已落盘, jammdb 事务提交之前
+    BeforeCommit,
+    /// jammdb 事务已提交, 引擎更新内存中的状态之前
+    AfterCommit,
+}
+
+type CrashHook = Box<dyn Fn(CrashPoint) -> bool + Send + Sync>;
+
+// 钩子在 `point` 返回 true 时模拟崩溃, 操作以 `Io` 错误中止
+fn check_crash(hook: Option<&CrashHook>, point: CrashPoint) -> DbfsResult<()> {
+    match hook {
+        Some(hook) if hook(point) => {
+            log::warn!("simulated crash at {:?}", point);
+            Err(DbfsError::Io)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `TransactionEngine::reader` 返回的只读句柄
+pub struct SnapshotReader<D: BlockDevice> {
     db: DB,
     log_manager: LogManager<D>,
+    inodes_bucket: String,
 }
 
-impl<D: BlockDevice> TransactionEngine<D> {
-    pub fn new(db: DB, log_manager: LogManager<D>) -> Self {
-        Self { db, log_manager }
+impl<D: BlockDevice> SnapshotReader<D> {
+    /// 参见 [`TransactionEngine::read_snapshot`]
+    pub fn read_snapshot(&self, ino: u64, offset: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        read_in(
+            &tx,
+            &self.inodes_bucket,
+            &self.log_manager,
+            ino,
+            offset,
+            buf,
+        )
     }
+}
 
-    pub fn write_file_transactional(&mut self, ino: u64, offset: u64, data: &[u8]) -> DbfsResult<()> {
-        // --- 步骤 1: 数据持久化 (数据层先走) ---
-        // 即使这一步写完后断电，因为没有索引，数据在重启后是“不可见”的。
-        let p_ptr = self.log_manager.append_data(data)?;
+/// `TransactionEngine::stage_write` 返回的写入, 数据写入日志后由 `commit_staged` 提交
+pub struct StagedWrite<D: BlockDevice> {
+    ino: u64,
+    offset: u64,
+    len: u64,
+    slot: LogSlot<D>,
+    /// 数据写入之后才有
+    crc: Option<u32>,
+}
 
-        // --- 步骤 2: 开启数据库事务 (索引层后跟) ---
-        let tx = self.db.begin_batch();
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
+impl<D: BlockDevice> StagedWrite<D> {
+    /// 把数据写入预留的日志区域, 不需要持有引擎
+    pub fn write_data(&mut self, data: &[u8]) -> DbfsResult<()> {
+        self.slot.write(data)?;
+        self.crc = Some(crc32(data));
+        Ok(())
+    }
+}
+
+/// 记录成功提交次数的 jammdb 数据库
+///
+/// 引擎的每次提交都经过 [`CountingDb::commit`], 只有 jammdb 报告成功的提交才被计数。
+/// 快照视图共享同一个计数。
+#[derive(Clone)]
+pub struct CountingDb {
+    db: DB,
+    commits: Arc<AtomicU64>,
+}
+
+impl CountingDb {
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            commits: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 记录 `tx.commit()` 的结果
+    pub fn commit(&self, result: Result<(), jammdb::Error>) -> DbfsResult<()> {
+        result.map_err(|_| DbfsError::Io)?;
+        self.commits.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// 已成功提交的事务数
+    pub fn commits(&self) -> u64 {
+        self.commits.load(Ordering::Relaxed)
+    }
+}
+
+impl core::ops::Deref for CountingDb {
+    type Target = DB;
+
+    fn deref(&self) -> &DB {
+        &self.db
+    }
+}
+
+pub struct TransactionEngine<D: BlockDevice> {
+    db: CountingDb,
+    log_manager: LogManager<D>,
+    /// bucket 名前缀: 活动文件系统为空, 快照视图为 `snap_{name}_`, 快照视图只读
+    namespace: String,
+    /// 尚未持久化的 atime (Inode -> atime), 由完整的 `fsync` 写入
+    pending_atime: BTreeMap<u64, i64>,
+    /// 故障注入钩子, 只用于测试
+    crash_hook: Option<CrashHook>,
+    /// 每个 Inode 最多的 extent 数
+    max_extents: usize,
+    /// 由名字哈希索引直接判定不存在的查找次数
+    name_index_misses: AtomicU64,
+    /// 是否对写入的数据块去重
+    dedup: bool,
+    /// 是否压缩写入日志的数据
+    compression: bool,
+    /// 计时回调, `None` 时不计时
+    metrics: Option<Metrics>,
+}
 
-        // --- 步骤 3: 读取-修改-写回 (Read-Modify-Write) ---
+/// 批处理上下文: 所有修改都落在同一个 jammdb 事务中, 由 `TransactionEngine::batch` 一次提交
+pub struct BatchCtx<'a, 'tx, D: BlockDevice> {
+    tx: &'a jammdb::Tx<'tx>,
+    log_manager: &'a mut LogManager<D>,
+    crash_hook: Option<&'a CrashHook>,
+    max_extents: usize,
+    dedup: bool,
+    compression: bool,
+}
+
+impl<'a, 'tx, D: BlockDevice> BatchCtx<'a, 'tx, D> {
+    /// 写入文件数据: 数据先追加到日志, 再在事务中记录 extent
+    ///
+    /// 跨过 [`MAX_EXTENT_LEN`] 边界的数据记录为多个 extent。
+    pub fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> DbfsResult<()> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
         let ino_key = ino.to_be_bytes();
         let kv = bucket.get(&ino_key).ok_or(DbfsError::NotFound)?;
-        
-        let mut meta: InodeMetadata = deserialize(kv.kv().value())?;
-        
+        let mut meta = decode_meta(kv.kv().value())?;
+
+        // 超出配额的写入在追加日志之前拒绝, 不浪费日志空间
+        let end = checked_end(offset, data.len())?;
+        charge_quota(self.tx, meta.uid, meta.size, end.max(meta.size))?;
+
+        check_crash(self.crash_hook, CrashPoint::BeforeDataWrite)?;
+
+        // --- 步骤 1: 数据持久化 (数据层先走) ---
+        // 即使这一步写完后断电，因为没有索引，数据在重启后是“不可见”的。
+        // --- 步骤 2: 在批处理事务中更新索引 (索引层后跟), 读取-修改-写回 ---
         // 增加新的映射关系
-        meta.extents.push(Extent {
-            logical_off: offset,
-            physical_ptr: p_ptr,
-            len: data.len() as u64,
-            crc: crc32(data),
-        });
+        let aligned = offset % DEDUP_BLOCK_SIZE == 0 && data.len() as u64 % DEDUP_BLOCK_SIZE == 0;
+        if self.dedup && aligned && !data.is_empty() {
+            let extents = self.append_dedup(&meta.extents, offset, data)?;
+            meta.extents.extend(extents);
+        } else {
+            for (start, piece) in extent_pieces(offset, data) {
+                let logical_off = offset + start as u64;
+                let piece_end = logical_off + piece.len() as u64;
+                let fresh = piece.len() as u64 - covered_len(&meta.extents, logical_off, piece_end);
+                // 压缩后没有变小的数据按原样保存
+                let stored = if self.compression {
+                    compress(piece)
+                } else {
+                    None
+                };
+                let p_ptr = self
+                    .log_manager
+                    .append_data(stored.as_deref().unwrap_or(piece), fresh)?;
+                meta.extents.push(Extent {
+                    logical_off,
+                    physical_ptr: p_ptr,
+                    len: piece.len() as u64,
+                    crc: crc32(piece),
+                    reserved: false,
+                    compressed: stored.is_some(),
+                    stored_len: stored.as_ref().map_or(0, |stored| stored.len() as u64),
+                });
+            }
+        }
         meta.size = core::cmp::max(meta.size, offset + data.len() as u64);
         // meta.mtime = now(); // TODO: 实现获取当前时间的逻辑
 
         // 将新的元数据覆盖写入数据库
         bucket.put(ino_key, serialize(&meta)?)?;
+        self.check_extent_limit(&meta)
+    }
+
+    // 按块追加对齐的数据: 日志中已有相同内容的块直接引用, 其余连续的块合并成一次追加
+    fn append_dedup(
+        &mut self,
+        existing: &[Extent],
+        offset: u64,
+        data: &[u8],
+    ) -> DbfsResult<Vec<Extent>> {
+        let dedup = self
+            .tx
+            .get_or_create_bucket("dedup")
+            .map_err(|_| DbfsError::Io)?;
+        let refs = self
+            .tx
+            .get_or_create_bucket("dedup_refs")
+            .map_err(|_| DbfsError::Io)?;
+        let block = DEDUP_BLOCK_SIZE as usize;
+        let mut extents = Vec::new();
+        // 尚未追加的连续新块 [run_start, i)
+        let mut run_start = 0;
+        for i in (0..data.len())
+            .step_by(block)
+            .chain(core::iter::once(data.len()))
+        {
+            let shared = match data.get(i..i + block) {
+                Some(chunk) => self.find_block(&dedup, chunk)?,
+                None => None,
+            };
+            if (shared.is_some() || i == data.len()) && run_start < i {
+                let run = &data[run_start..i];
+                let logical_off = offset + run_start as u64;
+                let end = logical_off + run.len() as u64;
+                let fresh = run.len() as u64 - covered_len(existing, logical_off, end);
+                let p_ptr = self.log_manager.append_data(run, fresh)?;
+                for (n, chunk) in run.chunks(block).enumerate() {
+                    let ptr = p_ptr + (n * block) as u64;
+                    let hash = content_hash(chunk);
+                    if dedup.get_kv(hash).is_none() {
+                        dedup.put(hash, ptr.to_be_bytes())?;
+                        refs.put(ptr.to_be_bytes(), dedup_ref_value(hash, 1))?;
+                    }
+                }
+                // 一次追加, 按 `MAX_EXTENT_LEN` 分段记录
+                for (start, piece) in extent_pieces(logical_off, run) {
+                    extents.push(Extent {
+                        logical_off: logical_off + start as u64,
+                        physical_ptr: p_ptr + start as u64,
+                        len: piece.len() as u64,
+                        crc: crc32(piece),
+                        reserved: false,
+                        compressed: false,
+                        stored_len: 0,
+                    });
+                }
+            }
+            if let Some(ptr) = shared {
+                let chunk = &data[i..i + block];
+                let count = dedup_refs(&refs, ptr)?.map_or(0, |(_, count)| count);
+                refs.put(
+                    ptr.to_be_bytes(),
+                    dedup_ref_value(content_hash(chunk), count + 1),
+                )?;
+                extents.push(Extent {
+                    logical_off: offset + i as u64,
+                    physical_ptr: ptr,
+                    len: DEDUP_BLOCK_SIZE,
+                    crc: crc32(chunk),
+                    reserved: false,
+                    compressed: false,
+                    stored_len: 0,
+                });
+                run_start = i + block;
+            }
+        }
+        Ok(extents)
+    }
+
+    // 去重表中与 `chunk` 内容相同的块; 哈希相同时还要逐字节比较
+    fn find_block(&self, dedup: &jammdb::Bucket, chunk: &[u8]) -> DbfsResult<Option<u64>> {
+        let ptr = match dedup.get_kv(content_hash(chunk)) {
+            Some(kv) => u64::from_be_bytes(kv.value().try_into().map_err(|_| DbfsError::Other)?),
+            None => return Ok(None),
+        };
+        if ptr + chunk.len() as u64 > self.log_manager.next_append_pos() {
+            return Ok(None);
+        }
+        let mut same = false;
+        self.log_manager
+            .with_data(ptr, chunk.len(), &mut |data| same = data == chunk)?;
+        Ok(same.then_some(ptr))
+    }
+
+    /// 提交已写入日志的 [`StagedWrite`], 效果与 `write` 相同
+    pub fn commit_staged(&mut self, staged: &StagedWrite<D>) -> DbfsResult<()> {
+        let crc = staged.crc.ok_or(DbfsError::InvalidArgument)?;
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+        let ino_key = staged.ino.to_be_bytes();
+        let kv = bucket.get(&ino_key).ok_or(DbfsError::NotFound)?;
+        let mut meta = decode_meta(kv.kv().value())?;
+
+        let end = staged
+            .offset
+            .checked_add(staged.len)
+            .ok_or(DbfsError::InvalidArgument)?;
+        charge_quota(self.tx, meta.uid, meta.size, end.max(meta.size))?;
+
+        // 数据是在日志管理器之外写入的, 提交前同样要先落盘
+        self.log_manager.mark_unflushed();
+        meta.extents.push(Extent {
+            logical_off: staged.offset,
+            physical_ptr: staged.slot.pos(),
+            len: staged.len,
+            crc,
+            reserved: false,
+            compressed: false,
+            stored_len: 0,
+        });
+        meta.size = core::cmp::max(meta.size, end);
+        bucket.put(ino_key, serialize(&meta)?)?;
+        self.check_extent_limit(&meta)
+    }
+
+    /// 为 `[offset, offset + len)` 预留日志空间 (`fallocate`)
+    ///
+    /// 预留的 extent 插在 extent 列表的最前面, 已有数据和之后的写入都会覆盖它,
+    /// 其余部分读取时返回 0。范围超出文件末尾时扩大文件。
+    pub fn preallocate(&mut self, ino: u64, offset: u64, len: u64) -> DbfsResult<()> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+        let ino_key = ino.to_be_bytes();
+        let kv = bucket.get(&ino_key).ok_or(DbfsError::NotFound)?;
+        let mut meta = decode_meta(kv.kv().value())?;
+
+        let end = offset.checked_add(len).ok_or(DbfsError::InvalidArgument)?;
+        charge_quota(self.tx, meta.uid, meta.size, end.max(meta.size))?;
+
+        check_crash(self.crash_hook, CrashPoint::BeforeDataWrite)?;
+        let p_ptr = self.log_manager.reserve(len)?;
+
+        meta.extents.insert(
+            0,
+            Extent {
+                logical_off: offset,
+                physical_ptr: p_ptr,
+                len,
+                crc: 0,
+                reserved: true,
+                compressed: false,
+                stored_len: 0,
+            },
+        );
+        meta.size = core::cmp::max(meta.size, end);
+        bucket.put(ino_key, serialize(&meta)?)?;
+        self.check_extent_limit(&meta)
+    }
+
+    /// 把文件的当前内容重写为连续的 extent
+    ///
+    /// 内容按 [`COMPACT_CHUNK`] 分段读出并重新追加到日志, extent 列表被替换为这些新段,
+    /// 空洞和预留区域写成 0。旧 extent 占用的日志空间由 `gc` 回收。
+    pub fn compact_extents(&mut self, ino: u64) -> DbfsResult<()> {
+        let mut meta = self.get_metadata(ino)?;
+        let mut extents = Vec::new();
+        let mut buf = alloc::vec![0u8; COMPACT_CHUNK.min(meta.size) as usize];
+        let mut offset = 0;
+        while offset < meta.size {
+            let chunk = &mut buf[..(meta.size - offset).min(COMPACT_CHUNK) as usize];
+            let n = read_in(self.tx, "inodes", self.log_manager, ino, offset, chunk)?;
+            chunk[n..].fill(0);
+            let p_ptr = self.log_manager.append_data(chunk, 0)?;
+            extents.push(Extent {
+                logical_off: offset,
+                physical_ptr: p_ptr,
+                len: chunk.len() as u64,
+                crc: crc32(chunk),
+                reserved: false,
+                compressed: false,
+                stored_len: 0,
+            });
+            offset += chunk.len() as u64;
+        }
+        release_dedup(self.tx, &meta.extents)?;
+        meta.extents = extents;
+        self.update_metadata(&meta)
+    }
+
+    // extent 数超出上限时先整理; 整理之后仍然超出 (文件太大) 返回 NoSpace, 整个批次被丢弃
+    fn check_extent_limit(&mut self, meta: &InodeMetadata) -> DbfsResult<()> {
+        if meta.extents.len() <= self.max_extents {
+            return Ok(());
+        }
+        self.compact_extents(meta.ino)?;
+        if self.get_metadata(meta.ino)?.extents.len() > self.max_extents {
+            return Err(DbfsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// 在文件末尾追加数据, 返回写入位置
+    pub fn append(&mut self, ino: u64, data: &[u8]) -> DbfsResult<u64> {
+        let offset = self.get_metadata(ino)?.size;
+        self.write(ino, offset, data)?;
+        Ok(offset)
+    }
+
+    /// 分配新的 Inode 号
+    pub fn allocate_inode(&mut self, mode: u32) -> DbfsResult<u64> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+
+        // 与 rvfs2 共用 super_blk 中持久化的计数器, 计数器与新 Inode 在同一个事务中提交
+        let new_ino = crate::fs_common::dbfs_common_alloc_ino(self.tx)? as u64;
+
+        // 初始化新 Inode 元数据
+        let meta = InodeMetadata {
+            ino: new_ino,
+            size: 0,
+            mode,
+            nlink: 1,
+            extents: alloc::vec::Vec::new(),
+            atime: 0,
+            mtime: 0,
+            uid: 0,
+            entry_count: ((mode & 0o170000) == 0o040000).then_some(0),
+            version: INODE_METADATA_VERSION,
+        };
+        bucket.put(new_ino.to_be_bytes(), serialize(&meta)?)?;
+        Ok(new_ino)
+    }
+
+    /// 添加目录项, 名字按原始字节保存, 不要求是 UTF-8
+    pub fn add_dentry<N: AsRef<[u8]>>(
+        &mut self,
+        parent_ino: u64,
+        name: N,
+        child_ino: u64,
+    ) -> DbfsResult<()> {
+        let name = name.as_ref();
+        dbfs_check_name_bytes(name)?;
+
+        let bucket_name = alloc::format!("dir_{}", parent_ino);
+        let bucket = self
+            .tx
+            .get_or_create_bucket(&bucket_name)
+            .map_err(|_| DbfsError::Io)?;
+
+        let is_new = bucket.get(name).is_none();
+        bucket.put(name, child_ino.to_be_bytes())?;
+        name_index_insert(
+            self.tx,
+            &alloc::format!("dirhash_{}", parent_ino),
+            name,
+            child_ino,
+        )?;
+        if is_new {
+            self.adjust_entry_count(parent_ino, name, 1)?;
+        }
+        Ok(())
+    }
+
+    // 在同一个事务中更新父目录缓存的目录项数, `.` 和 `..` 不计入
+    fn adjust_entry_count(&mut self, parent_ino: u64, name: &[u8], delta: i64) -> DbfsResult<()> {
+        if name == b"." || name == b".." {
+            return Ok(());
+        }
+        let mut parent = match self.get_metadata(parent_ino) {
+            Ok(meta) => meta,
+            // 没有元数据的目录 (如测试中的根目录) 不缓存计数
+            Err(DbfsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if let Some(count) = parent.entry_count {
+            parent.entry_count = Some(count.saturating_add_signed(delta));
+            self.update_metadata(&parent)?;
+        }
+        Ok(())
+    }
+
+    /// 查找目录项 (可以看到本批次中尚未提交的修改)
+    pub fn lookup_dentry<N: AsRef<[u8]>>(
+        &self,
+        parent_ino: u64,
+        name: N,
+    ) -> DbfsResult<Option<u64>> {
+        if let Some(found) = name_index_lookup(
+            self.tx,
+            &alloc::format!("dirhash_{}", parent_ino),
+            name.as_ref(),
+        )? {
+            return Ok(found);
+        }
+        let bucket_name = alloc::format!("dir_{}", parent_ino);
+        let bucket = match self.tx.get_bucket(&bucket_name) {
+            Ok(b) => b,
+            Err(_) => return Ok(None),
+        };
+        match bucket.get(name.as_ref()) {
+            Some(val) => Ok(Some(u64::from_be_bytes(
+                val.kv().value().try_into().map_err(|_| DbfsError::Other)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// 删除目录项
+    pub fn delete_dentry<N: AsRef<[u8]>>(&mut self, parent_ino: u64, name: N) -> DbfsResult<()> {
+        let bucket_name = alloc::format!("dir_{}", parent_ino);
+        let bucket = self
+            .tx
+            .get_bucket(&bucket_name)
+            .map_err(|_| DbfsError::NotFound)?;
+
+        bucket.delete(name.as_ref()).map_err(|_| DbfsError::Io)?;
+        name_index_remove(
+            self.tx,
+            &alloc::format!("dirhash_{}", parent_ino),
+            name.as_ref(),
+        )?;
+        self.adjust_entry_count(parent_ino, name.as_ref(), -1)
+    }
+
+    /// 为目录 `ino` 建立名字哈希索引, 已有索引时重建
+    ///
+    /// 索引保存在 `dirhash_{ino}` bucket 中, key 是名字的 64 位哈希, value 是哈希相同的
+    /// 目录项组成的冲突链。之后的 `add_dentry`/`delete_dentry` 在同一个事务中维护索引。
+    pub fn build_name_index(&mut self, ino: u64) -> DbfsResult<()> {
+        let index_name = alloc::format!("dirhash_{}", ino);
+        let _ = self.tx.delete_bucket(&index_name);
+        let mut entries = Vec::new();
+        if let Ok(dir) = self.tx.get_bucket(&alloc::format!("dir_{}", ino)) {
+            for kv in dir.cursor() {
+                let child =
+                    u64::from_be_bytes(kv.kv().value().try_into().map_err(|_| DbfsError::Other)?);
+                entries.push((kv.key().to_vec(), child));
+            }
+        }
+        self.tx
+            .create_bucket(&index_name)
+            .map_err(|_| DbfsError::Io)?;
+        for (name, child) in entries {
+            name_index_insert(self.tx, &index_name, &name, child)?;
+        }
+        Ok(())
+    }
+
+    /// 获取 Inode 元数据 (可以看到本批次中尚未提交的修改)
+    pub fn get_metadata(&self, ino: u64) -> DbfsResult<InodeMetadata> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+        let kv = bucket.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
+        decode_meta(kv.kv().value())
+    }
+
+    /// 更新 Inode 元数据
+    pub fn update_metadata(&mut self, meta: &InodeMetadata) -> DbfsResult<()> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+
+        bucket.put(meta.ino.to_be_bytes(), serialize(meta)?)?;
+        Ok(())
+    }
+
+    // 导入 `(路径, mode, mtime, 内容)`, 路径相对于 `root_ino`
+    #[cfg(feature = "fuse")]
+    fn import_entries(
+        &mut self,
+        root_ino: u64,
+        entries: &[(Vec<u8>, u32, i64, Vec<u8>)],
+    ) -> DbfsResult<()> {
+        for (path, mode, mtime, data) in entries {
+            let mut components = Vec::new();
+            for name in path.split(|&b| b == b'/') {
+                match name {
+                    b"" | b"." => continue,
+                    b".." => return Err(DbfsError::InvalidArgument),
+                    name => components.push(name),
+                }
+            }
+            let (name, parents) = match components.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut parent = root_ino;
+            for dir in parents {
+                parent = match self.lookup_dentry(parent, dir)? {
+                    Some(ino) => ino,
+                    None => {
+                        let ino = self.allocate_inode(0o040755)?;
+                        self.add_dentry(parent, dir, ino)?;
+                        ino
+                    }
+                };
+            }
+
+            let is_dir = |mode: u32| (mode & 0o170000) == 0o040000;
+            let ino = match self.lookup_dentry(parent, name)? {
+                // 目录可能先于其中的文件被隐式创建, 之后的条目只更新属性
+                Some(ino) => {
+                    if !is_dir(*mode) || !is_dir(self.get_metadata(ino)?.mode) {
+                        return Err(DbfsError::FileExists);
+                    }
+                    ino
+                }
+                None => {
+                    let ino = self.allocate_inode(*mode)?;
+                    if !data.is_empty() {
+                        self.write(ino, 0, data)?;
+                    }
+                    self.add_dentry(parent, name, ino)?;
+                    ino
+                }
+            };
+            let mut meta = self.get_metadata(ino)?;
+            meta.mode = *mode;
+            meta.mtime = *mtime;
+            meta.atime = *mtime;
+            self.update_metadata(&meta)?;
+        }
+        Ok(())
+    }
+
+    // 把各文件的数据依次追加到日志, 用指向新位置的 extent 替换原来的 extent
+    //
+    // 副本不再共享数据块, 去重表指向的位置随之失效, 一并清空
+    fn relocate(&mut self, live: &[(u64, Vec<(u64, Vec<u8>)>)]) -> DbfsResult<()> {
+        let _ = self.tx.delete_bucket("dedup");
+        let _ = self.tx.delete_bucket("dedup_refs");
+        for (ino, segments) in live {
+            let mut meta = self.get_metadata(*ino)?;
+            let mut extents = Vec::with_capacity(segments.len());
+            for (offset, data) in segments {
+                let p_ptr = self.log_manager.append_data(data, 0)?;
+                extents.push(Extent {
+                    logical_off: *offset,
+                    physical_ptr: p_ptr,
+                    len: data.len() as u64,
+                    crc: crc32(data),
+                    reserved: false,
+                    compressed: false,
+                    stored_len: 0,
+                });
+            }
+            meta.extents = extents;
+            self.update_metadata(&meta)?;
+        }
+        Ok(())
+    }
+
+    /// 删除 Inode 及其目录项 bucket, 归还属主占用的配额; Inode 不存在时什么也不做
+    pub fn delete_inode(&mut self, ino: u64) -> DbfsResult<()> {
+        let bucket = self
+            .tx
+            .get_bucket("inodes")
+            .map_err(|_| DbfsError::NotFound)?;
+
+        if let Some(kv) = bucket.get(&ino.to_be_bytes()) {
+            let meta = decode_meta(kv.kv().value())?;
+            charge_quota(self.tx, meta.uid, meta.size, 0)?;
+            release_dedup(self.tx, &meta.extents)?;
+            bucket
+                .delete(&ino.to_be_bytes())
+                .map_err(|_| DbfsError::Io)?;
+        }
+
+        // 如果是目录，删除其目录项 bucket 和名字索引
+        let _ = self.tx.delete_bucket(&alloc::format!("dir_{}", ino));
+        let _ = self.tx.delete_bucket(&alloc::format!("dirhash_{}", ino));
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> TransactionEngine<D> {
+    pub fn new(db: DB, log_manager: LogManager<D>) -> Self {
+        Self {
+            db: CountingDb::new(db),
+            log_manager,
+            namespace: String::new(),
+            pending_atime: BTreeMap::new(),
+            crash_hook: None,
+            max_extents: DEFAULT_MAX_EXTENTS_PER_INODE,
+            name_index_misses: AtomicU64::new(0),
+            dedup: false,
+            compression: false,
+            metrics: None,
+        }
+    }
+
+    /// 设置故障注入钩子
+    ///
+    /// 写操作经过每个 [`CrashPoint`] 时调用 `hook`, 返回 true 则操作在该点中止, 之后的步骤
+    /// 不再执行, 与在该点断电时磁盘上的状态相同。用于确定性的崩溃恢复测试。
+    pub fn set_crash_hook(&mut self, hook: impl Fn(CrashPoint) -> bool + Send + Sync + 'static) {
+        self.crash_hook = Some(Box::new(hook));
+    }
+
+    /// 移除故障注入钩子
+    pub fn clear_crash_hook(&mut self) {
+        self.crash_hook = None;
+    }
+
+    /// 设置每个 Inode 最多的 extent 数, 默认为 [`DEFAULT_MAX_EXTENTS_PER_INODE`]
+    ///
+    /// 写入使 extent 数超出上限时自动调用 [`BatchCtx::compact_extents`] 整理, 整理之后仍然超出
+    /// (文件大于 `limit` 个 [`COMPACT_CHUNK`]) 时写入失败并返回 `NoSpace`。
+    pub fn set_max_extents_per_inode(&mut self, limit: usize) {
+        self.max_extents = limit;
+    }
+
+    /// 每个 Inode 最多的 extent 数
+    pub fn max_extents_per_inode(&self) -> usize {
+        self.max_extents
+    }
+
+    /// 开启或关闭写入时的数据块去重, 默认关闭
+    ///
+    /// 开启后偏移和长度都按 [`DEDUP_BLOCK_SIZE`] 对齐的 `write` 逐块计算内容哈希, 在 `dedup`
+    /// bucket 中找到内容相同的块时新 extent 直接指向它, 不再追加。`dedup_refs` 记录每个共享块
+    /// 被多少个 extent 引用, 删除、截断和整理时递减, 减到 0 时从去重表中移除, 之后的写入不会
+    /// 再指向 `gc` 可以回收的块。关闭去重不影响已共享的块。
+    pub fn set_dedup(&mut self, enabled: bool) {
+        self.dedup = enabled;
+    }
+
+    /// 是否开启了数据块去重
+    pub fn dedup_enabled(&self) -> bool {
+        self.dedup
+    }
+
+    /// 开启或关闭写入时的数据压缩, 默认关闭
+    ///
+    /// 开启后 `write` 追加到日志的数据先用 LZ4 压缩, 压缩后没有变小时按原样保存。压缩的
+    /// extent 标记为 `compressed`, 读取时整体解压。去重的块不压缩。
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// 是否开启了数据压缩
+    pub fn compression_enabled(&self) -> bool {
+        self.compression
+    }
+
+    /// 安装或移除计时回调
+    ///
+    /// 安装后 `write_file_transactional`、`read_file`、日志追加和批处理的 jammdb 提交成功时
+    /// 以 [`ops`] 中的名字上报耗时。
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Option<Metrics>) {
+        self.metrics = metrics;
+        self.log_manager.set_metrics(metrics);
+    }
+
+    /// 日志写指针, 下一次追加的物理偏移
+    pub fn next_append_pos(&self) -> u64 {
+        self.log_manager.next_append_pos()
+    }
+
+    /// 当前视图的 Inode 表 bucket 名
+    fn inodes_bucket(&self) -> String {
+        alloc::format!("{}inodes", self.namespace)
+    }
+
+    /// 当前视图中目录 `ino` 的目录项 bucket 名
+    fn dir_bucket(&self, ino: u64) -> String {
+        alloc::format!("{}dir_{}", self.namespace, ino)
+    }
+
+    /// 当前视图中目录 `ino` 的名字哈希索引 bucket 名
+    fn name_index_bucket(&self, ino: u64) -> String {
+        alloc::format!("{}dirhash_{}", self.namespace, ino)
+    }
+
+    /// 快照视图不允许修改
+    fn check_writable(&self) -> DbfsResult<()> {
+        if self.namespace.is_empty() {
+            Ok(())
+        } else {
+            Err(DbfsError::PermissionDenied)
+        }
+    }
+
+    /// 为当前文件系统状态创建名为 `name` 的快照
+    ///
+    /// 日志只追加不覆盖, 快照只需复制 Inode 表和目录项 (extent 指针与活动文件系统共享),
+    /// 并记录此刻的日志写指针。快照保存在 `snap_{name}_` 前缀的 bucket 中。
+    pub fn snapshot(&mut self, name: &str) -> DbfsResult<()> {
+        self.check_writable()?;
+        dbfs_check_name(name)?;
+
+        let tx = self.db.begin_batch();
+        let snapshots = tx
+            .get_or_create_bucket("snapshots")
+            .map_err(|_| DbfsError::Io)?;
+        if snapshots.get(name.as_bytes()).is_some() {
+            return Err(DbfsError::FileExists);
+        }
+        snapshots.put(
+            name.as_bytes(),
+            self.log_manager.next_append_pos().to_be_bytes(),
+        )?;
+
+        // 先收集再写入, 遍历 bucket 时不能创建新的 bucket
+        let mut copies = Vec::new();
+        for (bucket_name, bucket) in tx.buckets() {
+            let bucket_name = bucket_name.name();
+            if bucket_name != b"inodes" && !bucket_name.starts_with(b"dir_") {
+                continue;
+            }
+            let mut kvs = Vec::new();
+            for data in bucket.cursor() {
+                if let Data::KeyValue(kv) = data {
+                    kvs.push((kv.key().to_vec(), kv.value().to_vec()));
+                }
+            }
+            let mut target = alloc::format!("snap_{}_", name).into_bytes();
+            target.extend_from_slice(bucket_name);
+            copies.push((target, kvs));
+        }
+        for (target, kvs) in copies {
+            let bucket = tx.create_bucket(target).map_err(|_| DbfsError::Io)?;
+            for (key, value) in kvs {
+                bucket.put(key, value)?;
+            }
+        }
+
+        self.db.commit(tx.commit())
+    }
+
+    /// 打开名为 `name` 的快照, 返回只读的快照视图
+    pub fn open_snapshot(&self, name: &str) -> DbfsResult<TransactionEngine<D>> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let snapshots = tx
+            .get_bucket("snapshots")
+            .map_err(|_| DbfsError::NotFound)?;
+        let kv = snapshots.get(name.as_bytes()).ok_or(DbfsError::NotFound)?;
+        let log_end = u64::from_be_bytes(kv.kv().value().try_into().map_err(|_| DbfsError::Other)?);
+
+        Ok(TransactionEngine {
+            db: self.db.clone(),
+            log_manager: self.log_manager.fork(log_end),
+            namespace: alloc::format!("snap_{}_", name),
+            pending_atime: BTreeMap::new(),
+            crash_hook: None,
+            max_extents: DEFAULT_MAX_EXTENTS_PER_INODE,
+            name_index_misses: AtomicU64::new(0),
+            dedup: false,
+            compression: false,
+            metrics: self.metrics,
+        })
+    }
+
+    /// 在一个 jammdb 事务中执行多个修改, 只提交一次
+    ///
+    /// `f` 返回错误时事务被丢弃, 本批次的元数据修改全部不可见。
+    pub fn batch<F: FnOnce(&mut BatchCtx<D>) -> DbfsResult<()>>(&mut self, f: F) -> DbfsResult<()> {
+        self.check_writable()?;
+        let tx = self.db.begin_batch();
+        let mut ctx = BatchCtx {
+            tx: &tx,
+            log_manager: &mut self.log_manager,
+            crash_hook: self.crash_hook.as_ref(),
+            max_extents: self.max_extents,
+            dedup: self.dedup,
+            compression: self.compression,
+        };
+        f(&mut ctx)?;
+
+        // 先数据后索引: 数据落盘之后才提交指向它的 extent
+        self.log_manager.flush()?;
 
         // --- 故障注入测试点 ---
-        // if cfg!(feature = "crash_test") { panic!("Simulated Crash before commit!"); }
+        check_crash(self.crash_hook.as_ref(), CrashPoint::BeforeCommit)?;
 
-        // --- 步骤 4: 原子提交 (The Commit) ---
+        // --- 原子提交 (The Commit) ---
         // 这是唯一的故障切换点。jammdb 保证此操作要么全成功，要么全失败。
-        tx.commit().map_err(|_| DbfsError::Io)?;
+        let timer = Timer::start(self.metrics);
+        self.db.commit(tx.commit())?;
+        timer.stop(ops::DB_COMMIT);
+        check_crash(self.crash_hook.as_ref(), CrashPoint::AfterCommit)
+    }
+
+    /// 已提交的 jammdb 事务数
+    pub fn commit_count(&self) -> u64 {
+        self.db.commits()
+    }
+
+    /// 日志的写放大统计
+    pub fn log_stats(&self) -> LogStats {
+        self.log_manager.stats()
+    }
+
+    /// 设置 `uid` 可以使用的块数上限, 已用块数不变
+    ///
+    /// 上限低于已用块数时, 已有数据保留, 之后增长文件的写入和截断被拒绝。
+    pub fn set_quota(&mut self, uid: u32, blocks_limit: u64) -> DbfsResult<()> {
+        self.batch(|ctx| {
+            let quotas = ctx
+                .tx
+                .get_or_create_bucket("quotas")
+                .map_err(|_| DbfsError::Io)?;
+            let mut quota = read_quota(&quotas, uid)?;
+            quota.blocks_limit = blocks_limit;
+            write_quota(&quotas, uid, quota)
+        })
+    }
+
+    /// 查询 `uid` 的配额, 从未使用过的 uid 返回默认值 (没有上限)
+    pub fn get_quota(&self, uid: u32) -> DbfsResult<Quota> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        match tx.get_bucket("quotas") {
+            Ok(quotas) => read_quota(&quotas, uid),
+            Err(_) => Ok(Quota::default()),
+        }
+    }
 
+    pub fn write_file_transactional(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> DbfsResult<()> {
+        let timer = Timer::start(self.metrics);
+        self.batch(|ctx| ctx.write(ino, offset, data))?;
+        timer.stop(ops::WRITE_FILE);
         Ok(())
     }
 
-    /// 从文件中读取数据
-    pub fn read_file(&self, ino: u64, offset: u64, buf: &mut [u8]) -> DbfsResult<usize> {
-        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        
-        let ino_key = ino.to_be_bytes();
-        let kv = bucket.get(&ino_key).ok_or(DbfsError::NotFound)?;
-        let meta: InodeMetadata = deserialize(kv.kv().value())?;
-        
-        if offset >= meta.size {
-            return Ok(0);
+    /// 分阶段写入的第一步: 在日志中为 `data` 预留空间
+    ///
+    /// 把 `write_file_transactional` 拆成预留、[`StagedWrite::write_data`] 和
+    /// [`TransactionEngine::commit_staged`] 三步, 只有第一步和第三步需要借用引擎; 中间写设备的
+    /// 一步可以在释放引擎的锁之后进行, 不同文件的数据写入因此可以并发。同一文件的分阶段写入
+    /// 由调用者串行化, 否则 extent 的提交顺序可能与写入顺序不一致。配额在提交时检查,
+    /// 被拒绝的写入预留的日志空间由 `gc` 回收。
+    pub fn stage_write(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> DbfsResult<StagedWrite<D>> {
+        self.check_writable()?;
+        let meta = self.get_metadata(ino)?;
+        check_crash(self.crash_hook.as_ref(), CrashPoint::BeforeDataWrite)?;
+
+        let len = data.len() as u64;
+        let fresh = len - covered_len(&meta.extents, offset, checked_end(offset, data.len())?);
+        let slot = self.log_manager.stage(len, fresh)?;
+        Ok(StagedWrite {
+            ino,
+            offset,
+            len,
+            slot,
+            crc: None,
+        })
+    }
+
+    /// 分阶段写入的最后一步: 在一个事务中提交已写入日志的数据, 数据尚未写入时返回 `InvalidArgument`
+    pub fn commit_staged(&mut self, staged: StagedWrite<D>) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.commit_staged(&staged))
+    }
+
+    /// 预分配 `[offset, offset + len)`, 参见 [`BatchCtx::preallocate`]
+    pub fn preallocate(&mut self, ino: u64, offset: u64, len: u64) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.preallocate(ino, offset, len))
+    }
+
+    /// 整理文件的 extent 列表, 参见 [`BatchCtx::compact_extents`]
+    pub fn compact_extents(&mut self, ino: u64) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.compact_extents(ino))
+    }
+
+    /// 在文件末尾追加数据 (O_APPEND), 返回写入位置
+    ///
+    /// 读取 size 与写入在同一个事务中完成, 并发追加不会互相覆盖。
+    pub fn append_file(&mut self, ino: u64, data: &[u8]) -> DbfsResult<u64> {
+        let mut offset = 0;
+        self.batch(|ctx| {
+            offset = ctx.append(ino, data)?;
+            Ok(())
+        })?;
+        Ok(offset)
+    }
+
+    /// 从文件中读取数据, 隔离级别同 [`TransactionEngine::read_snapshot`]
+    pub fn read_file(&self, ino: u64, offset: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        let timer = Timer::start(self.metrics);
+        let len = self.read_snapshot(ino, offset, buf)?;
+        timer.stop(ops::READ_FILE);
+        Ok(len)
+    }
+
+    /// 在一致的快照上读取文件
+    ///
+    /// 元数据和 extent 在同一个 jammdb 读事务 (MVCC) 中取得, 视图固定在读事务开始的时刻。
+    /// 写入先把数据追加到日志的新位置, 再在事务中提交指向它的 extent, 日志中已被引用的数据
+    /// 不会被原地修改, 因此读者看到的要么是写入之前、要么是写入提交之后的完整内容, 不会看到
+    /// 写了一半的数据。多次读取之间的一致性不做保证, 每次调用各自固定一个视图。
+    pub fn read_snapshot(&self, ino: u64, offset: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        read_in(
+            &tx,
+            &self.inodes_bucket(),
+            &self.log_manager,
+            ino,
+            offset,
+            buf,
+        )
+    }
+
+    /// 创建可以移到其他线程的只读句柄, 与引擎共享数据库和日志设备
+    ///
+    /// 读者不需要持有引擎的锁, 写者提交期间也可以并发读取, 隔离级别同 `read_snapshot`。
+    pub fn reader(&self) -> SnapshotReader<D> {
+        SnapshotReader {
+            db: self.db.db.clone(),
+            log_manager: self.log_manager.fork(self.log_manager.next_append_pos()),
+            inodes_bucket: self.inodes_bucket(),
+        }
+    }
+
+    /// 零拷贝读取: 以借用切片的形式把 `offset` 处的数据交给回调
+    ///
+    /// 切片是从 `offset` 开始、在日志中物理连续的一段数据 (最多到所在 extent 或文件的末尾),
+    /// 它借用自读事务和块设备, 只在回调执行期间有效, 不能被保存到回调之外; 需要保留数据时
+    /// 请在回调中自行拷贝。`offset` 位于文件末尾之后或空洞中时回调收到空切片。
+    pub fn with_block<R, F: FnOnce(&[u8]) -> R>(
+        &self,
+        ino: u64,
+        offset: u64,
+        f: F,
+    ) -> DbfsResult<R> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket = tx
+            .get_bucket(&self.inodes_bucket())
+            .map_err(|_| DbfsError::NotFound)?;
+        let kv = bucket.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
+        let meta = decode_meta(kv.kv().value())?;
+
+        // 后写入的 extent 覆盖先写入的, 因此从后往前找
+        let found = meta.extents.iter().enumerate().rev().find(|(_, e)| {
+            offset < meta.size && offset >= e.logical_off && offset < e.logical_off + e.len
+        });
+        let (idx, extent) = match found {
+            Some(found) => found,
+            None => return Ok(f(&[])),
+        };
+
+        // 切片不能越过文件末尾, 也不能越过更新的 extent 覆盖的位置
+        let mut end = core::cmp::min(extent.logical_off + extent.len, meta.size);
+        for e in &meta.extents[idx + 1..] {
+            if e.logical_off > offset && e.logical_off < end {
+                end = e.logical_off;
+            }
+        }
+
+        if extent.reserved {
+            return Ok(f(&alloc::vec![0u8; (end - offset) as usize]));
+        }
+        if extent.compressed {
+            let data = extent_data(&self.log_manager, extent)?;
+            let start = (offset - extent.logical_off) as usize;
+            return Ok(f(&data[start..start + (end - offset) as usize]));
+        }
+
+        let pos = extent.physical_ptr + (offset - extent.logical_off);
+        let mut f = Some(f);
+        let mut result = None;
+        self.log_manager
+            .with_data(pos, (end - offset) as usize, &mut |slice| {
+                if let Some(f) = f.take() {
+                    result = Some(f(slice));
+                }
+            })?;
+        result.ok_or(DbfsError::Io)
+    }
+
+    /// 分配新的 Inode 号
+    pub fn allocate_inode(&mut self, mode: u32) -> DbfsResult<u64> {
+        let mut ino = 0;
+        self.batch(|ctx| {
+            ino = ctx.allocate_inode(mode)?;
+            Ok(())
+        })?;
+        Ok(ino)
+    }
+
+    /// 添加目录项
+    pub fn add_dentry<N: AsRef<[u8]>>(
+        &mut self,
+        parent_ino: u64,
+        name: N,
+        child_ino: u64,
+    ) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.add_dentry(parent_ino, name, child_ino))
+    }
+
+    /// 查找目录项, 不存在时创建新的 Inode (`O_CREAT`)
+    ///
+    /// 查找与创建在同一个事务中完成, 并发调用者不会各自创建一个同名文件。
+    /// 返回 Inode 号以及它是否由本次调用创建。
+    pub fn lookup_or_create<N: AsRef<[u8]>>(
+        &mut self,
+        parent_ino: u64,
+        name: N,
+        mode: u32,
+    ) -> DbfsResult<(u64, bool)> {
+        let name = name.as_ref();
+        let mut result = (0, false);
+        self.batch(|ctx| {
+            result = match ctx.lookup_dentry(parent_ino, name)? {
+                Some(ino) => (ino, false),
+                None => {
+                    let ino = ctx.allocate_inode(mode)?;
+                    ctx.add_dentry(parent_ino, name, ino)?;
+                    (ino, true)
+                }
+            };
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// 创建新的 Inode, 名字已存在时返回 `FileExists` (`O_CREAT | O_EXCL`)
+    pub fn create_exclusive<N: AsRef<[u8]>>(
+        &mut self,
+        parent_ino: u64,
+        name: N,
+        mode: u32,
+    ) -> DbfsResult<u64> {
+        let name = name.as_ref();
+        let mut ino = 0;
+        self.batch(|ctx| {
+            if ctx.lookup_dentry(parent_ino, name)?.is_some() {
+                return Err(DbfsError::FileExists);
+            }
+            ino = ctx.allocate_inode(mode)?;
+            ctx.add_dentry(parent_ino, name, ino)
+        })?;
+        Ok(ino)
+    }
+
+    /// 写时复制地克隆文件 (reflink), 返回新 Inode 号
+    ///
+    /// 新 Inode 复制源文件的 extent 列表, 与源文件共享日志中的数据而不拷贝。日志只追加不覆盖,
+    /// 之后对任一文件的写入都追加新的 extent 到自己的列表中, 另一个文件看到的数据不变。
+    /// 克隆出的 Inode 没有目录项, nlink 为 1, 由调用者通过 `add_dentry` 链接到目录中。
+    pub fn clone_file(&mut self, src_ino: u64) -> DbfsResult<u64> {
+        let mut ino = 0;
+        self.batch(|ctx| {
+            let src = ctx.get_metadata(src_ino)?;
+            if (src.mode & 0o170000) == 0o040000 {
+                return Err(DbfsError::IsDir);
+            }
+            ino = ctx.allocate_inode(src.mode)?;
+            let clone = InodeMetadata {
+                ino,
+                size: src.size,
+                mode: src.mode,
+                nlink: 1,
+                extents: src.extents.clone(),
+                atime: src.atime,
+                mtime: src.mtime,
+                uid: src.uid,
+                entry_count: None,
+                version: INODE_METADATA_VERSION,
+            };
+            retain_dedup(ctx.tx, &clone.extents)?;
+            ctx.update_metadata(&clone)
+        })?;
+        Ok(ino)
+    }
+
+    /// 查找目录项, 名字按原始字节比较
+    ///
+    /// 目录有名字哈希索引时先查索引, 不存在的名字不读取目录项 bucket。
+    pub fn lookup_dentry<N: AsRef<[u8]>>(&self, parent_ino: u64, name: N) -> DbfsResult<u64> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        match name_index_lookup(&tx, &self.name_index_bucket(parent_ino), name.as_ref())? {
+            Some(Some(ino)) => return Ok(ino),
+            Some(None) => {
+                self.name_index_misses.fetch_add(1, Ordering::Relaxed);
+                return Err(DbfsError::NotFound);
+            }
+            None => {}
+        }
+        let bucket_name = self.dir_bucket(parent_ino);
+        let bucket = tx
+            .get_bucket(&bucket_name)
+            .map_err(|_| DbfsError::NotFound)?;
+
+        let val = bucket.get(name.as_ref()).ok_or(DbfsError::NotFound)?;
+        let ino = u64::from_be_bytes(val.kv().value().try_into().map_err(|_| DbfsError::Other)?);
+
+        Ok(ino)
+    }
+
+    /// 为目录 `ino` 建立名字哈希索引, 参见 [`BatchCtx::build_name_index`]
+    ///
+    /// 索引是可选的, 适合目录项很多、经常查找不存在的名字 (如创建前的检查) 的目录。
+    pub fn enable_name_index(&mut self, ino: u64) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.build_name_index(ino))
+    }
+
+    /// 删除目录 `ino` 的名字哈希索引, 之后的查找直接读取目录项 bucket
+    pub fn disable_name_index(&mut self, ino: u64) -> DbfsResult<()> {
+        self.check_writable()?;
+        let tx = self.db.tx(true).map_err(|_| DbfsError::Io)?;
+        let _ = tx.delete_bucket(&self.name_index_bucket(ino));
+        self.db.commit(tx.commit())
+    }
+
+    /// 目录 `ino` 是否有名字哈希索引
+    pub fn has_name_index(&self, ino: u64) -> DbfsResult<bool> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        Ok(tx.get_bucket(&self.name_index_bucket(ino)).is_ok())
+    }
+
+    /// 由名字哈希索引直接判定不存在、没有读取目录项 bucket 的查找次数
+    pub fn name_index_misses(&self) -> u64 {
+        self.name_index_misses.load(Ordering::Relaxed)
+    }
+
+    /// 列出目录项
+    ///
+    /// 非 UTF-8 的名字被有损地转换, 需要原始名字时使用 `list_dentries_raw`。
+    pub fn list_dentries(
+        &self,
+        parent_ino: u64,
+        start_index: usize,
+    ) -> DbfsResult<Option<(alloc::string::String, u64)>> {
+        Ok(self
+            .list_dentries_raw(parent_ino, start_index)?
+            .map(|(name, ino)| (String::from_utf8_lossy(&name).into_owned(), ino)))
+    }
+
+    /// 列出目录项, 返回原始字节形式的名字
+    pub fn list_dentries_raw(
+        &self,
+        parent_ino: u64,
+        start_index: usize,
+    ) -> DbfsResult<Option<(Vec<u8>, u64)>> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket_name = self.dir_bucket(parent_ino);
+        let bucket = match tx.get_bucket(&bucket_name) {
+            Ok(b) => b,
+            Err(_) => return Ok(None),
+        };
+
+        let entry = bucket.cursor().nth(start_index);
+        if let Some(kv) = entry {
+            let ino = u64::from_be_bytes(kv.kv().value().try_into().map_err(|_| DbfsError::Other)?);
+            Ok(Some((kv.key().to_vec(), ino)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 目录中 `.` 和 `..` 以外的目录项数
+    ///
+    /// 读取目录 Inode 中缓存的计数, 只有旧记录没有计数时才扫描目录项 bucket。
+    pub fn entry_count(&self, ino: u64) -> DbfsResult<u64> {
+        if let Ok(Some(count)) = self.get_metadata(ino).map(|meta| meta.entry_count) {
+            return Ok(count);
+        }
+        self.scan_entry_count(ino)
+    }
+
+    /// 扫描目录项 bucket 得到的目录项数, 不使用缓存
+    pub fn scan_entry_count(&self, ino: u64) -> DbfsResult<u64> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket = match tx.get_bucket(&self.dir_bucket(ino)) {
+            Ok(b) => b,
+            Err(_) => return Ok(0),
+        };
+        Ok(bucket
+            .cursor()
+            .filter(|kv| kv.key() != b"." && kv.key() != b"..")
+            .count() as u64)
+    }
+
+    /// 目录中是否没有 `.` 和 `..` 以外的目录项
+    ///
+    /// 有缓存的计数时是 O(1) 的; 否则只用游标查看开头的几个 key, 不解码目录项。
+    pub fn is_dir_empty(&self, ino: u64) -> DbfsResult<bool> {
+        if let Ok(Some(count)) = self.get_metadata(ino).map(|meta| meta.entry_count) {
+            return Ok(count == 0);
+        }
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket_name = self.dir_bucket(ino);
+        let bucket = match tx.get_bucket(&bucket_name) {
+            Ok(b) => b,
+            Err(_) => return Ok(true),
+        };
+
+        let child = bucket
+            .cursor()
+            .find(|kv| kv.key() != b"." && kv.key() != b"..");
+        Ok(child.is_none())
+    }
+
+    /// 删除目录项
+    pub fn delete_dentry<N: AsRef<[u8]>>(&mut self, parent_ino: u64, name: N) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.delete_dentry(parent_ino, name))
+    }
+
+    /// 删除 Inode, 连同尚未写入的 atime
+    pub fn delete_inode(&mut self, ino: u64) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.delete_inode(ino))?;
+        self.pending_atime.remove(&ino);
+        Ok(())
+    }
+
+    /// 递归删除目录 `parent_ino` 下的 `name` 及其所有后代
+    ///
+    /// 先在读事务中遍历整棵树, 再按子节点先于父节点的顺序删除, 每个事务最多删除
+    /// [`REMOVE_TREE_BATCH`] 个目录项, 删除大目录树时不会产生过大的事务。中途失败时已提交的
+    /// 批次保留, 剩余部分仍然是一棵完整的子树, 可以再次调用继续删除。
+    /// 文件的 nlink 减一, 减到 0 时释放 Inode 和 extent; 目录直接释放。
+    /// 遍历使用堆上的栈, 不随深度递归; 嵌套超过 [`MAX_TREE_DEPTH`] 层时返回 `NameTooLong`,
+    /// 不删除任何内容。
+    pub fn remove_tree<N: AsRef<[u8]>>(&mut self, parent_ino: u64, name: N) -> DbfsResult<()> {
+        self.check_writable()?;
+        let name = name.as_ref();
+        let root = self.lookup_dentry(parent_ino, name)?;
+
+        // 1. 先序遍历, 反转后子节点排在父节点之前
+        let mut nodes: Vec<(u64, Vec<u8>, u64)> = Vec::new();
+        let mut visited = alloc::collections::BTreeSet::new();
+        let mut stack = alloc::vec![(parent_ino, name.to_vec(), root, 1)];
+        {
+            let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+            let inodes = tx
+                .get_bucket(&self.inodes_bucket())
+                .map_err(|_| DbfsError::NotFound)?;
+            while let Some((parent, name, ino, depth)) = stack.pop() {
+                if depth > MAX_TREE_DEPTH {
+                    return Err(DbfsError::NameTooLong);
+                }
+                let kv = inodes.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
+                let meta = decode_meta(kv.kv().value())?;
+                // 同一个目录出现两次说明存在环, 只删除目录项, 不再进入
+                if (meta.mode & 0o170000) == 0o040000 && visited.insert(ino) {
+                    if let Ok(dir) = tx.get_bucket(&self.dir_bucket(ino)) {
+                        for kv in dir.cursor() {
+                            if kv.key() == b"." || kv.key() == b".." {
+                                continue;
+                            }
+                            let child = u64::from_be_bytes(
+                                kv.kv().value().try_into().map_err(|_| DbfsError::Other)?,
+                            );
+                            stack.push((ino, kv.key().to_vec(), child, depth + 1));
+                        }
+                    }
+                } else if (meta.mode & 0o170000) == 0o040000 {
+                    log::warn!("remove_tree: directory {} reached twice, skipping", ino);
+                }
+                nodes.push((parent, name, ino));
+            }
+        }
+        nodes.reverse();
+
+        // 2. 分批删除
+        for chunk in nodes.chunks(REMOVE_TREE_BATCH) {
+            self.batch(|ctx| {
+                for (parent, name, ino) in chunk {
+                    ctx.delete_dentry(*parent, name)?;
+                    let mut meta = match ctx.get_metadata(*ino) {
+                        Ok(meta) => meta,
+                        // 环中的目录已经在之前的批次中释放
+                        Err(DbfsError::NotFound) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    if (meta.mode & 0o170000) != 0o040000 && meta.nlink > 1 {
+                        meta.nlink -= 1;
+                        ctx.update_metadata(&meta)?;
+                    } else {
+                        ctx.delete_inode(*ino)?;
+                    }
+                }
+                Ok(())
+            })?;
+            for (_, _, ino) in chunk {
+                if self.get_metadata(*ino).is_err() {
+                    self.pending_atime.remove(ino);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 更新 Inode 元数据
+    pub fn update_metadata(&mut self, meta: &InodeMetadata) -> DbfsResult<()> {
+        self.batch(|ctx| ctx.update_metadata(meta))
+    }
+
+    /// 在一个事务中整体写入 Inode 的元数据, 供迁移工具直接设置大小、权限、时间和 extent
+    ///
+    /// 与 [`update_metadata`](Self::update_metadata) 不同, 写入前检查记录是否合法: extent 按
+    /// 逻辑偏移升序排列且互不重叠, 长度非零, 在日志中的范围不超过写指针; 版本不比当前格式新。
+    /// 不合法时返回 `InvalidArgument`, 不做任何修改。
+    pub fn put_metadata(&mut self, meta: &InodeMetadata) -> DbfsResult<()> {
+        self.check_writable()?;
+        if meta.ino == 0 || meta.version > INODE_METADATA_VERSION {
+            return Err(DbfsError::InvalidArgument);
+        }
+        let log_end = self.log_manager.next_append_pos();
+        let mut prev_end = 0;
+        for extent in &meta.extents {
+            let end = extent
+                .logical_off
+                .checked_add(extent.len)
+                .ok_or(DbfsError::InvalidArgument)?;
+            let physical_end = extent
+                .physical_ptr
+                .checked_add(extent.physical_len())
+                .ok_or(DbfsError::InvalidArgument)?;
+            if extent.len == 0 || extent.logical_off < prev_end || physical_end > log_end {
+                return Err(DbfsError::InvalidArgument);
+            }
+            prev_end = end;
+        }
+        self.batch(|ctx| ctx.update_metadata(meta))
+    }
+
+    /// 获取 Inode 元数据
+    pub fn get_metadata(&self, ino: u64) -> DbfsResult<InodeMetadata> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket = tx
+            .get_bucket(&self.inodes_bucket())
+            .map_err(|_| DbfsError::NotFound)?;
+        let kv = bucket.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
+        decode_meta(kv.kv().value())
+    }
+
+    /// 文件的物理布局 (FIEMAP), 按逻辑偏移升序覆盖 `[0, size)`
+    ///
+    /// extent 相互覆盖时只报告可见的部分, 物理上连续且属于同一个 extent 的范围合并为一项。
+    /// 空洞标记为 `HOLE`, 预留未写入的范围为 `UNWRITTEN`, 压缩的 extent 为 `ENCODED` (物理
+    /// 地址是压缩数据的起点), 去重后被多处引用的块为 `SHARED`, 最后一项带 `LAST`。
+    pub fn extent_map(&self, ino: u64) -> DbfsResult<Vec<ExtentMapping>> {
+        let meta = self.get_metadata(ino)?;
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let refs = tx.get_bucket("dedup_refs").ok();
+        let mut flags = Vec::with_capacity(meta.extents.len());
+        for e in &meta.extents {
+            let mut f = ExtentFlags::empty();
+            if e.reserved {
+                f |= ExtentFlags::UNWRITTEN;
+            } else if e.compressed {
+                f |= ExtentFlags::ENCODED;
+            } else if let Some(refs) = &refs {
+                for ptr in
+                    (e.physical_ptr..e.physical_ptr + e.len).step_by(DEDUP_BLOCK_SIZE as usize)
+                {
+                    if dedup_refs(refs, ptr)?.map_or(false, |(_, count)| count > 1) {
+                        f |= ExtentFlags::SHARED;
+                        break;
+                    }
+                }
+            }
+            flags.push(f);
+        }
+
+        // 在所有 extent 的边界处切分, 每一段由覆盖它的最后一个 extent 决定
+        let mut points = alloc::vec![0, meta.size];
+        for e in &meta.extents {
+            points.push(e.logical_off.min(meta.size));
+            points.push((e.logical_off + e.len).min(meta.size));
+        }
+        points.sort_unstable();
+        points.dedup();
+
+        let mut map: Vec<(ExtentMapping, Option<usize>)> = Vec::new();
+        for w in points.windows(2) {
+            let (start, end) = (w[0], w[1]);
+            let found = meta
+                .extents
+                .iter()
+                .rposition(|e| start >= e.logical_off && start < e.logical_off + e.len);
+            let entry = match found {
+                Some(idx) => {
+                    let e = &meta.extents[idx];
+                    let physical = if e.compressed {
+                        e.physical_ptr
+                    } else {
+                        e.physical_ptr + (start - e.logical_off)
+                    };
+                    (start, physical, end - start, flags[idx])
+                }
+                None => (start, 0, end - start, ExtentFlags::HOLE),
+            };
+            match map.last_mut() {
+                Some((last, last_idx))
+                    if *last_idx == found
+                        && (found.is_none()
+                            || (!last.3.contains(ExtentFlags::ENCODED)
+                                && last.1 + last.2 == entry.1)) =>
+                {
+                    last.2 += entry.2;
+                }
+                _ => map.push((entry, found)),
+            }
+        }
+        if let Some((last, _)) = map.last_mut() {
+            last.3 |= ExtentFlags::LAST;
+        }
+        Ok(map.into_iter().map(|(entry, _)| entry).collect())
+    }
+
+    /// 遍历所有 Inode, 按 Inode 号升序返回 (Inode 号, 元数据)
+    ///
+    /// 迭代器持有的是读事务中的快照。无法反序列化的条目被跳过并记录日志。
+    pub fn iter_inodes(&self) -> DbfsResult<impl Iterator<Item = (u64, InodeMetadata)>> {
+        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+        let bucket = tx
+            .get_bucket(&self.inodes_bucket())
+            .map_err(|_| DbfsError::NotFound)?;
+
+        let mut inodes = Vec::new();
+        for data in bucket.cursor() {
+            let kv = match data {
+                Data::KeyValue(kv) => kv,
+                Data::Bucket(_) => continue,
+            };
+            let ino = match kv.key().try_into() {
+                Ok(key) => u64::from_be_bytes(key),
+                Err(_) => continue,
+            };
+            match decode_meta(kv.value()) {
+                Ok(meta) => inodes.push((ino, meta)),
+                Err(_) => log::warn!("iter_inodes: skipping undecodable inode {}", ino),
+            }
+        }
+        Ok(inodes.into_iter())
+    }
+
+    /// 导出钩子: 对每个 Inode 调用 `sink(元数据, 文件内容)`, 供打包/备份工具使用
+    ///
+    /// 只有普通文件带有内容, 其他类型的 Inode 收到空切片。`sink` 返回错误时导出中止。
+    pub fn export_stream<F>(&self, mut sink: F) -> DbfsResult<()>
+    where
+        F: FnMut(&InodeMetadata, &[u8]) -> DbfsResult<()>,
+    {
+        for (_, meta) in self.iter_inodes()? {
+            if (meta.mode & 0o170000) == 0o100000 {
+                let mut data = alloc::vec![0u8; meta.size as usize];
+                let n = self.read_from_log(&meta, 0, &mut data)?;
+                sink(&meta, &data[..n])?;
+            } else {
+                sink(&meta, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 从 tar 流导入目录树到目录 `root_ino` 下, 返回导入的条目数
+    ///
+    /// 支持 ustar 格式的目录、普通文件和符号链接 (目标作为内容保存), 以及 GNU 长文件名;
+    /// 保留权限位和 mtime。路径中缺少的父目录自动创建 (不计入条目数), 硬链接、设备文件和
+    /// pax 扩展头被跳过。条目按 [`IMPORT_BATCH`] 个一组在同一个事务中提交; 中途出错时
+    /// 已提交的部分保留。名字已存在时返回 `FileExists`, 目录除外。
+    #[cfg(feature = "fuse")]
+    pub fn import_stream<R: std::io::Read>(
+        &mut self,
+        root_ino: u64,
+        mut reader: R,
+    ) -> DbfsResult<usize> {
+        self.check_writable()?;
+        let mut imported = 0;
+        let mut pending = Vec::new();
+        let mut long_name = None;
+        loop {
+            let mut header = [0u8; 512];
+            if !read_block(&mut reader, &mut header)? || header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let entry = TarEntry::parse(&header)?;
+            let mut data = alloc::vec![0u8; entry.size as usize];
+            reader
+                .read_exact(&mut data)
+                .map_err(|_| DbfsError::InvalidArgument)?;
+            let padding = (512 - entry.size % 512) % 512;
+            let mut skip = [0u8; 512];
+            reader
+                .read_exact(&mut skip[..padding as usize])
+                .map_err(|_| DbfsError::InvalidArgument)?;
+
+            let path = long_name.take().unwrap_or(entry.path);
+            let kind = match entry.typeflag {
+                b'L' => {
+                    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    long_name = Some(data[..end].to_vec());
+                    continue;
+                }
+                b'0' | 0 | b'7' => 0o100000,
+                b'5' => 0o040000,
+                b'2' => {
+                    data = entry.link_name;
+                    0o120000
+                }
+                other => {
+                    log::warn!("import_stream: skipping tar entry type {:?}", other as char);
+                    continue;
+                }
+            };
+            pending.push((path, kind | (entry.mode & 0o7777), entry.mtime, data));
+
+            if pending.len() == IMPORT_BATCH {
+                imported += pending.len();
+                let batch = core::mem::take(&mut pending);
+                self.batch(|ctx| ctx.import_entries(root_ino, &batch))?;
+            }
+        }
+        imported += pending.len();
+        self.batch(|ctx| ctx.import_entries(root_ino, &pending))?;
+        Ok(imported)
+    }
+
+    /// 一致性检查, 用于崩溃后审计数据库
+    ///
+    /// 遍历所有 `dir_*` 目录项, 检查指向的 Inode 是否存在; 检查非目录 Inode 的 nlink 是否等于
+    /// 引用它的目录项数 (`.` 和 `..` 不计); 检查 extent 是否越过日志写指针。
+    /// `repair` 为 true 时删除悬空目录项并修正 nlink, 孤儿 Inode 和越界 extent 只报告。
+    pub fn fsck(&mut self, repair: bool) -> DbfsResult<FsckReport> {
+        if repair {
+            self.check_writable()?;
         }
-        
-        let read_len = core::cmp::min(buf.len() as u64, meta.size - offset) as usize;
-        let mut total_read = 0;
-        
-        // 遍历 extents 找到对应数据
-        // 注意：这是一个简单实现，实际应按 offset 排序或使用更高效的索引
-        for extent in &meta.extents {
-            if total_read >= read_len {
-                break;
+        let mut report = FsckReport::default();
+        let log_end = self.log_manager.next_append_pos();
+        let tx = self.db.tx(repair).map_err(|_| DbfsError::Io)?;
+
+        let mut metas = BTreeMap::new();
+        let inodes = tx
+            .get_bucket(&self.inodes_bucket())
+            .map_err(|_| DbfsError::NotFound)?;
+        for data in inodes.cursor() {
+            if let Data::KeyValue(kv) = data {
+                let meta = decode_meta(kv.value())?;
+                metas.insert(meta.ino, meta);
             }
-            
-            // 检查 extent 是否与请求范围重叠
-            let extent_end = extent.logical_off + extent.len;
-            let request_end = offset + read_len as u64;
-            
-            if extent.logical_off < request_end && extent_end > offset {
-                let overlap_start = core::cmp::max(extent.logical_off, offset);
-                let overlap_end = core::cmp::min(extent_end, request_end);
-                
-                let extent_offset = overlap_start - extent.logical_off;
-                let buf_offset = overlap_start - offset;
-                let copy_len = (overlap_end - overlap_start) as usize;
-                
-                let mut temp_buf = alloc::vec![0u8; copy_len];
-                self.log_manager.read_data(extent.physical_ptr + extent_offset, &mut temp_buf)?;
-                
-                buf[buf_offset as usize..(buf_offset + copy_len as u64) as usize].copy_from_slice(&temp_buf);
-                total_read = core::cmp::max(total_read, (buf_offset + copy_len as u64) as usize);
-            }
-        }
-        
-        Ok(total_read)
-    }
+        }
 
-    /// 分配新的 Inode 号
-    pub fn allocate_inode(&mut self, mode: u32) -> DbfsResult<u64> {
-        let tx = self.db.begin_batch();
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        
-        // 简单实现：查找当前最大的 Inode 号并 +1
-        let mut max_ino = 1u64;
-        for kv in bucket.cursor() {
-            let ino = u64::from_be_bytes(kv.key().try_into().map_err(|_| DbfsError::Other)?);
-            if ino > max_ino {
-                max_ino = ino;
+        // 1. 目录项: 统计引用数, 找出悬空目录项
+        let mut refs: BTreeMap<u64, u32> = BTreeMap::new();
+        // 修复时按原始键删除, 报告中的名字对非 UTF-8 的键是有损的
+        let mut dangling_keys = Vec::new();
+        for (name, bucket) in tx.buckets() {
+            let parent = match core::str::from_utf8(name.name())
+                .ok()
+                .and_then(|n| n.strip_prefix(self.namespace.as_str()))
+                .and_then(|n| n.strip_prefix("dir_"))
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                Some(parent) => parent,
+                None => continue,
+            };
+            for data in bucket.cursor() {
+                let kv = match data {
+                    Data::KeyValue(kv) => kv,
+                    Data::Bucket(_) => continue,
+                };
+                if kv.key() == b"." || kv.key() == b".." {
+                    continue;
+                }
+                let child =
+                    u64::from_be_bytes(kv.value().try_into().map_err(|_| DbfsError::Other)?);
+                if metas.contains_key(&child) {
+                    *refs.entry(child).or_insert(0) += 1;
+                } else {
+                    let name = String::from_utf8_lossy(kv.key()).into_owned();
+                    report.dangling_dentries.push((parent, name, child));
+                    dangling_keys.push((parent, kv.key().to_vec()));
+                }
             }
         }
-        let new_ino = max_ino + 1;
-        
-        // 初始化新 Inode 元数据
-        let meta = InodeMetadata {
-            ino: new_ino,
-            size: 0,
-            mode,
-            nlink: 1,
-            extents: alloc::vec::Vec::new(),
-            atime: 0,
-            mtime: 0,
-        };
-        bucket.put(new_ino.to_be_bytes(), serialize(&meta)?)?;
-        
-        tx.commit().map_err(|_| DbfsError::Io)?;
-        Ok(new_ino)
+
+        // 2. Inode: 孤儿、nlink 和 extent
+        for (ino, meta) in metas.iter_mut() {
+            let count = refs.get(ino).copied().unwrap_or(0);
+            if count == 0 {
+                if *ino != 1 {
+                    report.orphaned_inodes.push(*ino);
+                }
+            } else if (meta.mode & 0o170000) != 0o040000 && meta.nlink != count {
+                report.nlink_mismatches.push((*ino, meta.nlink, count));
+                meta.nlink = count;
+            }
+            for (i, extent) in meta.extents.iter().enumerate() {
+                if extent.physical_ptr.saturating_add(extent.physical_len()) > log_end {
+                    report.bad_extents.push((*ino, i));
+                }
+            }
+        }
+
+        if !repair || (report.dangling_dentries.is_empty() && report.nlink_mismatches.is_empty()) {
+            return Ok(report);
+        }
+
+        for (parent, key) in &dangling_keys {
+            let bucket = tx
+                .get_bucket(alloc::format!("dir_{}", parent))
+                .map_err(|_| DbfsError::Io)?;
+            bucket.delete(key).map_err(|_| DbfsError::Io)?;
+            name_index_remove(&tx, &alloc::format!("dirhash_{}", parent), key)?;
+        }
+        let inodes = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
+        for (ino, _, _) in &report.nlink_mismatches {
+            inodes.put(ino.to_be_bytes(), serialize(&metas[ino])?)?;
+        }
+
+        self.db.commit(tx.commit())?;
+        report.repaired = true;
+        Ok(report)
     }
 
-    /// 添加目录项
-    pub fn add_dentry(&mut self, parent_ino: u64, name: &str, child_ino: u64) -> DbfsResult<()> {
-        let tx = self.db.begin_batch();
-        let bucket_name = alloc::format!("dir_{}", parent_ino);
-        let bucket = tx.get_or_create_bucket(&bucket_name).map_err(|_| DbfsError::Io)?;
-        
-        bucket.put(name.as_bytes(), child_ino.to_be_bytes())?;
-        
-        tx.commit().map_err(|_| DbfsError::Io)?;
+    /// 记录访问时间, 不立即提交
+    ///
+    /// atime 只是元数据, 留到完整的 `fsync` 再写入, 读操作不必每次都提交一个 jammdb 事务。
+    /// `get_metadata` 返回已提交的值, 尚未写入的 atime 通过 `pending_atime` 查询。
+    pub fn touch_atime(&mut self, ino: u64, atime: i64) -> DbfsResult<()> {
+        self.check_writable()?;
+        self.pending_atime.insert(ino, atime);
         Ok(())
     }
 
-    /// 查找目录项
-    pub fn lookup_dentry(&self, parent_ino: u64, name: &str) -> DbfsResult<u64> {
-        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
-        let bucket_name = alloc::format!("dir_{}", parent_ino);
-        let bucket = tx.get_bucket(&bucket_name).map_err(|_| DbfsError::NotFound)?;
-        
-        let val = bucket.get(name.as_bytes()).ok_or(DbfsError::NotFound)?;
-        let ino = u64::from_be_bytes(val.kv().value().try_into().map_err(|_| DbfsError::Other)?);
-        
-        Ok(ino)
+    /// 尚未持久化的 atime
+    pub fn pending_atime(&self, ino: u64) -> Option<i64> {
+        self.pending_atime.get(&ino).copied()
     }
 
-    /// 列出目录项
-    pub fn list_dentries(&self, parent_ino: u64, start_index: usize) -> DbfsResult<Option<(alloc::string::String, u64)>> {
-        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
-        let bucket_name = alloc::format!("dir_{}", parent_ino);
-        let bucket = match tx.get_bucket(&bucket_name) {
-            Ok(b) => b,
-            Err(_) => return Ok(None),
-        };
-
-        let entry = bucket.cursor().nth(start_index);
-        if let Some(kv) = entry {
-            let name = alloc::string::String::from_utf8(kv.key().to_vec()).map_err(|_| DbfsError::Other)?;
-            let ino = u64::from_be_bytes(kv.kv().value().try_into().map_err(|_| DbfsError::Other)?);
-            Ok(Some((name, ino)))
-        } else {
-            Ok(None)
+    /// 卸载文件系统
+    ///
+    /// 依次让日志落盘、提交所有尚未持久化的 atime, 最后在超级块中记录日志写指针,
+    /// 下次挂载由 [`recover_append_pos`] 取回。任何一步失败都返回错误, 此时不记录写指针,
+    /// 下次挂载会按异常关机处理。快照视图没有需要写回的状态, 直接返回。
+    pub fn unmount(&mut self) -> DbfsResult<()> {
+        if self.check_writable().is_err() {
+            return Ok(());
         }
-    }
+        self.force_checkpoint()?;
 
-    /// 删除目录项
-    pub fn delete_dentry(&mut self, parent_ino: u64, name: &str) -> DbfsResult<()> {
-        let tx = self.db.begin_batch();
-        let bucket_name = alloc::format!("dir_{}", parent_ino);
-        let bucket = tx.get_bucket(&bucket_name).map_err(|_| DbfsError::NotFound)?;
-        
-        bucket.delete(name.as_bytes()).map_err(|_| DbfsError::Io)?;
-        
-        tx.commit().map_err(|_| DbfsError::Io)?;
-        Ok(())
+        let next_append_pos = self.log_manager.next_append_pos();
+        self.batch(|ctx| {
+            let sb = ctx
+                .tx
+                .get_or_create_bucket("super_blk")
+                .map_err(|_| DbfsError::Io)?;
+            sb.put(NEXT_APPEND_POS_KEY, next_append_pos.to_be_bytes())?;
+            Ok(())
+        })
     }
 
-    /// 删除 Inode
-    pub fn delete_inode(&mut self, ino: u64) -> DbfsResult<()> {
-        let tx = self.db.begin_batch();
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        
-        bucket.delete(&ino.to_be_bytes()).map_err(|_| DbfsError::Io)?;
-        
-        // 如果是目录，删除其目录项 bucket
-        let _ = tx.delete_bucket(&alloc::format!("dir_{}", ino));
-        
-        tx.commit().map_err(|_| DbfsError::Io)?;
-        Ok(())
+    /// 让所有修改落盘: 日志设备落盘, 并在一个事务中提交所有尚未持久化的 atime
+    pub fn force_checkpoint(&mut self) -> DbfsResult<()> {
+        self.check_writable()?;
+        self.log_manager.flush()?;
+
+        let pending = core::mem::take(&mut self.pending_atime);
+        let result = self.batch(|ctx| {
+            for (&ino, &atime) in &pending {
+                let mut meta = match ctx.get_metadata(ino) {
+                    Ok(meta) => meta,
+                    // 已删除的文件不需要写回 atime
+                    Err(DbfsError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+                meta.atime = atime;
+                ctx.update_metadata(&meta)?;
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            self.pending_atime = pending;
+        }
+        result
     }
 
-    /// 更新 Inode 元数据
-    pub fn update_metadata(&mut self, meta: &InodeMetadata) -> DbfsResult<()> {
-        let tx = self.db.begin_batch();
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        
-        bucket.put(meta.ino.to_be_bytes(), serialize(meta)?)?;
-        
-        tx.commit().map_err(|_| DbfsError::Io)?;
-        Ok(())
+    /// 回收日志中不再被引用的空间, 返回写指针后退的字节数
+    ///
+    /// 分两个阶段, 每个阶段都是一次原子提交, 任何时刻断电都不会丢数据:
+    /// 1. 把所有文件中可见的数据连续地追加到日志末尾, 提交指向新位置的 extent, 此后日志区
+    ///    开头到原写指针之间的数据都不再被引用;
+    /// 2. 把这份数据再写到日志区开头, 提交指向开头的 extent, 写指针随之移到它的末尾。
+    ///
+    /// 空洞保持为空洞, 预分配而未写入的范围被写成 0。有快照时快照仍引用旧数据, 不做回收,
+    /// 返回 0; 日志末尾放不下第一阶段的副本时返回 `NoSpace`。
+    pub fn gc(&mut self) -> DbfsResult<u64> {
+        self.check_writable()?;
+        {
+            let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
+            if let Ok(snapshots) = tx.get_bucket("snapshots") {
+                if snapshots.cursor().next().is_some() {
+                    return Ok(0);
+                }
+            }
+        }
+
+        // 读出每个文件中 extent 覆盖的范围
+        let mut live: Vec<(u64, Vec<(u64, Vec<u8>)>)> = Vec::new();
+        let mut live_len = 0;
+        for (ino, meta) in self.iter_inodes()? {
+            let mut segments = Vec::new();
+            for (start, end) in live_ranges(&meta.extents, meta.size) {
+                let mut data = alloc::vec![0u8; (end - start) as usize];
+                let n = self.read_snapshot(ino, start, &mut data)?;
+                data.truncate(n);
+                live_len += data.len() as u64;
+                segments.push((start, data));
+            }
+            if !meta.extents.is_empty() {
+                live.push((ino, segments));
+            }
+        }
+
+        let start = self.log_manager.start();
+        let old_end = self.log_manager.next_append_pos();
+        if start + live_len >= old_end {
+            return Ok(0);
+        }
+        if old_end.saturating_add(live_len) > self.log_manager.device_size() {
+            return Err(DbfsError::NoSpace);
+        }
+
+        // 阶段 1: 副本追加到日志末尾
+        self.batch(|ctx| ctx.relocate(&live))?;
+        // 阶段 2: 日志区开头已不再被引用, 副本写回开头
+        self.log_manager.rewind(start);
+        self.batch(|ctx| ctx.relocate(&live))?;
+
+        Ok(old_end - self.log_manager.next_append_pos())
     }
 
-    /// 获取 Inode 元数据
-    pub fn get_metadata(&self, ino: u64) -> DbfsResult<InodeMetadata> {
-        let tx = self.db.tx(false).map_err(|_| DbfsError::Io)?;
-        let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        let kv = bucket.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
-        deserialize(kv.kv().value())
+    /// 让 `ino` 的修改落盘
+    ///
+    /// 数据和 size 在写入时已随 jammdb 事务提交, 这里只需确保日志设备落盘。`datasync` 为
+    /// true 时 (`fdatasync`) 跳过 atime 等只影响元数据的修改, 为 false 时一并提交。
+    pub fn fsync(&mut self, ino: u64, datasync: bool) -> DbfsResult<()> {
+        self.log_manager.flush()?;
+        if datasync {
+            return Ok(());
+        }
+        let atime = match self.pending_atime.get(&ino) {
+            Some(&atime) => atime,
+            None => return Ok(()),
+        };
+        let result = self.batch(|ctx| {
+            let mut meta = ctx.get_metadata(ino)?;
+            meta.atime = atime;
+            ctx.update_metadata(&meta)
+        });
+        match result {
+            // 已删除的文件不需要写回 atime
+            Ok(()) | Err(DbfsError::NotFound) => {
+                self.pending_atime.remove(&ino);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
     }
 
     /// 截断文件
     pub fn truncate_file(&mut self, ino: u64, new_size: u64) -> DbfsResult<()> {
+        self.check_writable()?;
         let tx = self.db.begin_batch();
         let bucket = tx.get_bucket("inodes").map_err(|_| DbfsError::NotFound)?;
-        
+
         let ino_key = ino.to_be_bytes();
         let kv = bucket.get(&ino_key).ok_or(DbfsError::NotFound)?;
-        let mut meta: InodeMetadata = deserialize(kv.kv().value())?;
-        
+        let mut meta = decode_meta(kv.kv().value())?;
+
         if new_size < meta.size {
             // 缩小文件：保留逻辑偏移量小于 new_size 的 extents
             // 注意：这里需要处理跨越 new_size 边界的 extent
             let mut new_extents = Vec::new();
+            let mut dropped = Vec::new();
             for mut extent in meta.extents {
                 if extent.logical_off < new_size {
-                    if extent.logical_off + extent.len > new_size {
-                        // 截断最后一个 extent
+                    let extent_end = extent
+                        .logical_off
+                        .checked_add(extent.len)
+                        .ok_or(DbfsError::InvalidArgument)?;
+                    if extent_end > new_size {
+                        // 截断最后一个 extent, 被截掉的数据仍留在日志中, 只是索引变了
                         extent.len = new_size - extent.logical_off;
-                        // 注意：crc 可能失效，或者我们选择不更新 crc (因为 read 时会校验)
-                        // 实际实现中，截断后的部分数据可能依然在磁盘上，只是索引变了
+                        // crc 按新长度重新计算, 否则校验会失败
+                        extent.crc = if extent.compressed {
+                            crc32(&extent_data(&self.log_manager, &extent)?)
+                        } else {
+                            let mut crc = 0;
+                            self.log_manager.with_data(
+                                extent.physical_ptr,
+                                extent.len as usize,
+                                &mut |data| {
+                                    crc = crc32(data);
+                                },
+                            )?;
+                            crc
+                        };
                     }
                     new_extents.push(extent);
                 } else {
                     // 逻辑偏移量 >= new_size 的 extent 直接丢弃
                     // TODO: 在物理日志中标记这些空间可以回收 (DBFS-T 是追加写，暂不回收)
+                    dropped.push(extent);
                 }
             }
+            release_dedup(&tx, &dropped)?;
             meta.extents = new_extents;
         }
-        
+
+        // 扩大文件同样占用配额, 缩小时归还
+        charge_quota(&tx, meta.uid, meta.size, new_size)?;
         meta.size = new_size;
         // meta.mtime = now();
 
         bucket.put(ino_key, serialize(&meta)?)?;
-        tx.commit().map_err(|_| DbfsError::Io)?;
-        Ok(())
+        check_crash(self.crash_hook.as_ref(), CrashPoint::BeforeCommit)?;
+        self.db.commit(tx.commit())?;
+        check_crash(self.crash_hook.as_ref(), CrashPoint::AfterCommit)
+    }
+
+    /// 读出 extent 在日志中的数据, 检查 crc 是否与记录的一致
+    pub fn verify_extent(&self, extent: &Extent) -> DbfsResult<bool> {
+        // 预留的 extent 没有写入数据, 也就没有校验和
+        if extent.reserved {
+            return Ok(true);
+        }
+        if extent.compressed {
+            return Ok(extent_data(&self.log_manager, extent)
+                .map_or(false, |data| crc32(&data) == extent.crc));
+        }
+        let mut crc = None;
+        self.log_manager
+            .with_data(extent.physical_ptr, extent.len as usize, &mut |data| {
+                if data.len() as u64 == extent.len {
+                    crc = Some(crc32(data));
+                }
+            })?;
+        Ok(crc == Some(extent.crc))
     }
 
     /// 根据 Extents 从日志读取数据
-    pub fn read_from_log(&self, meta: &InodeMetadata, offset: u64, buf: &mut [u8]) -> DbfsResult<usize> {
+    pub fn read_from_log(
+        &self,
+        meta: &InodeMetadata,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> DbfsResult<usize> {
+        checked_end(offset, buf.len())?;
         if offset >= meta.size {
             return Ok(0);
         }
 
-        let mut bytes_read = 0;
+        // 1. 先规划每个 extent 对应的物理位置和长度, 各段在 buf 中依次相连
+        //    预留的 extent 没有物理数据, 对应的片段填 0
+        let mut segments = Vec::new();
         let mut current_offset = offset;
         let mut buf_pos = 0;
 
         while buf_pos < buf.len() && current_offset < meta.size {
             // 查找包含 current_offset 的 extent
             let extent = meta.extents.iter().find(|e| {
-                current_offset >= e.logical_off && current_offset < e.logical_off + e.len
+                current_offset >= e.logical_off && current_offset - e.logical_off < e.len
             });
 
             if let Some(e) = extent {
                 let off_in_extent = current_offset - e.logical_off;
-                let len_in_extent = core::cmp::min(
-                    (e.len - off_in_extent) as usize,
-                    buf.len() - buf_pos
-                );
-
-                let physical_pos = e.physical_ptr + off_in_extent;
-                self.log_manager.read_data(physical_pos, &mut buf[buf_pos..buf_pos + len_in_extent])?;
+                let len_in_extent =
+                    core::cmp::min((e.len - off_in_extent) as usize, buf.len() - buf_pos);
 
-                bytes_read += len_in_extent;
+                segments.push((e, off_in_extent, len_in_extent));
                 buf_pos += len_in_extent;
                 current_offset += len_in_extent as u64;
             } else {
@@ -291,7 +2067,24 @@ impl<D: BlockDevice> TransactionEngine<D> {
             }
         }
 
-        Ok(bytes_read)
+        // 2. 把 buf 切分成对应的片段, 一次性提交给设备; 压缩的片段单独读出并解压
+        let mut reqs = Vec::with_capacity(segments.len());
+        let mut rest = &mut buf[..buf_pos];
+        for (e, off_in_extent, len) in segments {
+            let (head, tail) = rest.split_at_mut(len);
+            if e.reserved {
+                head.fill(0);
+            } else if e.compressed {
+                let data = extent_data(&self.log_manager, e)?;
+                head.copy_from_slice(&data[off_in_extent as usize..off_in_extent as usize + len]);
+            } else {
+                reqs.push((e.physical_ptr + off_in_extent, head));
+            }
+            rest = tail;
+        }
+        self.log_manager.read_data_vectored(&mut reqs)?;
+
+        Ok(buf_pos)
     }
 }
 
@@ -304,3 +2097,545 @@ fn serialize<T: serde::Serialize>(obj: &T) -> DbfsResult<Vec<u8>> {
 fn deserialize<'a, T: serde::Deserialize<'a>>(data: &'a [u8]) -> DbfsResult<T> {
     serde_json::from_slice(data).map_err(|_| DbfsError::Other)
 }
+
+// Inode 元数据反序列化: 旧版本的记录在内存中升级到当前格式, 写回时按当前格式序列化
+fn decode_meta(data: &[u8]) -> DbfsResult<InodeMetadata> {
+    let mut meta: InodeMetadata = deserialize(data)?;
+    if !meta.migrate() {
+        return Err(DbfsError::NotSupported);
+    }
+    Ok(meta)
+}
+
+/// tar 头中 `import_stream` 用到的字段
+#[cfg(feature = "fuse")]
+struct TarEntry {
+    path: Vec<u8>,
+    mode: u32,
+    size: u64,
+    mtime: i64,
+    typeflag: u8,
+    link_name: Vec<u8>,
+}
+
+#[cfg(feature = "fuse")]
+impl TarEntry {
+    fn parse(header: &[u8; 512]) -> DbfsResult<Self> {
+        // 校验和按校验和字段全为空格计算
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u64
+                } else {
+                    b as u64
+                }
+            })
+            .sum();
+        if tar_number(&header[148..156])? != sum {
+            return Err(DbfsError::InvalidArgument);
+        }
+
+        let mut path = tar_string(&header[0..100]).to_vec();
+        // ustar 把长路径拆成前缀和名字两部分
+        if &header[257..262] == b"ustar" {
+            let prefix = tar_string(&header[345..500]);
+            if !prefix.is_empty() {
+                let mut full = prefix.to_vec();
+                full.push(b'/');
+                full.extend_from_slice(&path);
+                path = full;
+            }
+        }
+        Ok(Self {
+            path,
+            mode: tar_number(&header[100..108])? as u32,
+            size: tar_number(&header[124..136])?,
+            mtime: tar_number(&header[136..148])? as i64,
+            typeflag: header[156],
+            link_name: tar_string(&header[157..257]).to_vec(),
+        })
+    }
+}
+
+// 以 NUL 结尾的字段
+#[cfg(feature = "fuse")]
+fn tar_string(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+// 八进制数字段; 最高位置位时是 GNU 的 base-256 编码
+#[cfg(feature = "fuse")]
+fn tar_number(field: &[u8]) -> DbfsResult<u64> {
+    if field.first().map_or(false, |&b| b & 0x80 != 0) {
+        return Ok(field[1..].iter().fold(0u64, |n, &b| (n << 8) | b as u64));
+    }
+    let digits = tar_string(field);
+    let digits = core::str::from_utf8(digits).map_err(|_| DbfsError::InvalidArgument)?;
+    let digits = digits.trim_matches(|c| c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| DbfsError::InvalidArgument)
+}
+
+// 读满一个 512 字节的块; 流在块边界结束时返回 false
+#[cfg(feature = "fuse")]
+fn read_block<R: std::io::Read>(reader: &mut R, block: &mut [u8; 512]) -> DbfsResult<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(DbfsError::InvalidArgument),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(DbfsError::Io),
+        }
+    }
+    Ok(true)
+}
+
+/// 在读事务 `tx` 固定的视图中读取文件
+fn read_in<D: BlockDevice>(
+    tx: &jammdb::Tx,
+    inodes_bucket: &str,
+    log_manager: &LogManager<D>,
+    ino: u64,
+    offset: u64,
+    buf: &mut [u8],
+) -> DbfsResult<usize> {
+    let bucket = tx
+        .get_bucket(inodes_bucket)
+        .map_err(|_| DbfsError::NotFound)?;
+    let kv = bucket.get(&ino.to_be_bytes()).ok_or(DbfsError::NotFound)?;
+    let meta = decode_meta(kv.kv().value())?;
+
+    checked_end(offset, buf.len())?;
+    if offset >= meta.size {
+        return Ok(0);
+    }
+
+    let read_len = core::cmp::min(buf.len() as u64, meta.size - offset) as usize;
+    let mut total_read = 0;
+
+    // 遍历 extents 找到对应数据
+    // 注意：这是一个简单实现，实际应按 offset 排序或使用更高效的索引
+    // 后写入的 extent 覆盖先写入的, 因此即使请求范围已经填满也要遍历完
+    for extent in &meta.extents {
+        // 检查 extent 是否与请求范围重叠
+        let extent_end = extent
+            .logical_off
+            .checked_add(extent.len)
+            .ok_or(DbfsError::InvalidArgument)?;
+        let request_end = offset + read_len as u64;
+
+        if extent.logical_off < request_end && extent_end > offset {
+            let overlap_start = core::cmp::max(extent.logical_off, offset);
+            let overlap_end = core::cmp::min(extent_end, request_end);
+
+            let extent_offset = overlap_start - extent.logical_off;
+            let buf_offset = overlap_start - offset;
+            let copy_len = (overlap_end - overlap_start) as usize;
+
+            let mut temp_buf = alloc::vec![0u8; copy_len];
+            if extent.compressed {
+                let data = extent_data(log_manager, extent)?;
+                temp_buf.copy_from_slice(
+                    &data[extent_offset as usize..extent_offset as usize + copy_len],
+                );
+            } else if !extent.reserved {
+                log_manager.read_data(extent.physical_ptr + extent_offset, &mut temp_buf)?;
+            }
+
+            buf[buf_offset as usize..(buf_offset + copy_len as u64) as usize]
+                .copy_from_slice(&temp_buf);
+            total_read = core::cmp::max(total_read, (buf_offset + copy_len as u64) as usize);
+        }
+    }
+
+    Ok(total_read)
+}
+
+/// 挂载时确定日志写指针
+///
+/// 上次正常卸载时记录的写指针被取出并从超级块中删除, 这样之后异常关机时不会误用它;
+/// 没有记录时 (异常关机或旧版本) 扫描所有 extent 和快照, 从它们的最大末尾之后继续追加,
+/// 不覆盖任何仍被引用的日志数据。结果不小于 `reserved_size`。
+pub fn recover_append_pos(db: &DB, reserved_size: u64) -> DbfsResult<u64> {
+    let tx = db.tx(true).map_err(|_| DbfsError::Io)?;
+    if let Ok(sb) = tx.get_bucket("super_blk") {
+        if let Some(kv) = sb.get_kv(NEXT_APPEND_POS_KEY) {
+            let pos = u64::from_be_bytes(kv.value().try_into().map_err(|_| DbfsError::Other)?);
+            sb.delete(NEXT_APPEND_POS_KEY).map_err(|_| DbfsError::Io)?;
+            tx.commit().map_err(|_| DbfsError::Io)?;
+            return Ok(pos.max(reserved_size));
+        }
+    }
+
+    let mut end = reserved_size;
+    if let Ok(inodes) = tx.get_bucket("inodes") {
+        for data in inodes.cursor() {
+            if let Data::KeyValue(kv) = data {
+                let meta = decode_meta(kv.value())?;
+                for e in &meta.extents {
+                    end = end.max(e.physical_ptr.saturating_add(e.physical_len()));
+                }
+            }
+        }
+    }
+    // 快照引用的数据都在它记录的写指针之前
+    if let Ok(snapshots) = tx.get_bucket("snapshots") {
+        for data in snapshots.cursor() {
+            if let Data::KeyValue(kv) = data {
+                end = end.max(u64::from_be_bytes(
+                    kv.value().try_into().map_err(|_| DbfsError::Other)?,
+                ));
+            }
+        }
+    }
+    Ok(end)
+}
+
+// 把写到 `offset` 的 `data` 在文件中按 `MAX_EXTENT_LEN` 对齐的位置切开, 返回各段在 `data` 中的
+// 起点和内容
+fn extent_pieces(offset: u64, data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut start = 0;
+    core::iter::from_fn(move || {
+        if start >= data.len() {
+            return None;
+        }
+        let pos = offset + start as u64;
+        let room = (MAX_EXTENT_LEN - pos % MAX_EXTENT_LEN) as usize;
+        let piece = &data[start..data.len().min(start + room)];
+        let item = (start, piece);
+        start += piece.len();
+        Some(item)
+    })
+}
+
+// `offset + len`, 超出 u64 时返回 InvalidArgument 而不是回绕
+fn checked_end(offset: u64, len: usize) -> DbfsResult<u64> {
+    offset
+        .checked_add(len as u64)
+        .ok_or(DbfsError::InvalidArgument)
+}
+
+// 压缩一段数据 (开头记录原始长度), 压缩后没有变小时返回 None
+#[cfg(feature = "compression")]
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let stored = lz4_flex::block::compress_prepend_size(data);
+    (stored.len() < data.len()).then_some(stored)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompress(stored: &[u8]) -> DbfsResult<Vec<u8>> {
+    lz4_flex::block::decompress_size_prepended(stored).map_err(|_| DbfsError::Io)
+}
+
+// 没有启用压缩时无法读取压缩的 extent
+#[cfg(not(feature = "compression"))]
+fn decompress(_stored: &[u8]) -> DbfsResult<Vec<u8>> {
+    Err(DbfsError::NotSupported)
+}
+
+// 读出压缩的 extent 并解压, 截断过的 extent 只保留前 `len` 字节
+fn extent_data<D: BlockDevice>(
+    log_manager: &LogManager<D>,
+    extent: &Extent,
+) -> DbfsResult<Vec<u8>> {
+    let mut stored = alloc::vec![0u8; extent.stored_len as usize];
+    log_manager.read_data(extent.physical_ptr, &mut stored)?;
+    let mut data = decompress(&stored)?;
+    if (data.len() as u64) < extent.len {
+        return Err(DbfsError::Io);
+    }
+    data.truncate(extent.len as usize);
+    Ok(data)
+}
+
+// 64 位 FNV-1a 哈希
+fn fnv1a64(data: &[u8], seed: u64) -> u64 {
+    let mut hash = seed;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// 目录项名字的哈希, 作为名字索引的 key
+fn name_hash(name: &[u8]) -> [u8; 8] {
+    fnv1a64(name, 0xcbf2_9ce4_8422_2325).to_be_bytes()
+}
+
+// 数据块的 128 位内容哈希 (两个不同种子的 FNV-1a), 作为去重表的 key
+fn content_hash(data: &[u8]) -> [u8; 16] {
+    let mut hash = [0u8; 16];
+    hash[..8].copy_from_slice(&fnv1a64(data, 0xcbf2_9ce4_8422_2325).to_be_bytes());
+    hash[8..].copy_from_slice(&fnv1a64(data, 0x6c62_272e_07bb_0142).to_be_bytes());
+    hash
+}
+
+// `dedup_refs` 中的一项: [内容哈希 16 字节][引用数 u64]
+fn dedup_ref_value(hash: [u8; 16], count: u64) -> [u8; 24] {
+    let mut value = [0u8; 24];
+    value[..16].copy_from_slice(&hash);
+    value[16..].copy_from_slice(&count.to_be_bytes());
+    value
+}
+
+fn dedup_refs(refs: &jammdb::Bucket, ptr: u64) -> DbfsResult<Option<([u8; 16], u64)>> {
+    match refs.get_kv(ptr.to_be_bytes()) {
+        Some(kv) => {
+            let value = kv.value();
+            if value.len() != 24 {
+                return Err(DbfsError::Other);
+            }
+            Ok(Some((
+                value[..16].try_into().unwrap(),
+                u64::from_be_bytes(value[16..].try_into().unwrap()),
+            )))
+        }
+        None => Ok(None),
+    }
+}
+
+// `extents` 被复制时其中共享块的引用数加一
+fn retain_dedup(tx: &jammdb::Tx, extents: &[Extent]) -> DbfsResult<()> {
+    let refs = match tx.get_bucket("dedup_refs") {
+        Ok(refs) => refs,
+        Err(_) => return Ok(()),
+    };
+    for extent in extents {
+        if extent.reserved || extent.compressed || extent.len % DEDUP_BLOCK_SIZE != 0 {
+            continue;
+        }
+        for ptr in (extent.physical_ptr..extent.physical_ptr + extent.len)
+            .step_by(DEDUP_BLOCK_SIZE as usize)
+        {
+            if let Some((hash, count)) = dedup_refs(&refs, ptr)? {
+                refs.put(ptr.to_be_bytes(), dedup_ref_value(hash, count + 1))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 不再被 `extents` 引用的共享块引用数减一, 减到 0 时移出去重表
+fn release_dedup(tx: &jammdb::Tx, extents: &[Extent]) -> DbfsResult<()> {
+    let refs = match tx.get_bucket("dedup_refs") {
+        Ok(refs) => refs,
+        Err(_) => return Ok(()),
+    };
+    let dedup = tx.get_bucket("dedup").map_err(|_| DbfsError::Io)?;
+    for extent in extents {
+        if extent.reserved || extent.compressed || extent.len % DEDUP_BLOCK_SIZE != 0 {
+            continue;
+        }
+        for ptr in (extent.physical_ptr..extent.physical_ptr + extent.len)
+            .step_by(DEDUP_BLOCK_SIZE as usize)
+        {
+            let (hash, count) = match dedup_refs(&refs, ptr)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if count > 1 {
+                refs.put(ptr.to_be_bytes(), dedup_ref_value(hash, count - 1))?;
+            } else {
+                refs.delete(ptr.to_be_bytes()).map_err(|_| DbfsError::Io)?;
+                dedup.delete(hash).map_err(|_| DbfsError::Io)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 名字索引的冲突链依次存放 [名字长度 u16][名字][子 Inode u64], 返回每一项
+fn name_chain(chain: &[u8]) -> DbfsResult<Vec<(&[u8], u64)>> {
+    let mut entries = Vec::new();
+    let mut rest = chain;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err(DbfsError::Other);
+        }
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        if rest.len() < 2 + len + 8 {
+            return Err(DbfsError::Other);
+        }
+        let ino = u64::from_be_bytes(rest[2 + len..2 + len + 8].try_into().unwrap());
+        entries.push((&rest[2..2 + len], ino));
+        rest = &rest[2 + len + 8..];
+    }
+    Ok(entries)
+}
+
+fn encode_name_chain(entries: &[(&[u8], u64)]) -> Vec<u8> {
+    let mut chain = Vec::new();
+    for (name, ino) in entries {
+        chain.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        chain.extend_from_slice(name);
+        chain.extend_from_slice(&ino.to_be_bytes());
+    }
+    chain
+}
+
+// 在名字索引中查找, 目录没有索引时返回 None, 索引中没有这个名字时返回 Some(None)
+fn name_index_lookup(
+    tx: &jammdb::Tx,
+    index_name: &str,
+    name: &[u8],
+) -> DbfsResult<Option<Option<u64>>> {
+    let index = match tx.get_bucket(index_name) {
+        Ok(index) => index,
+        Err(_) => return Ok(None),
+    };
+    match index.get_kv(name_hash(name)) {
+        Some(kv) => Ok(Some(
+            name_chain(kv.value())?
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, ino)| ino),
+        )),
+        None => Ok(Some(None)),
+    }
+}
+
+// 在目录有名字索引时加入或更新一项
+fn name_index_insert(tx: &jammdb::Tx, index_name: &str, name: &[u8], ino: u64) -> DbfsResult<()> {
+    let index = match tx.get_bucket(index_name) {
+        Ok(index) => index,
+        Err(_) => return Ok(()),
+    };
+    let key = name_hash(name);
+    let old = index
+        .get_kv(key)
+        .map(|kv| kv.value().to_vec())
+        .unwrap_or_default();
+    let mut entries: Vec<(&[u8], u64)> = name_chain(&old)?
+        .into_iter()
+        .filter(|(n, _)| *n != name)
+        .collect();
+    entries.push((name, ino));
+    index.put(key, encode_name_chain(&entries))?;
+    Ok(())
+}
+
+// 在目录有名字索引时删除一项, 冲突链为空时删除整个 key
+fn name_index_remove(tx: &jammdb::Tx, index_name: &str, name: &[u8]) -> DbfsResult<()> {
+    let index = match tx.get_bucket(index_name) {
+        Ok(index) => index,
+        Err(_) => return Ok(()),
+    };
+    let key = name_hash(name);
+    let old = match index.get_kv(key) {
+        Some(kv) => kv.value().to_vec(),
+        None => return Ok(()),
+    };
+    let entries: Vec<(&[u8], u64)> = name_chain(&old)?
+        .into_iter()
+        .filter(|(n, _)| *n != name)
+        .collect();
+    if entries.is_empty() {
+        index.delete(key).map_err(|_| DbfsError::Io)?;
+    } else {
+        index.put(key, encode_name_chain(&entries))?;
+    }
+    Ok(())
+}
+
+// 文件大小占用的配额块数
+fn quota_blocks(size: u64) -> u64 {
+    size.div_ceil(QUOTA_BLOCK_SIZE)
+}
+
+fn read_quota(quotas: &jammdb::Bucket, uid: u32) -> DbfsResult<Quota> {
+    match quotas.get_kv(uid.to_be_bytes()) {
+        Some(kv) => {
+            let value = kv.value();
+            if value.len() != 16 {
+                return Err(DbfsError::Other);
+            }
+            Ok(Quota {
+                blocks_used: u64::from_be_bytes(value[..8].try_into().unwrap()),
+                blocks_limit: u64::from_be_bytes(value[8..].try_into().unwrap()),
+            })
+        }
+        None => Ok(Quota::default()),
+    }
+}
+
+fn write_quota(quotas: &jammdb::Bucket, uid: u32, quota: Quota) -> DbfsResult<()> {
+    let mut value = [0u8; 16];
+    value[..8].copy_from_slice(&quota.blocks_used.to_be_bytes());
+    value[8..].copy_from_slice(&quota.blocks_limit.to_be_bytes());
+    quotas.put(uid.to_be_bytes(), value)?;
+    Ok(())
+}
+
+// 文件大小从 old_size 变为 new_size 时更新 uid 的已用块数, 增长超出上限时返回 NoSpace
+fn charge_quota(tx: &jammdb::Tx, uid: u32, old_size: u64, new_size: u64) -> DbfsResult<()> {
+    let (old, new) = (quota_blocks(old_size), quota_blocks(new_size));
+    if old == new {
+        return Ok(());
+    }
+    let quotas = tx
+        .get_or_create_bucket("quotas")
+        .map_err(|_| DbfsError::Io)?;
+    let mut quota = read_quota(&quotas, uid)?;
+    if new > old {
+        let used = quota.blocks_used.saturating_add(new - old);
+        if used > quota.blocks_limit {
+            return Err(DbfsError::NoSpace);
+        }
+        quota.blocks_used = used;
+    } else {
+        quota.blocks_used = quota.blocks_used.saturating_sub(old - new);
+    }
+    write_quota(&quotas, uid, quota)
+}
+
+// 已有 extent 覆盖 [start, end) 中的字节数, 重叠的 extent 只计一次
+// extent 在 `[0, size)` 中覆盖的范围, 按偏移排序并合并相邻的范围
+fn live_ranges(extents: &[Extent], size: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = extents
+        .iter()
+        .map(|e| (e.logical_off, (e.logical_off + e.len).min(size)))
+        .filter(|(s, e)| s < e)
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (s, e) in ranges {
+        match merged.last_mut() {
+            Some(last) if s <= last.1 => last.1 = last.1.max(e),
+            _ => merged.push((s, e)),
+        }
+    }
+    merged
+}
+
+fn covered_len(extents: &[Extent], start: u64, end: u64) -> u64 {
+    let mut ranges: Vec<(u64, u64)> = extents
+        .iter()
+        .map(|e| (e.logical_off.max(start), (e.logical_off + e.len).min(end)))
+        .filter(|(s, e)| s < e)
+        .collect();
+    ranges.sort_unstable();
+
+    let mut covered = 0;
+    let mut reached = start;
+    for (s, e) in ranges {
+        if e > reached {
+            covered += e - s.max(reached);
+            reached = e;
+        }
+    }
+    covered
+}
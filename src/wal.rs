@@ -1,15 +1,56 @@
+use crate::common::{crc32, crc32_update};
 use crate::operation::TransactionOperation;
-use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::sync::Arc;
-use serde::{Deserialize, Serialize};
+use alloc::vec::Vec;
+
+/// Marks the start of every frame, so zero-filled or garbage tails are not parsed as entries
+const FRAME_MAGIC: u32 = 0x5741_4c32; // "WAL2", entries in the binary encoding
+/// Largest entry a frame may carry, and recovery will allocate a buffer for
+const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+/// Encoded size of a `Write` entry apart from its data: txn id, tag, inode, offset, length
+const WRITE_ENTRY_OVERHEAD: usize = 8 + 1 + 8 + 8 + 4;
+/// Largest data a single logged `Write` carries; longer writes are split
+const MAX_WRITE_CHUNK: usize = MAX_FRAME_SIZE as usize - WRITE_ENTRY_OVERHEAD;
+/// Magic and size in front of the entry
+const FRAME_HEADER: u64 = 8;
+/// CRC of the entry behind it
+const FRAME_TRAILER: u64 = 4;
+
+/// Capacity the in-memory entry buffer may keep once it has drained
+const RETAINED_CAPACITY: usize = 64;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct WalEntry {
     pub txn_id: u64,
     pub operation: TransactionOperation,
 }
 
+impl WalEntry {
+    /// The transaction id, little-endian, followed by the encoded operation
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.txn_id.to_le_bytes());
+        self.operation.encode(&mut data);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let txn_id = u64::from_le_bytes(data.get(..8)?.try_into().unwrap());
+        let operation = TransactionOperation::decode(&data[8..])?;
+        Some(Self { txn_id, operation })
+    }
+}
+
+/// How the log ended when it was recovered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryStatus {
+    /// The log ended at a frame boundary
+    Clean,
+    /// The last frame was only partly written by a crash mid-append and has been cut off
+    TruncatedTail,
+}
+
 pub trait WalStorage: Send + Sync {
     fn write(&self, offset: u64, data: &[u8]) -> Result<(), String>;
     fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), String>;
@@ -17,10 +58,26 @@ pub trait WalStorage: Send + Sync {
     fn flush(&self) -> Result<(), String>;
 }
 
+/// Snapshot of WAL pressure, for embedders deciding when to checkpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalStats {
+    /// Logged entries that have not been applied yet
+    pub pending_entries: usize,
+    /// Bytes written to storage since the last checkpoint
+    pub bytes_on_disk: u64,
+    /// Offset the next entry will be written at
+    pub next_offset: u64,
+    /// Highest transaction id covered by the last checkpoint, 0 if none
+    pub last_checkpoint_txn: u64,
+}
+
 pub struct WriteAheadLog {
     entries: Vec<WalEntry>,
     storage: Option<Arc<dyn WalStorage>>,
     next_offset: u64,
+    /// Highest transaction id appended so far
+    last_txn: u64,
+    last_checkpoint_txn: u64,
 }
 
 impl WriteAheadLog {
@@ -29,6 +86,8 @@ impl WriteAheadLog {
             entries: Vec::new(),
             storage: None,
             next_offset: 0,
+            last_txn: 0,
+            last_checkpoint_txn: 0,
         }
     }
 
@@ -36,22 +95,60 @@ impl WriteAheadLog {
         self.storage = Some(storage);
     }
 
+    /// The device the log is written to, if any.
+    pub fn storage(&self) -> Option<Arc<dyn WalStorage>> {
+        self.storage.clone()
+    }
+
+    /// Log `op` as part of transaction `txn_id`.
+    ///
+    /// A write too large for one frame is logged as consecutive writes of the same
+    /// transaction; any other entry over [`MAX_FRAME_SIZE`] is rejected.
     pub fn append(&mut self, txn_id: u64, op: TransactionOperation) -> Result<(), String> {
-        let entry = WalEntry { txn_id, operation: op };
-        
-        // Serialize
-        let data = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
-        
-        // Format: [size: u32] [data: Vec<u8>]
-        if let Some(ref storage) = self.storage {
-            let size_bytes = (data.len() as u32).to_le_bytes();
-            storage.write(self.next_offset, &size_bytes)?;
-            self.next_offset += 4;
+        if let TransactionOperation::Write { ino, offset, data } = &op {
+            if data.len() > MAX_WRITE_CHUNK {
+                for (i, chunk) in data.chunks(MAX_WRITE_CHUNK).enumerate() {
+                    let operation = TransactionOperation::Write {
+                        ino: *ino,
+                        offset: offset + (i * MAX_WRITE_CHUNK) as u64,
+                        data: chunk.to_vec(),
+                    };
+                    self.append_entry(WalEntry { txn_id, operation })?;
+                }
+                return Ok(());
+            }
+        }
+        self.append_entry(WalEntry {
+            txn_id,
+            operation: op,
+        })
+    }
+
+    fn append_entry(&mut self, entry: WalEntry) -> Result<(), String> {
+        let data = entry.encode();
+        if data.len() > MAX_FRAME_SIZE as usize {
+            return Err(alloc::format!(
+                "WAL entry of {} bytes exceeds the frame size limit",
+                data.len()
+            ));
+        }
 
-            storage.write(self.next_offset, &data)?;
-            self.next_offset += data.len() as u64;
+        // Format: [magic: u32] [size: u32] [data: Vec<u8>] [crc: u32]
+        // The crc goes out last, so a frame torn by a crash never carries a matching one
+        if let Some(ref storage) = self.storage {
+            let mut frame = Vec::with_capacity(FRAME_HEADER as usize + data.len());
+            frame.extend_from_slice(&FRAME_MAGIC.to_le_bytes());
+            frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&data);
+            storage.write(self.next_offset, &frame)?;
+            storage.write(
+                self.next_offset + frame.len() as u64,
+                &crc32(&data).to_le_bytes(),
+            )?;
+            self.next_offset += frame.len() as u64 + FRAME_TRAILER;
         }
 
+        self.last_txn = self.last_txn.max(entry.txn_id);
         self.entries.push(entry);
         Ok(())
     }
@@ -63,42 +160,163 @@ impl WriteAheadLog {
         Ok(())
     }
 
-    pub fn recover(&mut self) -> Result<Vec<WalEntry>, String> {
+    /// Number of logged entries that have not been applied yet.
+    pub fn pending(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Capacity of the in-memory entry buffer.
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    /// Give back buffer memory left over from a burst of appends.
+    fn release(&mut self) {
+        let len = self.entries.len();
+        if self.entries.capacity() > RETAINED_CAPACITY && len * 4 <= self.entries.capacity() {
+            self.entries.shrink_to(RETAINED_CAPACITY.max(len * 2));
+        }
+    }
+
+    /// Current log statistics.
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            pending_entries: self.entries.len(),
+            // The log is truncated to zero on checkpoint, so everything before next_offset is live
+            bytes_on_disk: self.next_offset,
+            next_offset: self.next_offset,
+            last_checkpoint_txn: self.last_checkpoint_txn,
+        }
+    }
+
+    /// Remove and return the pending entries matching `pred`, in log order.
+    pub fn take_pending<F: Fn(&WalEntry) -> bool>(&mut self, pred: F) -> Vec<WalEntry> {
+        let (taken, kept) = core::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| pred(e));
+        self.entries = kept;
+        self.release();
+        taken
+    }
+
+    /// Remove and return the pending entries up to and including the last one matching
+    /// `pred`, in log order, so they can be applied without overtaking older entries.
+    pub fn take_through<F: Fn(&WalEntry) -> bool>(&mut self, pred: F) -> Vec<WalEntry> {
+        let Some(last) = self.entries.iter().rposition(|e| pred(e)) else {
+            return Vec::new();
+        };
+        let kept = self.entries.split_off(last + 1);
+        let taken = core::mem::replace(&mut self.entries, kept);
+        self.release();
+        taken
+    }
+
+    /// Put entries taken with `take_pending` back in front of the log, keeping their order.
+    pub fn requeue(&mut self, mut entries: Vec<WalEntry>) {
+        entries.append(&mut self.entries);
+        self.entries = entries;
+    }
+
+    /// Read back the logged entries.
+    ///
+    /// A position without the magic is the clean end of the log; zero-filled or stale bytes
+    /// behind it are left alone. A frame that has the magic but an empty size, cannot be read
+    /// in full, or fails its crc was torn by a crash mid-append: it is cut off the storage and
+    /// reported as [`RecoveryStatus::TruncatedTail`], keeping every frame before it. A complete
+    /// frame larger than [`MAX_FRAME_SIZE`], or one whose entry does not decode, can only be
+    /// corruption and is reported as an error, leaving the log untouched.
+    pub fn recover(&mut self) -> Result<(Vec<WalEntry>, RecoveryStatus), String> {
         let mut recovered = Vec::new();
         let mut offset = 0;
+        let mut status = RecoveryStatus::Clean;
 
         if let Some(ref storage) = self.storage {
             loop {
-                let mut size_buf = [0u8; 4];
-                // Try to read the size
-                if let Ok(_) = storage.read(offset, &mut size_buf) {
-                    let size = u32::from_le_bytes(size_buf);
-                    if size == 0 || size > 1024 * 1024 { // Sanity check
-                        break;
-                    }
-                    offset += 4;
-
-                    let mut data = alloc::vec![0u8; size as usize];
-                    if let Ok(_) = storage.read(offset, &mut data) {
-                        if let Ok(entry) = serde_json::from_slice::<WalEntry>(&data) {
-                            recovered.push(entry);
-                        }
-                        offset += size as u64;
-                    } else {
-                        break;
+                let mut header = [0u8; FRAME_HEADER as usize];
+                if storage.read(offset, &mut header).is_err() {
+                    break;
+                }
+                let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+                let size = u32::from_le_bytes(header[4..].try_into().unwrap());
+                if magic != FRAME_MAGIC {
+                    break;
+                }
+                if size == 0 {
+                    status = RecoveryStatus::TruncatedTail;
+                    break;
+                }
+                if size > MAX_FRAME_SIZE {
+                    // `append` never writes such a frame; if it is complete the log is corrupt
+                    if Self::frame_intact(storage.as_ref(), offset, size) {
+                        return Err(alloc::format!(
+                            "Corrupt WAL frame of {} bytes at offset {}",
+                            size,
+                            offset
+                        ));
                     }
-                } else {
+                    status = RecoveryStatus::TruncatedTail;
                     break;
                 }
+
+                let mut data = alloc::vec![0u8; size as usize];
+                let mut crc = [0u8; FRAME_TRAILER as usize];
+                let intact = storage.read(offset + FRAME_HEADER, &mut data).is_ok()
+                    && storage
+                        .read(offset + FRAME_HEADER + size as u64, &mut crc)
+                        .is_ok()
+                    && u32::from_le_bytes(crc) == crc32(&data);
+                if !intact {
+                    status = RecoveryStatus::TruncatedTail;
+                    break;
+                }
+                // The crc matched, so an entry that does not decode was written that way
+                let entry = WalEntry::decode(&data)
+                    .ok_or_else(|| alloc::format!("Corrupt WAL entry at offset {}", offset))?;
+                self.last_txn = self.last_txn.max(entry.txn_id);
+                recovered.push(entry);
+                offset += FRAME_HEADER + size as u64 + FRAME_TRAILER;
+            }
+
+            if status == RecoveryStatus::TruncatedTail {
+                storage.truncate(offset)?;
             }
         }
         self.next_offset = offset;
-        Ok(recovered)
+        Ok((recovered, status))
     }
-    
+
+    /// Whether the `size`-byte frame at `offset` can be read in full and matches its crc,
+    /// checked piecewise so an oversized frame is never held in memory.
+    fn frame_intact(storage: &dyn WalStorage, offset: u64, size: u32) -> bool {
+        let mut buf = alloc::vec![0u8; 64 * 1024];
+        let mut crc = 0xFFFF_FFFF;
+        let mut pos = 0u64;
+        while pos < size as u64 {
+            let len = buf.len().min((size as u64 - pos) as usize);
+            if storage
+                .read(offset + FRAME_HEADER + pos, &mut buf[..len])
+                .is_err()
+            {
+                return false;
+            }
+            crc = crc32_update(crc, &buf[..len]);
+            pos += len as u64;
+        }
+        let mut stored = [0u8; FRAME_TRAILER as usize];
+        storage
+            .read(offset + FRAME_HEADER + size as u64, &mut stored)
+            .is_ok()
+            && u32::from_le_bytes(stored) == !crc
+    }
+
+    /// Drop the entries of a transaction that has been applied.
+    ///
+    /// Entries leave memory as soon as their transaction is done, even while other
+    /// transactions keep the on-disk log from being truncated.
     pub fn clear_txn(&mut self, txn_id: u64) {
         self.entries.retain(|e| e.txn_id != txn_id);
-        
+        self.release();
+
         // If all transactions are cleared, we can checkpoint
         if self.entries.is_empty() {
             if let Err(e) = self.checkpoint() {
@@ -112,6 +330,7 @@ impl WriteAheadLog {
             storage.truncate(0)?;
             self.next_offset = 0;
         }
+        self.last_checkpoint_txn = self.last_txn;
         Ok(())
     }
 }